@@ -59,5 +59,24 @@
 #define SYS_print_cwd         52
 #define SYS_link              53
 #define SYS_unlink            54
+#define SYS_symlink           55
+#define SYS_readlink          56
+#define SYS_trapstat          57
+#define SYS_vfork             58
+#define SYS_access            59
+#define SYS_chmod             60
+#define SYS_mincore           61
+#define SYS_madvise           62
+#define SYS_uptime            63
+#define SYS_nanosleep         64
+#define SYS_pipe              65
+#define SYS_times             66
+#define SYS_fsync             67
+#define SYS_gettid            68
+#define SYS_rename            69
+#define SYS_truncate          70
+#define SYS_rmdir             71
+#define SYS_waitid            72
+#define SYS_sync              73
 
 #endif // GLENDA_SYSCALL_NUM_H
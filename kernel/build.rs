@@ -5,14 +5,21 @@ fn main() {
     println!("cargo:rerun-if-changed=src/asm/enter.S");
     println!("cargo:rerun-if-changed=src/asm/switch.S");
     println!("cargo:rerun-if-changed=src/asm/trampoline.S");
+    println!("cargo:rerun-if-changed=src/asm/uaccess.S");
     println!("cargo:rerun-if-changed=src/linker.ld");
-    cc::Build::new()
+    let mut build = cc::Build::new();
+    // 与 kernel 的 vectored-trap feature 对应, 决定是否汇编内核的中断向量表
+    if std::env::var_os("CARGO_FEATURE_VECTORED_TRAP").is_some() {
+        build.define("VECTORED_TRAP", None);
+    }
+    build
         .file("src/asm/boot.S")
         .file("src/asm/vector.S")
         .file("src/asm/sbi.S")
         .file("src/asm/enter.S")
         .file("src/asm/switch.S")
         .file("src/asm/trampoline.S")
+        .file("src/asm/uaccess.S")
         .flag("-march=rv64gc")
         .flag("-mabi=lp64d")
         .compile("boot");
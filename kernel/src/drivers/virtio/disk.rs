@@ -1,6 +1,6 @@
 use super::NUM_DESCS;
 use super::VRingDesc;
-use super::vring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+use super::vring::{self, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE, VRingUsedElem};
 use super::{VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SCSI};
 use super::{
     VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_DRIVER_OK,
@@ -14,20 +14,40 @@ use super::{
     VIRTIO_MMIO_QUEUE_PFN, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID,
     VIRTIO_MMIO_VERSION, VIRTIO_MMIO_QUEUE_ALIGN,
 };
-use super::{reg_read, reg_write};
+use super::{BASE, IRQ, probe_blk, reg_read, reg_write};
 use crate::mem::PGSIZE;
 use crate::mem::frame::PhysFrame;
 use crate::printk;
+use crate::proc::scheduler;
+use core::hint::spin_loop;
+use core::mem::size_of;
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering, fence};
 use riscv::register::sstatus;
 use spin::Mutex;
 
 struct Disk {
     pub pages: Option<PhysFrame>,
     pub init_done: bool,
+    free: u64,               // 空闲描述符位图, 第 i 位为 1 表示描述符 i 空闲
+    used_idx: u16,           // 已经收取到的 used 环位置
+    done: [bool; NUM_DESCS], // 以首描述符为下标: 设备已把该请求放进 used 环, 等待提交者收取
+    headers: [BlkOutHdr; NUM_DESCS],
+    status: [u8; NUM_DESCS],
 }
 
-static DISK: Mutex<Disk> = Mutex::new(Disk { pages: None, init_done: false });
+const _: () = assert!(NUM_DESCS <= 64 && vring::ring_size(NUM_DESCS) <= PGSIZE, "virtio ring must fit in one page");
+const ALL_FREE: u64 = if NUM_DESCS == 64 { !0 } else { (1 << NUM_DESCS) - 1 };
+
+static DISK: Mutex<Disk> = Mutex::new(Disk {
+    pages: None,
+    init_done: false,
+    free: ALL_FREE,
+    used_idx: 0,
+    done: [false; NUM_DESCS],
+    headers: [BlkOutHdr { _type: 0, reserved: 0, sector: 0 }; NUM_DESCS],
+    status: [0; NUM_DESCS],
+});
 
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -39,97 +59,225 @@ struct BlkOutHdr {
 
 const VIRTIO_BLK_T_IN: u32 = 0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
 
-struct DiskState {
-    headers: [BlkOutHdr; NUM_DESCS],
-    status: [u8; NUM_DESCS],
+// 开机以来提交的写请求数, 测试据此确认哪些写真正到达了磁盘
+static WRITE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(dead_code)]
+pub fn write_count() -> usize {
+    WRITE_COUNT.load(Ordering::Relaxed)
 }
 
-static DISK_STATE: Mutex<DiskState> = Mutex::new(DiskState {
-    headers: [BlkOutHdr { _type: 0, reserved: 0, sector: 0 }; NUM_DESCS],
-    status: [0; NUM_DESCS],
-});
+impl Disk {
+    fn ring(&self) -> usize {
+        self.pages.as_ref().expect("virtio not initialized").addr()
+    }
 
-pub fn rw(buf: *mut u8, blockno: u32, write: bool) {
-    // Disable interrupts to avoid deadlock with ISR
-    let sstatus_val = sstatus::read();
-    let sie_enabled = sstatus_val.sie();
-    unsafe {
-        sstatus::clear_sie();
+    fn desc(&self, i: usize) -> *mut VRingDesc {
+        (self.ring() as *mut VRingDesc).wrapping_add(i)
     }
 
-    let disk = DISK.lock();
-    let idx = 0;
+    // 取三个空闲描述符 (header, data, status), 不够时返回 None
+    fn alloc3(&mut self) -> Option<[usize; 3]> {
+        if self.free.count_ones() < 3 {
+            return None;
+        }
+        let mut idx = [0; 3];
+        for i in idx.iter_mut() {
+            *i = self.free.trailing_zeros() as usize;
+            self.free &= !(1 << *i);
+        }
+        Some(idx)
+    }
 
-    let sector = blockno as u64 * (PGSIZE as u64 / 512);
+    // 沿 next 链归还一个请求的全部描述符
+    fn free_chain(&mut self, head: usize) {
+        let mut i = head;
+        loop {
+            let (flags, next) = unsafe { ((*self.desc(i)).flags, (*self.desc(i)).next) };
+            assert!(self.free & (1 << i) == 0, "virtio: descriptor {} freed twice", i);
+            self.free |= 1 << i;
+            if flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            i = next as usize;
+        }
+    }
 
-    let mut state = DISK_STATE.lock();
-    state.headers[idx].sector = sector;
-    state.headers[idx]._type = if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN };
-    state.headers[idx].reserved = 0;
+    // 收取 used 环中新完成的请求, 只做标记, 描述符由各自的提交者释放; 返回这次收取到的首描述符位图
+    fn collect_used(&mut self) -> u64 {
+        let used = self.ring() + vring::used_offset(NUM_DESCS);
+        let dev_idx = unsafe { read_volatile((used + 2) as *const u16) };
+        fence(Ordering::SeqCst);
+        let mut finished = 0;
+        while self.used_idx != dev_idx {
+            let elem = (used + 4 + (self.used_idx as usize % NUM_DESCS) * 8) as *const VRingUsedElem;
+            let id = unsafe { read_volatile(&raw const (*elem).id) } as usize;
+            self.done[id] = true;
+            finished |= 1 << id;
+            self.used_idx = self.used_idx.wrapping_add(1);
+        }
+        finished
+    }
 
-    let head_pa = &state.headers[idx] as *const BlkOutHdr as u64;
-    let data_pa = buf as u64;
-    let status_pa = &state.status[idx] as *const u8 as u64;
+    // 请求已完成时检查设备写回的状态并归还描述符
+    fn take_done(&mut self, head: usize) -> Option<u8> {
+        if !self.done[head] {
+            return None;
+        }
+        self.done[head] = false;
+        let status = unsafe { read_volatile(&raw const self.status[head]) };
+        self.free_chain(head);
+        Some(status)
+    }
+}
 
-    let page = disk.pages.as_ref().expect("virtio not initialized").addr();
+// intr 也要获取 DISK, 持锁期间关闭本 hart 的中断, 避免中断处理程序在同一 hart 上等锁
+fn with_disk<R>(f: impl FnOnce(&mut Disk) -> R) -> R {
+    let sie_enabled = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+    let r = f(&mut DISK.lock());
+    if sie_enabled {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+    r
+}
+
+/// 已提交但还没有收取的请求, 以首描述符标识, 必须交给 wait
+#[must_use]
+pub struct Request {
+    head: usize,
+    blockno: u32,
+}
+
+// 描述符不够时原地重试: 占用描述符的请求都在各自提交者的 wait 中, 完成后就会归还
+pub fn submit(buf: *mut u8, blockno: u32, write: bool) -> Request {
+    if write {
+        WRITE_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    loop {
+        if let Some(head) = with_disk(|disk| try_submit(disk, buf, blockno, write)) {
+            return Request { head, blockno };
+        }
+        spin_loop();
+    }
+}
+
+fn try_submit(disk: &mut Disk, buf: *mut u8, blockno: u32, write: bool) -> Option<usize> {
+    let [head, data, status] = disk.alloc3()?;
 
-    let desc_ptr = page as *mut VRingDesc;
+    disk.headers[head] = BlkOutHdr {
+        _type: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
+        reserved: 0,
+        sector: blockno as u64 * (PGSIZE as u64 / 512),
+    };
+    disk.status[head] = 0xFF;
+    disk.done[head] = false;
+    let head_pa = &disk.headers[head] as *const BlkOutHdr as u64;
+    let status_pa = &disk.status[head] as *const u8 as u64;
 
     unsafe {
-        (*desc_ptr.add(0)).addr = head_pa;
-        (*desc_ptr.add(0)).len = 16;
-        (*desc_ptr.add(0)).flags = VRING_DESC_F_NEXT;
-        (*desc_ptr.add(0)).next = 1;
-
-        (*desc_ptr.add(1)).addr = data_pa;
-        (*desc_ptr.add(1)).len = PGSIZE as u32;
-        (*desc_ptr.add(1)).flags =
-            VRING_DESC_F_NEXT | (if !write { VRING_DESC_F_WRITE } else { 0 });
-        (*desc_ptr.add(1)).next = 2;
-
-        (*desc_ptr.add(2)).addr = status_pa;
-        (*desc_ptr.add(2)).len = 1;
-        (*desc_ptr.add(2)).flags = VRING_DESC_F_WRITE;
-        (*desc_ptr.add(2)).next = 0;
-
-        let avail_ptr = (page + 128) as *mut u8;
+        let d = disk.desc(head);
+        (*d).addr = head_pa;
+        (*d).len = size_of::<BlkOutHdr>() as u32;
+        (*d).flags = VRING_DESC_F_NEXT;
+        (*d).next = data as u16;
+
+        let d = disk.desc(data);
+        (*d).addr = buf as u64;
+        (*d).len = PGSIZE as u32;
+        (*d).flags = VRING_DESC_F_NEXT | if write { 0 } else { VRING_DESC_F_WRITE };
+        (*d).next = status as u16;
+
+        let d = disk.desc(status);
+        (*d).addr = status_pa;
+        (*d).len = 1;
+        (*d).flags = VRING_DESC_F_WRITE;
+        (*d).next = 0;
+
+        let avail_ptr = (disk.ring() + vring::avail_offset(NUM_DESCS)) as *mut u8;
         let avail_idx_ptr = avail_ptr.add(2) as *mut u16;
         let avail_ring_ptr = avail_ptr.add(4) as *mut u16;
 
         let idx_val = read_volatile(avail_idx_ptr);
-        write_volatile(avail_ring_ptr.add((idx_val % 8) as usize), 0);
+        write_volatile(avail_ring_ptr.add(idx_val as usize % NUM_DESCS), head as u16);
 
-        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        fence(Ordering::SeqCst);
 
         write_volatile(avail_idx_ptr, idx_val.wrapping_add(1));
 
         reg_write(VIRTIO_MMIO_QUEUE_NOTIFY, 0);
     }
+    Some(head)
+}
 
-    state.status[idx] = 0xFF; // In-progress
-    drop(state);
-    drop(disk);
+// 每个在飞请求一个睡眠通道
+fn request_chan(head: usize) -> usize {
+    &DISK as *const _ as usize + head
+}
 
-    if sie_enabled {
-        unsafe {
-            sstatus::set_sie();
+// 等待该请求完成, 其他请求可以同时在飞: 有进程时睡眠, 由 intr 收取 used 环后唤醒;
+// 启动阶段没有进程可以睡眠, 轮询 used 环; 调用者不能持有任何自旋锁 (见 fs::buffer 的锁规则)
+pub fn wait(req: Request) {
+    loop {
+        let (status, finished) = with_disk(|disk| {
+            let finished = disk.collect_used();
+            (disk.take_done(req.head), finished)
+        });
+        // 顺带收取到的其他请求可能有人在睡眠等待, intr 不会再看到它们
+        wake_finished(finished & !(1 << req.head));
+        match status {
+            Some(VIRTIO_BLK_S_OK) => return,
+            Some(s) => panic!("virtio: request for block {} failed with status {}", req.blockno, s),
+            None => {}
+        }
+        if crate::hart::get().proc.is_null() {
+            spin_loop();
+        } else {
+            // 在 PROC_TABLE 锁内复查, 收取者先标记完成再 wakeup, 不会错过唤醒
+            scheduler::sleep_unless(request_chan(req.head), || with_disk(|disk| disk.done[req.head]));
         }
     }
+}
 
-    loop {
-        let state = DISK_STATE.lock();
-        if state.status[idx] != 0xFF {
-            break;
+pub fn rw(buf: *mut u8, blockno: u32, write: bool) {
+    wait(submit(buf, blockno, write));
+}
+
+// 应答中断并收取 used 环, 唤醒在新完成的请求上睡眠的进程; 中断处理程序中调用, 此时中断已关闭
+pub fn intr() {
+    let finished = {
+        let mut disk = DISK.lock();
+        let status = reg_read(VIRTIO_MMIO_INTERRUPT_STATUS);
+        reg_write(VIRTIO_MMIO_INTERRUPT_ACK, status & 0x3);
+        if !disk.init_done {
+            return;
+        }
+        disk.collect_used()
+    };
+    wake_finished(finished);
+}
+
+// 谁收取了 used 环谁负责唤醒, 必须在放开 DISK 之后调用
+fn wake_finished(finished: u64) {
+    for head in 0..NUM_DESCS {
+        if finished & (1 << head) != 0 {
+            scheduler::wakeup(request_chan(head));
         }
-        drop(state);
     }
 }
 
-pub fn intr() {
-    let _disk = DISK.lock();
-    let status = reg_read(VIRTIO_MMIO_INTERRUPT_STATUS);
-    reg_write(VIRTIO_MMIO_INTERRUPT_ACK, status & 0x3);
+// 描述符表、avail 环和按 16 字节对齐的 used 环放在同一页 (见 vring::ring_size)
+const RING_PAGES: usize = 1;
+
+// 分配 virtio 环使用的 DMA 页, 内存不足时返回 Err, 由调用者报告
+pub(crate) fn alloc_ring() -> Result<PhysFrame, ()> {
+    PhysFrame::alloc().ok_or(())
 }
 
 pub fn init() {
@@ -138,7 +286,13 @@ pub fn init() {
         return;
     }
 
-    if reg_read(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+    let (base, irq) = probe_blk().unwrap_or_else(|| panic!("VirtIO: no block device found"));
+    BASE.store(base, Ordering::Relaxed);
+    IRQ.store(irq, Ordering::Relaxed);
+    crate::irq::enable_external(irq);
+    printk!("VirtIO: block device @ {:#x}, irq {}\n", base, irq);
+
+    if reg_read(VIRTIO_MMIO_MAGIC_VALUE) != super::VIRTIO_MAGIC
         || reg_read(VIRTIO_MMIO_VERSION) != 1
         || reg_read(VIRTIO_MMIO_DEVICE_ID) != 2
         || reg_read(VIRTIO_MMIO_VENDOR_ID) != 0x554d4551
@@ -184,9 +338,10 @@ pub fn init() {
 
     reg_write(VIRTIO_MMIO_QUEUE_NUM, NUM_DESCS as u32);
 
-    // Desc (16*8=128) + Avail (6+2*8=22) + Pad -> 4096 -> Used (6+8*8=70)
-    // We set alignment to 16, so everything fits in 1 page.
-    let frame = PhysFrame::alloc().expect("VirtIO: failed to alloc page");
+    let frame = match alloc_ring() {
+        Ok(frame) => frame,
+        Err(()) => panic!("virtio: failed to allocate DMA ring (need {} page)", RING_PAGES),
+    };
     let page = frame.addr();
 
     disk.pages = Some(frame);
@@ -194,8 +349,7 @@ pub fn init() {
     // Setup Legacy Registers
     reg_write(VIRTIO_MMIO_GUEST_PAGE_SIZE, PGSIZE as u32);
     reg_write(VIRTIO_MMIO_QUEUE_PFN, (page / PGSIZE) as u32);
-    // Set alignment to 16 bytes
-    reg_write(VIRTIO_MMIO_QUEUE_ALIGN, 16);
+    reg_write(VIRTIO_MMIO_QUEUE_ALIGN, vring::QUEUE_ALIGN as u32);
 
     status |= VIRTIO_CONFIG_S_DRIVER_OK;
     reg_write(VIRTIO_MMIO_STATUS, status);
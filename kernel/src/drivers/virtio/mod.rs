@@ -3,7 +3,9 @@ pub mod vring;
 
 pub use vring::VRingDesc;
 
+use crate::dtb::{self, MemoryRange};
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 // VirtIO MMIO register offsets
 const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
@@ -38,17 +40,51 @@ const VIRTIO_F_ANY_LAYOUT: u64 = 1 << 27;
 const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
 const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
 
-const NUM_DESCS: usize = 8; // Ring size
+const NUM_DESCS: usize = 32; // Ring size, 每个请求占 3 个描述符
 
-// MMIO Base Address
+// QEMU virt 的第一个 virtio-mmio 槽位, 设备树没有列出任何槽位时使用
 const VIRTIO0: usize = 0x10001000;
+const FALLBACK_MMIO: [MemoryRange; 1] = [MemoryRange { start: VIRTIO0, size: 0x1000 }];
+
+// VIRTIO0 在 QEMU virt 上的 PLIC 中断号, 设备树没有给出中断号时使用
+const VIRTIO0_IRQ: usize = 1;
+
+const VIRTIO_MAGIC: u32 = 0x74726976; // "virt"
+const VIRTIO_DEVICE_BLK: u32 = 2;
+
+// 块设备所在槽位的基址和中断号, disk::init 探测后设置
+static BASE: AtomicUsize = AtomicUsize::new(VIRTIO0);
+static IRQ: AtomicUsize = AtomicUsize::new(VIRTIO0_IRQ);
+
+pub fn irq() -> usize {
+    IRQ.load(Ordering::Relaxed)
+}
+
+// 需要映射并探测的 virtio-mmio 槽位
+pub fn mmio_regions() -> &'static [MemoryRange] {
+    let regions = dtb::virtio_mmio();
+    if regions.is_empty() { &FALLBACK_MMIO } else { regions }
+}
+
+fn read_at(base: usize, offset: usize) -> u32 {
+    unsafe { read_volatile((base + offset) as *const u32) }
+}
+
+// 逐个槽位检查 magic 和 device id: 没有挂设备的槽位 device id 为 0; 返回基址和中断号
+fn probe_blk() -> Option<(usize, usize)> {
+    let (i, r) = mmio_regions().iter().enumerate().find(|(_, r)| {
+        read_at(r.start, VIRTIO_MMIO_MAGIC_VALUE) == VIRTIO_MAGIC
+            && read_at(r.start, VIRTIO_MMIO_DEVICE_ID) == VIRTIO_DEVICE_BLK
+    })?;
+    Some((r.start, dtb::virtio_irq(i).unwrap_or(VIRTIO0_IRQ)))
+}
 
 fn reg_read(offset: usize) -> u32 {
-    unsafe { read_volatile((VIRTIO0 + offset) as *const u32) }
+    read_at(BASE.load(Ordering::Relaxed), offset)
 }
 
 fn reg_write(offset: usize, val: u32) {
-    unsafe { write_volatile((VIRTIO0 + offset) as *mut u32, val) }
+    unsafe { write_volatile((BASE.load(Ordering::Relaxed) + offset) as *mut u32, val) }
 }
 
 pub fn init() {
@@ -10,8 +10,24 @@ pub struct VRingDesc {
 #[repr(C)]
 #[repr(align(4))]
 pub struct VRingUsedElem {
-    id: u32,
-    len: u32,
+    pub id: u32, // 请求的首描述符
+    pub len: u32,
+}
+
+// legacy 布局: 描述符表后紧跟 avail 环 (flags, idx, ring[n], used_event),
+// used 环 (flags, idx, ring[n], avail_event) 从 QUEUE_ALIGN 对齐处开始
+pub const QUEUE_ALIGN: usize = 16;
+
+pub const fn avail_offset(n: usize) -> usize {
+    16 * n
+}
+
+pub const fn used_offset(n: usize) -> usize {
+    (avail_offset(n) + 6 + 2 * n).next_multiple_of(QUEUE_ALIGN)
+}
+
+pub const fn ring_size(n: usize) -> usize {
+    used_offset(n) + 6 + 8 * n
 }
 
 // Descriptor flags
@@ -8,14 +8,18 @@ use crate::printk;
 use fdt::Fdt;
 use spin::Once;
 
-pub use types::{DeviceTreeInfo, MemoryRange};
+pub use types::{DeviceTreeInfo, MAX_MEMORY, MemoryRange};
 
 static DEVICE_TREE: Once<DeviceTreeInfo> = Once::new();
 
-fn _init(dtb: *const u8) -> Result<&'static DeviceTreeInfo, fdt::FdtError> {
+// 只解析不保存, 测试可以用它检查手工构造的设备树
+pub fn parse(dtb: *const u8) -> Result<DeviceTreeInfo, fdt::FdtError> {
     let fdt = unsafe { Fdt::from_ptr(dtb)? };
-    let info = parser::parse_device_tree(&fdt);
-    
+    Ok(parser::parse_device_tree(&fdt, dtb))
+}
+
+fn _init(dtb: *const u8) -> Result<&'static DeviceTreeInfo, fdt::FdtError> {
+    let info = parse(dtb)?;
     Ok(DEVICE_TREE.call_once(|| info))
 }
 
@@ -23,6 +27,14 @@ pub fn hart_count() -> usize {
     DEVICE_TREE.get().map(DeviceTreeInfo::hart_count).unwrap_or(1)
 }
 
+// QEMU virt 的 timebase-frequency, 设备树没有给出时沿用
+pub const DEFAULT_TIMEBASE_FREQ: u64 = 10_000_000;
+
+// time CSR 每秒的计数
+pub fn timebase_frequency() -> u64 {
+    DEVICE_TREE.get().and_then(DeviceTreeInfo::timebase_frequency).unwrap_or(DEFAULT_TIMEBASE_FREQ)
+}
+
 pub fn uart_config() -> Option<UartConfig> {
     DEVICE_TREE.get().and_then(DeviceTreeInfo::uart)
 }
@@ -31,10 +43,50 @@ pub fn memory_range() -> Option<MemoryRange> {
     DEVICE_TREE.get().and_then(DeviceTreeInfo::memory)
 }
 
+// 设备树描述的全部物理内存段, 按出现顺序
+pub fn memory_regions() -> &'static [MemoryRange] {
+    DEVICE_TREE.get().map(DeviceTreeInfo::memory_regions).unwrap_or(&[])
+}
+
 pub fn plic_base() -> Option<usize> {
     DEVICE_TREE.get().and_then(DeviceTreeInfo::plic_base)
 }
 
+// 设备树列出的 virtio-mmio 槽位, 按出现顺序
+pub fn virtio_mmio() -> &'static [MemoryRange] {
+    DEVICE_TREE.get().map(DeviceTreeInfo::virtio_mmio).unwrap_or(&[])
+}
+
+pub fn virtio_irq(i: usize) -> Option<usize> {
+    DEVICE_TREE.get().and_then(|info| info.virtio_irq(i))
+}
+
+// 设备树 blob 本身所在的物理内存
+pub fn dtb_range() -> Option<MemoryRange> {
+    DEVICE_TREE.get().map(DeviceTreeInfo::blob)
+}
+
+pub fn initrd_range() -> Option<MemoryRange> {
+    DEVICE_TREE.get().and_then(DeviceTreeInfo::initrd)
+}
+
+// /memreserve/ 描述的保留区 (通常是固件)
+pub fn memory_reservations() -> &'static [MemoryRange] {
+    DEVICE_TREE.get().map(DeviceTreeInfo::reserved).unwrap_or(&[])
+}
+
+pub fn bootargs() -> &'static str {
+    DEVICE_TREE.get().map(DeviceTreeInfo::bootargs).unwrap_or("")
+}
+
+// 在 bootargs 中查找 "key=数值", 数值按十进制解析
+pub fn bootarg(key: &str) -> Option<usize> {
+    bootargs().split_ascii_whitespace().find_map(|arg| {
+        let (k, v) = arg.split_once('=')?;
+        if k == key { v.parse().ok() } else { None }
+    })
+}
+
 pub fn init(dtb: *const u8) {
     // 解析设备树
     let dtb_result = _init(dtb);
@@ -42,6 +94,7 @@ pub fn init(dtb: *const u8) {
         Ok(_) => {
             printk!("Device tree blob at {:p}\n", dtb);
             printk!("{} harts detected\n", hart_count());
+            printk!("timebase frequency {} Hz\n", timebase_frequency());
         }
         Err(err) => {
             panic!("Device tree parsing failed: {:?}\n", err);
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use crate::drivers::uart::Config as UartConfig;
-use super::types::{DeviceTreeInfo, MemoryRange};
+use super::types::{DeviceTreeInfo, MAX_BOOTARGS, MAX_MEMORY, MAX_RESERVED, MAX_VIRTIO_MMIO, MemoryRange};
 use fdt::Fdt;
 
 pub fn parse_uart(fdt: &Fdt) -> Option<UartConfig> {
@@ -30,13 +30,25 @@ pub fn parse_hart_count(fdt: &Fdt) -> usize {
     core::cmp::max(count, 1)
 }
 
-pub fn parse_memory(fdt: &Fdt) -> Option<MemoryRange> {
-    let memory = fdt.memory();
-    let mut regions = memory.regions();
-    regions.find_map(|region| {
-        let start = region.starting_address as usize;
-        region.size.map(|size| MemoryRange { start, size })
-    })
+// 所有 device_type = "memory" 的节点, 每个节点可以有多个 reg 条目 (分段的内存)
+pub fn parse_memory(fdt: &Fdt) -> ([MemoryRange; MAX_MEMORY], usize) {
+    let mut out = [MemoryRange { start: 0, size: 0 }; MAX_MEMORY];
+    let mut n = 0;
+    let nodes = fdt.all_nodes().filter(|node| {
+        node.property("device_type").and_then(|prop| prop.as_str()) == Some("memory")
+    });
+    for node in nodes {
+        let Some(regs) = node.reg() else { continue };
+        for region in regs {
+            let Some(size) = region.size.filter(|&size| size > 0) else { continue };
+            if n == MAX_MEMORY {
+                return (out, n);
+            }
+            out[n] = MemoryRange { start: region.starting_address as usize, size };
+            n += 1;
+        }
+    }
+    (out, n)
 }
 
 pub fn parse_plic_base(fdt: &Fdt) -> Option<usize> {
@@ -57,11 +69,96 @@ pub fn parse_plic_base(fdt: &Fdt) -> Option<usize> {
     None
 }
 
-pub fn parse_device_tree(fdt: &Fdt) -> DeviceTreeInfo {
+// compatible = "virtio,mmio" 的节点, 不区分设备类型 (没有挂设备的槽位也在其中), 由驱动探测
+pub fn parse_virtio_mmio(fdt: &Fdt) -> ([MemoryRange; MAX_VIRTIO_MMIO], [usize; MAX_VIRTIO_MMIO], usize) {
+    let mut out = [MemoryRange { start: 0, size: 0 }; MAX_VIRTIO_MMIO];
+    let mut irqs = [0; MAX_VIRTIO_MMIO];
+    let mut n = 0;
+    for node in fdt.all_nodes() {
+        let is_virtio = node.compatible().map(|c| c.all().any(|s| s == "virtio,mmio")).unwrap_or(false);
+        if !is_virtio {
+            continue;
+        }
+        let Some(region) = node.reg().and_then(|mut regs| regs.next()) else { continue };
+        if n == MAX_VIRTIO_MMIO {
+            break;
+        }
+        out[n] = MemoryRange { start: region.starting_address as usize, size: region.size.unwrap_or(0) };
+        irqs[n] = node.interrupts().and_then(|mut it| it.next()).unwrap_or(0);
+        n += 1;
+    }
+    (out, irqs, n)
+}
+
+// time CSR 的计数频率, 通常写在 /cpus 上, 个别平台只写在各个 cpu 节点上
+pub fn parse_timebase_frequency(fdt: &Fdt) -> Option<u64> {
+    let from_cpus = fdt.find_node("/cpus").and_then(|cpus| cpus.property("timebase-frequency"));
+    let prop = from_cpus.or_else(|| fdt.cpus().find_map(|cpu| cpu.property("timebase-frequency")))?;
+    let freq = prop.as_usize()? as u64;
+    if freq == 0 { None } else { Some(freq) }
+}
+
+pub fn parse_initrd(fdt: &Fdt) -> Option<MemoryRange> {
+    let chosen = fdt.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+    if end <= start {
+        return None;
+    }
+    Some(MemoryRange { start, size: end - start })
+}
+
+pub fn parse_reservations(fdt: &Fdt) -> ([MemoryRange; MAX_RESERVED], usize) {
+    let mut out = [MemoryRange { start: 0, size: 0 }; MAX_RESERVED];
+    let mut n = 0;
+    for rsv in fdt.memory_reservations() {
+        if n == MAX_RESERVED {
+            break;
+        }
+        if rsv.size() == 0 {
+            continue;
+        }
+        out[n] = MemoryRange { start: rsv.address() as usize, size: rsv.size() };
+        n += 1;
+    }
+    (out, n)
+}
+
+// QEMU 的 -append 写入 /chosen/bootargs
+pub fn parse_bootargs(fdt: &Fdt) -> ([u8; MAX_BOOTARGS], usize) {
+    let mut out = [0u8; MAX_BOOTARGS];
+    let args = fdt
+        .find_node("/chosen")
+        .and_then(|chosen| chosen.property("bootargs"))
+        .and_then(|prop| prop.as_str())
+        .unwrap_or("");
+    let n = core::cmp::min(args.len(), MAX_BOOTARGS);
+    out[..n].copy_from_slice(&args.as_bytes()[..n]);
+    (out, n)
+}
+
+pub fn parse_device_tree(fdt: &Fdt, blob: *const u8) -> DeviceTreeInfo {
     let hart_count = parse_hart_count(fdt);
+    let timebase_frequency = parse_timebase_frequency(fdt);
     let uart = parse_uart(fdt);
     let memory = parse_memory(fdt);
     let plic_base = parse_plic_base(fdt);
+    let virtio_mmio = parse_virtio_mmio(fdt);
+    let blob = MemoryRange { start: blob as usize, size: fdt.total_size() };
+    let initrd = parse_initrd(fdt);
+    let reserved = parse_reservations(fdt);
+    let bootargs = parse_bootargs(fdt);
 
-    DeviceTreeInfo::new(uart, hart_count, memory, plic_base)
+    DeviceTreeInfo::new(
+        uart,
+        hart_count,
+        timebase_frequency,
+        memory,
+        plic_base,
+        virtio_mmio,
+        blob,
+        initrd,
+        reserved,
+        bootargs,
+    )
 }
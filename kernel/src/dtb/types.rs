@@ -15,22 +15,64 @@ impl MemoryRange {
     }
 }
 
+// 内存节点 (含同一节点的多个 reg 条目) 上限, 超出部分忽略
+pub const MAX_MEMORY: usize = 4;
+// virtio-mmio 槽位上限 (QEMU virt 有 8 个)
+pub const MAX_VIRTIO_MMIO: usize = 8;
+// /memreserve/ 条目上限, 超出部分忽略
+pub const MAX_RESERVED: usize = 8;
+// /chosen/bootargs 长度上限, 超出部分截断
+pub const MAX_BOOTARGS: usize = 128;
+
 #[derive(Debug, Clone, Copy)]
 pub struct DeviceTreeInfo {
     uart: Option<UartConfig>,
     hart_count: usize,
-    memory: Option<MemoryRange>,
+    timebase_frequency: Option<u64>,
+    memory: [MemoryRange; MAX_MEMORY],
+    nmemory: usize,
     plic_base: Option<usize>,
+    virtio_mmio: [MemoryRange; MAX_VIRTIO_MMIO],
+    virtio_irqs: [usize; MAX_VIRTIO_MMIO], // 与 virtio_mmio 一一对应, 0 表示节点没有给出中断号
+    nvirtio_mmio: usize,
+    blob: MemoryRange,
+    initrd: Option<MemoryRange>,
+    reserved: [MemoryRange; MAX_RESERVED],
+    nreserved: usize,
+    bootargs: [u8; MAX_BOOTARGS],
+    bootargs_len: usize,
 }
 
 impl DeviceTreeInfo {
     pub(crate) fn new(
         uart: Option<UartConfig>,
         hart_count: usize,
-        memory: Option<MemoryRange>,
+        timebase_frequency: Option<u64>,
+        (memory, nmemory): ([MemoryRange; MAX_MEMORY], usize),
         plic_base: Option<usize>,
+        (virtio_mmio, virtio_irqs, nvirtio_mmio): ([MemoryRange; MAX_VIRTIO_MMIO], [usize; MAX_VIRTIO_MMIO], usize),
+        blob: MemoryRange,
+        initrd: Option<MemoryRange>,
+        (reserved, nreserved): ([MemoryRange; MAX_RESERVED], usize),
+        (bootargs, bootargs_len): ([u8; MAX_BOOTARGS], usize),
     ) -> Self {
-        Self { uart, hart_count, memory, plic_base }
+        Self {
+            uart,
+            hart_count,
+            timebase_frequency,
+            memory,
+            nmemory,
+            plic_base,
+            virtio_mmio,
+            virtio_irqs,
+            nvirtio_mmio,
+            blob,
+            initrd,
+            reserved,
+            nreserved,
+            bootargs,
+            bootargs_len,
+        }
     }
 
     pub fn uart(&self) -> Option<UartConfig> {
@@ -41,11 +83,45 @@ impl DeviceTreeInfo {
         cmp::max(self.hart_count, 1)
     }
 
+    pub fn timebase_frequency(&self) -> Option<u64> {
+        self.timebase_frequency
+    }
+
+    // 第一段内存, 只关心单段内存的调用者使用
     pub fn memory(&self) -> Option<MemoryRange> {
-        self.memory
+        self.memory_regions().first().copied()
+    }
+
+    pub fn memory_regions(&self) -> &[MemoryRange] {
+        &self.memory[..self.nmemory]
     }
 
     pub fn plic_base(&self) -> Option<usize> {
         self.plic_base
     }
+
+    pub fn virtio_mmio(&self) -> &[MemoryRange] {
+        &self.virtio_mmio[..self.nvirtio_mmio]
+    }
+
+    // 第 i 个 virtio-mmio 槽位在 PLIC 上的中断号
+    pub fn virtio_irq(&self, i: usize) -> Option<usize> {
+        self.virtio_irqs[..self.nvirtio_mmio].get(i).copied().filter(|&irq| irq != 0)
+    }
+
+    pub fn blob(&self) -> MemoryRange {
+        self.blob
+    }
+
+    pub fn initrd(&self) -> Option<MemoryRange> {
+        self.initrd
+    }
+
+    pub fn reserved(&self) -> &[MemoryRange] {
+        &self.reserved[..self.nreserved]
+    }
+
+    pub fn bootargs(&self) -> &str {
+        core::str::from_utf8(&self.bootargs[..self.bootargs_len]).unwrap_or("")
+    }
 }
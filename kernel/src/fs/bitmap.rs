@@ -2,71 +2,106 @@
 
 use crate::fs::buffer;
 use crate::fs::buffer::BLOCK_SIZE;
-use crate::fs::fs::get_sb;
+use crate::fs::fs::{SuperBlock, get_sb};
 
 // Allocate a block from the data bitmap
+// 位图可能跨多个块, 第 k 块记录 [k * BITS_PER_BLOCK, (k + 1) * BITS_PER_BLOCK) 号数据块
 pub fn alloc() -> u32 {
     let sb = get_sb();
-    let bmap_start = sb.bmap_start;
-
-    let b = buffer::read(0, bmap_start);
-    let data = buffer::get_data_ptr(b);
-
     let total_data_blocks = sb.nblocks;
 
-    for i in 0..BLOCK_SIZE {
-        let byte = unsafe { *data.add(i) };
-        if byte != 0xFF {
-            for j in 0..8 {
-                if (byte & (1 << j)) == 0 {
-                    // Found free bit
-                    let bit_idx = i * 8 + j;
-                    if bit_idx as u32 >= total_data_blocks {
+    for k in 0..sb.bmap_blocks() {
+        let b = buffer::read(0, sb.bmap_start + k);
+        let data = buffer::get_data_ptr(b);
+
+        for i in 0..BLOCK_SIZE {
+            let byte = unsafe { *data.add(i) };
+            if byte != 0xFF {
+                for j in 0..8 {
+                    if (byte & (1 << j)) == 0 {
+                        // Found free bit
+                        let bit_idx = k * SuperBlock::BITS_PER_BLOCK + (i * 8 + j) as u32;
+                        if bit_idx >= total_data_blocks {
+                            buffer::release(b);
+                            panic!("buffer_alloc: out of blocks");
+                        }
+
+                        unsafe {
+                            *data.add(i) |= 1 << j;
+                        }
+
+                        buffer::write(b);
                         buffer::release(b);
-                        panic!("buffer_alloc: out of blocks");
-                    }
-
-                    unsafe {
-                        *data.add(i) |= 1 << j;
-                    }
 
-                    buffer::write(b);
-                    buffer::release(b);
+                        // Zero the allocated block
+                        let abs_block = sb.data_start() + bit_idx;
 
-                    // Zero the allocated block
-                    let data_start = bmap_start + 1;
-                    let abs_block = data_start + bit_idx as u32;
+                        let zero_buf = buffer::read(0, abs_block);
+                        let zero_ptr = buffer::get_data_ptr(zero_buf);
+                        unsafe {
+                            core::ptr::write_bytes(zero_ptr, 0, BLOCK_SIZE);
+                        }
+                        buffer::write(zero_buf);
+                        buffer::release(zero_buf);
 
-                    let zero_buf = buffer::read(0, abs_block);
-                    let zero_ptr = buffer::get_data_ptr(zero_buf);
-                    unsafe {
-                        core::ptr::write_bytes(zero_ptr, 0, BLOCK_SIZE);
+                        return abs_block;
                     }
-                    buffer::write(zero_buf);
-                    buffer::release(zero_buf);
-
-                    return abs_block;
                 }
             }
         }
+        buffer::release(b);
     }
 
-    buffer::release(b);
     panic!("buffer_alloc: out of blocks");
 }
 
+// 位图中已分配的数据块数
+pub fn count_allocated() -> u32 {
+    let sb = get_sb();
+    let mut n = 0;
+    for k in 0..sb.bmap_blocks() {
+        let b = buffer::read(0, sb.bmap_start + k);
+        let data = buffer::get_data_ptr(b);
+        let first = k * SuperBlock::BITS_PER_BLOCK;
+        let bits = sb.nblocks.saturating_sub(first).min(SuperBlock::BITS_PER_BLOCK) as usize;
+        for i in 0..bits {
+            if unsafe { *data.add(i / 8) } & (1 << (i % 8)) != 0 {
+                n += 1;
+            }
+        }
+        buffer::release(b);
+    }
+    n
+}
+
+// 数据块 block_no 在位图中是否已分配, 不在数据区的块返回 false
+pub fn is_allocated(block_no: u32) -> bool {
+    let sb = get_sb();
+    let data_start = sb.data_start();
+    if block_no < data_start || block_no >= data_start + sb.nblocks {
+        return false;
+    }
+    let bit_idx = block_no - data_start;
+    let b = buffer::read(0, sb.bmap_start + bit_idx / SuperBlock::BITS_PER_BLOCK);
+    let bit_idx = (bit_idx % SuperBlock::BITS_PER_BLOCK) as usize;
+    let byte = unsafe { *buffer::get_data_ptr(b).add(bit_idx / 8) };
+    buffer::release(b);
+    byte & (1 << (bit_idx % 8)) != 0
+}
+
 pub fn free(block_no: u32) {
     let sb = get_sb();
-    let bmap_start = sb.bmap_start;
-    let data_start = bmap_start + 1;
+    let data_start = sb.data_start();
 
     if block_no < data_start || block_no >= data_start + sb.nblocks {
         panic!("buffer_free: block out of data range");
     }
 
-    let bit_idx = (block_no - data_start) as usize;
+    let bit_idx = block_no - data_start;
+    let bmap_block = sb.bmap_start + bit_idx / SuperBlock::BITS_PER_BLOCK;
+    let bit_idx = (bit_idx % SuperBlock::BITS_PER_BLOCK) as usize;
 
-    let b = buffer::read(0, bmap_start);
+    let b = buffer::read(0, bmap_block);
     let data = buffer::get_data_ptr(b);
 
     let byte_idx = bit_idx / 8;
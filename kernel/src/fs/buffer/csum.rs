@@ -0,0 +1,256 @@
+//! 可选的块校验和
+//!
+//! 每次把缓冲区写到磁盘前记录整块的 CRC32, 从磁盘读入后重新计算并比较,
+//! 用来发现写路径的 bug 或磁盘内容被改动。默认关闭, 启动参数 blkcsum=1 打开。
+//! 表按块号索引, 每项 8 字节: 低 32 位是 CRC, VALID 位表示有记录 (CRC 本身可能为 0)。
+//! 镜像带校验和区 (超级块 csum_start 不为 0) 时, 打开时从磁盘读入整张表, save 把改过的页写回,
+//! 因此重启后仍能发现损坏; 没有校验和区的旧镜像只在本次启动内校验。
+//! 校验和区第 0 项对应超级块 (超级块不校验), 改存 AREA_SYNCED 表示磁盘上的表与数据一致:
+//! 表写回之后的第一次记录先把它清掉, 断电后下次打开会丢弃整张表而不是误报。
+//! 打开之后 disable 只停止核对, 记录照常进行, 再次打开时表仍然可信。
+//! TABLE 是叶子锁, 持有它时不会再获取其他锁, 也不做磁盘 I/O。
+
+use crate::mem::pmem;
+use crate::mem::slab::Slab;
+use crate::printk;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use super::{BLOCK_SIZE, BlockNo};
+
+const VALID: u64 = 1 << 32;
+const AREA_SYNCED: u64 = 0x434e_5953_4d55_5343; // "CSUMSYNC"
+// 校验和区每块的表项数
+const PER_BLOCK: usize = BLOCK_SIZE / 8;
+
+struct Table {
+    crcs: Slab<u64>,
+    dirty: Slab<bool>, // 按校验和区的块记录, 写回前为 true
+    start: BlockNo,    // 校验和区起始块号, 0 表示镜像没有校验和区
+    synced: bool,      // 磁盘上的 AREA_SYNCED 是否有效
+    records: usize,    // 记录次数, save 据此判断写回期间表是否又被改过
+}
+
+// 表已分配并读入; 之后 record 照常记录
+static READY: AtomicBool = AtomicBool::new(false);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TABLE: Mutex<Table> = Mutex::new(Table {
+    crcs: Slab::empty(),
+    dirty: Slab::empty(),
+    start: 0,
+    synced: false,
+    records: 0,
+});
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+// IEEE 802.3 CRC32 (与 zlib 的 crc32 相同)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc = CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn block_crc(data: *const u8) -> u32 {
+    crc32(unsafe { core::slice::from_raw_parts(data, BLOCK_SIZE) })
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+// 打开校验: 表只在第一次打开时分配, 大小取当时的块数; 镜像有校验和区时从磁盘读入
+pub fn enable(nblocks: usize, start: BlockNo) {
+    let fresh = {
+        let mut table = TABLE.lock();
+        let fresh = table.crcs.len() == 0;
+        if fresh {
+            let len = nblocks.min(Slab::<u64>::MAX_LEN);
+            table.crcs.init(len, || 0);
+            table.dirty.init(len.div_ceil(PER_BLOCK), || false);
+            table.start = start;
+        }
+        fresh
+    };
+    if fresh {
+        if start != 0 {
+            load();
+        }
+        READY.store(true, Ordering::Release);
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+// 第 page 页表项在内存与 scratch 之间拷贝, 调用者持有 TABLE
+fn copy_page(table: &mut Table, page: usize, scratch: *mut u64, to_disk: bool) {
+    let first = page * PER_BLOCK;
+    for i in 0..PER_BLOCK.min(table.crcs.len() - first) {
+        unsafe {
+            if to_disk {
+                *scratch.add(i) = table.crcs[first + i];
+            } else {
+                table.crcs[first + i] = *scratch.add(i);
+            }
+        }
+    }
+}
+
+// 读入校验和区; 上次没有正常写回 (没有 AREA_SYNCED) 时丢弃全部记录
+fn load() {
+    let scratch = pmem::alloc(true);
+    let (start, pages) = {
+        let table = TABLE.lock();
+        (table.start, table.dirty.len())
+    };
+    let mut synced = false;
+    for page in 0..pages {
+        super::disk_rw(scratch, start + page as BlockNo, false);
+        let mut table = TABLE.lock();
+        copy_page(&mut table, page, scratch as *mut u64, false);
+        if page == 0 {
+            synced = table.crcs[0] == AREA_SYNCED;
+            table.crcs[0] = 0;
+        }
+    }
+
+    let mut table = TABLE.lock();
+    if !synced {
+        for i in 0..table.crcs.len() {
+            table.crcs[i] = 0;
+        }
+        for page in 0..pages {
+            table.dirty[page] = true;
+        }
+    }
+    table.synced = synced;
+    drop(table);
+    if !synced {
+        printk!("buffer: checksum area at block {} is stale, starting over\n", start);
+    }
+    pmem::free(scratch as usize, true);
+}
+
+// 表写回之后第一次改动: 把磁盘上的第 0 页连同清掉的 AREA_SYNCED 写回
+fn mark_unsynced() {
+    let scratch = pmem::alloc(true);
+    let start = {
+        let mut table = TABLE.lock();
+        copy_page(&mut table, 0, scratch as *mut u64, true);
+        table.start
+    };
+    super::disk_rw(scratch, start, true);
+    pmem::free(scratch as usize, true);
+}
+
+// 写盘前调用
+pub fn record(blockno: BlockNo, data: *const u8) {
+    if !READY.load(Ordering::Acquire) || blockno == 0 {
+        return;
+    }
+    let crc = block_crc(data);
+    let was_synced = {
+        let mut table = TABLE.lock();
+        let b = blockno as usize;
+        if b >= table.crcs.len() {
+            return;
+        }
+        table.crcs[b] = VALID | crc as u64;
+        table.dirty[b / PER_BLOCK] = true;
+        table.records += 1;
+        core::mem::take(&mut table.synced)
+    };
+    // 数据写盘之前让磁盘上的表失效
+    if was_synced {
+        mark_unsynced();
+    }
+}
+
+// 把改过的表页写回校验和区, 第 0 页最后写并带上 AREA_SYNCED;
+// 写回期间又有新记录时不标记, 留给下一次 save
+pub fn save() {
+    if !READY.load(Ordering::Acquire) {
+        return;
+    }
+    let (start, pages, records) = {
+        let table = TABLE.lock();
+        (table.start, table.dirty.len(), table.records)
+    };
+    if start == 0 {
+        return;
+    }
+    let scratch = pmem::alloc(true);
+    for page in (1..pages).chain(core::iter::once(0)) {
+        {
+            let mut table = TABLE.lock();
+            if page != 0 && !table.dirty[page] {
+                continue;
+            }
+            if page == 0 && !table.dirty[0] && table.synced {
+                break;
+            }
+            table.dirty[page] = false;
+            copy_page(&mut table, page, scratch as *mut u64, true);
+            if page == 0 && table.records == records {
+                unsafe { *(scratch as *mut u64) = AREA_SYNCED };
+                table.synced = true;
+            }
+        }
+        super::disk_rw(scratch, start + page as BlockNo, true);
+    }
+    pmem::free(scratch as usize, true);
+}
+
+// 镜像有校验和区但本次启动不打开校验: 之后的写不会记录, 先让磁盘上的表失效
+pub fn forget(start: BlockNo) {
+    if start == 0 || READY.load(Ordering::Acquire) {
+        return;
+    }
+    let scratch = pmem::alloc(true);
+    super::disk_rw(scratch, start, false);
+    let first = scratch as *mut u64;
+    if unsafe { *first } == AREA_SYNCED {
+        unsafe { *first = 0 };
+        super::disk_rw(scratch, start, true);
+    }
+    pmem::free(scratch as usize, true);
+}
+
+// 读盘后调用, 没有记录的块视为通过; 不一致时返回 Err((期望值, 实际值))
+pub fn verify(blockno: BlockNo, data: *const u8) -> Result<(), (u32, u32)> {
+    if !enabled() || blockno == 0 {
+        return Ok(());
+    }
+    let entry = {
+        let table = TABLE.lock();
+        if (blockno as usize) >= table.crcs.len() {
+            return Ok(());
+        }
+        table.crcs[blockno as usize]
+    };
+    if entry & VALID == 0 {
+        return Ok(());
+    }
+    let expected = entry as u32;
+    let actual = block_crc(data);
+    if actual == expected { Ok(()) } else { Err((expected, actual)) }
+}
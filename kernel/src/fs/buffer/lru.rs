@@ -4,15 +4,16 @@
 //! for buffer management, replacing manual linked list manipulation with
 //! clearer abstractions.
 
-use super::{Buffer, N_BUFFER};
+use super::Buffer;
+use crate::mem::slab::Slab;
 
 /// Type-safe buffer index
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct BufferId(usize);
 
 impl BufferId {
-    pub const fn new(idx: usize) -> Option<Self> {
-        if idx < N_BUFFER {
+    pub fn new(idx: usize) -> Option<Self> {
+        if idx < super::nbuf() {
             Some(Self(idx))
         } else {
             None
@@ -43,8 +44,8 @@ impl ListNode {
 
 /// LRU cache structure with type-safe linked list
 pub struct LRUCache {
-    buffers: [Buffer; N_BUFFER],
-    nodes: [ListNode; N_BUFFER],
+    buffers: Slab<Buffer>,
+    nodes: Slab<ListNode>,
     active_head: Option<BufferId>,
     inactive_head: Option<BufferId>,
 }
@@ -52,8 +53,8 @@ pub struct LRUCache {
 impl LRUCache {
     pub const fn new() -> Self {
         Self {
-            buffers: [const { Buffer::new() }; N_BUFFER],
-            nodes: [const { ListNode::new() }; N_BUFFER],
+            buffers: Slab::empty(),
+            nodes: Slab::empty(),
             active_head: None,
             inactive_head: None,
         }
@@ -146,6 +147,11 @@ impl LRUCache {
         }
     }
 
+    /// The LRU buffer, if it still has unwritten data
+    pub fn dirty_lru(&self) -> Option<BufferId> {
+        self.get_lru().filter(|&id| self.buffers[id.as_index()].dirty)
+    }
+
     /// Find a buffer in the active list
     pub fn find_active(&self, dev: u32, blockno: u32) -> Option<BufferId> {
         let mut current = self.active_head?;
@@ -202,27 +208,24 @@ impl LRUCache {
         lru
     }
 
-    /// Initialize the cache with all buffers in the inactive list
-    pub fn init(&mut self) {
-        // Clear all nodes
-        for node in &mut self.nodes {
-            *node = ListNode::new();
-        }
+    /// Allocate n buffers and put them all in the inactive list
+    pub fn init(&mut self, n: usize, make: impl Fn() -> Buffer) {
+        self.buffers.init(n, make);
+        self.nodes.init(n, ListNode::new);
 
         // Build initial inactive list (all buffers)
-        if N_BUFFER == 0 {
+        if n == 0 {
             return;
         }
 
         // Link all buffers in a chain
-        for i in 0..N_BUFFER {
-            let id = BufferId::new(i).unwrap();
+        for i in 0..n {
             let node = &mut self.nodes[i];
-            node.prev = if i > 0 { BufferId::new(i - 1) } else { None };
-            node.next = if i < N_BUFFER - 1 { BufferId::new(i + 1) } else { None };
+            node.prev = if i > 0 { Some(BufferId(i - 1)) } else { None };
+            node.next = if i < n - 1 { Some(BufferId(i + 1)) } else { None };
         }
 
-        self.inactive_head = BufferId::new(0);
+        self.inactive_head = Some(BufferId(0));
         self.active_head = None;
     }
 
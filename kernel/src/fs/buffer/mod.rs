@@ -1,48 +1,164 @@
 #![allow(dead_code)]
 
+//! 块缓存
+//!
+//! 锁顺序: Inode::lock -> INODE_CACHE -> CACHE -> virtio DISK。
+//! 磁盘 I/O (virtio::disk::rw) 在有进程时睡眠等待磁盘中断 (启动阶段轮询),
+//! 因此任何线程都不能在持有 CACHE 或 inode 锁时进入磁盘 I/O:
+//! - read 先在锁内取出缓冲区地址, 放锁后再读磁盘, 完成后重新加锁更新状态;
+//! - write 只做标记, 脏块由 flush_block/flush_all 写回, 或在 get 回收它之前写回;
+//! - inode_rw/inode_read_data/inode_write_data 只持有缓冲区引用 (refcnt), 不持有任何锁;
+//! - Inode::lock 只保护 refcnt 等字段, 在调用 inode_rw 之前释放;
+//! - FILE_TABLE 只在取文件字段和更新偏移时短暂持有, 跨磁盘 I/O 的互斥用 inode 睡眠锁 (inode_lock),
+//!   kmap_temporary 的窗口也不能跨磁盘 I/O 持有。
+//! CACHE 只能通过 cache() 获取, disk_rw 检查当前 hart 没有持有它。
+//! cache() 与 PROC_TABLE 一样在持锁期间关闭 SIE: sleep_unless 在 PROC_TABLE 锁内取 CACHE,
+//! 持有 CACHE 时进入的时钟中断又会在 wakeup 中取 PROC_TABLE, 不关中断会互相等待。
+//! locked 是缓冲区的睡眠锁, 同一时刻只有一个持有者: get 遇到已上锁的块时放开 CACHE,
+//! 睡在该 Buffer 的地址上 (启动阶段没有进程时自旋), release 解锁后唤醒等待者。
+//! 因此同一线程不能在持有一块的同时再次 read 这一块。
+//! 打开块校验 (csum) 时, 每次写盘前记录校验和, 每次读盘后核对; flush_all 顺带写回校验和表。
+//! 写回时机: 返回用户态时每 FLUSH_INTERVAL_TICKS 个节拍一次 (flush_if_due), SYS_sync,
+//! 以及初始进程退出时 (内核没有关机路径, 之后只剩调度器空转)。
+
+mod csum;
 mod lru;
 
 use crate::drivers::virtio;
+use crate::irq::timer;
+use crate::dtb;
+use crate::hart::{self, MAX_HARTS};
+use crate::mem::{PGSIZE, pmem};
+use crate::mem::slab::Slab;
 use crate::printk;
+use crate::proc::scheduler;
+use core::hint::spin_loop;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::Mutex;
+use riscv::register::sstatus;
+use spin::{Mutex, MutexGuard};
 use lru::{BufferId, LRUCache};
 
 pub const BLOCK_SIZE: usize = 4096;
-pub const N_BUFFER: usize = 32;
+// 缓冲区个数默认值, 启动参数 nbuf=N 可以调整
+pub const DEFAULT_N_BUFFER: usize = 32;
+
+const _: () = assert!(BLOCK_SIZE == PGSIZE, "buffer data is one physical page");
 
 pub type BlockNo = u32;
 
+// init 之后的缓冲区个数, BufferId 据此检查下标
+static NBUF: AtomicUsize = AtomicUsize::new(0);
+
+pub fn nbuf() -> usize {
+    NBUF.load(Ordering::Relaxed)
+}
+
 pub struct Buffer {
-    pub data: [u8; BLOCK_SIZE], // Data buffer
+    pub data: *mut u8,          // Data buffer, 单独的一页
     pub block_no: BlockNo,      // Block number on disk
     pub dev: u32,               // Device ID
     pub refcnt: u32,            // Reference count
     pub valid: bool,            // Is data valid?
     pub dirty: bool,            // Does data need writing to disk?
     pub locked: bool,           // SleepLock equivalent
+    pub waiting: bool,          // 有线程在等 locked 清除, release 时需要 wakeup
 }
 
 impl Buffer {
     pub const fn new() -> Self {
         Self {
-            data: [0; BLOCK_SIZE],
+            data: core::ptr::null_mut(),
             block_no: 0,
             dev: 0,
             refcnt: 0,
             valid: false,
             dirty: false,
             locked: false,
+            waiting: false,
         }
     }
 }
 
+// data 指向的页只通过 CACHE 和缓冲区引用计数访问
+unsafe impl Send for Buffer {}
+
 static CACHE: Mutex<LRUCache> = Mutex::new(LRUCache::new());
 
+// 每个 hart 当前持有 CACHE 的层数
+static CACHE_HELD: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+struct CacheGuard {
+    guard: ManuallyDrop<MutexGuard<'static, LRUCache>>,
+    hartid: usize,
+    sie_enabled: bool, // 取锁前的 SIE, 放锁后恢复
+}
+
+impl Deref for CacheGuard {
+    type Target = LRUCache;
+    fn deref(&self) -> &LRUCache {
+        &self.guard
+    }
+}
+
+impl DerefMut for CacheGuard {
+    fn deref_mut(&mut self) -> &mut LRUCache {
+        &mut self.guard
+    }
+}
+
+impl Drop for CacheGuard {
+    fn drop(&mut self) {
+        CACHE_HELD[self.hartid].fetch_sub(1, Ordering::Relaxed);
+        // 先放锁再开中断, 否则中断处理可能在本 hart 上等一把自己持有的锁
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.sie_enabled { unsafe { sstatus::set_sie(); } }
+    }
+}
+
+fn cache() -> CacheGuard {
+    let sie_enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie(); }
+    let guard = CACHE.lock();
+    let hartid = hart::getid();
+    CACHE_HELD[hartid].fetch_add(1, Ordering::Relaxed);
+    CacheGuard { guard: ManuallyDrop::new(guard), hartid, sie_enabled }
+}
+
+fn disk_rw(buf: *mut u8, blockno: u32, write: bool) {
+    assert_eq!(
+        CACHE_HELD[hart::getid()].load(Ordering::Relaxed),
+        0,
+        "buffer: disk I/O on block {} while holding the cache lock",
+        blockno
+    );
+    virtio::disk::rw(buf, blockno, write);
+}
+
+// 缓冲区睡眠锁的等待通道
+fn buffer_chan(c: &LRUCache, id: BufferId) -> usize {
+    c.get_buffer(id) as *const Buffer as usize
+}
+
+// 缓冲区已被别人上锁: 登记等待后放开 CACHE, 等到它解锁再返回, 调用者重新查找
+// 等待期间不持有引用, 缓冲区可能已被回收给别的块
+fn wait_unlocked(mut c: CacheGuard, id: BufferId) {
+    let chan = buffer_chan(&c, id);
+    c.get_buffer_mut(id).waiting = true;
+    drop(c);
+    if hart::get().proc.is_null() {
+        spin_loop();
+    } else {
+        // 在 PROC_TABLE 锁内复查, release 先解锁再 wakeup, 不会错过唤醒
+        scheduler::sleep_unless(chan, || !cache().get_buffer(id).locked);
+    }
+}
+
 static STATE_COUNTER: AtomicUsize = AtomicUsize::new(1);
 
 pub fn debug_state() {
-    let c = CACHE.lock();
+    let c = cache();
     let state_num = STATE_COUNTER.fetch_add(1, Ordering::Relaxed);
     
     crate::printk!("state-{} buffer cache information:\n", state_num);
@@ -54,7 +170,7 @@ pub fn debug_state() {
             "buffer {}(ref ={}): page(pa = 0x{:x})-> block[{}]\n",
             id.as_usize(),
             b.refcnt,
-            b.data.as_ptr() as usize,
+            b.data as usize,
             b.block_no
         );
     }
@@ -67,7 +183,7 @@ pub fn debug_state() {
             "buffer {}(ref ={}): page(pa = 0x{:x})-> block[{}]\n",
             id.as_usize(),
             b.refcnt,
-            b.data.as_ptr() as usize,
+            b.data as usize,
             b.block_no
         );
     }
@@ -75,85 +191,272 @@ pub fn debug_state() {
 }
 
 pub fn init() {
-    let mut c = CACHE.lock();
-    c.init();
-    printk!("Buffer: cache initialized with {} buffers\n", N_BUFFER);
+    let n = dtb::bootarg("nbuf")
+        .unwrap_or(DEFAULT_N_BUFFER)
+        .clamp(1, Slab::<Buffer>::MAX_LEN);
+    let mut c = cache();
+    c.init(n, || {
+        let mut buf = Buffer::new();
+        buf.data = pmem::alloc(true);
+        buf
+    });
+    NBUF.store(n, Ordering::Relaxed);
+    printk!("Buffer: cache initialized with {} buffers\n", n);
 }
 
 fn get(dev: u32, blockno: u32) -> BufferId {
-    let mut c = CACHE.lock();
+    loop {
+        let mut c = cache();
 
-    // Search Active List
-    if let Some(id) = c.find_active(dev, blockno) {
-        let buf = c.get_buffer_mut(id);
-        if buf.locked {
-            // TODO: Implement sleep waiting for buffer lock
-            // For now, we assume no contention or handle it higher up
+        // Search Active List
+        if let Some(id) = c.find_active(dev, blockno) {
+            if c.get_buffer(id).locked {
+                wait_unlocked(c, id);
+                continue;
+            }
+            let buf = c.get_buffer_mut(id);
+            // 与 promote_to_active 一致, 每个持有者各占一个引用
+            buf.refcnt += 1;
+            buf.locked = true;
+            return id;
         }
-        buf.locked = true;
-        return id;
-    }
 
-    // Search Inactive List
-    if let Some(id) = c.find_inactive(dev, blockno) {
-        c.promote_to_active(id);
-        c.get_buffer_mut(id).locked = true;
-        return id;
+        // Search Inactive List
+        if let Some(id) = c.find_inactive(dev, blockno) {
+            c.promote_to_active(id);
+            c.get_buffer_mut(id).locked = true;
+            return id;
+        }
+
+        // Not cached - recycle LRU buffer
+        // LRU 缓冲区是脏的: 以原块号持有它写回磁盘, 放锁期间 blockno 可能已被别人读入, 所以重新查找
+        match c.dirty_lru() {
+            Some(victim) => {
+                c.promote_to_active(victim);
+                c.get_buffer_mut(victim).locked = true;
+                drop(c);
+                write_back(victim);
+                release(victim.as_usize());
+            }
+            None => return c.recycle_lru(dev, blockno),
+        }
     }
+}
 
-    // Not cached - recycle LRU buffer
-    c.recycle_lru(dev, blockno)
+// 持有引用的缓冲区为脏时写回磁盘
+fn write_back(id: BufferId) {
+    let (buf_ptr, blockno, dirty) = {
+        let c = cache();
+        let buf = c.get_buffer(id);
+        (buf.data, buf.block_no, buf.dirty)
+    };
+    if dirty {
+        csum::record(blockno, buf_ptr);
+        disk_rw(buf_ptr, blockno, true);
+        cache().get_buffer_mut(id).dirty = false;
+    }
 }
 
+// 读入块并持有缓冲区; 校验和不一致时只打印报告, 照常返回磁盘上的内容而不是停机。
+// 元数据路径没有办法把错误交给调用者, 用这个; 文件数据用 try_read
 pub fn read(dev: u32, blockno: u32) -> usize {
+    match fill(dev, blockno, true) {
+        Ok(idx) => idx,
+        Err(()) => unreachable!(),
+    }
+}
+
+// 读入块并持有缓冲区; 打开块校验且从磁盘读入的内容与记录不符时返回 Err,
+// 缓冲区保持无效并已释放, 下次读取会重新访问磁盘
+pub fn try_read(dev: u32, blockno: u32) -> Result<usize, ()> {
+    fill(dev, blockno, false)
+}
+
+// keep_corrupt 为 true 时校验失败的内容也当作有效数据
+fn fill(dev: u32, blockno: u32, keep_corrupt: bool) -> Result<usize, ()> {
     let id = get(dev, blockno);
     let valid = {
-        let c = CACHE.lock();
+        let c = cache();
         c.get_buffer(id).valid
     };
 
     if !valid {
         let buf_ptr = {
-            let c = CACHE.lock();
-            c.get_buffer(id).data.as_ptr() as *mut u8
+            let c = cache();
+            c.get_buffer(id).data
         };
 
-        virtio::disk::rw(buf_ptr, blockno, false);
+        disk_rw(buf_ptr, blockno, false);
+
+        if let Err((expected, actual)) = csum::verify(blockno, buf_ptr) {
+            printk!(
+                "buffer: checksum mismatch on block {}: expected {:#010x}, got {:#010x}\n",
+                blockno,
+                expected,
+                actual
+            );
+            if !keep_corrupt {
+                release(id.as_usize());
+                return Err(());
+            }
+        }
 
-        let mut c = CACHE.lock();
+        let mut c = cache();
         c.get_buffer_mut(id).valid = true;
     }
-    id.as_usize()
+    Ok(id.as_usize())
 }
 
+// 延迟写: 只把缓冲区标记为脏, 调用者随后照常 release; 需要落盘时用 flush_block 或 flush_all
 pub fn write(idx: usize) {
     let id = BufferId::new(idx).expect("Invalid buffer index");
-    let (buf_ptr, blockno) = {
-        let c = CACHE.lock();
-        let buf = c.get_buffer(id);
-        (buf.data.as_ptr() as *mut u8, buf.block_no)
+    cache().get_buffer_mut(id).dirty = true;
+}
+
+// 块在缓存中且为脏时写回磁盘, 返回是否发生了写; 不在缓存中的块不会被读入
+// 块被别人持有时等它释放, 调用者自己不能持有这一块
+pub fn flush_block(dev: u32, blockno: BlockNo) -> bool {
+    let id = loop {
+        let mut c = cache();
+        let id = match c.find_active(dev, blockno) {
+            Some(id) if c.get_buffer(id).locked => {
+                wait_unlocked(c, id);
+                continue;
+            }
+            Some(id) if c.get_buffer(id).dirty => {
+                c.get_buffer_mut(id).refcnt += 1;
+                id
+            }
+            Some(_) => return false,
+            None => match c.find_inactive(dev, blockno) {
+                Some(id) if c.get_buffer(id).dirty => {
+                    c.promote_to_active(id);
+                    id
+                }
+                _ => return false,
+            },
+        };
+        c.get_buffer_mut(id).locked = true;
+        break id;
     };
-    virtio::disk::rw(buf_ptr, blockno, true);
+    write_back(id);
+    release(id.as_usize());
+    true
+}
 
-    let mut c = CACHE.lock();
-    c.get_buffer_mut(id).dirty = false;
+// 写回缓存中全部脏块, 返回写盘的块数
+// 按下标逐个检查, 每块只看一次: 扫描期间被别人重新弄脏的块留给下一次 flush
+// 正被持有的块跳过而不等待 (调用者自己可能持有缓冲区), 由下一次 flush 或回收时写回
+pub fn flush_all() -> usize {
+    let mut n = 0;
+    for idx in 0..nbuf() {
+        let id = BufferId::new(idx).expect("Invalid buffer index");
+        {
+            let mut c = cache();
+            let buf = c.get_buffer_mut(id);
+            if !buf.dirty || buf.locked {
+                continue;
+            }
+            // 与 get 一致: 被引用的缓冲区在活跃链表上, 否则提升回活跃链表
+            if buf.refcnt > 0 {
+                buf.refcnt += 1;
+            } else {
+                c.promote_to_active(id);
+            }
+            c.get_buffer_mut(id).locked = true;
+        }
+        write_back(id);
+        release(idx);
+        n += 1;
+    }
+    csum::save();
+    n
+}
+
+// 定期写回的间隔, 延迟写的脏块最多在缓存里停留这么久 (5 秒)
+pub const FLUSH_INTERVAL_TICKS: usize = 50;
+
+// 下一次定期写回的节拍
+static NEXT_FLUSH: AtomicUsize = AtomicUsize::new(FLUSH_INTERVAL_TICKS);
+
+// 到了定期写回的时间就写回全部脏块, 返回是否写回过; 多个 hart 同时到期只有一个去写
+// 会等待磁盘, 调用者必须在进程上下文, 不持有任何锁且开着中断
+pub fn flush_if_due() -> bool {
+    let now = timer::uptime_ticks();
+    let due = NEXT_FLUSH.load(Ordering::Relaxed);
+    if now < due
+        || NEXT_FLUSH.compare_exchange(due, now + FLUSH_INTERVAL_TICKS, Ordering::Relaxed, Ordering::Relaxed).is_err()
+    {
+        return false;
+    }
+    flush_all();
+    true
+}
+
+// 丢弃块在缓存中的干净副本, 下次 read 重新从磁盘读入; 被引用或为脏的块不受影响
+pub fn invalidate(dev: u32, blockno: BlockNo) {
+    let mut c = cache();
+    if let Some(id) = c.find_active(dev, blockno).or_else(|| c.find_inactive(dev, blockno)) {
+        let buf = c.get_buffer_mut(id);
+        if buf.refcnt == 0 && !buf.dirty {
+            buf.valid = false;
+        }
+    }
+}
+
+// 打开块校验, nblocks 为磁盘总块数, csum_start 为校验和区起始块号 (0 表示没有)
+pub fn enable_checksums(nblocks: usize, csum_start: BlockNo) {
+    csum::enable(nblocks, csum_start);
+}
+
+// 本次启动不校验时调用, 让镜像上的校验和表失效
+pub fn forget_checksums(csum_start: BlockNo) {
+    csum::forget(csum_start);
+}
+
+pub fn disable_checksums() {
+    csum::disable();
+}
+
+pub fn checksums_enabled() -> bool {
+    csum::enabled()
+}
+
+// 块是否在缓存中且尚未写回
+pub fn is_dirty(dev: u32, blockno: BlockNo) -> bool {
+    let c = cache();
+    c.find_active(dev, blockno)
+        .or_else(|| c.find_inactive(dev, blockno))
+        .is_some_and(|id| c.get_buffer(id).dirty)
 }
 
 pub fn release(idx: usize) {
     let id = BufferId::new(idx).expect("Invalid buffer index");
-    let mut c = CACHE.lock();
+    let mut c = cache();
+    let chan = buffer_chan(&c, id);
     let buf = c.get_buffer_mut(id);
     buf.refcnt -= 1;
     buf.locked = false;
+    let waiting = core::mem::take(&mut buf.waiting);
 
     if buf.refcnt == 0 {
         // Move from Active to Inactive Head (MRU)
         c.demote_to_inactive(id);
     }
+    drop(c);
+    if waiting {
+        scheduler::wakeup(chan);
+    }
+}
+
+// refcnt 不为 0 的缓冲区个数
+pub fn in_use() -> usize {
+    let c = cache();
+    c.iter_active().filter(|&id| c.get_buffer(id).refcnt > 0).count()
 }
 
 pub fn get_data_ptr(idx: usize) -> *mut u8 {
     let id = BufferId::new(idx).expect("Invalid buffer index");
-    let c = CACHE.lock();
-    c.get_buffer(id).data.as_ptr() as *mut u8
+    let c = cache();
+    c.get_buffer(id).data
 }
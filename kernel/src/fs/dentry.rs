@@ -1,5 +1,6 @@
 use crate::fs::inode::{self, Inode, DentryDisk, MAXLEN_FILENAME};
 use crate::fs::buffer::BLOCK_SIZE;
+use crate::fs::fs::get_sb;
 use crate::printk;
 use core::mem::size_of;
 use core::slice;
@@ -40,7 +41,52 @@ pub fn dentry_search(dir: &mut Inode, name: &[u8]) -> Option<u32> {
     None
 }
 
+// 目录项名字: 非空, 不超过 MAXLEN_FILENAME, 不含 '/' 或 NUL, 且不是保留的 "." / ".."
+pub fn name_valid(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name.len() <= MAXLEN_FILENAME
+        && name != b"."
+        && name != b".."
+        && !name.iter().any(|&c| c == b'/' || c == 0)
+}
+
+// 目录自身的 "." 和指向父目录的 ".."
+pub fn is_dot(name: &[u8]) -> bool {
+    name == b"." || name == b".."
+}
+
+fn entry_name(dentry: &DentryDisk) -> &[u8] {
+    let len = dentry.name.iter().position(|&b| b == 0).unwrap_or(MAXLEN_FILENAME);
+    &dentry.name[..len]
+}
+
 pub fn dentry_create(dir: &mut Inode, target_inum: u32, name: &[u8]) -> i32 {
+    if !name_valid(name) {
+        return -1;
+    }
+    add_entry(dir, target_inum, name)
+}
+
+// 给新目录写入 "." 和 "..", 已有的不重复写; 根目录的 ".." 指向自己
+pub fn dir_init(dir: &mut Inode, parent_inum: u32) -> i32 {
+    let self_inum = dir.inode_num;
+    if dentry_search(dir, b".").is_none() && add_entry(dir, self_inum, b".") != 0 {
+        return -1;
+    }
+    if dentry_search(dir, b"..").is_none() && add_entry(dir, parent_inum, b"..") != 0 {
+        return -1;
+    }
+    0
+}
+
+// 目录被移到另一个父目录下时改写它的 ".."
+pub fn dir_set_parent(dir: &mut Inode, parent_inum: u32) -> i32 {
+    dentry_delete(dir, b"..");
+    add_entry(dir, parent_inum, b"..")
+}
+
+// 不检查名字, "." 和 ".." 也经由这里写入
+fn add_entry(dir: &mut Inode, target_inum: u32, name: &[u8]) -> i32 {
     // Check if name already exists
     if dentry_search(dir, name).is_some() {
         return -1;
@@ -75,10 +121,7 @@ pub fn dentry_create(dir: &mut Inode, target_inum: u32, name: &[u8]) -> i32 {
         inode_num: target_inum,
     };
     
-    let len = if name.len() > MAXLEN_FILENAME { MAXLEN_FILENAME } else { name.len() };
-    for i in 0..len {
-        new_dentry.name[i] = name[i];
-    }
+    new_dentry.name[..name.len()].copy_from_slice(name);
 
     let src = unsafe {
         slice::from_raw_parts(&new_dentry as *const DentryDisk as *const u8, size_of::<DentryDisk>())
@@ -132,6 +175,63 @@ pub fn dentry_delete(dir: &mut Inode, name: &[u8]) -> i32 {
     -1
 }
 
+// 依次取出目录中的全部非空目录项 (名字, inode 号), 包括 "." 和 ".."; f 返回 false 时停止
+pub fn for_each_dentry(dir: &mut Inode, mut f: impl FnMut(&[u8], u32) -> bool) {
+    let mut off = 0;
+    let dentry_size = size_of::<DentryDisk>() as u32;
+    let mut buf = [0u8; size_of::<DentryDisk>()];
+
+    while off < dir.disk.size {
+        if inode::inode_read_data(dir, off, dentry_size, &mut buf) != dentry_size {
+            break;
+        }
+        let dentry = unsafe { &*(buf.as_ptr() as *const DentryDisk) };
+        if dentry.name[0] != 0 && !f(entry_name(dentry), dentry.inode_num) {
+            return;
+        }
+        off += dentry_size;
+    }
+}
+
+// 同 for_each_dentry, 但跳过 "." 和 "..", 只给出 inode 号
+fn for_each_entry(dir: &mut Inode, mut f: impl FnMut(u32) -> bool) {
+    for_each_dentry(dir, |name, inum| is_dot(name) || f(inum));
+}
+
+// 目录中除 "." 和 ".." 外没有任何目录项
+pub fn dir_is_empty(dir: &mut Inode) -> bool {
+    let mut empty = true;
+    for_each_entry(dir, |_| {
+        empty = false;
+        false
+    });
+    empty
+}
+
+// dir 是否是 start 本身或 start 的祖先, 用于拒绝把目录移进它自己的子树
+// 从 start 沿 ".." 向上走到根, 每层只查一个目录项, 与 dir 子树的大小无关
+pub fn dir_is_ancestor(dir: u32, start: &mut Inode) -> bool {
+    if start.inode_num == dir {
+        return true;
+    }
+    let mut cur = start.inode_num;
+    let mut parent = dentry_search(start, b"..");
+    // 合法的链不会超过 ninodes 层, 更长说明 ".." 成环 (损坏的镜像), 按包含处理
+    for _ in 0..get_sb().ninodes {
+        match parent {
+            Some(p) if p == dir => return true,
+            Some(p) if p != cur && cur != inode::ROOT_INODE => {
+                cur = p;
+                let ip = inode::inode_get(p);
+                parent = dentry_search(ip, b"..");
+                inode::inode_put(ip);
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
 pub fn dentry_print(dir: &mut Inode) {
     let mut off = 0;
     let size = dir.disk.size;
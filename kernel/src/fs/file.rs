@@ -1,4 +1,5 @@
 use crate::fs::inode::{self, Inode};
+use crate::fs::pipe;
 use spin::Mutex;
 
 pub const NFILE: usize = 128; // 全局最大文件数
@@ -8,13 +9,14 @@ pub enum FileType {
     None,
     Inode,
     Device { major: u16, minor: u16 },
-    Pipe,
+    Pipe { id: u16 },
 }
 
 pub struct File {
     pub ty: FileType,
     pub readable: bool,
     pub writable: bool,
+    pub append: bool, // O_APPEND: 每次写之前定位到文件末尾
     pub off: u32,
     pub inum: u32,
     pub refcnt: u32,
@@ -26,6 +28,7 @@ impl File {
             ty: FileType::None,
             readable: false,
             writable: false,
+            append: false,
             off: 0,
             inum: 0,
             refcnt: 0,
@@ -79,15 +82,108 @@ pub fn file_close(f_idx: usize) {
     // Truly close
     let ty = f.ty;
     let inum = f.inum;
+    let writable = f.writable;
     f.ty = FileType::None;
 
     drop(table); // Release table lock before calling inode_put which might lock other things
 
-    if let FileType::Inode = ty {
-        let inode_ref = inode::inode_get(inum);
-        inode::inode_put(inode_ref);
-        inode::inode_put(inode_ref);
+    match ty {
+        FileType::Inode => {
+            let inode_ref = inode::inode_get(inum);
+            inode::inode_put(inode_ref);
+            inode::inode_put(inode_ref);
+        }
+        FileType::Pipe { id } => pipe::pipe_close(id as usize, writable),
+        _ => {}
+    }
+}
+
+// 读 inode 文件: dst(data, done) 收下从第 done 字节起读到的数据, 返回实际读到的字节数
+// 与 file_write 相同, 偏移在 inode 睡眠锁内读取和更新, 磁盘 I/O 期间不持有文件表锁
+pub fn file_read(
+    f_idx: usize,
+    len: usize,
+    mut dst: impl FnMut(&[u8], usize) -> Result<(), ()>,
+) -> Result<usize, ()> {
+    let (inum, readable) = {
+        let table = FILE_TABLE.lock();
+        let f = &table.files[f_idx];
+        (f.inum, f.readable)
+    };
+    if !readable {
+        return Err(());
+    }
+
+    let ip = inode::inode_get(inum);
+    inode::inode_lock(ip);
+    let mut off = FILE_TABLE.lock().files[f_idx].off;
+    let mut total_read = 0;
+    let mut buf = [0u8; 512];
+    let mut result = Ok(());
+
+    while total_read < len {
+        let chunk_len = core::cmp::min(len - total_read, buf.len());
+        let read = inode::inode_read_data(ip, off, chunk_len as u32, &mut buf[..chunk_len]);
+        if read == 0 {
+            break;
+        }
+        if dst(&buf[..read as usize], total_read).is_err() {
+            result = Err(());
+            break;
+        }
+        total_read += read as usize;
+        off += read;
+        if read < chunk_len as u32 {
+            break;
+        }
+    }
+    FILE_TABLE.lock().files[f_idx].off = off;
+    inode::inode_unlock(ip);
+    inode::inode_put(ip);
+    result.map(|_| total_read)
+}
+
+// 写 inode 文件: src(buf, done) 把待写数据从第 done 字节起填满 buf, 返回实际写入的字节数
+// 偏移的读取与更新都在 inode 睡眠锁内, O_APPEND 在同一把锁下取文件末尾, 并发追加不会互相覆盖;
+// 文件表锁只在取字段和更新偏移时短暂持有, 磁盘 I/O 和 src 可能睡眠或缺页
+pub fn file_write(
+    f_idx: usize,
+    len: usize,
+    mut src: impl FnMut(&mut [u8], usize) -> Result<(), ()>,
+) -> Result<usize, ()> {
+    let (inum, writable, append) = {
+        let table = FILE_TABLE.lock();
+        let f = &table.files[f_idx];
+        (f.inum, f.writable, f.append)
+    };
+    if !writable {
+        return Err(());
+    }
+
+    let ip = inode::inode_get(inum);
+    inode::inode_lock(ip);
+    let mut off = if append { ip.disk.size } else { FILE_TABLE.lock().files[f_idx].off };
+    let mut total_written = 0;
+    let mut buf = [0u8; 512];
+    let mut result = Ok(());
+
+    while total_written < len {
+        let chunk_len = core::cmp::min(len - total_written, buf.len());
+        if src(&mut buf[..chunk_len], total_written).is_err() {
+            result = Err(());
+            break;
+        }
+        let written = inode::inode_write_data(ip, off, chunk_len as u32, &buf[..chunk_len]);
+        total_written += written as usize;
+        off += written;
+        if written < chunk_len as u32 {
+            break;
+        }
     }
+    FILE_TABLE.lock().files[f_idx].off = off;
+    inode::inode_unlock(ip);
+    inode::inode_put(ip);
+    result.map(|_| total_written)
 }
 
 // --- Userspace Compatibility Structures ---
@@ -101,6 +197,9 @@ pub struct Stat {
     pub major: u16,
     pub minor: u16,
     pub inum: u32,
+    pub atime: u32, // 开机以来的秒数
+    pub mtime: u32,
+    pub ctime: u32,
 }
 
 #[repr(C)]
@@ -3,7 +3,9 @@
 use crate::fs::buffer;
 use crate::fs::inode;
 use crate::fs::dentry;
+use crate::fs::fsck;
 use crate::fs::path;
+use crate::dtb;
 use crate::printk;
 use core::ptr;
 use spin::Once;
@@ -11,6 +13,8 @@ use spin::Once;
 // Filesystem constants
 pub const MAGIC: u32 = 0x10203040;
 pub const BSIZE: usize = 4096; // Block size = Page size
+// 磁盘格式版本: 0 为旧格式 (64 字节 inode, 无权限位), 1 起 inode 带 mode
+pub const FS_VERSION: u32 = 1;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -21,6 +25,23 @@ pub struct SuperBlock {
     pub ninodes: u32,
     pub inode_start: u32,
     pub bmap_start: u32,
+    pub version: u32,
+    pub csum_start: u32, // 块校验和区起始块号, 位于数据区之后; 0 表示没有 (旧镜像)
+}
+
+impl SuperBlock {
+    pub const BITS_PER_BLOCK: u32 = (BSIZE * 8) as u32;
+
+    /// 数据位图占用的块数, 紧跟在 bmap_start 之后连续存放
+    /// 旧镜像的 nblocks 不超过一块的位数, 算出来仍是 1, 布局不变
+    pub fn bmap_blocks(&self) -> u32 {
+        self.nblocks.div_ceil(Self::BITS_PER_BLOCK)
+    }
+
+    /// 第一个数据块的块号
+    pub fn data_start(&self) -> u32 {
+        self.bmap_start + self.bmap_blocks()
+    }
 }
 
 static SB: Once<SuperBlock> = Once::new();
@@ -46,18 +67,36 @@ pub fn fs_init() {
         );
     }
 
+    // inode 位图只有 inode_start - 1 这一块
+    if sb.ninodes > SuperBlock::BITS_PER_BLOCK {
+        panic!("fs_init: {} inodes do not fit in one inode bitmap block", sb.ninodes);
+    }
+
     // Store superblock
     SB.call_once(|| sb);
 
+    if dtb::bootarg("blkcsum") == Some(1) {
+        buffer::enable_checksums(sb.size as usize, sb.csum_start);
+        printk!("FS: block checksums enabled\n");
+    } else {
+        buffer::forget_checksums(sb.csum_start);
+    }
+
     let sb = get_sb();
     printk!(
-        "FS: Superblock read: size={} blocks, inodes={}, bmap_start={}\n",
+        "FS: Superblock read: size={} blocks, inodes={}, bmap_start={}, bmap_blocks={}, version={}\n",
         sb.size,
         sb.ninodes,
-        sb.bmap_start
+        sb.bmap_start,
+        sb.bmap_blocks(),
+        sb.version
     );
 
-    inode::inode_init();
+    // fsck=1 只检查, fsck=2 同时清掉泄漏的块
+    if let Some(mode @ 1..=2) = dtb::bootarg("fsck") {
+        fsck::fsck(mode == 2);
+    }
+
     fs_test();
 }
 
@@ -66,7 +105,7 @@ fn fs_test() {
 
     // Test 1: Inode allocation and manipulation
     printk!("Test 1: Inode alloc/free...\n");
-    let inode = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0);
+    let inode = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("fs_test: inode_create failed");
     let inum = inode.inode_num;
     printk!("  Allocated inode {}\n", inum);
     inode::inode_print(inode, "Created");
@@ -83,7 +122,7 @@ fn fs_test() {
 
     // Test 2: Data R/W
     printk!("Test 2: Data R/W...\n");
-    let inode = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0);
+    let inode = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("fs_test: inode_create failed");
     let mut buf = [0u8; 100];
     for i in 0..100 { buf[i] = i as u8; }
     inode::inode_write_data(inode, 0, 100, &buf);
@@ -95,6 +134,26 @@ fn fs_test() {
         }
     }
     printk!("  Data R/W passed.\n");
+
+    // len 大于切片时按切片长度截断, 切片之外的字节保持不变
+    let mut guarded = [0xeeu8; 64];
+    let n = inode::inode_read_data(inode, 0, 100, &mut guarded[..32]);
+    if n != 32 || guarded[..32] != buf[..32] || guarded[32..].iter().any(|&b| b != 0xee) {
+        panic!("Test 2 failed: short read slice overrun (n={})", n);
+    }
+    let n = inode::inode_write_data(inode, 100, 50, &buf[..10]);
+    if n != 10 || inode.disk.size != 110 {
+        panic!("Test 2 failed: short write slice not clamped (n={}, size={})", n, inode.disk.size);
+    }
+    printk!("  Short slices clamped.\n");
+    // 读取只更新内存中的 atime, 磁盘上的 inode 等到下一次写回才变
+    let on_disk = inode::inode_snapshot(inode.inode_num).disk.atime;
+    inode.disk.atime = u32::MAX;
+    inode::inode_read_data(inode, 0, 1, &mut read_buf);
+    if !inode.atime_dirty || inode::inode_snapshot(inode.inode_num).disk.atime != on_disk {
+        panic!("Test 2 failed: read wrote atime to disk");
+    }
+    printk!("  atime kept in memory.\n");
     // Cleanup
     inode.disk.nlink = 0;
     inode::inode_rw(inode, true);
@@ -117,14 +176,16 @@ fn fs_test() {
 
     if !is_inum_set(inode::ROOT_INODE) {
         let root_inum = inode::alloc();
-        if root_inum != inode::ROOT_INODE {
-            panic!("fs_test: expected to allocate inode 0 for root, got {}", root_inum);
+        if root_inum != Ok(inode::ROOT_INODE) {
+            panic!("fs_test: expected to allocate inode 0 for root, got {:?}", root_inum);
         }
         let root_init = inode::inode_get(inode::ROOT_INODE);
         root_init.disk.type_ = inode::INODE_TYPE_DIR;
         root_init.disk.nlink = 2; // . and ..
         root_init.disk.size = 0;
+        root_init.disk.mode = inode::default_mode(inode::INODE_TYPE_DIR);
         inode::inode_rw(root_init, true);
+        dentry::dir_init(root_init, inode::ROOT_INODE);
         inode::inode_put(root_init);
     } else {
         // If already allocated, ensure it's sane and not accidentally deletable
@@ -164,7 +225,7 @@ fn fs_test() {
     // Test 4: Path
     printk!("Test 4: Path resolution...\n");
     let root = inode::inode_get(inode::ROOT_INODE);
-    let inode = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0);
+    let inode = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("fs_test: inode_create failed");
     dentry::dentry_create(root, inode.inode_num, b"test_path");
     inode::inode_put(root);
     inode::inode_put(inode);
@@ -173,15 +234,39 @@ fn fs_test() {
     if found_inode.is_none() {
         panic!("Test 4 failed: /test_path not found");
     }
-    printk!("  /test_path found: inode {}\n", found_inode.as_ref().unwrap().inode_num);
+    let inum = found_inode.as_ref().unwrap().inode_num;
+    printk!("  /test_path found: inode {}\n", inum);
     inode::inode_put(found_inode.unwrap());
 
+    // "." 是当前目录, 根目录的 ".." 仍是根目录; 连续和末尾的 '/' 不影响结果
+    let same = |path: &[u8], want: u32| match path::path_to_inode(path) {
+        Some(ip) => {
+            let ok = ip.inode_num == want;
+            inode::inode_put(ip);
+            ok
+        }
+        None => false,
+    };
+    for (path, want) in [
+        (&b"/.."[..], inode::ROOT_INODE),
+        (b"/../..", inode::ROOT_INODE),
+        (b"/./test_path", inum),
+        (b"/../test_path", inum),
+        (b"//test_path/", inum),
+    ] {
+        if !same(path, want) {
+            panic!("Test 4 failed: {:?} did not resolve to inode {}", core::str::from_utf8(path), want);
+        }
+    }
+
     // Cleanup
     let root = inode::inode_get(inode::ROOT_INODE);
     dentry::dentry_delete(root, b"test_path");
     inode::inode_put(root);
+    inode::inode_discard(inode::inode_get(inum));
 
     printk!("  Path passed.\n");
+
     printk!("FS: All self-tests passed!\n");
 }
 
@@ -0,0 +1,286 @@
+//! 文件系统一致性检查
+//!
+//! 扫描 inode 位图中已分配的全部 inode, 重新计算它们引用的数据块 (包括索引块) 并与数据位图比较,
+//! 再按目录项重新统计每个 inode 的链接数。启动参数 fsck=1 在挂载时检查一遍, fsck=2 还会把
+//! 泄漏的块 (位图已分配但没有 inode 引用) 在位图中清掉, 其他问题只报告不修复。
+//! inode 经由 inode_snapshot 直接从磁盘读取, 不占用 inode 缓存, 也不会回收 nlink 为 0 的 inode;
+//! 读目录只改副本的 atime, 副本不写回, 只检查时磁盘上的 inode 保持不变。
+//! 每个位图块用一页记录引用过的块, 链接数每页统计 COUNTS_PER_PAGE 个 inode。
+//! 一次遍历全部 inode 填满至多 MAX_BATCH 页, 内存充足时块检查和链接数检查各只遍历一遍。
+
+use crate::fs::buffer;
+use crate::fs::dentry;
+use crate::fs::fs::{SuperBlock, get_sb};
+use crate::fs::inode::{self, INODE_INDEX_1, INODE_INDEX_2, INODE_TYPE_DIR, InodeDisk, NINDIRECT, ROOT_INODE};
+use crate::mem::PGSIZE;
+use crate::mem::frame::PhysFrame;
+use crate::printk;
+
+// 每页统计链接数的 inode 个数
+const COUNTS_PER_PAGE: u32 = (PGSIZE / 2) as u32;
+// 一次遍历 inode 时同时使用的页数上限
+const MAX_BATCH: usize = 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FsckReport {
+    pub leaked: u32,     // 位图中已分配但没有 inode 引用的块
+    pub unmarked: u32,   // 被引用但位图中空闲的块
+    pub duplicate: u32,  // 被引用不止一次的块
+    pub bad_blocks: u32, // 块号不在数据区的引用
+    pub bad_nlink: u32,  // nlink 与目录项统计不符的 inode
+    pub dangling: u32,   // 指向未分配 inode 的目录项
+    pub orphans: u32,    // 已分配但没有任何目录项的 inode (根目录除外)
+    pub repaired: u32,   // 已在位图中清掉的泄漏块
+}
+
+impl FsckReport {
+    pub fn problems(&self) -> u32 {
+        self.leaked + self.unmarked + self.duplicate + self.bad_blocks + self.bad_nlink + self.dangling + self.orphans
+    }
+}
+
+fn in_data(blk: u32) -> bool {
+    let sb = get_sb();
+    blk >= sb.data_start() && blk < sb.data_start() + sb.nblocks
+}
+
+// 按 for_each_block 的布局访问 inode 引用的全部块号 (包括索引块); 不在数据区的索引块不展开
+fn walk_blocks(disk: &InodeDisk, mut f: impl FnMut(u32)) {
+    for &blk in &disk.index[..INODE_INDEX_1] {
+        if blk != 0 {
+            f(blk);
+        }
+    }
+    walk_index(disk.index[INODE_INDEX_1], 1, &mut f);
+    walk_index(disk.index[INODE_INDEX_2], 2, &mut f);
+}
+
+fn walk_index(blk: u32, level: u32, f: &mut impl FnMut(u32)) {
+    if blk == 0 {
+        return;
+    }
+    f(blk);
+    if !in_data(blk) {
+        return;
+    }
+    let b = buffer::read(0, blk);
+    let data = buffer::get_data_ptr(b) as *const u32;
+    for i in 0..NINDIRECT {
+        let entry = unsafe { *data.add(i) };
+        // 指回自己的项不展开, 否则会在持有缓冲区时再次读同一块
+        if level == 1 || entry == blk {
+            if entry != 0 {
+                f(entry);
+            }
+        } else {
+            walk_index(entry, 1, f);
+        }
+    }
+    buffer::release(b);
+}
+
+// 一页大小的位图, 恰好对应一个数据位图块
+struct PageBits(PhysFrame);
+
+impl PageBits {
+    fn new() -> Option<Self> {
+        let frame = PhysFrame::alloc()?;
+        unsafe { core::ptr::write_bytes(frame.addr() as *mut u8, 0, PGSIZE) };
+        Some(Self(frame))
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.0.addr() as *mut u8, PGSIZE) }
+    }
+
+    // 置位并返回原来的值
+    fn test_and_set(&mut self, i: usize) -> bool {
+        let byte = &mut self.bytes()[i / 8];
+        let old = *byte & (1 << (i % 8)) != 0;
+        *byte |= 1 << (i % 8);
+        old
+    }
+
+    fn counts(&mut self) -> &mut [u16] {
+        unsafe { core::slice::from_raw_parts_mut(self.0.addr() as *mut u16, COUNTS_PER_PAGE as usize) }
+    }
+}
+
+// 分配至多 want 页 (不超过 MAX_BATCH), 返回实际得到的页数; 一页也没有时为 0
+fn alloc_batch(pages: &mut [Option<PageBits>; MAX_BATCH], want: usize) -> usize {
+    let mut n = 0;
+    while n < want.min(MAX_BATCH) {
+        match PageBits::new() {
+            Some(page) => pages[n] = Some(page),
+            None => break,
+        }
+        n += 1;
+    }
+    n
+}
+
+// 依次给出已分配 inode 的号码和磁盘内容
+fn for_each_inode(mut f: impl FnMut(u32, &mut inode::Inode)) {
+    for inum in 0..get_sb().ninodes {
+        if inode::is_allocated(inum) {
+            let mut ip = inode::inode_snapshot(inum);
+            f(inum, &mut ip);
+        }
+    }
+}
+
+// 核对第 first_k 个起, 每页一个的位图块覆盖的数据块; 数据区外的引用只在第 0 批报告, 避免重复计数
+fn check_blocks(first_k: u32, pages: &mut [Option<PageBits>], repair: bool, report: &mut FsckReport) {
+    let sb = get_sb();
+    let first = first_k * SuperBlock::BITS_PER_BLOCK;
+    let end = sb.nblocks.min(first + pages.len() as u32 * SuperBlock::BITS_PER_BLOCK);
+
+    for_each_inode(|inum, ip| {
+        walk_blocks(&ip.disk, |blk| {
+            if !in_data(blk) {
+                if first_k == 0 {
+                    printk!("fsck: inode {} references block {} outside the data area\n", inum, blk);
+                    report.bad_blocks += 1;
+                }
+                return;
+            }
+            let idx = blk - sb.data_start();
+            if idx < first || idx >= end {
+                return;
+            }
+            let off = idx - first;
+            let seen = pages[(off / SuperBlock::BITS_PER_BLOCK) as usize].as_mut().unwrap();
+            if seen.test_and_set((off % SuperBlock::BITS_PER_BLOCK) as usize) {
+                printk!("fsck: block {} referenced more than once (again by inode {})\n", blk, inum);
+                report.duplicate += 1;
+            }
+        });
+    });
+
+    for (i, seen) in pages.iter_mut().enumerate() {
+        compare_bitmap(first_k + i as u32, seen.as_mut().unwrap(), repair, report);
+    }
+}
+
+// 把第 k 个位图块与引用记录比较, repair 时清掉泄漏的块
+fn compare_bitmap(k: u32, seen: &mut PageBits, repair: bool, report: &mut FsckReport) {
+    let sb = get_sb();
+    let first = k * SuperBlock::BITS_PER_BLOCK;
+    let nbits = sb.nblocks.saturating_sub(first).min(SuperBlock::BITS_PER_BLOCK);
+
+    let b = buffer::read(0, sb.bmap_start + k);
+    let data = buffer::get_data_ptr(b);
+    let mut changed = false;
+    for i in 0..nbits as usize {
+        let marked = unsafe { *data.add(i / 8) } & (1 << (i % 8)) != 0;
+        let used = seen.bytes()[i / 8] & (1 << (i % 8)) != 0;
+        let blk = sb.data_start() + first + i as u32;
+        if marked && !used {
+            printk!("fsck: block {} allocated but not referenced\n", blk);
+            report.leaked += 1;
+            if repair {
+                unsafe { *data.add(i / 8) &= !(1 << (i % 8)) };
+                report.repaired += 1;
+                changed = true;
+            }
+        } else if used && !marked {
+            printk!("fsck: block {} referenced but free in the bitmap\n", blk);
+            report.unmarked += 1;
+        }
+    }
+    if changed {
+        buffer::write(b);
+    }
+    buffer::release(b);
+}
+
+// 批内第 off 个 inode 的计数
+fn link_count(pages: &mut [Option<PageBits>], off: u32) -> &mut u16 {
+    let page = pages[(off / COUNTS_PER_PAGE) as usize].as_mut().unwrap();
+    &mut page.counts()[(off % COUNTS_PER_PAGE) as usize]
+}
+
+// 统计从 base 起, 每页 COUNTS_PER_PAGE 个 inode 的目录项引用数并与 nlink 比较
+// 目录的 nlink 包括父目录中的名字, 自己的 "." 和每个子目录的 "..", 都是指向它的目录项
+fn check_links(base: u32, pages: &mut [Option<PageBits>], report: &mut FsckReport) {
+    let ninodes = get_sb().ninodes;
+    let end = (base + pages.len() as u32 * COUNTS_PER_PAGE).min(ninodes);
+
+    for_each_inode(|dir_inum, dir| {
+        if dir.disk.type_ != INODE_TYPE_DIR {
+            return;
+        }
+        // 目录本身引用了数据区外的块时不读它的内容
+        let mut readable = true;
+        walk_blocks(&dir.disk, |blk| readable &= in_data(blk));
+        if !readable {
+            return;
+        }
+        dentry::for_each_dentry(dir, |name, inum| {
+            if base == 0 && !inode::is_allocated(inum) {
+                printk!(
+                    "fsck: dentry {:?} in directory {} points to unallocated inode {}\n",
+                    core::str::from_utf8(name).unwrap_or("?"),
+                    dir_inum,
+                    inum
+                );
+                report.dangling += 1;
+            } else if inum >= base && inum < end {
+                let c = link_count(pages, inum - base);
+                *c = c.saturating_add(1);
+            }
+            true
+        });
+    });
+
+    for inum in base..end {
+        if !inode::is_allocated(inum) {
+            continue;
+        }
+        let refs = *link_count(pages, inum - base);
+        let nlink = inode::inode_snapshot(inum).disk.nlink;
+        if refs == 0 && inum != ROOT_INODE {
+            printk!("fsck: inode {} (nlink {}) is not in any directory\n", inum, nlink);
+            report.orphans += 1;
+        } else if refs != nlink {
+            printk!("fsck: inode {} has nlink {}, but {} directory entries\n", inum, nlink, refs);
+            report.bad_nlink += 1;
+        }
+    }
+}
+
+// 检查整个文件系统; repair 为 true 时清掉泄漏的块。内存不足时跳过对应的检查
+pub fn fsck(repair: bool) -> FsckReport {
+    let sb = get_sb();
+    let mut report = FsckReport::default();
+
+    let mut k = 0;
+    while k < sb.bmap_blocks() {
+        let mut pages = [const { None }; MAX_BATCH];
+        let n = alloc_batch(&mut pages, (sb.bmap_blocks() - k) as usize);
+        if n == 0 {
+            printk!("fsck: out of memory, block check skipped\n");
+            break;
+        }
+        check_blocks(k, &mut pages[..n], repair, &mut report);
+        k += n as u32;
+    }
+    let mut base = 0;
+    while base < sb.ninodes {
+        let mut pages = [const { None }; MAX_BATCH];
+        let n = alloc_batch(&mut pages, (sb.ninodes - base).div_ceil(COUNTS_PER_PAGE) as usize);
+        if n == 0 {
+            printk!("fsck: out of memory, link count check skipped\n");
+            break;
+        }
+        check_links(base, &mut pages[..n], &mut report);
+        base += n as u32 * COUNTS_PER_PAGE;
+    }
+
+    if report.problems() == 0 {
+        printk!("fsck: clean\n");
+    } else {
+        printk!("fsck: {} problems, {} blocks repaired\n", report.problems(), report.repaired);
+    }
+    report
+}
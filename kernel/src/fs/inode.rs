@@ -2,15 +2,21 @@ use crate::fs::buffer;
 use crate::fs::buffer::BLOCK_SIZE;
 use crate::fs::fs::get_sb;
 use crate::fs::bitmap;
+use crate::dtb;
+use crate::mem::slab::Slab;
 use crate::printk;
+use crate::proc::scheduler;
 use spin::Mutex;
 use core::mem::size_of;
 use core::ptr;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 // Constants
 pub const ROOT_INODE: u32 = 0;
 pub const INODE_TYPE_DIR: u16 = 1;
 pub const INODE_TYPE_DATA: u16 = 2;
+pub const INODE_TYPE_SYMLINK: u16 = 3;
 
 // Index layout
 pub const INODE_INDEX_1: usize = 10; // Direct
@@ -18,6 +24,27 @@ pub const INODE_INDEX_2: usize = 12; // +2 Indirect Level 1
 pub const INODE_INDEX_3: usize = 13; // +1 Indirect Level 2
 pub const NINDIRECT: usize = BLOCK_SIZE / 4;
 pub const MAXLEN_FILENAME: usize = 60;
+pub const MAXLEN_SYMLINK: usize = 255; // 符号链接目标路径最大长度
+
+// Permission bits (rwxrwxrwx), only owner bits are checked for now
+pub const MODE_MASK: u16 = 0o777;
+// 旧格式磁盘的 inode 没有 mode, 读入内存时标记为这个值, 不做权限检查
+// 它带有 MODE_MASK 以外的位, chmod 给不出, 也不会写到有 mode 字段的磁盘上
+pub const MODE_UNCHECKED: u16 = u16::MAX;
+// access() 请求位, 与 POSIX 一致 (F_OK = 0 只检查存在)
+pub const X_OK: u16 = 1;
+pub const W_OK: u16 = 2;
+pub const R_OK: u16 = 4;
+
+// 旧格式 (version 0) 的 inode 没有 mode 字段
+pub const LEGACY_INODE_SIZE: usize = 64;
+// version 1 有 mode 但没有时间戳
+pub const V1_INODE_SIZE: usize = 68;
+
+// inode_touch 要更新的时间戳
+pub const TOUCH_ATIME: u8 = 1;
+pub const TOUCH_MTIME: u8 = 2;
+pub const TOUCH_CTIME: u8 = 4;
 
 // Disk Structures
 #[repr(C)]
@@ -29,6 +56,11 @@ pub struct InodeDisk {
     pub nlink: u16,
     pub size: u32,
     pub index: [u32; INODE_INDEX_3],
+    pub mode: u16, // 旧磁盘上读入为 MODE_UNCHECKED
+    pub _reserved: u16,
+    pub atime: u32, // 以下为开机以来的秒数, 旧磁盘上为 0
+    pub mtime: u32,
+    pub ctime: u32,
 }
 
 #[repr(C)]
@@ -45,6 +77,9 @@ pub struct Inode {
     pub inode_num: u32,
     pub refcnt: u32,
     pub lock: Mutex<()>,
+    pub busy: AtomicBool,    // 睡眠锁, 见 inode_lock
+    pub waiting: AtomicBool, // 有线程在等 busy 清除, inode_unlock 时需要 wakeup
+    pub atime_dirty: bool, // atime 只改了内存副本, 随下一次 inode_rw 或最后一次 inode_put 写回
 }
 
 impl Inode {
@@ -57,24 +92,40 @@ impl Inode {
                 nlink: 0,
                 size: 0,
                 index: [0; INODE_INDEX_3],
+                mode: 0,
+                _reserved: 0,
+                atime: 0,
+                mtime: 0,
+                ctime: 0,
             },
             valid: false,
             inode_num: 0,
             refcnt: 0,
             lock: Mutex::new(()),
+            busy: AtomicBool::new(false),
+            waiting: AtomicBool::new(false),
+            atime_dirty: false,
         }
     }
 }
 
-pub const N_INODE: usize = 50;
+// 缓存槽位数默认值, 启动参数 ninode=N 可以调整
+pub const DEFAULT_N_INODE: usize = 50;
+// 为路径解析等临时引用预留的槽位, 打开的文件最多占用其余部分
+pub const N_INODE_RESERVED: usize = 8;
 
 pub struct InodeCache {
-    pub inodes: [Inode; N_INODE],
+    pub inodes: Slab<Inode>,
 }
 
-pub static INODE_CACHE: Mutex<InodeCache> = Mutex::new(InodeCache {
-    inodes: [const { Inode::new() }; N_INODE],
-});
+pub static INODE_CACHE: Mutex<InodeCache> = Mutex::new(InodeCache { inodes: Slab::empty() });
+
+// 每释放一个槽位加一, inode_get 据此判断睡眠前是否已有槽位被释放
+static INODE_RELEASES: AtomicUsize = AtomicUsize::new(0);
+
+fn inode_cache_chan() -> usize {
+    &INODE_CACHE as *const _ as usize
+}
 
 fn locate_or_add_block(inode: &mut Inode, mut lbn: u32, grow: bool) -> Option<u32> {
     // 1. Direct Blocks
@@ -174,12 +225,12 @@ fn locate_or_add_block(inode: &mut Inode, mut lbn: u32, grow: bool) -> Option<u3
     panic!("locate_or_add_block: block index out of range");
 }
 
-fn free_data_blocks(inode: &mut Inode) {
+// 按后序访问 inode 占用的全部块: 数据块在前, 索引块在它指向的块之后
+fn for_each_block(inode: &Inode, mut f: impl FnMut(u32)) {
     // 1. Direct Blocks
     for i in 0..INODE_INDEX_1 {
         if inode.disk.index[i] != 0 {
-            bitmap::free(inode.disk.index[i]);
-            inode.disk.index[i] = 0;
+            f(inode.disk.index[i]);
         }
     }
 
@@ -191,12 +242,11 @@ fn free_data_blocks(inode: &mut Inode) {
         for i in 0..NINDIRECT {
             let blk = unsafe { *data.add(i) };
             if blk != 0 {
-                bitmap::free(blk);
+                f(blk);
             }
         }
         buffer::release(b);
-        bitmap::free(indirect_blk);
-        inode.disk.index[INODE_INDEX_1] = 0;
+        f(indirect_blk);
     }
 
     // 3. Indirect Level 2
@@ -213,30 +263,139 @@ fn free_data_blocks(inode: &mut Inode) {
                 for j in 0..NINDIRECT {
                     let blk = unsafe { *data_l2.add(j) };
                     if blk != 0 {
-                        bitmap::free(blk);
+                        f(blk);
                     }
                 }
                 buffer::release(b_l2);
-                bitmap::free(l2_blk);
+                f(l2_blk);
             }
         }
         buffer::release(b_l1);
-        bitmap::free(l1_blk);
-        inode.disk.index[INODE_INDEX_2] = 0;
+        f(l1_blk);
+    }
+}
+
+fn free_data_blocks(inode: &mut Inode) {
+    for_each_block(inode, bitmap::free);
+    inode.disk.index = [0; INODE_INDEX_3];
+}
+
+// 释放文件的全部数据块并把长度置 0, 写回磁盘 inode; 对空文件调用没有副作用
+pub fn inode_trunc(inode: &mut Inode) {
+    free_data_blocks(inode);
+    inode.disk.size = 0;
+    inode_rw(inode, true);
+}
+
+// 把文件长度改为 len 并写回磁盘 inode
+// 变长时不分配块, 新增部分是空洞, 读出为零; 变短时释放 len 之后的块 (以及因此变空的索引块),
+// 并把最后一个保留块中 len 之后的字节清零, 以后再变长时读到的仍是零
+pub fn inode_trunc_to(inode: &mut Inode, len: u32) {
+    if len == 0 {
+        inode_trunc(inode);
+        return;
+    }
+    if len < inode.disk.size {
+        let keep = (len as usize).div_ceil(BLOCK_SIZE);
+        for i in keep.min(INODE_INDEX_1)..INODE_INDEX_1 {
+            if inode.disk.index[i] != 0 {
+                bitmap::free(inode.disk.index[i]);
+                inode.disk.index[i] = 0;
+            }
+        }
+        let start = keep.saturating_sub(INODE_INDEX_1);
+        if inode.disk.index[INODE_INDEX_1] != 0 && free_index_from(inode.disk.index[INODE_INDEX_1], start, 1) {
+            inode.disk.index[INODE_INDEX_1] = 0;
+        }
+        let start = keep.saturating_sub(INODE_INDEX_1 + NINDIRECT);
+        if inode.disk.index[INODE_INDEX_2] != 0 && free_index_from(inode.disk.index[INODE_INDEX_2], start, 2) {
+            inode.disk.index[INODE_INDEX_2] = 0;
+        }
+
+        let tail = len as usize % BLOCK_SIZE;
+        if tail != 0 {
+            if let Some(blk) = locate_or_add_block(inode, len / BLOCK_SIZE as u32, false) {
+                let b = buffer::read(0, blk);
+                unsafe { ptr::write_bytes(buffer::get_data_ptr(b).add(tail), 0, BLOCK_SIZE - tail) };
+                buffer::write(b);
+                buffer::release(b);
+            }
+        }
     }
+    inode.disk.size = len;
+    inode_rw(inode, true);
+}
+
+// 释放 level 级索引块 blk 所覆盖的第 start 个数据块及之后的全部块 (level 1 每项一个数据块,
+// level 2 每项一个一级索引块); 返回 blk 是否整块释放, 否则只清掉被释放的项
+fn free_index_from(blk: u32, start: usize, level: u32) -> bool {
+    let per_entry = if level == 1 { 1 } else { NINDIRECT };
+    let b = buffer::read(0, blk);
+    let data = buffer::get_data_ptr(b) as *mut u32;
+    let mut changed = false;
+    for i in 0..NINDIRECT {
+        let entry = unsafe { *data.add(i) };
+        let first = i * per_entry;
+        if entry == 0 || first + per_entry <= start {
+            continue;
+        }
+        let freed = if level == 1 {
+            bitmap::free(entry);
+            true
+        } else {
+            free_index_from(entry, start.saturating_sub(first), 1)
+        };
+        if freed {
+            unsafe { *data.add(i) = 0 };
+            changed = true;
+        }
+    }
+    if start == 0 {
+        buffer::release(b);
+        bitmap::free(blk);
+        return true;
+    }
+    if changed {
+        buffer::write(b);
+    }
+    buffer::release(b);
+    false
 }
 
 pub fn inode_init() {
-    let _cache = INODE_CACHE.lock();
-    printk!("Inode cache initialized with {} inodes\n", N_INODE);
+    let n = dtb::bootarg("ninode")
+        .unwrap_or(DEFAULT_N_INODE)
+        .clamp(N_INODE_RESERVED + 1, Slab::<Inode>::MAX_LEN);
+    let mut cache = INODE_CACHE.lock();
+    cache.inodes.init(n, Inode::new);
+    printk!("Inode cache initialized with {} inodes\n", n);
+}
+
+// 缓存槽位数, 不能在持有 INODE_CACHE 时调用
+pub fn n_inode() -> usize {
+    INODE_CACHE.lock().inodes.len()
 }
 
 
+// On-disk inode size, depends on the superblock version
+pub fn inode_disk_size() -> usize {
+    match get_sb().version {
+        0 => LEGACY_INODE_SIZE,
+        1 => V1_INODE_SIZE,
+        _ => size_of::<InodeDisk>(),
+    }
+}
+
+// 磁盘 inode 所在的块号与块内偏移
+fn inode_location(inum: u32) -> (u32, usize) {
+    let isize = inode_disk_size();
+    let ipb = (BLOCK_SIZE / isize) as u32; // Inodes per block
+    (get_sb().inode_start + inum / ipb, (inum % ipb) as usize * isize)
+}
+
 pub fn inode_rw(inode: &mut Inode, write: bool) {
-    let sb = get_sb();
-    let ipb = (BLOCK_SIZE / size_of::<InodeDisk>()) as u32; // Inodes per block
-    let block = sb.inode_start + (inode.inode_num / ipb);
-    let offset = (inode.inode_num % ipb) as usize * size_of::<InodeDisk>();
+    let isize = inode_disk_size();
+    let (block, offset) = inode_location(inode.inode_num);
 
     let b = buffer::read(0, block); // Assuming dev 0 for now
     let data_ptr = buffer::get_data_ptr(b);
@@ -244,35 +403,111 @@ pub fn inode_rw(inode: &mut Inode, write: bool) {
     unsafe {
         if write {
             // Copy from Inode to disk buffer
-            let inode_disk_ptr = &inode.disk as *const InodeDisk;
-            ptr::copy_nonoverlapping(inode_disk_ptr, (data_ptr as *mut u8).add(offset) as *mut InodeDisk, 1);
+            let inode_disk_ptr = &inode.disk as *const InodeDisk as *const u8;
+            ptr::copy_nonoverlapping(inode_disk_ptr, (data_ptr as *mut u8).add(offset), isize);
             buffer::write(b);
         } else {
+            // 旧格式只拷贝前 isize 字节, 缺少的 mode 标记为不检查, 时间戳保持为 0
+            inode.disk.mode = MODE_UNCHECKED;
+            inode.disk._reserved = 0;
+            inode.disk.atime = 0;
+            inode.disk.mtime = 0;
+            inode.disk.ctime = 0;
             // Copy from disk buffer to Inode
-            let inode_disk_ptr = &mut inode.disk as *mut InodeDisk;
-            ptr::copy_nonoverlapping((data_ptr as *const u8).add(offset) as *const InodeDisk, inode_disk_ptr, 1);
+            let inode_disk_ptr = &mut inode.disk as *mut InodeDisk as *mut u8;
+            ptr::copy_nonoverlapping((data_ptr as *const u8).add(offset), inode_disk_ptr, isize);
         }
     }
     buffer::release(b);
+    // 两个方向上内存副本与磁盘都已一致
+    inode.atime_dirty = false;
+}
+
+// 检查 inode 的权限位是否满足 access() 请求 (R_OK/W_OK/X_OK 组合)
+// 旧磁盘上的 inode (MODE_UNCHECKED) 一律放行; mode 为 0 与 POSIX 一样什么都不允许
+pub fn inode_permits(inode: &Inode, want: u16) -> bool {
+    if inode.disk.mode == MODE_UNCHECKED {
+        return true;
+    }
+    let mode = inode.disk.mode & MODE_MASK;
+    let owner = (mode >> 6) & 0o7;
+    (owner & want) == want
+}
+
+pub fn default_mode(type_: u16) -> u16 {
+    match type_ {
+        INODE_TYPE_DIR => 0o755,
+        INODE_TYPE_SYMLINK => 0o777,
+        _ => 0o644,
+    }
 }
 
 
 pub fn inode_get(inum: u32) -> &'static mut Inode {
+    // 启动阶段没有进程可以睡眠, 缓存耗尽只能是配置错误
+    wait_for_slot(|| inode_try_get(inum)).unwrap_or_else(|| panic!("inode_get: no free inode in cache"))
+}
+
+// 反复尝试 f, 所有槽位都被引用时等待 inode_put 释放一个; 启动阶段无法睡眠, 返回 None
+fn wait_for_slot(mut f: impl FnMut() -> Option<&'static mut Inode>) -> Option<&'static mut Inode> {
+    loop {
+        let seen = INODE_RELEASES.load(Ordering::Acquire);
+        if let Some(inode) = f() {
+            return Some(inode);
+        }
+        if crate::hart::get().proc.is_null() {
+            return None;
+        }
+        scheduler::sleep_unless(inode_cache_chan(), || {
+            INODE_RELEASES.load(Ordering::Acquire) != seen
+        });
+    }
+}
+
+// 占住一个空闲槽位, 还没有绑定 inode 号 (valid = false), 供 inode_create 在分配位图前使用
+fn inode_reserve_slot() -> Option<&'static mut Inode> {
+    let mut cache_guard = INODE_CACHE.lock();
+    for i in 0..cache_guard.inodes.len() {
+        let inode = unsafe { &mut *(&raw mut cache_guard.inodes[i] as *mut Inode) };
+        if inode.refcnt == 0 {
+            inode.inode_num = 0;
+            inode.valid = false;
+            inode.refcnt = 1;
+            return Some(inode);
+        }
+    }
+    None
+}
+
+// 归还 inode_reserve_slot 占住的槽位
+fn inode_release_slot(inode: &mut Inode) {
+    {
+        let _cache_guard = INODE_CACHE.lock();
+        inode.refcnt = 0;
+        inode.valid = false;
+    }
+    INODE_RELEASES.fetch_add(1, Ordering::Release);
+    scheduler::wakeup(inode_cache_chan());
+}
+
+// 与 inode_get 相同, 但缓存中所有槽位都被引用时返回 None 而不是等待
+pub fn inode_try_get(inum: u32) -> Option<&'static mut Inode> {
     let mut cache_guard = INODE_CACHE.lock();
 
+    let n = cache_guard.inodes.len();
     // Search active cache for inode
-    for i in 0..N_INODE {
+    for i in 0..n {
         let inode = unsafe { &mut *(&raw mut cache_guard.inodes[i] as *mut Inode) };
         if inode.refcnt > 0 && inode.inode_num == inum && inode.valid {
             // Found in cache, increment refcnt
             inode.refcnt += 1;
             drop(cache_guard); // Release global cache lock
-            return inode;
+            return Some(inode);
         }
     }
 
     // Not in cache, find a free slot (refcnt == 0)
-    for i in 0..N_INODE {
+    for i in 0..n {
         let inode = unsafe { &mut *(&raw mut cache_guard.inodes[i] as *mut Inode) };
         if inode.refcnt == 0 {
             // Found a free slot
@@ -285,13 +520,17 @@ pub fn inode_get(inum: u32) -> &'static mut Inode {
             // Read InodeDisk from disk into inode.disk
             inode_rw(inode, false);
             inode.valid = true; // Mark as valid after reading
-            return inode;
+            return Some(inode);
         }
     }
 
-    // No free slot found
-    drop(cache_guard);
-    panic!("inode_get: no free inode in cache");
+    None
+}
+
+// 当前被引用 (refcnt > 0) 的缓存槽位数
+pub fn inode_in_use() -> usize {
+    let cache = INODE_CACHE.lock();
+    cache.inodes.iter().filter(|ip| ip.refcnt > 0).count()
 }
 
 pub fn inode_dup(inode: &mut Inode) {
@@ -301,12 +540,18 @@ pub fn inode_dup(inode: &mut Inode) {
 }
 
 pub fn inode_put(inode: &mut Inode) {
+    // 放掉最后一个引用前写回只在内存中更新的 atime, 之后槽位可能被别的 inode 复用
+    // 读 refcnt 不持锁: 并发的 inode_dup 至多让这次写回提前, 不会丢失
+    if inode.atime_dirty && inode.refcnt == 1 && inode.disk.nlink > 0 {
+        inode_rw(inode, true);
+    }
     let guard = inode.lock.lock(); // Acquire individual inode lock
     if inode.refcnt == 0 {
         panic!("inode_put: refcnt is already zero for inode {}", inode.inode_num);
     }
     inode.refcnt -= 1;
-    let should_delete = inode.refcnt == 0 && inode.disk.nlink == 0;
+    let released = inode.refcnt == 0;
+    let should_delete = released && inode.disk.nlink == 0;
     drop(guard); // Release lock before potential deletion logic
 
     if should_delete {
@@ -326,11 +571,57 @@ pub fn inode_put(inode: &mut Inode) {
         inode.valid = false;
         inode.inode_num = 0;
     }
+
+    if released {
+        INODE_RELEASES.fetch_add(1, Ordering::Release);
+        scheduler::wakeup(inode_cache_chan());
+    }
+}
+
+// inode 的睡眠锁: 保护文件偏移的 "读取 - 读写数据 - 更新", 持有期间可以做磁盘 I/O 和缺页,
+// 因此不能用自旋锁 lock; 调用者必须持有引用, 启动阶段没有进程可以睡眠时自旋
+pub fn inode_lock(inode: &Inode) {
+    while inode.busy.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        if crate::hart::get().proc.is_null() {
+            spin_loop();
+            continue;
+        }
+        inode.waiting.store(true, Ordering::SeqCst);
+        // 在 PROC_TABLE 锁内复查, inode_unlock 先清 busy 再看 waiting, 不会错过唤醒
+        scheduler::sleep_unless(inode_lock_chan(inode), || !inode.busy.load(Ordering::SeqCst));
+    }
 }
 
+pub fn inode_unlock(inode: &Inode) {
+    inode.busy.store(false, Ordering::SeqCst);
+    if inode.waiting.swap(false, Ordering::SeqCst) {
+        scheduler::wakeup(inode_lock_chan(inode));
+    }
+}
+
+fn inode_lock_chan(inode: &Inode) -> usize {
+    inode as *const Inode as usize
+}
+
+// 把 which 指定的时间戳设为当前时间, 不写回磁盘
+pub fn inode_touch(inode: &mut Inode, which: u8) {
+    let now = crate::irq::timer::seconds();
+    if which & TOUCH_ATIME != 0 {
+        inode.disk.atime = now;
+    }
+    if which & TOUCH_MTIME != 0 {
+        inode.disk.mtime = now;
+    }
+    if which & TOUCH_CTIME != 0 {
+        inode.disk.ctime = now;
+    }
+}
+
+// 从 off 起读至多 len 字节到 dst, 返回实际读到的字节数
+// len 超过 dst.len() 时按 dst.len() 截断, 不会越界写
 pub fn inode_read_data(inode: &mut Inode, off: u32, len: u32, dst: &mut [u8]) -> u32 {
     let mut off = off;
-    let mut len = len;
+    let mut len = len.min(dst.len() as u32);
     let mut dst_off = 0;
 
     if off >= inode.disk.size {
@@ -351,7 +642,11 @@ pub fn inode_read_data(inode: &mut Inode, off: u32, len: u32, dst: &mut [u8]) ->
 
         match locate_or_add_block(inode, lbn, false) {
             Some(block_no) => {
-                let b = buffer::read(0, block_no);
+                // 块校验失败时停在这里, 返回之前读到的字节数
+                let Ok(b) = buffer::try_read(0, block_no) else {
+                    len = dst_off as u32;
+                    break;
+                };
                 let data = buffer::get_data_ptr(b);
                 unsafe {
                     ptr::copy_nonoverlapping(
@@ -374,10 +669,19 @@ pub fn inode_read_data(inode: &mut Inode, off: u32, len: u32, dst: &mut [u8]) ->
         dst_off += copy_len;
     }
 
+    // 读取只更新内存中的 atime, 不为它单独写盘; inode_snapshot 的副本不会写回
+    if len > 0 && inode.disk.atime != crate::irq::timer::seconds() {
+        inode_touch(inode, TOUCH_ATIME);
+        inode.atime_dirty = true;
+    }
+
     len
 }
 
+// 把 src 的前 len 字节写到 off 处, 返回写入的字节数
+// len 超过 src.len() 时按 src.len() 截断, 不会越界读
 pub fn inode_write_data(inode: &mut Inode, off: u32, len: u32, src: &[u8]) -> u32 {
+    let len = len.min(src.len() as u32);
     let mut off = off;
     let end = off + len;
     let mut src_off = 0;
@@ -394,7 +698,10 @@ pub fn inode_write_data(inode: &mut Inode, off: u32, len: u32, src: &[u8]) -> u3
 
         let block_no = locate_or_add_block(inode, lbn, true).expect("inode_write_data: out of blocks");
 
-        let b = buffer::read(0, block_no);
+        // 块校验失败时不覆盖它, 返回之前写入的字节数
+        let Ok(b) = buffer::try_read(0, block_no) else {
+            break;
+        };
         let data = buffer::get_data_ptr(b);
 
         unsafe {
@@ -405,6 +712,7 @@ pub fn inode_write_data(inode: &mut Inode, off: u32, len: u32, src: &[u8]) -> u3
             );
         }
 
+        // 数据块延迟写回, 见 inode_sync
         buffer::write(b);
         buffer::release(b);
 
@@ -412,17 +720,41 @@ pub fn inode_write_data(inode: &mut Inode, off: u32, len: u32, src: &[u8]) -> u3
         src_off += copy_len;
     }
 
-    if end > inode.disk.size {
-        inode.disk.size = end;
+    // off 停在实际写到的位置, 中途失败时只计入已写的部分
+    if off > inode.disk.size {
+        inode.disk.size = off;
+    }
+    let written = src_off as u32;
+    if written > 0 {
+        inode_touch(inode, TOUCH_MTIME | TOUCH_CTIME);
         inode_rw(inode, true);
     }
 
-    len
+    written
 }
 
-pub fn inode_create(type_: u16, major: u16, minor: u16) -> &'static mut Inode {
-    let inum = alloc(); // Allocate a new inode number
-    let inode = inode_get(inum); // Get the inode from cache or disk
+// fsync: 只写回该文件在缓存中的脏块 (数据块与索引块) 和 inode 所在的块
+pub fn inode_sync(inode: &mut Inode) {
+    for_each_block(inode, |blk| {
+        buffer::flush_block(0, blk);
+    });
+    buffer::flush_block(0, inode_location(inode.inode_num).0);
+}
+
+// 先占缓存槽位再分配位图: 任何一步失败都返回 Err, 不会留下位图已置位却没有引用的 inode
+// 调用者随后把它链接进目录, 链接失败时用 inode_discard 撤销
+pub fn inode_create(type_: u16, major: u16, minor: u16) -> Result<&'static mut Inode, ()> {
+    let inode = wait_for_slot(inode_reserve_slot).ok_or(())?;
+    let inum = match alloc() {
+        Ok(inum) => inum,
+        Err(()) => {
+            inode_release_slot(inode);
+            return Err(());
+        }
+    };
+    inode.inode_num = inum;
+    inode_rw(inode, false);
+    inode.valid = true;
 
     // Acquire individual inode lock
     // This guard ensures that 'inode' is exclusively accessed during initialization.
@@ -433,21 +765,29 @@ pub fn inode_create(type_: u16, major: u16, minor: u16) -> &'static mut Inode {
     inode.disk.minor = minor;
     inode.disk.nlink = 1;
     inode.disk.size = 0;
+    inode.disk.mode = default_mode(type_);
     // Initialize index array to zeros
     for i in 0..INODE_INDEX_3 {
         inode.disk.index[i] = 0;
     }
 
     drop(guard); // Explicitly drop the guard here to release the lock on 'inode'
+    inode_touch(inode, TOUCH_ATIME | TOUCH_MTIME | TOUCH_CTIME);
 
     inode_rw(inode, true); // Write the initialized inode to disk
 
-    inode
+    Ok(inode)
+}
+
+// 撤销一个还没有链接进任何目录的新 inode: 释放数据块、位图和缓存引用
+pub fn inode_discard(inode: &mut Inode) {
+    inode.disk.nlink = 0;
+    inode_put(inode);
 }
 
 pub fn inode_print(inode: &Inode, tag: &str) {
     printk!(
-        "[{}] Inode {} (ref: {}, valid: {}): type={}, major={}, minor={}, nlink={}, size={}, index={:?}\n",
+        "[{}] Inode {} (ref: {}, valid: {}): type={}, major={}, minor={}, nlink={}, size={}, mode={:o}, index={:?}\n",
         tag,
         inode.inode_num,
         inode.refcnt,
@@ -457,11 +797,14 @@ pub fn inode_print(inode: &Inode, tag: &str) {
         inode.disk.minor,
         inode.disk.nlink,
         inode.disk.size,
+        inode.disk.mode,
         inode.disk.index
     );
 }
 
-pub fn alloc() -> u32 {    let sb = get_sb();
+// 分配一个 inode 号并在位图中置位, 用完时返回 Err
+pub fn alloc() -> Result<u32, ()> {
+    let sb = get_sb();
     let ibmap_block = sb.inode_start - 1;
 
     let b = buffer::read(0, ibmap_block);
@@ -477,7 +820,7 @@ pub fn alloc() -> u32 {    let sb = get_sb();
                     let bit_idx = i * 8 + j;
                     if bit_idx as u32 >= total_inodes {
                         buffer::release(b);
-                        panic!("inode_alloc: out of inodes");
+                        return Err(());
                     }
 
                     unsafe {
@@ -486,13 +829,49 @@ pub fn alloc() -> u32 {    let sb = get_sb();
                     buffer::write(b);
                     buffer::release(b);
 
-                    return bit_idx as u32;
+                    return Ok(bit_idx as u32);
                 }
             }
         }
     }
     buffer::release(b);
-    panic!("inode_alloc: out of inodes");
+    Err(())
+}
+
+// inode 位图中 inum 是否已分配
+pub fn is_allocated(inum: u32) -> bool {
+    let sb = get_sb();
+    if inum >= sb.ninodes {
+        return false;
+    }
+    let b = buffer::read(0, sb.inode_start - 1);
+    let byte = unsafe { *buffer::get_data_ptr(b).add(inum as usize / 8) };
+    buffer::release(b);
+    byte & (1 << (inum % 8)) != 0
+}
+
+// 从磁盘读出一个不进缓存的 inode 副本, 丢弃它不会写回, 也不会因为 nlink 为 0 回收 inode
+pub fn inode_snapshot(inum: u32) -> Inode {
+    let mut ip = Inode::new();
+    ip.inode_num = inum;
+    inode_rw(&mut ip, false);
+    ip
+}
+
+// 位图中已分配的 inode 数, 测试用
+#[allow(dead_code)]
+pub fn count_allocated() -> u32 {
+    let sb = get_sb();
+    let b = buffer::read(0, sb.inode_start - 1);
+    let data = buffer::get_data_ptr(b);
+    let mut n = 0;
+    for inum in 0..sb.ninodes as usize {
+        if unsafe { *data.add(inum / 8) } & (1 << (inum % 8)) != 0 {
+            n += 1;
+        }
+    }
+    buffer::release(b);
+    n
 }
 
 pub fn free(inode_idx: u32) {
@@ -3,5 +3,7 @@ pub mod buffer;
 pub mod dentry;
 pub mod file;
 pub mod fs;
+pub mod fsck;
 pub mod inode;
 pub mod path;
+pub mod pipe;
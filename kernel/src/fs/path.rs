@@ -1,6 +1,9 @@
-use crate::fs::inode::{self, Inode, ROOT_INODE, INODE_TYPE_DIR, MAXLEN_FILENAME};
+use crate::fs::inode::{self, Inode, ROOT_INODE, INODE_TYPE_DIR, INODE_TYPE_SYMLINK, MAXLEN_FILENAME, MAXLEN_SYMLINK};
 use crate::fs::dentry;
 
+// 符号链接最大嵌套深度, 超过视为环路
+pub const MAX_SYMLINK_DEPTH: usize = 8;
+
 fn get_element(path: &[u8], mut pos: usize) -> Option<(&[u8], usize)> {
     // Skip leading slashes
     while pos < path.len() && path[pos] == b'/' {
@@ -24,7 +27,37 @@ fn get_element(path: &[u8], mut pos: usize) -> Option<(&[u8], usize)> {
     Some((&path[start..pos], pos))
 }
 
-fn __path_to_inode_at(cwd_inum: u32, path: &[u8]) -> Option<&'static mut Inode> {
+// 在目录 dir 中查找一个路径分量: "." 是 dir 自身, 根目录的 ".." 仍是根目录,
+// 其余 (包括其他目录的 "..") 按目录项查找
+fn lookup(dir: &mut Inode, name: &[u8]) -> Option<u32> {
+    match name {
+        b"." => Some(dir.inode_num),
+        b".." if dir.inode_num == ROOT_INODE => Some(ROOT_INODE),
+        _ => dentry::dentry_search(dir, name),
+    }
+}
+
+// 若 ip 是符号链接则以 dir 为起点解析其目标, 否则原样返回
+// 会消耗 ip 的引用; dir 的引用保持不变
+fn follow_link(dir: &mut Inode, ip: &'static mut Inode, depth: usize) -> Option<&'static mut Inode> {
+    if ip.disk.type_ != INODE_TYPE_SYMLINK {
+        return Some(ip);
+    }
+    if depth >= MAX_SYMLINK_DEPTH {
+        inode::inode_put(ip);
+        return None;
+    }
+
+    let mut target = [0u8; MAXLEN_SYMLINK];
+    let len = inode::inode_read_data(ip, 0, MAXLEN_SYMLINK as u32, &mut target) as usize;
+    inode::inode_put(ip);
+    if len == 0 {
+        return None;
+    }
+    __path_to_inode_at(dir.inode_num, &target[..len], true, depth + 1)
+}
+
+fn __path_to_inode_at(cwd_inum: u32, path: &[u8], follow: bool, depth: usize) -> Option<&'static mut Inode> {
     let start_inum = if path.starts_with(b"/") {
         inode::ROOT_INODE
     } else {
@@ -46,9 +79,19 @@ fn __path_to_inode_at(cwd_inum: u32, path: &[u8]) -> Option<&'static mut Inode>
             return None;
         }
 
-        match dentry::dentry_search(inode, name) {
+        match lookup(inode, name) {
             Some(inum) => {
-                let next_inode = inode::inode_get(inum);
+                let mut next_inode = inode::inode_get(inum);
+                // 中间分量总是跟随, 末尾分量由 follow 决定
+                if follow || get_element(path, pos).is_some() {
+                    next_inode = match follow_link(inode, next_inode, depth) {
+                        Some(ip) => ip,
+                        None => {
+                            inode::inode_put(inode);
+                            return None;
+                        }
+                    };
+                }
                 inode::inode_put(inode);
                 inode = next_inode;
             }
@@ -61,11 +104,16 @@ fn __path_to_inode_at(cwd_inum: u32, path: &[u8]) -> Option<&'static mut Inode>
 }
 
 pub fn path_to_inode(path: &[u8]) -> Option<&'static mut Inode> {
-    __path_to_inode_at(inode::ROOT_INODE, path)
+    __path_to_inode_at(inode::ROOT_INODE, path, true, 0)
 }
 
 pub fn path_to_inode_at(cwd_inum: u32, path: &[u8]) -> Option<&'static mut Inode> {
-    __path_to_inode_at(cwd_inum, path)
+    __path_to_inode_at(cwd_inum, path, true, 0)
+}
+
+// 与 path_to_inode_at 相同, 但末尾分量若为符号链接则返回链接本身
+pub fn path_to_inode_nofollow_at(cwd_inum: u32, path: &[u8]) -> Option<&'static mut Inode> {
+    __path_to_inode_at(cwd_inum, path, false, 0)
 }
 
 pub fn path_to_parent_inode_at(cwd_inum: u32, path: &[u8], name_buf: &mut [u8]) -> Option<&'static mut Inode> {
@@ -90,7 +138,12 @@ pub fn path_to_parent_inode_at(cwd_inum: u32, path: &[u8], name_buf: &mut [u8])
         let (next_name, next_next_pos) = match get_element(path, pos) {
             Some(res) => res,
             None => {
-                let len = if name.len() > MAXLEN_FILENAME { MAXLEN_FILENAME } else { name.len() };
+                // 过长的名字无法存入目录项, 不能截断后当作另一个名字使用
+                if name.len() > MAXLEN_FILENAME {
+                    inode::inode_put(inode);
+                    return None;
+                }
+                let len = name.len();
                 for i in 0..len {
                     name_buf[i] = name[i];
                 }
@@ -108,9 +161,15 @@ pub fn path_to_parent_inode_at(cwd_inum: u32, path: &[u8], name_buf: &mut [u8])
             return None;
         }
 
-        match dentry::dentry_search(inode, name) {
+        match lookup(inode, name) {
             Some(inum) => {
-                let next_inode = inode::inode_get(inum);
+                let next_inode = match follow_link(inode, inode::inode_get(inum), 0) {
+                    Some(ip) => ip,
+                    None => {
+                        inode::inode_put(inode);
+                        return None;
+                    }
+                };
                 inode::inode_put(inode);
                 inode = next_inode;
             }
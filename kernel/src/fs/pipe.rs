@@ -0,0 +1,123 @@
+use crate::proc::scheduler;
+use spin::Mutex;
+
+pub const PIPE_SIZE: usize = 512;
+pub const NPIPE: usize = 16; // 全局最大管道数
+
+/// 内核中的有界字节流, 读端和写端各是一个 File
+/// readers/writers 统计的是 File 对象而不是 fd, fork/dup 共享同一个 File 时只加 File 的引用计数
+pub struct Pipe {
+    data: [u8; PIPE_SIZE],
+    nread: usize,  // 累计读出的字节数
+    nwrite: usize, // 累计写入的字节数
+    readers: u32,
+    writers: u32,
+}
+
+impl Pipe {
+    pub const fn new() -> Self {
+        Self { data: [0; PIPE_SIZE], nread: 0, nwrite: 0, readers: 0, writers: 0 }
+    }
+
+    fn is_free(&self) -> bool {
+        self.readers == 0 && self.writers == 0
+    }
+
+    fn readable(&self) -> bool {
+        self.nwrite != self.nread || self.writers == 0
+    }
+
+    fn writable(&self) -> bool {
+        self.nwrite != self.nread + PIPE_SIZE || self.readers == 0
+    }
+}
+
+static PIPE_TABLE: [Mutex<Pipe>; NPIPE] = [const { Mutex::new(Pipe::new()) }; NPIPE];
+
+// 睡眠通道: 读者等待数据, 写者等待空间
+fn read_chan(id: usize) -> usize {
+    &PIPE_TABLE[id] as *const _ as usize
+}
+
+fn write_chan(id: usize) -> usize {
+    read_chan(id) + 1
+}
+
+/// 分配一个读写端都已打开的管道
+pub fn pipe_alloc() -> Option<usize> {
+    for id in 0..NPIPE {
+        let mut pipe = PIPE_TABLE[id].lock();
+        if pipe.is_free() {
+            pipe.nread = 0;
+            pipe.nwrite = 0;
+            pipe.readers = 1;
+            pipe.writers = 1;
+            return Some(id);
+        }
+    }
+    None
+}
+
+/// 关闭一端并唤醒另一端: 读者在最后一个写端关闭后看到 EOF, 写者在最后一个读端关闭后出错返回
+pub fn pipe_close(id: usize, writable: bool) {
+    {
+        let mut pipe = PIPE_TABLE[id].lock();
+        if writable {
+            pipe.writers -= 1;
+        } else {
+            pipe.readers -= 1;
+        }
+    }
+    scheduler::wakeup(if writable { read_chan(id) } else { write_chan(id) });
+}
+
+/// 管道为空且仍有写端时阻塞, 返回读到的字节数; 返回 0 表示 EOF
+pub fn pipe_read(id: usize, dst: &mut [u8]) -> usize {
+    if dst.is_empty() {
+        return 0;
+    }
+    loop {
+        {
+            let mut pipe = PIPE_TABLE[id].lock();
+            if pipe.readable() {
+                let mut n = 0;
+                while n < dst.len() && pipe.nread != pipe.nwrite {
+                    dst[n] = pipe.data[pipe.nread % PIPE_SIZE];
+                    pipe.nread += 1;
+                    n += 1;
+                }
+                drop(pipe);
+                if n > 0 {
+                    scheduler::wakeup(write_chan(id));
+                }
+                return n;
+            }
+        }
+        // 写者先更新管道再 wakeup, sleep_unless 在 PROC_TABLE 锁内复查, 不会错过唤醒
+        scheduler::sleep_unless(read_chan(id), || PIPE_TABLE[id].lock().readable());
+    }
+}
+
+/// 写入全部数据, 管道满时阻塞; 读端全部关闭时提前返回, 返回值小于 src.len()
+pub fn pipe_write(id: usize, src: &[u8]) -> usize {
+    let mut n = 0;
+    while n < src.len() {
+        {
+            let mut pipe = PIPE_TABLE[id].lock();
+            if pipe.readers == 0 {
+                break;
+            }
+            while n < src.len() && pipe.nwrite != pipe.nread + PIPE_SIZE {
+                let pos = pipe.nwrite % PIPE_SIZE;
+                pipe.data[pos] = src[n];
+                pipe.nwrite += 1;
+                n += 1;
+            }
+        }
+        scheduler::wakeup(read_chan(id));
+        if n < src.len() {
+            scheduler::sleep_unless(write_chan(id), || PIPE_TABLE[id].lock().writable());
+        }
+    }
+    n
+}
@@ -1,6 +1,9 @@
 use crate::proc::{ProcContext, Process};
+use crate::printk;
+use crate::printk::{ANSI_RESET, ANSI_YELLOW};
 use core::arch::asm;
 use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const MAX_HARTS: usize = 8;
 
@@ -14,16 +17,40 @@ pub struct Hart {
     pub context: ProcContext,
     pub nest_count: usize,
     pub enabled: bool,
+    pub fault_fixup: usize, // 非 0 时, 内核态访存异常跳到此地址继续执行, 见 uvm::copyin
+    // 返回用户态时不变的值, 由 trap::init_user_return 记录一次
+    pub kernel_satp: usize,    // 内核页表的 satp
+    pub user_vector_va: usize, // 跳板页上 user_vector 的虚拟地址
+    pub user_return_va: usize, // 跳板页上 user_return 的虚拟地址
+    pub icache_gen: usize,     // 本 hart 上次 fence.i 时看到的 ICACHE_GEN
 }
 
 impl Hart {
     pub const fn new() -> Self {
-        Self { proc: ptr::null_mut(), context: ProcContext::new(), nest_count: 0, enabled: false }
+        Self {
+            proc: ptr::null_mut(),
+            context: ProcContext::new(),
+            nest_count: 0,
+            enabled: false,
+            fault_fixup: 0,
+            kernel_satp: 0,
+            user_vector_va: 0,
+            user_return_va: 0,
+            icache_gen: 0,
+        }
     }
 }
 
 pub static mut HARTS: [Hart; MAX_HARTS] = [Hart::new(); MAX_HARTS];
 
+// 正在调度器中 wfi 等待的 hart 位图, wakeup 据此决定向谁发 IPI
+static IDLE_HARTS: AtomicUsize = AtomicUsize::new(0);
+// 每个 hart 收到的 S 态软件中断次数
+static IPI_COUNT: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+// 写入可执行用户页的次数。fence.i 只作用于执行它的 hart, 其他 hart 在下次返回用户态前
+// 看到计数变化时补做一次, 这样在一个 hart 上装入的代码换到另一个 hart 运行也不会取到旧指令
+static ICACHE_GEN: AtomicUsize = AtomicUsize::new(0);
+
 #[inline(always)]
 pub fn getid() -> usize {
     let mut id: usize;
@@ -47,3 +74,66 @@ pub fn enable_hart(hartid: usize) {
     let hart = unsafe { &mut HARTS[hartid] };
     hart.enabled = true;
 }
+
+// 标记当前 hart 是否空闲; 置位后调度器必须再检查一次就绪队列才能 wfi
+pub fn set_idle(idle: bool) {
+    let bit = 1usize << getid();
+    if idle {
+        IDLE_HARTS.fetch_or(bit, Ordering::SeqCst);
+    } else {
+        IDLE_HARTS.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+pub fn is_idle(hartid: usize) -> bool {
+    IDLE_HARTS.load(Ordering::SeqCst) & (1usize << hartid) != 0
+}
+
+pub fn send_wakeup_ipi(target_hart: usize) {
+    if let Err(e) = crate::sbi::send_ipi(1usize << target_hart, 0) {
+        printk!("{}[WARN] hart: send_ipi to hart {} failed: {}{}\n", ANSI_YELLOW, target_hart, e, ANSI_RESET);
+    }
+}
+
+// 向至多 n 个空闲的其他 hart 发送唤醒 IPI
+pub fn kick_idle_harts(n: usize) {
+    let mut idle = IDLE_HARTS.load(Ordering::SeqCst) & !(1usize << getid());
+    for _ in 0..n {
+        if idle == 0 {
+            break;
+        }
+        let target = idle.trailing_zeros() as usize;
+        idle &= !(1usize << target);
+        send_wakeup_ipi(target);
+    }
+}
+
+/// 写完可执行的用户页后调用: 本 hart 立即 fence.i, 其他 hart 由 sync_icache 补做
+pub fn flush_icache() {
+    riscv::asm::fence_i();
+    let old = ICACHE_GEN.fetch_add(1, Ordering::Release);
+    // 期间没有别的 hart 写过代码时, 本 hart 已经是最新的, 返回用户态时不必再做一次
+    let hart = get();
+    if hart.icache_gen == old {
+        hart.icache_gen = old + 1;
+    }
+}
+
+/// 返回用户态前调用, 关中断以免换 hart
+pub fn sync_icache() {
+    let current = ICACHE_GEN.load(Ordering::Acquire);
+    let hart = get();
+    if hart.icache_gen != current {
+        riscv::asm::fence_i();
+        hart.icache_gen = current;
+    }
+}
+
+pub fn note_ipi() {
+    IPI_COUNT[getid()].fetch_add(1, Ordering::Relaxed);
+}
+
+#[allow(dead_code)]
+pub fn ipi_count(hartid: usize) -> usize {
+    IPI_COUNT[hartid].load(Ordering::Relaxed)
+}
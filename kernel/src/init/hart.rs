@@ -1,9 +1,15 @@
 use crate::hart;
+use crate::mem::pmem;
+use crate::mem::vm::{self, KernelStack};
 use crate::printk;
 use crate::printk::{ANSI_RED, ANSI_RESET};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 static BOOTSTRAP_DONE: AtomicBool = AtomicBool::new(false);
+// 成功启动的 hart 数 (含发起启动的 hart), 全部发起完才写入, 此前为 0
+static STARTED_HARTS: AtomicUsize = AtomicUsize::new(0);
+// 已切到自己内核栈上的 hart 数
+static LEFT_BOOT_STACK: AtomicUsize = AtomicUsize::new(0);
 /*
  由主 hart 通过 HSM 启动次级 hart 的入口
 
@@ -14,6 +20,10 @@ unsafe extern "C" {
     fn secondary_start(hartid: usize, dtb: *const u8) -> !;
 }
 
+unsafe extern "C" {
+    fn enter_hart_stack(hartid: usize, top: usize, entry: extern "C" fn(usize) -> !) -> !;
+}
+
 unsafe extern "C" {
     fn sbi_hart_start_asm(hartid: usize, start_addr: usize, opaque: usize) -> isize;
 }
@@ -33,12 +43,16 @@ pub fn bootstrap_secondary_harts(hartid: usize, dtb: *const u8) {
         let start_addr = secondary_start as usize;
         let opaque = dtb as usize;
         let harts = crate::dtb::hart_count();
+        let mut started = 1;
         for target in 0..harts {
             if target == hartid {
                 continue;
             }
             match sbi_hart_start(target, start_addr, opaque) {
-                Ok(()) => printk!("HARTS: Started hart {} via SBI\n", target),
+                Ok(()) => {
+                    started += 1;
+                    printk!("HARTS: Started hart {} via SBI\n", target);
+                }
                 Err(err) => printk!(
                     "{}HARTS: Failed to start hart {} via SBI: error {}{}\n",
                     ANSI_RED,
@@ -48,7 +62,27 @@ pub fn bootstrap_secondary_harts(hartid: usize, dtb: *const u8) {
                 ),
             }
         }
+        STARTED_HARTS.store(started, Ordering::SeqCst);
+    }
+}
+
+// 在本 hart 的内核栈上从 entry(hartid) 重新开始, 启动栈上的帧全部作废, 不再返回
+// 内核栈伴随 hart 终身, 不释放; 需要已开启内核页表
+pub fn leave_boot_stack(hartid: usize, entry: extern "C" fn(usize) -> !) -> ! {
+    let kstack = KernelStack::alloc(vm::hart_kstack_slot(hartid)).expect("HARTS: no memory for the hart kernel stack");
+    let top = kstack.top();
+    core::mem::forget(kstack);
+    unsafe { enter_hart_stack(hartid, top, entry) }
+}
+
+// entry 开头调用一次。最后一个离开启动栈的 hart 把已启动 hart 的启动栈交还内核区, 此时返回 true
+pub fn boot_stack_left() -> bool {
+    let left = LEFT_BOOT_STACK.fetch_add(1, Ordering::SeqCst) + 1;
+    if left != STARTED_HARTS.load(Ordering::SeqCst) {
+        return false;
     }
+    pmem::reclaim_started_boot_stacks();
+    true
 }
 
 pub fn init(hartid: usize, dtb: *const u8) {
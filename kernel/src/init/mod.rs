@@ -1,5 +1,7 @@
 mod hart;
 
+pub use hart::{boot_stack_left, leave_boot_stack};
+
 use spin::Once;
 
 // Global initialization guards - ensure each subsystem is initialized only once
@@ -24,6 +26,7 @@ pub fn init(hartid: usize, dtb: *const u8) {
     // Physical memory - global, once
     PMEM_INIT.call_once(|| {
         crate::mem::pmem::initialize_regions(hartid);
+        crate::mem::pmem::reclaim_boot_stacks(hartid);
     });
 
     // IRQ - global init once, then per-hart init
@@ -37,11 +40,13 @@ pub fn init(hartid: usize, dtb: *const u8) {
         crate::mem::vm::init_kernel_vm(hartid);
     });
     crate::mem::vm::switch_to_kernel(hartid);
+    crate::irq::trap::init_user_return(hartid);
 
     // File system - global, once
     FS_INIT.call_once(|| {
         crate::drivers::virtio::init();
         crate::fs::buffer::init();
+        crate::fs::inode::inode_init();
     });
 
     // Hart management - per-hart
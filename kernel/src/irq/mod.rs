@@ -4,9 +4,10 @@ mod plic;
 pub mod timer;
 pub mod trap;
 pub mod vector;
-pub use trap::{TrapContext, TrapFrame};
+pub use trap::{TrapContext, TrapFrame, init_trapframe};
 
 use crate::drivers;
+use crate::dtb;
 use crate::printk;
 
 pub fn init() {
@@ -17,10 +18,22 @@ pub fn init() {
     printk!("IRQ: Initialized global IRQs\n");
 }
 
+// 驱动探测到设备后打开它的中断源: 设置优先级并在每个 hart 的 S 态上下文中使能
+pub fn enable_external(id: usize) {
+    plic::set_priority(id, 1);
+    for hartid in 0..dtb::hart_count() {
+        plic::set_enable_s(hartid, id, true);
+    }
+}
+
 pub fn init_hart(hartid: usize) {
     plic::init_hart(hartid);
     vector::init();
     // 启用 S-mode 中断
     interrupt::enable_s();
     printk!("IRQ: Initialized for hart {}\n", hartid);
+    // 启动参数 irqdump=1 时打印每个 hart 初始化后的中断配置
+    if dtb::bootarg("irqdump") == Some(1) {
+        trap::dump_interrupt_state(hartid);
+    }
 }
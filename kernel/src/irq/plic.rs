@@ -92,6 +92,15 @@ pub fn get_enable_s(hartid: usize, id: usize) -> bool {
     }
 }
 
+// S 态上下文的第 word 个使能字, 位 i 对应中断源 word * 32 + i
+pub fn get_enable_word_s(hartid: usize, word: usize) -> u32 {
+    unsafe {
+        let context = ctx_index_s(hartid);
+        let addr = plic_base() + 0x2000 + context * 0x80 + word * 4;
+        read_volatile(addr as *const u32)
+    }
+}
+
 pub fn set_priority_m(hartid: usize, id: usize, priority: usize) {
     unsafe {
         let addr = plic_base() + 0x2000 + hartid * 0x100 + id * 4;
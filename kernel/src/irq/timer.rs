@@ -1,10 +1,26 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use riscv::register::time;
 
-const INTERVAL: usize = 1000000; // 100ms
+use crate::dtb;
+use crate::hart::{self, MAX_HARTS};
+use crate::proc::{current_proc, scheduler};
+
+// 节拍长度
+pub const TICK_MS: usize = 100;
+
+// 一个节拍对应的 time CSR 计数, 由设备树的 timebase-frequency 换算
+pub fn interval() -> u64 {
+    dtb::timebase_frequency() * TICK_MS as u64 / 1000
+}
 
 static SYS_TICKS: AtomicUsize = AtomicUsize::new(0);
 
+// 各 hart 下一个节拍的 time 值; 时钟中断可能为 sleep_until 提前到来, 不到这个值不算节拍
+static NEXT_TICK: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+// sleep_until 中最早的截止时间, 没有时为 u64::MAX
+static NEXT_WAKE: AtomicU64 = AtomicU64::new(u64::MAX);
+
 pub fn init(hartid: usize) {
     // SBI is nice
     program_next_tick();
@@ -16,24 +32,66 @@ pub fn create() {
 }
 pub fn update() {
     SYS_TICKS.fetch_add(1, Ordering::Relaxed);
-    crate::proc::scheduler::wakeup(&SYS_TICKS as *const _ as usize);
+    scheduler::wakeup(&SYS_TICKS as *const _ as usize);
 }
 
-pub fn get_ticks() -> usize {
+// 开机以来的时钟节拍数, 只由 hart 0 的时钟中断递增
+pub fn uptime_ticks() -> usize {
     SYS_TICKS.load(Ordering::Relaxed)
 }
 
+pub fn ticks_to_ms(ticks: usize) -> usize {
+    ticks * TICK_MS
+}
+
+// 开机以来的毫秒数, 精度为一个节拍
+pub fn uptime_ms() -> usize {
+    ticks_to_ms(uptime_ticks())
+}
+
+// 开机以来的秒数, 用作 inode 时间戳
+pub fn seconds() -> u32 {
+    (uptime_ms() / 1000) as u32
+}
+
 #[inline(always)]
 fn time_now() -> u64 {
     time::read() as u64
 }
 
 pub fn program_next_tick() {
-    let next = time_now().wrapping_add(INTERVAL as u64);
+    let hartid = hart::getid();
+    NEXT_TICK[hartid].store(time_now().wrapping_add(interval()), Ordering::SeqCst);
+    arm(hartid);
+}
+
+// 按下一个节拍与最早的截止时间中较早者设定本 hart 的时钟中断
+fn arm(hartid: usize) {
+    let next = NEXT_TICK[hartid].load(Ordering::SeqCst).min(NEXT_WAKE.load(Ordering::SeqCst));
     // FIXME: 错误处理
     let _ = crate::sbi::set_timer(next);
 }
 
+// 时钟中断: 先唤醒截止时间已到的 sleep_until, 再看是否到了节拍; 返回这次中断是否是节拍
+pub fn handle_interrupt() -> bool {
+    let now = time_now();
+    if now >= NEXT_WAKE.load(Ordering::SeqCst) {
+        // 先复位再扫描: 扫描期间登记的截止时间要么被扫到, 要么它的 fetch_min 落在复位之后
+        NEXT_WAKE.store(u64::MAX, Ordering::SeqCst);
+        NEXT_WAKE.fetch_min(scheduler::wakeup_deadlines(now), Ordering::SeqCst);
+    }
+    let hartid = hart::getid();
+    let tick = now >= NEXT_TICK[hartid].load(Ordering::SeqCst);
+    if tick {
+        if hartid == 0 {
+            update();
+        }
+        NEXT_TICK[hartid].store(now.wrapping_add(interval()), Ordering::SeqCst);
+    }
+    arm(hartid);
+    tick
+}
+
 pub fn start(hartid: usize) {
     if hartid == 0 {
         program_next_tick();
@@ -41,9 +99,43 @@ pub fn start(hartid: usize) {
 }
 
 pub fn wait(ticks: usize) {
-    let start = get_ticks();
+    let start = uptime_ticks();
     let target = start + ticks;
-    while get_ticks() < target {
-        crate::proc::scheduler::sleep(&SYS_TICKS as *const _ as usize);
+    while uptime_ticks() < target {
+        scheduler::sleep(&SYS_TICKS as *const _ as usize);
+    }
+}
+
+// 纳秒换算成 time CSR 计数, 超出 u64 时饱和
+pub fn ns_to_cycles(ns: u64) -> u64 {
+    let cycles = ns as u128 * dtb::timebase_frequency() as u128 / 1_000_000_000;
+    cycles.min(u64::MAX as u128) as u64
+}
+
+// 等到 time CSR 到达 deadline
+// 登记截止时间后睡在进程自己的通道上, 时钟按截止时间提前触发, 到点只唤醒一次;
+// 没有当前进程 (启动阶段) 时只能忙等
+pub fn sleep_until(deadline: u64) {
+    if hart::get().proc.is_null() {
+        while time_now() < deadline {
+            spin_loop();
+        }
+        return;
+    }
+    if time_now() >= deadline {
+        return;
     }
+    let p = current_proc();
+    let chan = scheduler::deadline_chan(p);
+    p.wake_at = deadline;
+    NEXT_WAKE.fetch_min(deadline, Ordering::SeqCst);
+    arm(hart::getid());
+    while time_now() < deadline {
+        scheduler::sleep_unless(chan, || time_now() >= deadline);
+    }
+    p.wake_at = 0;
+}
+
+pub fn nanosleep(ns: u64) {
+    sleep_until(time_now().saturating_add(ns_to_cycles(ns)));
 }
@@ -0,0 +1,90 @@
+use super::super::plic;
+use crate::hart;
+use crate::printk;
+use riscv::register::{sie, sip, sstatus, stvec};
+
+// sie/sip 中 S 态三种中断的位
+const SSIE: usize = 1 << 1;
+const STIE: usize = 1 << 5;
+const SEIE: usize = 1 << 9;
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// 当前 hart 与中断有关的 CSR 以及它在 PLIC 上 S 态上下文的配置
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptState {
+    pub hartid: usize,
+    pub sstatus: usize,
+    pub sie: usize,
+    pub sip: usize,
+    pub stvec: usize,
+    pub plic_threshold: usize,
+    pub plic_enable: u32, // 中断源 0..32 的使能位
+}
+
+impl InterruptState {
+    /// CSR 只能读当前 hart 的, hartid 必须是调用者所在的 hart
+    pub fn read(hartid: usize) -> Self {
+        assert_eq!(hartid, hart::getid(), "InterruptState: CSRs belong to hart {}", hart::getid());
+        Self {
+            hartid,
+            sstatus: sstatus::read().bits(),
+            sie: sie::read().bits(),
+            sip: sip::read().bits(),
+            stvec: stvec::read().bits(),
+            plic_threshold: plic::get_threshold_s(hartid),
+            plic_enable: plic::get_enable_word_s(hartid, 0),
+        }
+    }
+
+    pub fn irq_enabled(&self) -> bool {
+        self.sstatus & SSTATUS_SIE != 0
+    }
+
+    pub fn timer_enabled(&self) -> bool {
+        self.sie & STIE != 0
+    }
+
+    pub fn external_enabled(&self) -> bool {
+        self.sie & SEIE != 0
+    }
+
+    pub fn plic_source_enabled(&self, id: usize) -> bool {
+        id < 32 && self.plic_enable & (1 << id) != 0
+    }
+}
+
+fn bit(v: usize, mask: usize) -> u8 {
+    (v & mask != 0) as u8
+}
+
+/// 打印中断相关状态, 排查中断不来的问题时使用
+pub fn dump_interrupt_state(hartid: usize) -> InterruptState {
+    let st = InterruptState::read(hartid);
+    printk!(
+        "IRQ state (hart {}): sstatus={:#x} (SIE={}), stvec={:#x} (mode {})\n",
+        st.hartid,
+        st.sstatus,
+        st.irq_enabled() as u8,
+        st.stvec & !0b11,
+        st.stvec & 0b11
+    );
+    printk!(
+        "  sie={:#x} (SSIE={} STIE={} SEIE={}), sip={:#x} (SSIP={} STIP={} SEIP={})\n",
+        st.sie,
+        bit(st.sie, SSIE),
+        st.timer_enabled() as u8,
+        st.external_enabled() as u8,
+        st.sip,
+        bit(st.sip, SSIE),
+        bit(st.sip, STIE),
+        bit(st.sip, SEIE)
+    );
+    printk!(
+        "  PLIC S-context: threshold={}, enable[0]={:#010x} (uart={} virtio0={})\n",
+        st.plic_threshold,
+        st.plic_enable,
+        st.plic_source_enabled(plic::UART_IRQ) as u8,
+        st.plic_source_enabled(crate::drivers::virtio::irq()) as u8
+    );
+    st
+}
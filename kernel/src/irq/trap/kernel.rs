@@ -2,13 +2,16 @@ use super::super::TrapContext;
 use super::super::interrupt;
 use super::super::plic;
 use super::super::timer;
+use super::stat;
 use super::user;
 use super::{EXCEPTION_INFO, INTERRUPT_INFO};
 use crate::drivers;
 use crate::hart;
+use crate::mem::{PGSIZE, uvm};
 use crate::printk;
 use crate::printk::{ANSI_RED, ANSI_RESET, ANSI_YELLOW};
 use crate::proc;
+use crate::proc::{process, scheduler};
 use core::panic;
 use riscv::interrupt::Interrupt;
 use riscv::register::{
@@ -30,15 +33,46 @@ pub extern "C" fn trap_kernel_handler(ctx: &mut TrapContext) {
 
     match sc.cause() {
         Trap::Exception(e) => {
+            stat::record(false, e);
             exception_handler(e, epc, tval, sstatus_bits, ctx);
         }
         Trap::Interrupt(i) => {
+            stat::record(true, i);
             interrupt_handler(i, epc, tval, sstatus_bits, ctx);
         }
     }
     interrupt::exit();
 }
 
+/// 向量模式下各中断的入口, 由 kernel_vector_table 直接跳入, 不再读取 scause 分派
+/// 记账与 trap_kernel_handler 相同, 陷阱统计照常计数
+#[cfg(feature = "vectored-trap")]
+#[unsafe(no_mangle)]
+pub extern "C" fn trap_kernel_ssip(_ctx: &mut TrapContext) {
+    interrupt::enter();
+    stat::record(true, 1);
+    ipi_handler_ssip(sstatus::read().bits());
+    interrupt::exit();
+}
+
+#[cfg(feature = "vectored-trap")]
+#[unsafe(no_mangle)]
+pub extern "C" fn trap_kernel_stip(_ctx: &mut TrapContext) {
+    interrupt::enter();
+    stat::record(true, 5);
+    timer_handler_stip(sstatus::read().bits());
+    interrupt::exit();
+}
+
+#[cfg(feature = "vectored-trap")]
+#[unsafe(no_mangle)]
+pub extern "C" fn trap_kernel_seip(_ctx: &mut TrapContext) {
+    interrupt::enter();
+    stat::record(true, 9);
+    external_handler();
+    interrupt::exit();
+}
+
 /// 处理异常情况
 fn exception_handler(
     e: usize,
@@ -49,21 +83,109 @@ fn exception_handler(
 ) {
     // 8: Environment call from U-mode (syscall)
     if e == 8 {
+        // 系统调用体开中断执行, 长时间的调用不会挡住时钟和外设中断;
+        // kernel_vector 不保存 sepc/sstatus, 嵌套陷阱会覆盖它们, 所以写 sepc 之前先关中断,
+        // 之后一直关到 trampoline 返回用户态
+        unsafe {
+            sstatus::set_sie();
+        }
         user::syscall_handler(ctx);
+        unsafe {
+            sstatus::clear_sie();
+        }
         // advance sepc to next instruction
         unsafe {
-            sepc::write(epc.wrapping_add(4));
+            sepc::write(epc.wrapping_add(ECALL_LEN));
         }
         return;
     }
 
-    // 13: Load Page Fault, 15: Store/AMO Page Fault
-    if e == 13 || e == 15 {
+    // 5/7: Load/Store Access Fault, 13/15: Page Fault
+    // 内核在受保护的用户拷贝中出错时不再 panic, 转到恢复点由拷贝函数返回错误
+    if !from_user(sstatus_bits) && matches!(e, 5 | 7 | 13 | 15) {
+        let fixup = hart::get().fault_fixup;
+        if fixup != 0 {
+            unsafe {
+                sepc::write(fixup);
+            }
+            return;
+        }
+    }
+
+    // 3: Breakpoint, 可能是 c.ebreak (2 字节) 或 ebreak (4 字节)
+    if e == 3 {
+        let user = from_user(sstatus_bits);
+        printk!(
+            "{}[WARN] breakpoint at epc=0x{:x} ({}){}\n",
+            ANSI_YELLOW,
+            epc,
+            if user { "user" } else { "kernel" },
+            ANSI_RESET
+        );
+        unsafe {
+            sepc::write(epc.wrapping_add(insn_len(epc, user)));
+        }
+        return;
+    }
+
+    // 12: Instruction Page Fault, 13: Load Page Fault, 15: Store/AMO Page Fault
+    // 内核态 (开着 SUM 直接访问用户页) 只恢复当前进程用户地址上的读写缺页,
+    // 其余内核缺页 (内核地址, 没有当前进程, 取指) 都是内核 bug, 照常 panic
+    let recoverable = if from_user(sstatus_bits) {
+        matches!(e, 12 | 13 | 15)
+    } else {
+        matches!(e, 13 | 15) && kernel_fault_on_user_page(tval)
+    };
+    if recoverable {
         let p = proc::current_proc();
-        if p.ustack_grow(tval).is_ok() {
+        // 尚未装入的 ELF 段页
+        if p.load_segment_page(tval).is_ok() {
+            return;
+        }
+        if e == 15 && p.cow_fault(tval).is_ok() {
+            return;
+        }
+        if e != 12 && p.ustack_grow(tval).is_ok() {
             return;
         }
     }
+
+    // 用户态的其余异常: 以信号状态杀死进程, 不再拖垮内核
+    if from_user(sstatus_bits) {
+        let sig = match e {
+            2 => process::SIGILL,
+            4 | 6 => process::SIGBUS,
+            _ => process::SIGSEGV,
+        };
+        let p = proc::current_proc();
+        // 最低一页从不映射, 落在这里的访存/取指故障按空指针解引用单独报告
+        if matches!(e, 1 | 5 | 7 | 12 | 13 | 15) && tval < PGSIZE {
+            printk!(
+                "{}[WARN] pid {} killed by signal {}: null pointer dereference at 0x{:x} (epc=0x{:x}){}\n",
+                ANSI_YELLOW,
+                p.pid,
+                sig,
+                tval,
+                epc,
+                ANSI_RESET
+            );
+        } else {
+            printk!(
+                "{}[WARN] pid {} killed by signal {}: {} at epc=0x{:x}, tval=0x{:x}{}\n",
+                ANSI_YELLOW,
+                p.pid,
+                sig,
+                EXCEPTION_INFO.get(e).unwrap_or(&"Unknown Exception"),
+                epc,
+                tval,
+                ANSI_RESET
+            );
+        }
+        p.exit_code = process::signal_status(sig);
+        p.exit();
+        scheduler::yield_proc();
+        return;
+    }
     printk!(
         "{}TRAP(Exception){}: code={} ({}); epc=0x{:x}, tval=0x{:x}, sstatus=0x{:x}\n",
         ANSI_RED,
@@ -77,6 +199,12 @@ fn exception_handler(
     panic!("Kernel panic due to exception");
 }
 
+// 内核态缺页地址是否落在当前进程的用户地址空间 (TrapFrame 以下)
+fn kernel_fault_on_user_page(tval: usize) -> bool {
+    let hart = hart::get();
+    !hart.proc.is_null() && tval < unsafe { (*hart.proc).trapframe_va }
+}
+
 /// 处理中断情况
 fn interrupt_handler(
     e: usize,
@@ -89,8 +217,8 @@ fn interrupt_handler(
         9 => external_handler(),
         // S-mode timer interrupt
         5 => timer_handler_stip(sstatus_bits),
-        // S-mode software interrupt
-        1 => timer_handler_ssip(sstatus_bits),
+        // S-mode software interrupt: 其他 hart 发来的唤醒 IPI
+        1 => ipi_handler_ssip(sstatus_bits),
         // 剩下的被认为是需要打印的内容
         _ => {
             printk!(
@@ -116,7 +244,7 @@ pub fn external_handler() {
         plic::UART_IRQ => {
             drivers::uart::irq::handler();
         }
-        plic::VIRTIO0_IRQ => {
+        id if id == drivers::virtio::irq() => {
             drivers::virtio::disk::intr();
         }
         _ => {
@@ -127,10 +255,10 @@ pub fn external_handler() {
     plic::complete(hartid, id);
 }
 
-pub fn timer_handler_ssip(sstatus_bits: usize) {
-    if hart::getid() == 0 {
-        timer::update();
-    }
+// 时钟由 SBI set_timer 产生 STIP, SSIP 只用于跨 hart 唤醒
+// 被中断的是用户进程时让出 CPU, 在调度器里 wfi 时返回后会重新查找就绪进程
+pub fn ipi_handler_ssip(sstatus_bits: usize) {
+    hart::note_ipi();
     unsafe {
         sip::clear_pending(Interrupt::SupervisorSoft);
     }
@@ -141,12 +269,48 @@ pub fn timer_handler_ssip(sstatus_bits: usize) {
 }
 
 pub fn timer_handler_stip(sstatus_bits: usize) {
-    if hart::getid() == 0 {
-        timer::update();
+    // 只为唤醒 sleep_until 提前到来的中断不是节拍, 不记账也不抢占
+    if !timer::handle_interrupt() {
+        return;
+    }
+
+    // 按节拍记账到当前进程: 打断用户态记 utime, 打断系统调用记 stime
+    let p = hart::get().proc;
+    if !p.is_null() {
+        let p = unsafe { &mut *p };
+        if from_user(sstatus_bits) {
+            p.utime += 1;
+        } else {
+            p.stime += 1;
+        }
     }
-    timer::program_next_tick();
 
     if (sstatus_bits & (1 << 8)) == 0 {
         proc::scheduler::yield_proc();
     }
 }
+
+/// ecall 没有压缩编码, 固定 4 字节, 系统调用路径不必读回指令
+const ECALL_LEN: usize = 4;
+
+/// 根据 epc 处指令的低两位判断长度: 0b11 为 32 位指令, 否则为 16 位压缩指令
+/// 用户态地址经当前进程页表读取, 读不到时按 4 字节处理
+fn insn_len(epc: usize, from_user: bool) -> usize {
+    let mut half = [0u8; 2];
+    if from_user {
+        let p = proc::current_proc();
+        let pt = p.page_table();
+        if uvm::copyin(pt, &mut half, epc).is_err() {
+            return 4;
+        }
+    } else {
+        half = unsafe { core::ptr::read_unaligned(epc as *const [u8; 2]) };
+    }
+    if half[0] & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+/// sstatus.SPP 为 0 表示陷入前处于 U 模式
+#[inline]
+fn from_user(sstatus_bits: usize) -> bool {
+    sstatus_bits & (1 << 8) == 0
+}
@@ -1,6 +1,11 @@
+mod debug;
 mod kernel;
+pub mod stat;
 mod user;
 
+pub use debug::dump_interrupt_state;
+pub use user::{init_trapframe, init_user_return};
+
 /// 陷阱处理时的寄存器上下文结构
 /// 对应汇编代码中栈上的布局
 #[repr(C)]
@@ -80,6 +85,9 @@ impl TrapContext {
     }
 }
 
+/// TrapFrame.canary 的固定值 ("TRAPFRME"), 被改写说明汇编偏移错位或有代码越界写坏了 TrapFrame
+pub const TRAPFRAME_CANARY: usize = 0x5452_4150_4652_4d45;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct TrapFrame {
@@ -88,6 +96,7 @@ pub struct TrapFrame {
     pub kernel_trapvector: usize, // 内核陷阱向量地址
     pub kernel_epc: usize,        // 用户态程序计数器
     pub kernel_hartid: usize,     // 处理器核ID
+    pub canary: usize,            // 恒为 TRAPFRAME_CANARY
 
     // 通用寄存器
     pub ra: usize,
@@ -131,6 +140,7 @@ impl TrapFrame {
             kernel_trapvector: 0,
             kernel_epc: 0,
             kernel_hartid: 0,
+            canary: TRAPFRAME_CANARY,
             ra: 0,
             sp: 0,
             gp: 0,
@@ -164,6 +174,26 @@ impl TrapFrame {
             t6: 0,
         }
     }
+
+    pub fn canary_intact(&self) -> bool {
+        self.canary == TRAPFRAME_CANARY
+    }
+
+    /// canary 被覆盖时返回被写入的值, 供 check_canary 报告
+    pub fn canary_overwrite(&self) -> Option<usize> {
+        if self.canary_intact() { None } else { Some(self.canary) }
+    }
+
+    /// 在陷阱进出口检查 canary, site 标明检查位置
+    pub fn check_canary(&self, site: &str) {
+        if let Some(found) = self.canary_overwrite() {
+            panic!(
+                "TrapFrame at {:p} corrupted ({}): canary 0x{:x}, expected 0x{:x}",
+                self, site, found, TRAPFRAME_CANARY
+            );
+        }
+    }
+
     #[cfg(debug_assertions)]
     pub fn print(&self) {
         use crate::printk;
@@ -182,6 +212,16 @@ impl TrapFrame {
     }
 }
 
+// trampoline.S 按固定偏移存取 TrapFrame, 调整字段时要同步修改汇编
+const _: () = {
+    use core::mem::offset_of;
+    assert!(offset_of!(TrapFrame, kernel_hartid) == 32);
+    assert!(offset_of!(TrapFrame, canary) == 40);
+    assert!(offset_of!(TrapFrame, ra) == 48);
+    assert!(offset_of!(TrapFrame, a0) == 120);
+    assert!(offset_of!(TrapFrame, t6) == 288);
+};
+
 const EXCEPTION_INFO: [&str; 16] = [
     "Instruction address misaligned", // 0
     "Instruction access fault",       // 1
@@ -0,0 +1,26 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// 每类陷阱统计的 scause 编码数量, 与 EXCEPTION_INFO / INTERRUPT_INFO 对齐
+pub const NCAUSE: usize = 16;
+
+static EXCEPTION_COUNT: [AtomicU64; NCAUSE] = [const { AtomicU64::new(0) }; NCAUSE];
+static INTERRUPT_COUNT: [AtomicU64; NCAUSE] = [const { AtomicU64::new(0) }; NCAUSE];
+
+/// 在陷阱入口处记录一次陷阱, 超出范围的编码不计数
+#[inline(always)]
+pub fn record(interrupt: bool, code: usize) {
+    let table = if interrupt { &INTERRUPT_COUNT } else { &EXCEPTION_COUNT };
+    if let Some(cnt) = table.get(code) {
+        cnt.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// 返回计数快照: 前 NCAUSE 项为异常, 后 NCAUSE 项为中断
+pub fn snapshot() -> [u64; 2 * NCAUSE] {
+    let mut out = [0u64; 2 * NCAUSE];
+    for i in 0..NCAUSE {
+        out[i] = EXCEPTION_COUNT[i].load(Ordering::Relaxed);
+        out[NCAUSE + i] = INTERRUPT_COUNT[i].load(Ordering::Relaxed);
+    }
+    out
+}
@@ -1,6 +1,8 @@
 use super::super::vector;
 use super::super::{TrapContext, TrapFrame};
-use crate::mem::{PGSIZE, VA_MAX};
+use crate::fs::buffer;
+use crate::hart;
+use crate::mem::vm;
 use crate::proc::current_proc;
 use crate::syscall;
 use core::mem;
@@ -13,10 +15,8 @@ use riscv::register::{
 /// 在 kernel_vector 汇编代码中被调用
 #[unsafe(no_mangle)]
 pub extern "C" fn trap_user_handler(ctx: &mut TrapFrame) {
-    let kernel_vec_addr = vector::kernel_vector as usize;
-    unsafe {
-        stvec::write(Stvec::new(kernel_vec_addr, stvec::TrapMode::Direct));
-    }
+    ctx.check_canary("trap entry");
+    vector::set_kernel_vector();
     let epc = sepc::read();
     ctx.kernel_epc = epc;
 
@@ -55,6 +55,13 @@ pub extern "C" fn trap_user_handler(ctx: &mut TrapFrame) {
     };
     super::kernel::trap_kernel_handler(&mut kctx);
 
+    // exec 成功后进程换了 TrapFrame, 入口时的 ctx 已不属于它: 普通进程的旧页已经释放,
+    // vfork 子进程的旧页是父进程的 TrapFrame, 父进程此时已被唤醒。新 TrapFrame 由 exec 写好, 不再写回
+    if current_proc().trapframe != ctx as *mut TrapFrame {
+        trap_user_return(ctx);
+        return;
+    }
+
     ctx.ra = kctx.ra;
     ctx.sp = kctx.sp;
     ctx.gp = kctx.gp;
@@ -88,24 +95,47 @@ pub extern "C" fn trap_user_handler(ctx: &mut TrapFrame) {
     ctx.t6 = kctx.t6;
 
     ctx.kernel_epc = sepc::read();
+    // sepc 已存入 TrapFrame, 这里不持有任何锁, 可以开中断做定期写回
+    unsafe {
+        sstatus::set_sie();
+    }
+    buffer::flush_if_due();
     trap_user_return(ctx);
 }
 
+/// 记录本 hart 返回用户态时不变的值, 须在切换到内核页表之后调用
+pub fn init_user_return(hartid: usize) {
+    let hart = unsafe { &mut hart::HARTS[hartid] };
+    let tramp = vector::trampoline as usize;
+    hart.kernel_satp = satp::read().bits();
+    hart.user_vector_va = vm::TRAMPOLINE_VA + (vector::user_vector as usize - tramp);
+    hart.user_return_va = vm::TRAMPOLINE_VA + (vector::user_return as usize - tramp);
+}
+
+/// 初始化新 TrapFrame 中进入内核所需的字段; fork 复制 TrapFrame 时一并继承
+pub fn init_trapframe(tf: &mut TrapFrame) {
+    let hart = hart::get();
+    // 跳回 S 态的处理入口：trap_user_handler
+    tf.kernel_trapvector = trap_user_handler as usize;
+    // S 态页表, 所有 hart 共用同一张内核页表
+    tf.kernel_satp = hart.kernel_satp;
+    tf.kernel_hartid = hart::getid();
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn trap_user_return(_ctx: &mut TrapFrame) {
-    // TODO: Refactor this
     // 直接通过当前 hart 的进程状态获取 TrapFrame 的指针
     let proc = current_proc();
     let ctx: &mut TrapFrame = unsafe { &mut *proc.trapframe };
     unsafe {
         sstatus::clear_sie();
     }
+    let hart = hart::get();
+    // 别的 hart 写过用户代码时先让本 hart 的取指看到
+    hart::sync_icache();
     // 将 stvec 切换到用户态向量入口
-    let tramp_base_va = VA_MAX - PGSIZE;
-    let user_vec_off = (vector::user_vector as usize) - (vector::trampoline as usize);
-    let user_vec_addr = tramp_base_va + user_vec_off;
     unsafe {
-        stvec::write(Stvec::new(user_vec_addr, stvec::TrapMode::Direct));
+        stvec::write(Stvec::new(hart.user_vector_va, stvec::TrapMode::Direct));
     }
 
     unsafe {
@@ -118,28 +148,22 @@ pub extern "C" fn trap_user_return(_ctx: &mut TrapFrame) {
 
     ctx.t6 = ctx as *mut TrapFrame as usize;
 
-    // 跳回 S 态的处理入口：trap_user_handler
-    ctx.kernel_trapvector = trap_user_handler as usize;
-    // S 态页表
-    ctx.kernel_satp = satp::read().bits();
-    // S 态 hartid
-    ctx.kernel_hartid = crate::hart::getid();
-    // KSTACK(0) 顶部
-    // vm::map_kstack0();
+    // 陷阱入口和内核页表在 init_trapframe 时已写好, 这里只写随进程变化的字段:
+    // 进程可能换了 hart, 所以 hartid 每次都写; vfork 的父子进程共用 TrapFrame, 内核栈顶按当前进程写
+    ctx.kernel_hartid = hart::getid();
     ctx.kernel_sp = proc.kstack.as_ref().unwrap().top();
 
     // sscratch 指向 TrapFrame 的虚拟地址
-    let user_tf_va = (*current_proc()).trapframe_va as usize;
+    let user_tf_va = proc.trapframe_va;
     unsafe {
         sscratch::write(user_tf_va);
     }
 
     let user_satp = proc.root_satp() as u64;
+    ctx.check_canary("user return");
 
     // 通过 TRAMPOLINE 的高地址映射调用 user_return
-    let user_ret_off = (vector::user_return as usize) - (vector::trampoline as usize);
-    let user_ret_addr = tramp_base_va + user_ret_off;
-    let user_return_fn: extern "C" fn(u64, u64) -> ! = unsafe { mem::transmute(user_ret_addr) };
+    let user_return_fn: extern "C" fn(u64, u64) -> ! = unsafe { mem::transmute(hart.user_return_va) };
     user_return_fn(user_tf_va as u64, user_satp)
 }
 
@@ -2,6 +2,7 @@ use riscv::register::stvec::{self, Stvec};
 
 unsafe extern "C" {
     // 这个函数会保存所有通用寄存器，调用 trap_kernel_handler，然后恢复寄存器
+    #[cfg_attr(feature = "vectored-trap", allow(dead_code))]
     pub fn kernel_vector() -> !;
     // 用于处理机器模式下的时钟中断，并触发 S-mode 软件中断
     pub fn timer_vector_base();
@@ -13,9 +14,23 @@ unsafe extern "C" {
     pub fn trampoline() -> !;
 }
 
+#[cfg(feature = "vectored-trap")]
+unsafe extern "C" {
+    // 内核态向量表: 异常进入 kernel_vector, 时钟/外部/软件中断直接进入各自的入口
+    pub fn kernel_vector_table();
+}
+
 pub fn init() {
-    let kernel_vec_addr = kernel_vector as usize;
-    let vec = Stvec::new(kernel_vec_addr, stvec::TrapMode::Direct);
+    set_kernel_vector();
+}
+
+/// 把 stvec 指回内核入口; 打开 vectored-trap 时使用向量模式
+/// 用户态的入口始终是跳板页上的 user_vector (Direct 模式), 见 trap_user_return
+pub fn set_kernel_vector() {
+    #[cfg(not(feature = "vectored-trap"))]
+    let vec = Stvec::new(kernel_vector as usize, stvec::TrapMode::Direct);
+    #[cfg(feature = "vectored-trap")]
+    let vec = Stvec::new(kernel_vector_table as *const () as usize, stvec::TrapMode::Vectored);
     unsafe {
         // set supervisor trap vector address
         stvec::write(vec);
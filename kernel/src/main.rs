@@ -46,7 +46,15 @@ pub extern "C" fn glenda_main(hartid: usize, dtb: *const u8) -> ! {
         tests::test(hartid);
     }
 
-    if hartid == 0 {}
+    init::leave_boot_stack(hartid, hart_main)
+}
+
+// 在本 hart 的内核栈上运行调度器或空闲循环, 启动栈此后不再使用
+extern "C" fn hart_main(hartid: usize) -> ! {
+    if init::boot_stack_left() {
+        #[cfg(feature = "tests")]
+        tests::test_boot_stacks_reclaimed();
+    }
 
     if hartid == 0 {
         if HAS_PROC_PAYLOAD && !PROC_PAYLOAD.is_empty() {
@@ -6,7 +6,7 @@ pub type VirtAddr = usize;
 pub type PPN = usize;
 pub type VPN = usize;
 
-use super::{PGMASK, PGSIZE};
+use super::{PGMASK, PGSIZE, PT_LEVELS, VA_BITS};
 
 #[inline(always)]
 pub const fn align_up(value: usize) -> usize {
@@ -31,6 +31,19 @@ pub const fn page_offset(addr: VirtAddr) -> usize {
 }
 
 #[inline(always)]
-pub const fn vpn(addr: VirtAddr) -> [VPN; 3] {
-    [(addr >> 12) & 0x1FF, (addr >> 21) & 0x1FF, (addr >> 30) & 0x1FF]
+pub const fn vpn(addr: VirtAddr) -> [VPN; PT_LEVELS] {
+    let mut idx = [0; PT_LEVELS];
+    let mut level = 0;
+    while level < PT_LEVELS {
+        idx[level] = (addr >> (12 + 9 * level)) & 0x1FF;
+        level += 1;
+    }
+    idx
+}
+
+// 按 VA_BITS 符号扩展, 得到规范虚拟地址
+#[inline(always)]
+pub const fn canonical(addr: VirtAddr) -> VirtAddr {
+    let shift = usize::BITS as usize - VA_BITS;
+    (((addr << shift) as isize) >> shift) as usize
 }
@@ -3,16 +3,24 @@ pub struct PhysFrame {
 }
 
 impl PhysFrame {
+    // 内存耗尽时返回 None, 由调用者决定如何处理
     pub fn alloc() -> Option<Self> {
-        let pa = crate::mem::pmem::alloc(true) as usize;
-        if pa == 0 { None } else { Some(Self { addr: pa }) }
+        crate::mem::pmem::try_alloc(true).map(|pa| Self { addr: pa as usize })
     }
     pub fn addr(&self) -> usize {
         self.addr
     }
 
     /// Construct a PhysFrame from a raw physical address.
+    /// 调用者把页的唯一一份引用交给 PhysFrame: 页必须来自 pmem::alloc 且引用计数恰好为 1,
+    /// 之后不能再对它调用 pmem::free, 否则 drop 时会重复释放
     pub unsafe fn from_raw(addr: usize) -> Self {
+        debug_assert_eq!(
+            crate::mem::pmem::ref_count(addr),
+            1,
+            "PhysFrame::from_raw: page {:#x} is not exclusively owned",
+            addr
+        );
         Self { addr }
     }
 
@@ -3,6 +3,7 @@ use core::ptr::null_mut;
 
 use spin::{Mutex, Once};
 
+use super::PGSIZE;
 use crate::printk;
 
 // Number of mmap region nodes in the global warehouse
@@ -64,6 +65,129 @@ fn node_index(ptr: *mut MmapRegionNode, warehouse: &MmapWarehouse) -> Option<usi
     Some(idx)
 }
 
+// Per-process mmap regions, sorted by begin.
+// 有序指针数组, 随 mmap/munmap 原地插入删除, 同时维护链表的 next 指针; 包含查询走二分, O(log n)
+pub struct MmapIndex {
+    regions: [*mut MmapRegion; N_MMAP],
+    len: usize,
+}
+
+impl MmapIndex {
+    pub const fn new() -> Self {
+        Self { regions: [null_mut(); N_MMAP], len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // 链表头, 按地址顺序遍历用
+    pub fn head(&self) -> *mut MmapRegion {
+        if self.len == 0 { null_mut() } else { self.regions[0] }
+    }
+
+    pub fn get(&self, i: usize) -> *mut MmapRegion {
+        self.regions[i]
+    }
+
+    // 把区域节点全部还给仓库
+    pub fn free_all(&mut self) {
+        region_free_list(self.head());
+        self.len = 0;
+    }
+
+    // 在下标 i 处插入 region, 调用者保证插入后仍按地址有序且互不重叠
+    pub fn insert(&mut self, i: usize, region: *mut MmapRegion) {
+        assert!(self.len < N_MMAP, "MmapIndex: full");
+        self.regions.copy_within(i..self.len, i + 1);
+        self.regions[i] = region;
+        self.len += 1;
+        self.relink(i);
+    }
+
+    // 移出下标 i 处的区域, 节点由调用者释放
+    pub fn remove(&mut self, i: usize) -> *mut MmapRegion {
+        let region = self.regions[i];
+        self.regions.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+        unsafe { (*region).next = null_mut() };
+        if i > 0 {
+            self.relink(i - 1);
+        }
+        region
+    }
+
+    // 修正下标 i 处节点及其前驱的 next 指针
+    fn relink(&mut self, i: usize) {
+        let next = if i + 1 < self.len { self.regions[i + 1] } else { null_mut() };
+        unsafe {
+            (*self.regions[i]).next = next;
+            if i > 0 {
+                (*self.regions[i - 1]).next = self.regions[i];
+            }
+        }
+    }
+
+    // 下标 i 处区域的 [begin, end)
+    pub fn range(&self, i: usize) -> (usize, usize) {
+        let r = unsafe { &*self.regions[i] };
+        (r.begin, r.begin + r.npages as usize * PGSIZE)
+    }
+
+    // 第一个 begin >= va 的区域下标
+    pub fn lower_bound(&self, va: usize) -> usize {
+        self.partition(|begin, _| begin < va)
+    }
+
+    // 第一个 end > va 的区域下标, 即唯一可能包含 va 的区域
+    pub fn first_ending_after(&self, va: usize) -> usize {
+        self.partition(|_, end| end <= va)
+    }
+
+    // 区域有序且不重叠, begin 和 end 都单调递增, 可以按 pred 二分
+    fn partition(&self, pred: impl Fn(usize, usize) -> bool) -> usize {
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let (begin, end) = self.range(mid);
+            if pred(begin, end) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    // 包含 va 的区域
+    #[allow(dead_code)]
+    pub fn find(&self, va: usize) -> Option<*mut MmapRegion> {
+        let i = self.first_ending_after(va);
+        if i < self.len && self.range(i).0 <= va { Some(self.regions[i]) } else { None }
+    }
+
+    // [start, end) 是否与某个区域重叠
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        let i = self.first_ending_after(start);
+        i < self.len && self.range(i).0 < end
+    }
+
+    // [start, end) 是否完全被 mmap 区域覆盖 (允许跨越首尾相接的区域)
+    pub fn covers(&self, start: usize, end: usize) -> bool {
+        let mut need = start;
+        let mut i = self.first_ending_after(start);
+        while need < end && i < self.len {
+            let (begin, e) = self.range(i);
+            if begin > need {
+                return false;
+            }
+            need = e;
+            i += 1;
+        }
+        need >= end
+    }
+}
+
 pub fn init() {
     INIT_ONCE.call_once(|| {
         let mut warehouse = WAREHOUSE.lock();
@@ -119,6 +243,15 @@ pub fn region_free(region: *mut MmapRegion) {
     }
 }
 
+// Return every node of a per-process list, e.g. when the address space is torn down
+pub fn region_free_list(mut head: *mut MmapRegion) {
+    while !head.is_null() {
+        let next = unsafe { (*head).next };
+        region_free(head);
+        head = next;
+    }
+}
+
 // Debug helper: dump current free-list order by node index
 #[cfg(debug_assertions)]
 pub fn print_nodelist() {
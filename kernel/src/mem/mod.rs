@@ -1,7 +1,15 @@
 pub const PGSIZE: usize = 4096;
 pub const PGNUM: usize = PGSIZE / core::mem::size_of::<usize>(); // 2^9
 pub const PGMASK: usize = PGSIZE - 1;
-pub const VA_MAX: usize = 1 << 38;
+// 分页模式: 默认 Sv39 三级页表, 开启 sv48 feature 时为 Sv48 四级页表
+#[cfg(not(feature = "sv48"))]
+pub const PT_LEVELS: usize = 3;
+#[cfg(feature = "sv48")]
+pub const PT_LEVELS: usize = 4;
+// 虚拟地址位数, 每级页表索引 9 位
+pub const VA_BITS: usize = 12 + 9 * PT_LEVELS;
+// 只使用低半部分地址空间, 用户与内核地址都不需要符号扩展
+pub const VA_MAX: usize = 1 << (VA_BITS - 1);
 pub const KERN_PAGES: usize = 8192;
 pub const MMAP_END: usize = VA_MAX - (16 * 256 + 2) * PGSIZE;
 pub const MMAP_BEGIN: usize = MMAP_END - 64 * 256 * PGSIZE;
@@ -15,5 +23,6 @@ pub mod mmap;
 pub mod pagetable;
 pub mod pmem;
 pub mod pte;
+pub mod slab;
 pub mod uvm;
 pub mod vm;
@@ -1,10 +1,10 @@
 use crate::printk::{ANSI_RESET, ANSI_YELLOW};
 
-use super::addr::{align_down, align_up, vpn};
+use super::addr::{align_down, align_up, canonical, vpn};
 use super::pmem::{self, get_region};
-use super::pte::{self, PTE_U, PTE_V, PTE_X, Pte, pa_to_pte, pte_to_pa};
+use super::pte::{self, PTE_V, Pte, pa_to_pte, pte_to_pa};
 use super::uvm::UvmError;
-use super::{PGNUM, PGSIZE, PhysAddr, VA_MAX, VirtAddr};
+use super::{PGNUM, PGSIZE, PT_LEVELS, PhysAddr, VA_MAX, VirtAddr};
 use core::ptr;
 
 // align 4096 to avoid SFENCE.VMA issues with unaligned root pointers
@@ -25,7 +25,7 @@ impl PageTable {
             return None;
         }
         let mut table: *mut PageTable = self as *mut PageTable;
-        for level in (1..3).rev() {
+        for level in (1..PT_LEVELS).rev() {
             let idx = vpn(va)[level];
             let pte_ref = unsafe { &mut (*table).entries[idx] };
             if pte::is_valid(*pte_ref) {
@@ -41,10 +41,10 @@ impl PageTable {
                 if !alloc {
                     return None;
                 }
-                let new_table = pmem::alloc(true) as *mut PageTable;
-                if new_table.is_null() {
+                let Some(new_table) = pmem::try_alloc(true) else {
                     return None;
-                }
+                };
+                let new_table = new_table as *mut PageTable;
                 unsafe {
                     core::ptr::write_bytes(new_table as *mut u8, 0, PGSIZE);
                     let new_pte = pa_to_pte(new_table as usize, PTE_V);
@@ -61,7 +61,7 @@ impl PageTable {
             return None;
         }
         let mut table: *const PageTable = self as *const PageTable;
-        for level in (1..3).rev() {
+        for level in (1..PT_LEVELS).rev() {
             let idx = vpn(va)[level];
             let pte = unsafe { (*table).entries[idx] };
             if pte::is_valid(pte) {
@@ -160,87 +160,64 @@ impl PageTable {
             (pa >= k.begin && pa < k.end) || (pa >= u.begin && pa < u.end)
         }
 
-        #[inline(always)]
-        fn sv39_canon(va: usize) -> usize {
-            // sign-extend bit 38
-            let sign = (va >> 38) & 1;
-            if sign == 1 { va | (!0usize << 39) } else { va & ((1usize << 39) - 1) }
-        }
-
-        let pgtbl_2 = self as *const PageTable as usize;
-        printk!("L2 PT @ 0x{:x}\n", pgtbl_2);
-
-        for i in 0..PGNUM {
-            let pte2 = unsafe { (*(pgtbl_2 as *const PageTable)).entries[i] };
-            if !pte::is_valid(pte2) {
-                continue;
-            }
-            if !pte::is_table(pte2) {
-                printk!("ASSERT: L2 entry is not table, i={}\n", i);
-                return;
-            }
-
-            let pgtbl_1_pa = pte_to_pa(pte2);
-            if (pgtbl_1_pa & (PGSIZE - 1)) != 0 {
-                printk!("ASSERT: L1 pa not page-aligned: 0x{:x}\n", pgtbl_1_pa);
-                return;
-            }
-            if !pa_in_any_region(pgtbl_1_pa) {
-                printk!("ASSERT: L1 pa out of region: 0x{:x}\n", pgtbl_1_pa);
-                return;
+        fn indent(depth: usize) {
+            for _ in 0..depth {
+                printk!(".. ");
             }
+        }
 
-            printk!(".. L1[{}] pa=0x{:x}\n", i, pgtbl_1_pa);
-
-            let pgtbl_1 = pgtbl_1_pa as *const PageTable;
-            for j in 0..PGNUM {
-                let pte1 = unsafe { (*pgtbl_1).entries[j] };
-                if !pte::is_valid(pte1) {
+        // 递归打印 level 级页表, va_base 为该表覆盖区间的起始地址; 遇到异常表项返回 false
+        fn print_level(table_pa: usize, level: usize, va_base: usize) -> bool {
+            let table = table_pa as *const PageTable;
+            let depth = PT_LEVELS - level;
+            for i in 0..PGNUM {
+                let pte = unsafe { (*table).entries[i] };
+                if !pte::is_valid(pte) {
                     continue;
                 }
-                if !pte::is_table(pte1) {
-                    printk!("ASSERT: L1 entry is not table, j={}\n", j);
-                    return;
+                let va = va_base | (i << (12 + 9 * level));
+                if level == 0 {
+                    if !pte::is_leaf(pte) {
+                        printk!("ASSERT: L0 entry not leaf, k={}\n", i);
+                        return false;
+                    }
+                    indent(depth);
+                    printk!(
+                        "page {} VA=0x{:x} -> PA=0x{:x} {}\n",
+                        i,
+                        canonical(va),
+                        pte_to_pa(pte),
+                        pte::display(pte)
+                    );
+                    continue;
+                }
+                if !pte::is_table(pte) {
+                    printk!("ASSERT: L{} entry is not table, i={}\n", level, i);
+                    return false;
                 }
 
-                let pgtbl_0_pa = pte_to_pa(pte1);
-                if (pgtbl_0_pa & (PGSIZE - 1)) != 0 {
-                    printk!("ASSERT: L0 pa not page-aligned: 0x{:x}\n", pgtbl_0_pa);
-                    return;
+                let child_pa = pte_to_pa(pte);
+                if (child_pa & (PGSIZE - 1)) != 0 {
+                    printk!("ASSERT: L{} pa not page-aligned: 0x{:x}\n", level - 1, child_pa);
+                    return false;
                 }
-                if !pa_in_any_region(pgtbl_0_pa) {
-                    printk!("ASSERT: L0 pa out of region: 0x{:x}\n", pgtbl_0_pa);
-                    return;
+                if !pa_in_any_region(child_pa) {
+                    printk!("ASSERT: L{} pa out of region: 0x{:x}\n", level - 1, child_pa);
+                    return false;
                 }
 
-                printk!(".. .. L0[{}] pa=0x{:x}\n", j, pgtbl_0_pa);
-
-                let pgtbl_0 = pgtbl_0_pa as *const PageTable;
-                for k in 0..PGNUM {
-                    let pte0 = unsafe { (*pgtbl_0).entries[k] };
-                    if !pte::is_valid(pte0) {
-                        continue;
-                    }
-                    if !pte::is_leaf(pte0) {
-                        printk!("ASSERT: L0 entry not leaf, k={}\n", k);
-                        return;
-                    }
-
-                    let pa = pte_to_pa(pte0);
-                    let va_raw = ((i << 30) | (j << 21) | (k << 12)) as usize;
-                    let va = sv39_canon(va_raw);
-                    let flags = pte::get_flags(pte0);
-
-                    printk!(
-                        ".. .. .. page {} VA=0x{:x} -> PA=0x{:x} flags=0x{:x}\n",
-                        k,
-                        va,
-                        pa,
-                        flags
-                    );
+                indent(depth);
+                printk!("L{}[{}] pa=0x{:x}\n", level - 1, i, child_pa);
+                if !print_level(child_pa, level - 1, va) {
+                    return false;
                 }
             }
+            true
         }
+
+        let root = self as *const PageTable as usize;
+        printk!("L{} PT @ 0x{:x}\n", PT_LEVELS - 1, root);
+        print_level(root, PT_LEVELS - 1, 0);
     }
 
     pub fn destroy(&mut self) {
@@ -276,86 +253,81 @@ impl PageTable {
         destroy_level(root_pa);
     }
 
-    /// Deep-copy a Sv39 page table. Returns new root page table PA.
+    /// Deep-copy a page table. Returns new root page table PA.
     /// - For user pages: allocate new user page and copy data.
     /// - For trapframe-like pages: allocate new kernel page and copy data.
     /// - For trampoline-like pages: reuse the same PA, do not copy.
     /// TODO: handle copy-on-write pages.
     pub fn copy(&self) -> Result<PhysAddr, UvmError> {
-        let dst_root = pmem::alloc(true) as usize;
-        if dst_root == 0 {
-            return Err(UvmError::NoMem);
-        }
+        let dst_root = pmem::try_alloc(true).ok_or(UvmError::NoMem)? as usize;
         unsafe {
             core::ptr::write_bytes(dst_root as *mut u8, 0, PGSIZE);
         }
         let dst_pt = unsafe { &mut *(dst_root as *mut PageTable) };
 
-        unsafe {
-            let l2 = self as *const PageTable;
-            for i in 0..super::PGNUM {
-                let pte2 = (*l2).entries[i];
-                if !pte::is_valid(pte2) || !pte::is_table(pte2) {
-                    continue;
-                }
-                let l1_pa = pte_to_pa(pte2);
-                if l1_pa == 0 { continue; }
-                let l1 = l1_pa as *const PageTable;
-                for j in 0..super::PGNUM {
-                    let pte1 = (*l1).entries[j];
-                    if !pte::is_valid(pte1) || !pte::is_table(pte1) {
-                        continue;
-                    }
-                    let l0_pa = pte_to_pa(pte1);
-                    if l0_pa == 0 { continue; }
-                    let l0 = l0_pa as *const PageTable;
-                    for k in 0..super::PGNUM {
-                        let pte0 = (*l0).entries[k];
-                        if !pte::is_valid(pte0) || !pte::is_leaf(pte0) {
-                            continue;
-                        }
-                        let pa = pte_to_pa(pte0);
-                        let flags = pte::get_flags(pte0);
-                        let va_raw = ((i << 30) | (j << 21) | (k << 12)) as usize;
-                        let sign = (va_raw >> 38) & 1;
-                        let va = if sign == 1 {
-                            va_raw | (!0usize << 39)
-                        } else {
-                            va_raw & ((1usize << 39) - 1)
-                        };
+        // 中途失败时拆掉已复制的部分, 已共享的叶子页随之减去引用
+        if let Err(e) = unsafe { copy_level(self as *const PageTable, PT_LEVELS - 1, 0, dst_pt) } {
+            dst_pt.destroy();
+            pmem::free(dst_root, true);
+            return Err(e);
+        }
+        Ok(dst_root)
+    }
+}
 
-                        if (flags & PTE_U) != 0 {
-                            // User page
-                            match pmem::get_region(pa) {
-                                Some(for_kernel) if !for_kernel => {
-                                    let new_pa = pmem::alloc(false) as usize;
-                                    if new_pa == 0 { return Err(UvmError::NoMem); }
-                                    ptr::copy_nonoverlapping(pa as *const u8, new_pa as *mut u8, PGSIZE);
-                                    if !dst_pt.map(va, new_pa, PGSIZE, flags) { return Err(UvmError::MapFailed); }
-                                }
-                                _ => {
-                                    // Ignore this
-                                }
-                            }
-                        } else if (flags & PTE_X) != 0 {
-                            // Kernel text/trampoline (RX) - Map as is (shared)
-                            if !dst_pt.map(va, pa, PGSIZE, flags) { return Err(UvmError::MapFailed); }
-                        } else {
-                            // Trapframe or other Kernel Data (RW)
-                            match pmem::get_region(pa) {
-                                Some(for_kernel) if for_kernel => {
-                                    let new_pa = pmem::alloc(true) as usize;
-                                    if new_pa == 0 { return Err(UvmError::NoMem); }
-                                    ptr::copy_nonoverlapping(pa as *const u8, new_pa as *mut u8, PGSIZE);
-                                    if !dst_pt.map(va, new_pa, PGSIZE, flags) { return Err(UvmError::MapFailed); }
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                }
+// 复制 level 级页表 src 中的所有映射到 dst, va_base 为该表覆盖区间的起始地址
+unsafe fn copy_level(src: *const PageTable, level: usize, va_base: usize, dst_pt: &mut PageTable) -> Result<(), UvmError> {
+    for i in 0..PGNUM {
+        let pte = unsafe { (*src).entries[i] };
+        if !pte::is_valid(pte) {
+            continue;
+        }
+        let va = va_base | (i << (12 + 9 * level));
+        if level > 0 {
+            if !pte::is_table(pte) {
+                continue;
             }
+            let child_pa = pte_to_pa(pte);
+            if child_pa == 0 { continue; }
+            unsafe { copy_level(child_pa as *const PageTable, level - 1, va, dst_pt)? };
+        } else if pte::is_leaf(pte) {
+            unsafe { copy_leaf(dst_pt, canonical(va), pte)? };
+        }
+    }
+    Ok(())
+}
+
+unsafe fn copy_leaf(dst_pt: &mut PageTable, va: VirtAddr, pte0: Pte) -> Result<(), UvmError> {
+    let pa = pte_to_pa(pte0);
+    let flags = pte::get_flags(pte0);
+    if pmem::is_zero_frame(pa) {
+        // 尚未写入的匿名页和只读 BSS 页: 继续共享零页, 写时复制标记随 flags 一起保留
+        if !dst_pt.map(va, pa, PGSIZE, flags) { return Err(UvmError::MapFailed); }
+    } else if pte::is_user(flags) {
+        // User page
+        match pmem::get_region(pa) {
+            Some(for_kernel) if !for_kernel => {
+                let new_pa = pmem::try_alloc(false).ok_or(UvmError::NoMem)? as usize;
+                unsafe { ptr::copy_nonoverlapping(pa as *const u8, new_pa as *mut u8, PGSIZE) };
+                if !dst_pt.map(va, new_pa, PGSIZE, flags) { return Err(UvmError::MapFailed); }
+            }
+            _ => {
+                // Ignore this
+            }
+        }
+    } else if pte::is_executable(flags) {
+        // Kernel text/trampoline (RX) - Map as is (shared)
+        if !dst_pt.map(va, pa, PGSIZE, flags) { return Err(UvmError::MapFailed); }
+    } else {
+        // Trapframe or other Kernel Data (RW)
+        match pmem::get_region(pa) {
+            Some(for_kernel) if for_kernel => {
+                let new_pa = pmem::try_alloc(true).ok_or(UvmError::NoMem)? as usize;
+                unsafe { ptr::copy_nonoverlapping(pa as *const u8, new_pa as *mut u8, PGSIZE) };
+                if !dst_pt.map(va, new_pa, PGSIZE, flags) { return Err(UvmError::MapFailed); }
+            }
+            _ => {}
         }
-        Ok(dst_root)
     }
+    Ok(())
 }
@@ -2,9 +2,9 @@
 
 use core::cell::OnceCell;
 use core::ptr::{self, NonNull, addr_of_mut};
-use core::sync::atomic::{AtomicU8, Ordering};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
-use spin::Mutex;
+use spin::{Mutex, Once};
 
 use super::addr::{align_down, align_up};
 use super::{KERN_PAGES, PGSIZE, PhysAddr};
@@ -13,7 +13,13 @@ use crate::printk;
 
 const PHY_MEM_START: usize = 0x8000_0000;
 const TOTAL_PAGES: usize = 128 * 1024 * 1024 / PGSIZE; // 32768
+// PAGE_REF 覆盖的物理地址上界, 之外的内存无法记录引用计数, 不交给分配器
+const PHY_MEM_END: usize = PHY_MEM_START + TOTAL_PAGES * PGSIZE;
+// 设备树没有内存节点时假定的布局 (QEMU virt -m 128M)
+const DEFAULT_MEMORY: dtb::MemoryRange = dtb::MemoryRange { start: PHY_MEM_START, size: TOTAL_PAGES * PGSIZE };
 static PAGE_REF: [AtomicU8; TOTAL_PAGES] = [const { AtomicU8::new(0) }; TOTAL_PAGES];
+// 全局共享的只读零页: 匿名页在首次写入前都映射到这里, 永不释放
+static ZERO_FRAME: Once<PhysAddr> = Once::new();
 
 fn pa_to_index(pa: usize) -> usize {
     if pa < PHY_MEM_START {
@@ -28,37 +34,85 @@ fn pa_to_index(pa: usize) -> usize {
 }
 
 unsafe extern "C" {
+    static mut __text_start: u8;
     static mut __bss_end: u8;
     static mut __alloc_start: u8;
+    static mut boot_stack: u8;
+    static mut boot_stack_top: u8;
 }
 
-pub fn initialize_regions(hartid: usize) {
-    let kernel_end = align_up(addr_of_mut!(__bss_end) as PhysAddr);
+// 与 boot.S 一致: hart i 的启动栈是 [boot_stack_top - (i + 1) * BOOT_STACK_SIZE, boot_stack_top - i * BOOT_STACK_SIZE)
+const BOOT_STACK_SIZE: usize = 65536;
+const MAX_BOOT_HARTS: usize = 8;
 
-    let mem_range = dtb::memory_range()
-        .unwrap_or_else(|| dtb::MemoryRange { start: 0x8000_0000, size: 128 * 1024 * 1024 });
-    let mem_end = mem_range.start + mem_range.size;
+// 内核镜像 (含 .bss 中的启动栈), 按页对齐
+fn kernel_image_range() -> (PhysAddr, PhysAddr) {
+    (
+        align_down(addr_of_mut!(__text_start) as PhysAddr),
+        align_up(addr_of_mut!(__alloc_start) as PhysAddr),
+    )
+}
 
-    if kernel_end >= mem_end {
-        panic!("pmem_init: kernel end {:#x} beyond memory end {:#x}", kernel_end, mem_end);
+// [pa, pa + PGSIZE) 是否与正在使用的内存重叠: 内核镜像、设备树、initrd、/memreserve/
+pub fn is_reserved(pa: PhysAddr) -> bool {
+    let overlaps = |start: usize, end: usize| pa < end && start < pa + PGSIZE;
+    let (kstart, kend) = kernel_image_range();
+    if overlaps(kstart, kend) {
+        return true;
+    }
+    if let Some(r) = dtb::dtb_range() {
+        if overlaps(r.start, r.end()) {
+            return true;
+        }
+    }
+    if let Some(r) = dtb::initrd_range() {
+        if overlaps(r.start, r.end()) {
+            return true;
+        }
     }
+    dtb::memory_reservations().iter().any(|r| overlaps(r.start, r.end()))
+}
 
+pub fn initialize_regions(hartid: usize) {
+    let kernel_end = align_up(addr_of_mut!(__bss_end) as PhysAddr);
     let alloc_begin = addr_of_mut!(__alloc_start) as PhysAddr;
-    debug_assert!(alloc_begin >= align_up(addr_of_mut!(__bss_end) as PhysAddr));
+    debug_assert!(alloc_begin >= kernel_end);
     debug_assert_eq!(alloc_begin & (PGSIZE - 1), 0, "__alloc_start must be 4K-aligned");
 
-    let alloc_end = mem_end;
-    let total_free = alloc_end.saturating_sub(alloc_begin);
+    let mut regions = dtb::memory_regions();
+    if regions.is_empty() {
+        regions = core::slice::from_ref(&DEFAULT_MEMORY);
+    }
+    for r in regions {
+        printk!("PMEM: physical memory [{:#x}, {:#x}) -> {} MiB\n", r.start, r.end(), r.size / (1024 * 1024));
+    }
+
+    // 内核镜像所在的那段内存切分为内核区和用户区, 其余各段整段并入用户区
+    let home = regions
+        .iter()
+        .find(|r| r.start <= kernel_end && kernel_end < r.end())
+        .unwrap_or_else(|| panic!("pmem_init: kernel end {:#x} outside all memory regions", kernel_end));
+    let alloc_end = home.end().min(PHY_MEM_END);
+    if kernel_end >= alloc_end {
+        panic!("pmem_init: kernel end {:#x} beyond memory end {:#x}", kernel_end, alloc_end);
+    }
     printk!(
-        "PMEM: physical memory [{:#x}, {:#x}) -> {} MiB, free [{:#x}, {:#x}) -> {} MiB\n",
-        mem_range.start,
-        mem_end,
-        mem_range.size / (1024 * 1024),
+        "PMEM: free [{:#x}, {:#x}) -> {} MiB\n",
         alloc_begin,
         alloc_end,
-        total_free / (1024 * 1024)
+        alloc_end.saturating_sub(alloc_begin) / (1024 * 1024)
     );
 
+    if let Some(r) = dtb::dtb_range() {
+        printk!("PMEM: reserve DTB [{:#x}, {:#x})\n", r.start, r.end());
+    }
+    if let Some(r) = dtb::initrd_range() {
+        printk!("PMEM: reserve initrd [{:#x}, {:#x})\n", r.start, r.end());
+    }
+    for r in dtb::memory_reservations() {
+        printk!("PMEM: reserve [{:#x}, {:#x})\n", r.start, r.end());
+    }
+
     let mut kernel_split = align_up(alloc_begin + KERN_PAGES * PGSIZE);
     if kernel_split > alloc_end {
         kernel_split = alloc_end;
@@ -67,9 +121,24 @@ pub fn initialize_regions(hartid: usize) {
         kernel_split = alloc_begin;
     }
 
+    let mut user = [(0, 0); dtb::MAX_MEMORY];
+    user[0] = (kernel_split, alloc_end);
+    let mut nuser = 1;
+    for r in regions.iter().filter(|r| r.start != home.start) {
+        let start = r.start.max(PHY_MEM_START);
+        let end = r.end().min(PHY_MEM_END);
+        if start >= end {
+            printk!("PMEM: ignore [{:#x}, {:#x}), outside tracked memory\n", r.start, r.end());
+            continue;
+        }
+        printk!("PMEM: extra bank [{:#x}, {:#x}) -> user\n", start, end);
+        user[nuser] = (start, end);
+        nuser += 1;
+    }
+
     unsafe {
-        KERNEL_REGION.init(alloc_begin, kernel_split);
-        USER_REGION.init(kernel_split, alloc_end);
+        KERNEL_REGION.init(&[(alloc_begin, kernel_split)]);
+        USER_REGION.init(&user[..nuser]);
     }
 
     let k = KERNEL_REGION.info();
@@ -89,6 +158,8 @@ pub fn initialize_regions(hartid: usize) {
         u.allocable,
         hartid
     );
+
+    ZERO_FRAME.call_once(|| alloc(false) as PhysAddr);
 }
 
 #[repr(C)]
@@ -100,6 +171,7 @@ struct FreePage {
 struct RegionInner {
     head: Option<NonNull<FreePage>>,
     allocable: usize,
+    total: usize, // 初始化时进入空闲链表的页数
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -110,6 +182,9 @@ struct RegionBounds {
 
 struct AllocRegion {
     bounds: OnceCell<RegionBounds>,
+    // 初始化后并入的一段内存 [extra_begin, extra_end), 不在 bounds 内, 只能向上接续扩展
+    extra_begin: AtomicUsize,
+    extra_end: AtomicUsize,
     inner: Mutex<RegionInner>,
 }
 
@@ -119,37 +194,80 @@ impl AllocRegion {
     const fn new() -> Self {
         Self {
             bounds: OnceCell::new(),
-            inner: Mutex::new(RegionInner { head: None, allocable: 0 }),
+            extra_begin: AtomicUsize::new(0),
+            extra_end: AtomicUsize::new(0),
+            inner: Mutex::new(RegionInner { head: None, allocable: 0, total: 0 }),
         }
     }
 
     fn contains(&self, addr: PhysAddr) -> bool {
-        if let Some(b) = self.bounds.get() { addr >= b.begin && addr < b.end } else { false }
+        let within = |b: &RegionBounds| addr >= b.begin && addr < b.end;
+        self.bounds.get().is_some_and(within) || self.extra().is_some_and(|e| within(&e))
     }
 
-    unsafe fn init(&self, begin: PhysAddr, end: PhysAddr) {
-        let begin_aligned = align_up(begin);
-        let end_aligned = align_down(end);
+    fn extra(&self) -> Option<RegionBounds> {
+        let begin = self.extra_begin.load(Ordering::Acquire);
+        let end = self.extra_end.load(Ordering::Acquire);
+        if begin < end { Some(RegionBounds { begin, end }) } else { None }
+    }
 
+    // 把已不再使用的 [begin, end) 并入空闲链表; 不检查 is_reserved, 由调用者保证
+    // 第二次起 begin 必须紧接上一段的末尾. 先扩展范围再放入链表, 新页被分配出去之前 contains 已经认得它
+    fn add_extra(&self, begin: PhysAddr, end: PhysAddr) -> usize {
+        let begin = align_up(begin);
+        let end = align_down(end).max(begin);
+        let mut inner = self.inner.lock();
+        match self.extra() {
+            Some(e) => assert_eq!(e.end, begin, "AllocRegion::add_extra: range not contiguous"),
+            None => self.extra_begin.store(begin, Ordering::Release),
+        }
+        self.extra_end.store(end, Ordering::Release);
+
+        for page in (begin..end).step_by(PGSIZE) {
+            let page = page as *mut FreePage;
+            unsafe { (*page).next = inner.head };
+            inner.head = NonNull::new(page);
+        }
+        let count = (end - begin) / PGSIZE;
+        inner.allocable += count;
+        inner.total += count;
+        count
+    }
+
+    // ranges 是若干段 [begin, end), 段之间的空洞不进入空闲链表; bounds 取覆盖全部段的最小区间
+    unsafe fn init(&self, ranges: &[(PhysAddr, PhysAddr)]) {
         let mut head: Option<NonNull<FreePage>> = None;
         let mut count = 0usize;
-        let mut current = begin_aligned;
-
-        while current + PGSIZE <= end_aligned {
-            let page = current as *mut FreePage;
-            unsafe {
-                (*page).next = head;
+        let mut bounds: Option<RegionBounds> = None;
+
+        for &(begin, end) in ranges {
+            let begin_aligned = align_up(begin);
+            let end_aligned = align_down(end).max(begin_aligned);
+            let mut current = begin_aligned;
+
+            while current + PGSIZE <= end_aligned {
+                if is_reserved(current) {
+                    current += PGSIZE;
+                    continue;
+                }
+                let page = current as *mut FreePage;
+                unsafe {
+                    (*page).next = head;
+                }
+                head = NonNull::new(page);
+                count += 1;
+                current += PGSIZE;
             }
-            head = NonNull::new(page);
-            count += 1;
-            current += PGSIZE;
+
+            bounds = Some(match bounds {
+                None => RegionBounds { begin: begin_aligned, end: end_aligned },
+                Some(b) => RegionBounds { begin: b.begin.min(begin_aligned), end: b.end.max(end_aligned) },
+            });
         }
 
-        self.bounds
-            .set(RegionBounds { begin: begin_aligned, end: end_aligned })
-            .expect("AllocRegion::init called twice");
+        self.bounds.set(bounds.expect("AllocRegion::init: no ranges")).expect("AllocRegion::init called twice");
 
-        *self.inner.lock() = RegionInner { head, allocable: count };
+        *self.inner.lock() = RegionInner { head, allocable: count, total: count };
     }
 
     fn info(&self) -> RegionInfo {
@@ -177,9 +295,38 @@ impl AllocRegion {
         Some(p)
     }
 
+    // 空闲链表上的页引用计数都是 0, 链表长度等于 allocable, 其余页恰好都有引用
+    fn audit(&self, name: &'static str) -> Result<(), (&'static str, usize, usize)> {
+        let b = *self.bounds.get().expect("region not initialized");
+        let inner = self.inner.lock();
+        let mut len = 0;
+        let mut page = inner.head;
+        while let Some(p) = page {
+            let pa = p.as_ptr() as usize;
+            if PAGE_REF[pa_to_index(pa)].load(Ordering::SeqCst) != 0 {
+                return Err((name, 0, pa));
+            }
+            len += 1;
+            page = unsafe { (*p.as_ptr()).next };
+        }
+        if len != inner.allocable {
+            return Err((name, inner.allocable, len));
+        }
+        let extra = self.extra().map_or(0..0, |e| e.begin..e.end);
+        let in_use = (b.begin..b.end)
+            .step_by(PGSIZE)
+            .chain(extra.step_by(PGSIZE))
+            .filter(|&pa| PAGE_REF[pa_to_index(pa)].load(Ordering::SeqCst) != 0)
+            .count();
+        if in_use != inner.total - inner.allocable {
+            return Err((name, inner.total - inner.allocable, in_use));
+        }
+        Ok(())
+    }
+
     fn free(&self, addr: PhysAddr) {
         let b = *self.bounds.get().expect("region not initialized");
-        if addr < b.begin || addr >= b.end || addr % PGSIZE != 0 {
+        if !self.contains(addr) || addr % PGSIZE != 0 {
             panic!("pmem_free: address {:#x} out of bounds [{:#x}, {:#x}]", addr, b.begin, b.end);
         }
 
@@ -225,12 +372,14 @@ pub fn alloc(for_kernel: bool) -> *mut u8 {
     }
 }
 
-#[cfg(debug_assertions)]
 pub fn try_alloc(for_kernel: bool) -> Option<*mut u8> {
     allocate_page(for_kernel)
 }
 
 pub fn free(addr: PhysAddr, _for_kernel: bool) {
+    if is_zero_frame(addr) {
+        return;
+    }
     if KERNEL_REGION.contains(addr) {
         KERNEL_REGION.free(addr);
     } else if USER_REGION.contains(addr) {
@@ -240,6 +389,29 @@ pub fn free(addr: PhysAddr, _for_kernel: bool) {
     }
 }
 
+// 物理页当前的引用计数, 空闲页为 0
+pub fn ref_count(pa: PhysAddr) -> usize {
+    PAGE_REF[pa_to_index(pa)].load(Ordering::SeqCst) as usize
+}
+
+// 核对两个区域的引用计数与空闲链表是否一致, 不一致时返回 (区域名, 期望值, 实际值);
+// 空闲链表上出现有引用的页时期望值为 0, 实际值是该页地址。分配与释放在锁外改引用计数,
+// 只能在没有并发分配的时候调用 (测试)
+#[allow(dead_code)]
+pub fn audit_refcounts() -> Result<(), (&'static str, usize, usize)> {
+    KERNEL_REGION.audit("kernel")?;
+    USER_REGION.audit("user")
+}
+
+pub fn zero_frame() -> PhysAddr {
+    *ZERO_FRAME.get().expect("pmem: zero frame not initialized")
+}
+
+#[inline]
+pub fn is_zero_frame(pa: PhysAddr) -> bool {
+    ZERO_FRAME.get() == Some(&pa)
+}
+
 pub fn kernel_region_info() -> RegionInfo {
     KERNEL_REGION.info()
 }
@@ -263,13 +435,58 @@ pub fn kernel_pool_range() -> (PhysAddr, PhysAddr) {
 }
 
 pub fn get_region(pa: PhysAddr) -> Option<bool> {
-    let kern_region = kernel_region_info();
-    let user_region = user_region_info();
-    if pa >= kern_region.begin && pa < kern_region.end {
-        return Some(true);
-    } else if pa >= user_region.begin && pa < user_region.end {
-        return Some(false);
+    if KERNEL_REGION.contains(pa) {
+        Some(true)
+    } else if USER_REGION.contains(pa) {
+        Some(false)
     } else {
+        None
+    }
+}
+
+// 启动栈区域分成 (begin, split, top): [begin, split) 是从不启动的 hart 的槽位, [split, top) 是 0..hart_count 号 hart 的启动栈
+// hart 数超过槽位时, 多出的 hart 都从 boot_stack_top 开始与 0 号 hart 共用栈, 哪一段都不能回收
+fn boot_stack_split() -> Option<(PhysAddr, PhysAddr, PhysAddr)> {
+    let nharts = dtb::hart_count();
+    if nharts > MAX_BOOT_HARTS {
         return None;
     }
+    let top = addr_of_mut!(boot_stack_top) as PhysAddr;
+    debug_assert_eq!(top - addr_of_mut!(boot_stack) as PhysAddr, BOOT_STACK_SIZE * MAX_BOOT_HARTS);
+    let begin = top - MAX_BOOT_HARTS * BOOT_STACK_SIZE;
+    Some((begin, top - nharts * BOOT_STACK_SIZE, top))
+}
+
+// 把不会有 hart 使用的启动栈并入内核区。只会启动 0..hart_count 号 hart (见 init::hart),
+// 编号更大的槽位从未被使用; 已启动 hart 的启动栈等它们都切到自己的内核栈后由 reclaim_started_boot_stacks 回收
+pub fn reclaim_boot_stacks(hartid: usize) {
+    let Some((begin, end, _)) = boot_stack_split() else {
+        return;
+    };
+    if hartid >= dtb::hart_count() || begin == end {
+        return;
+    }
+    let pages = KERNEL_REGION.add_extra(begin, end);
+    printk!(
+        "PMEM: reclaimed boot stacks of harts {}..{} [{:#x}, {:#x}) -> {} pages\n",
+        dtb::hart_count(),
+        MAX_BOOT_HARTS,
+        begin,
+        end,
+        pages
+    );
+}
+
+// 所有启动过的 hart 都已离开启动栈 (见 init::hart::leave_boot_stack), 其余启动栈一并回收
+pub fn reclaim_started_boot_stacks() {
+    let Some((_, begin, end)) = boot_stack_split() else {
+        return;
+    };
+    let pages = KERNEL_REGION.add_extra(begin, end);
+    printk!("PMEM: reclaimed boot stacks of harts 0..{} [{:#x}, {:#x}) -> {} pages\n", dtb::hart_count(), begin, end, pages);
+}
+
+// 已回收的启动栈范围, 没有回收时为 None
+pub fn reclaimed_boot_stacks() -> Option<(PhysAddr, PhysAddr)> {
+    KERNEL_REGION.extra().map(|e| (e.begin, e.end))
 }
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use super::PhysAddr;
+use core::fmt;
 
 pub const PTE_V: usize = 1 << 0; // Valid
 pub const PTE_R: usize = 1 << 1; // Read
@@ -10,6 +11,7 @@ pub const PTE_U: usize = 1 << 4; // User
 pub const PTE_G: usize = 1 << 5; // Global
 pub const PTE_A: usize = 1 << 6; // Accessed
 pub const PTE_D: usize = 1 << 7; // Dirty
+pub const PTE_COW: usize = 1 << 8; // RSW: 写时复制, 写入时由缺页处理分配私有页
 
 // TODO: change to struct
 pub type Pte = usize;
@@ -45,6 +47,31 @@ pub const fn is_leaf(pte: Pte) -> bool {
     (pte & (PTE_R | PTE_W | PTE_X)) != 0
 }
 
+#[inline(always)]
+pub const fn is_readable(pte: Pte) -> bool {
+    (pte & PTE_R) != 0
+}
+
+#[inline(always)]
+pub const fn is_writable(pte: Pte) -> bool {
+    (pte & PTE_W) != 0
+}
+
+#[inline(always)]
+pub const fn is_executable(pte: Pte) -> bool {
+    (pte & PTE_X) != 0
+}
+
+#[inline(always)]
+pub const fn is_user(pte: Pte) -> bool {
+    (pte & PTE_U) != 0
+}
+
+#[inline(always)]
+pub const fn is_cow(pte: Pte) -> bool {
+    (pte & PTE_COW) != 0
+}
+
 // 中间页表条目：有效但不是 leaf
 #[inline(always)]
 pub const fn is_table(pte: Pte) -> bool {
@@ -60,3 +87,33 @@ pub const fn pte_to_pa(pte: Pte) -> PhysAddr {
 pub const fn pa_to_pte(pa: PhysAddr, flags: PteFlags) -> Pte {
     (((pa >> 12) & 0xFFFFFFFFFFF) << 10) | (flags & 0x3FF)
 }
+
+/// 调试输出用, 形如 `V R-X U-- AD ppn=0x80200`
+/// 三组分别为 V, R/W/X, U/G/COW(C), A/D, 未置位的显示为 `-`
+pub struct PteDisplay(pub Pte);
+
+impl fmt::Display for PteDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pte = self.0;
+        let bit = |mask: usize, c: char| if pte & mask != 0 { c } else { '-' };
+        write!(
+            f,
+            "{} {}{}{} {}{}{} {}{} ppn=0x{:x}",
+            bit(PTE_V, 'V'),
+            bit(PTE_R, 'R'),
+            bit(PTE_W, 'W'),
+            bit(PTE_X, 'X'),
+            bit(PTE_U, 'U'),
+            bit(PTE_G, 'G'),
+            bit(PTE_COW, 'C'),
+            bit(PTE_A, 'A'),
+            bit(PTE_D, 'D'),
+            get_ppn(pte)
+        )
+    }
+}
+
+#[inline(always)]
+pub const fn display(pte: Pte) -> PteDisplay {
+    PteDisplay(pte)
+}
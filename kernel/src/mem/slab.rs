@@ -0,0 +1,78 @@
+use super::pmem;
+use super::{PGNUM, PGSIZE, PhysAddr};
+use core::marker::PhantomData;
+use core::ops::{Index, IndexMut};
+
+/// 容量在运行时确定的定长对象数组, 存放在按页分配的内核物理页中
+/// 对象不跨页, 各页地址记录在一个目录页里, 因此不需要连续的物理内存;
+/// 初始化后对象地址不再变化, 可以像静态数组元素一样长期持有引用
+pub struct Slab<T> {
+    dir: *mut PhysAddr, // 目录页, 第 i 项是第 i 个对象页的物理地址
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for Slab<T> {}
+unsafe impl<T: Sync> Sync for Slab<T> {}
+
+impl<T> Slab<T> {
+    pub const PER_PAGE: usize = PGSIZE / core::mem::size_of::<T>();
+    /// 目录页最多记录 PGNUM 个对象页
+    pub const MAX_LEN: usize = Self::PER_PAGE * PGNUM;
+
+    pub const fn empty() -> Self {
+        Self { dir: core::ptr::null_mut(), len: 0, _marker: PhantomData }
+    }
+
+    /// 分配 len 个对象并用 make 逐个初始化, 只能调用一次; 内核页不足时 panic
+    pub fn init(&mut self, len: usize, make: impl Fn() -> T) {
+        const { assert!(core::mem::size_of::<T>() <= PGSIZE, "Slab: object larger than a page") };
+        assert!(self.dir.is_null(), "Slab: already initialized");
+        assert!(len <= Self::MAX_LEN, "Slab: {} objects exceed capacity {}", len, Self::MAX_LEN);
+
+        // 缓存在启动时按配置大小建立, 分不到页就无法继续, 在写入任何对象之前明确报错
+        let alloc_page = || match pmem::try_alloc(true) {
+            Some(page) => page as PhysAddr,
+            None => panic!("Slab: out of kernel pages for {} objects", len),
+        };
+        let dir = alloc_page() as *mut PhysAddr;
+        unsafe { core::ptr::write_bytes(dir as *mut u8, 0, PGSIZE) };
+        for page in 0..len.div_ceil(Self::PER_PAGE) {
+            unsafe { *dir.add(page) = alloc_page() };
+        }
+        self.dir = dir;
+        for i in 0..len {
+            unsafe { self.slot(i).write(make()) };
+        }
+        self.len = len;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn slot(&self, i: usize) -> *mut T {
+        let page = unsafe { *self.dir.add(i / Self::PER_PAGE) };
+        (page as *mut T).wrapping_add(i % Self::PER_PAGE)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).map(move |i| &self[i])
+    }
+}
+
+impl<T> Index<usize> for Slab<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        assert!(i < self.len, "Slab: index {} out of range {}", i, self.len);
+        unsafe { &*self.slot(i) }
+    }
+}
+
+impl<T> IndexMut<usize> for Slab<T> {
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        assert!(i < self.len, "Slab: index {} out of range {}", i, self.len);
+        unsafe { &mut *self.slot(i) }
+    }
+}
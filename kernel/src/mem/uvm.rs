@@ -1,14 +1,17 @@
 use core::cmp::min;
 
 use super::addr::{align_down, align_up, page_offset};
-use super::mmap::{self, MmapRegion};
+use super::mmap::{self, MmapIndex};
 use super::pagetable::PageTable;
 use super::pmem;
-use super::pte::{self, PTE_A, PTE_D, PTE_R, PTE_U, PTE_W, pte_to_pa};
+use super::vm;
+use super::pte::{self, PTE_A, PTE_COW, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, Pte, pa_to_pte, pte_to_pa};
 use super::{MMAP_BEGIN, PGSIZE, VirtAddr};
 use core::cmp;
 use core::ptr;
 
+use crate::hart;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CopyError {
     NotMapped,
@@ -17,11 +20,64 @@ pub enum CopyError {
     TooLong,
 }
 
+unsafe extern "C" {
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __copy_user_fault();
+}
+
+// 在当前 hart 上设置恢复点后拷贝: PTE 指向的物理页不可访问时 (例如不在内存范围内),
+// 访存异常会回到 __copy_user_fault, 这里返回 Fault 而不是让内核 panic
+pub fn copy_guarded(dst: *mut u8, src: *const u8, len: usize) -> Result<(), CopyError> {
+    let hart = hart::get();
+    hart.fault_fixup = __copy_user_fault as *const () as usize;
+    let left = unsafe { __copy_user(dst, src, len) };
+    hart.fault_fixup = 0;
+    if left == 0 { Ok(()) } else { Err(CopyError::Fault) }
+}
+
+// 从当前进程的用户地址拷入, 未映射或不可访问的地址返回错误
+pub fn copy_from_user(dst: &mut [u8], src_va: VirtAddr) -> Result<(), CopyError> {
+    let hart = hart::get();
+    if hart.proc.is_null() {
+        return Err(CopyError::NotMapped);
+    }
+    let pt = unsafe { (*hart.proc).page_table() };
+    copyin(pt, dst, src_va)
+}
+
+// 拷出到当前进程的用户地址, 未映射或不可写的地址返回错误
+pub fn copy_to_user(dst_va: VirtAddr, src: &[u8]) -> Result<(), CopyError> {
+    let hart = hart::get();
+    if hart.proc.is_null() {
+        return Err(CopyError::NotMapped);
+    }
+    let pt = unsafe { (*hart.proc).page_table() };
+    copyout(pt, dst_va, src)
+}
+
+// 查找用户页的 PTE; 属于当前进程且尚未装入的 ELF 段页先按需装入
+fn user_pte(pt: &PageTable, page: VirtAddr) -> Option<*mut Pte> {
+    if let Some(pte_ptr) = pt.lookup(page) {
+        if pte::is_valid(unsafe { *pte_ptr }) {
+            return Some(pte_ptr);
+        }
+    }
+    let hart = hart::get();
+    if hart.proc.is_null() {
+        return pt.lookup(page);
+    }
+    let p = unsafe { &mut *hart.proc };
+    if p.root_pt_pa == pt as *const PageTable as usize {
+        let _ = p.load_segment_page(page);
+    }
+    pt.lookup(page)
+}
+
 pub fn copyin(pt: &PageTable, dst: &mut [u8], mut src_va: VirtAddr) -> Result<(), CopyError> {
     let mut copied = 0usize;
     while copied < dst.len() {
         let va = src_va;
-        let pte_ptr = match pt.lookup(align_down(va)) {
+        let pte_ptr = match user_pte(pt, align_down(va)) {
             Some(p) => p,
             None => return Err(CopyError::NotMapped),
         };
@@ -33,15 +89,13 @@ pub fn copyin(pt: &PageTable, dst: &mut [u8], mut src_va: VirtAddr) -> Result<()
         if (flags & 0xE) == 0 {
             return Err(CopyError::NotMapped);
         }
-        if (flags & PTE_U) == 0 || (flags & PTE_R) == 0 {
+        if !pte::is_user(flags) || !pte::is_readable(flags) {
             return Err(CopyError::NoPerm);
         }
         let pa = pte_to_pa(pte);
         let off = page_offset(va);
         let n = min(PGSIZE - off, dst.len() - copied);
-        unsafe {
-            ptr::copy_nonoverlapping((pa + off) as *const u8, dst.as_mut_ptr().add(copied), n);
-        }
+        copy_guarded(unsafe { dst.as_mut_ptr().add(copied) }, (pa + off) as *const u8, n)?;
         copied += n;
         src_va += n;
     }
@@ -52,27 +106,30 @@ pub fn copyout(pt: &PageTable, mut dst_va: VirtAddr, src: &[u8]) -> Result<(), C
     let mut copied = 0usize;
     while copied < src.len() {
         let va = dst_va;
-        let pte_ptr = match pt.lookup(align_down(va)) {
+        let pte_ptr = match user_pte(pt, align_down(va)) {
             Some(p) => p,
             None => return Err(CopyError::NotMapped),
         };
-        let pte = unsafe { *pte_ptr };
+        let mut pte = unsafe { *pte_ptr };
         if !pte::is_valid(pte) || !pte::is_leaf(pte) {
             return Err(CopyError::NotMapped);
         };
+        // 内核代写的写时复制页与用户写入一样需要先拆分
+        if pte::is_cow(pte) && !pte::is_writable(pte) {
+            cow_resolve(pte_ptr, align_down(va)).map_err(|_| CopyError::Fault)?;
+            pte = unsafe { *pte_ptr };
+        }
         let flags = pte::get_flags(pte);
         if (flags & 0xE) == 0 {
             return Err(CopyError::NotMapped);
         }
-        if (flags & PTE_U) == 0 || (flags & PTE_W) == 0 {
+        if !pte::is_user(flags) || !pte::is_writable(flags) {
             return Err(CopyError::NoPerm);
         }
         let pa = pte_to_pa(pte);
         let off = page_offset(va);
         let n = min(PGSIZE - off, src.len() - copied);
-        unsafe {
-            core::ptr::copy_nonoverlapping(src.as_ptr().add(copied), (pa + off) as *mut u8, n);
-        }
+        copy_guarded((pa + off) as *mut u8, unsafe { src.as_ptr().add(copied) }, n)?;
         copied += n;
         dst_va += n;
     }
@@ -87,7 +144,7 @@ pub fn copyin_str(
     let mut copied = 0usize;
     while copied < dst.len() {
         let page_base = align_down(src_va);
-        let pte_ptr = match pt.lookup(page_base) {
+        let pte_ptr = match user_pte(pt, page_base) {
             Some(p) => p,
             None => return Err(CopyError::NotMapped),
         };
@@ -99,7 +156,7 @@ pub fn copyin_str(
         if (flags & 0xE) == 0 {
             return Err(CopyError::NotMapped);
         }
-        if (flags & PTE_U) == 0 || (flags & PTE_R) == 0 {
+        if !pte::is_user(flags) || !pte::is_readable(flags) {
             return Err(CopyError::NoPerm);
         }
         let pa = pte_to_pa(pte);
@@ -130,17 +187,10 @@ pub fn heap_grow(pt: &mut PageTable, old_top: VirtAddr, new_top: VirtAddr) -> Re
     if new_top > MMAP_BEGIN {
         return Err(UvmError::OutOfRange);
     }
-    let mut a = align_up(old_top);
+    let a = align_up(old_top);
     let last = align_up(new_top);
-    while a < last {
-        let pa = pmem::alloc(false) as usize;
-        if pa == 0 {
-            return Err(UvmError::NoMem);
-        }
-        if !pt.map(a, pa, PGSIZE, PTE_U | PTE_R | PTE_W | PTE_A | PTE_D) {
-            return Err(UvmError::MapFailed);
-        }
-        a += PGSIZE;
+    if a < last {
+        map_pages(pt, a, (last - a) / PGSIZE)?;
     }
     Ok(())
 }
@@ -204,15 +254,13 @@ fn pages_of_len(len: usize) -> usize {
     (align_up(len) / PGSIZE).max(0)
 }
 
+// 匿名页先只读映射到共享零页, 首次写入时由 cow_fault 分配私有页
 fn map_pages(pt: &mut PageTable, va: VirtAddr, npages: usize) -> Result<(), UvmError> {
+    let zero = pmem::zero_frame();
     let mut a = align_down(va);
     let last = a + npages * PGSIZE;
     while a < last {
-        let pa = pmem::alloc(false) as usize;
-        if pa == 0 {
-            return Err(UvmError::NoMem);
-        }
-        if !pt.map(a, pa, PGSIZE, PTE_U | PTE_R | PTE_W | PTE_A | PTE_D) {
+        if !pt.map(a, zero, PGSIZE, PTE_U | PTE_R | PTE_A | PTE_COW) {
             return Err(UvmError::MapFailed);
         }
         a += PGSIZE;
@@ -220,12 +268,71 @@ fn map_pages(pt: &mut PageTable, va: VirtAddr, npages: usize) -> Result<(), UvmE
     Ok(())
 }
 
+// 拆分 va 处的写时复制页: 分配私有页 (零页无需拷贝), 恢复写权限并释放对旧页的引用
+fn cow_resolve(pte_ptr: *mut Pte, va: VirtAddr) -> Result<(), UvmError> {
+    let old = unsafe { *pte_ptr };
+    if !pte::is_valid(old) || !pte::is_cow(old) {
+        return Err(UvmError::OutOfRange);
+    }
+    let old_pa = pte_to_pa(old);
+    let pa = pmem::alloc(false) as usize;
+    if pa == 0 {
+        return Err(UvmError::NoMem);
+    }
+    if !pmem::is_zero_frame(old_pa) {
+        // 经临时映射访问两个物理页, 不依赖恒等映射
+        let src = vm::kmap_temporary(old_pa);
+        let mut dst = vm::kmap_temporary(pa);
+        unsafe { ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr::<u8>(), PGSIZE) };
+    }
+    let flags = (pte::get_flags(old) & !PTE_COW) | PTE_W | PTE_D;
+    unsafe { *pte_ptr = pa_to_pte(pa, flags) };
+    vm::sfence_user_va(va);
+    pmem::free(old_pa, false);
+    Ok(())
+}
+
+// 用户写缺页入口: 仅处理带 PTE_COW 标记的页
+pub fn cow_fault(pt: &PageTable, fault_va: VirtAddr) -> Result<(), UvmError> {
+    let pte_ptr = pt.lookup(align_down(fault_va)).ok_or(UvmError::OutOfRange)?;
+    cow_resolve(pte_ptr, align_down(fault_va))
+}
+
+// 页面是否有独立的物理页: 未映射或仍指向共享零页的懒分配页都算不驻留
+pub fn is_resident(pt: &mut PageTable, va: VirtAddr) -> bool {
+    match pt.walk(align_down(va), false) {
+        Some(pte_ptr) => {
+            let pte = unsafe { *pte_ptr };
+            pte::is_valid(pte) && !pmem::is_zero_frame(pte_to_pa(pte))
+        }
+        None => false,
+    }
+}
+
+/// mmap 标志: 必须映射在给定地址, 与已有区域重叠时失败; 不带此标志时地址只是提示
+pub const MAP_FIXED: usize = 0x10;
+
+// 在 [mmap_begin, mmap_end) 中找地址最低的、能放下 npages 页的空洞
+fn find_gap(regions: &MmapIndex, npages: usize, mmap_begin: usize, mmap_end: usize) -> Option<VirtAddr> {
+    let mut cursor = mmap_begin;
+    for i in regions.first_ending_after(mmap_begin)..regions.len() {
+        let (cur_begin, cur_end) = regions.range(i);
+        if cur_begin >= cursor && cur_begin - cursor >= npages * PGSIZE {
+            return Some(cursor);
+        }
+        cursor = cmp::max(cursor, cur_end);
+    }
+    if mmap_end.saturating_sub(cursor) >= npages * PGSIZE { Some(cursor) } else { None }
+}
+
+// begin 为 0 时自动选址; 否则带 MAP_FIXED 时必须放在 begin,
+// 不带时 begin 可用 (页对齐, 在范围内且空闲) 就放在 begin, 否则退回自动选址
 pub fn mmap(
     pt: &mut PageTable,
-    head: &mut *mut MmapRegion,
+    regions: &mut MmapIndex,
     mut begin: VirtAddr,
     len: usize,
-    _flags: usize,
+    flags: usize,
     mmap_begin: usize,
     mmap_end: usize,
 ) -> Result<VirtAddr, UvmError> {
@@ -237,126 +344,72 @@ pub fn mmap(
         return Err(UvmError::OutOfRange);
     }
 
-    unsafe {
-        if begin == 0 {
-            let mut cur = *head;
-            let mut cursor = mmap_begin;
-
-            while !cur.is_null() {
-                let cur_begin = (*cur).begin;
-                if cur_begin >= cursor {
-                    let gap = cur_begin.saturating_sub(cursor);
-                    if gap >= npages * PGSIZE {
-                        begin = cursor;
-                        break;
-                    }
-                    cursor = (*cur).begin + (*cur).npages as usize * PGSIZE;
-                }
-                cur = (*cur).next;
-            }
-            if begin == 0 {
-                if mmap_end.saturating_sub(cursor) >= npages * PGSIZE {
-                    begin = cursor;
-                } else {
-                    return Err(UvmError::OutOfRange);
-                }
-            }
-        }
-        // Sanity
-        if begin < mmap_begin || begin + npages * PGSIZE > mmap_end || begin & (PGSIZE - 1) != 0 {
-            return Err(UvmError::OutOfRange);
-        }
-        let end = begin + npages * PGSIZE;
-
-        let mut prev: *mut MmapRegion = ptr::null_mut();
-        let mut cur = *head;
-        while !cur.is_null() && (*cur).begin < begin {
-            prev = cur;
-            cur = (*cur).next;
-        }
-
-        if !prev.is_null() {
-            let prev_end = (*prev).begin + (*prev).npages as usize * PGSIZE;
-            if begin < prev_end {
-                return Err(UvmError::OutOfRange);
-            }
-        }
-        if !cur.is_null() {
-            if end > (*cur).begin {
-                return Err(UvmError::OutOfRange);
-            }
-        }
-
-        let mut merged_begin = begin;
-        let mut merged_end = end;
-        let mut use_prev = false;
-
-        if !prev.is_null() {
-            let prev_end = (*prev).begin + (*prev).npages as usize * PGSIZE;
-            if prev_end == begin {
-                merged_begin = (*prev).begin;
-                use_prev = true;
-            }
-        }
-        let mut consume_next = false;
-        if !cur.is_null() {
-            if end == (*cur).begin {
-                merged_end = (*cur).begin + (*cur).npages as usize * PGSIZE;
-                consume_next = true;
-            }
-        }
-
-        if use_prev {
-            // Map [prev_end, end)
-            let prev_end = (*prev).begin + (*prev).npages as usize * PGSIZE;
-            if end > prev_end {
-                map_pages(pt, prev_end, (end - prev_end) / PGSIZE)?;
-            }
-            (*prev).npages = ((merged_end - merged_begin) / PGSIZE) as u32;
-            if consume_next {
-                let next = cur;
-                let next_end = (*next).begin + (*next).npages as usize * PGSIZE;
-                (*prev).npages = ((next_end - (*prev).begin) / PGSIZE) as u32;
-                (*prev).next = (*next).next;
-                mmap::region_free(next);
-            }
-            return Ok((*prev).begin);
-        }
-
-        if consume_next {
-            // Map [begin, (*cur).begin)
-            map_pages(pt, begin, (end - begin) / PGSIZE)?;
-            let next = cur;
-            (*next).begin = merged_begin;
-            (*next).npages = ((merged_end - merged_begin) / PGSIZE) as u32;
-            if prev.is_null() {
-                *head = next;
-            } else {
-                (*prev).next = next;
-            }
-            return Ok((*next).begin);
+    if begin != 0 && flags & MAP_FIXED == 0 {
+        let usable = begin & (PGSIZE - 1) == 0
+            && begin >= mmap_begin
+            && begin.checked_add(npages * PGSIZE).is_some_and(|end| end <= mmap_end)
+            && !regions.overlaps(begin, begin + npages * PGSIZE);
+        if !usable {
+            begin = 0;
         }
+    }
+    if begin == 0 {
+        begin = find_gap(regions, npages, mmap_begin, mmap_end).ok_or(UvmError::OutOfRange)?;
+    }
+    // Sanity
+    if begin < mmap_begin || begin + npages * PGSIZE > mmap_end || begin & (PGSIZE - 1) != 0 {
+        return Err(UvmError::OutOfRange);
+    }
+    let end = begin + npages * PGSIZE;
+    if regions.overlaps(begin, end) {
+        return Err(UvmError::OutOfRange);
+    }
 
-        map_pages(pt, begin, npages)?;
+    // 新区域插在下标 i, 与首尾相接的前后区域合并
+    let i = regions.lower_bound(begin);
+    let join_prev = i > 0 && regions.range(i - 1).1 == begin;
+    let join_next = i < regions.len() && regions.range(i).0 == end;
+    let node = if join_prev || join_next {
+        ptr::null_mut()
+    } else {
         let node = mmap::region_alloc();
         if node.is_null() {
             return Err(UvmError::NoMem);
         }
-        (*node).begin = begin;
-        (*node).npages = npages as u32;
-        (*node).next = cur;
-        if prev.is_null() {
-            *head = node;
+        node
+    };
+    if let Err(e) = map_pages(pt, begin, npages) {
+        mmap::region_free(node);
+        return Err(e);
+    }
+
+    unsafe {
+        if join_prev {
+            let prev = regions.get(i - 1);
+            let merged_end = if join_next {
+                let (_, next_end) = regions.range(i);
+                mmap::region_free(regions.remove(i));
+                next_end
+            } else {
+                end
+            };
+            (*prev).npages = ((merged_end - (*prev).begin) / PGSIZE) as u32;
+        } else if join_next {
+            let next = regions.get(i);
+            (*next).npages += npages as u32;
+            (*next).begin = begin;
         } else {
-            (*prev).next = node;
+            (*node).begin = begin;
+            (*node).npages = npages as u32;
+            regions.insert(i, node);
         }
-        Ok(begin)
     }
+    Ok(begin)
 }
 
 pub fn munmap(
     pt: &mut PageTable,
-    head: &mut *mut MmapRegion,
+    regions: &mut MmapIndex,
     begin: VirtAddr,
     len: usize,
 ) -> Result<(), UvmError> {
@@ -369,75 +422,86 @@ pub fn munmap(
         return Ok(());
     }
 
-    unsafe {
-        let mut prev: *mut MmapRegion = ptr::null_mut();
-        let mut cur = *head;
-        while !cur.is_null() && (*cur).begin + (*cur).npages as usize * PGSIZE <= start {
-            prev = cur;
-            cur = (*cur).next;
-        }
-        if cur.is_null() {
-            return Ok(());
+    // 从第一个可能与 [start, end) 相交的区域开始
+    let mut i = regions.first_ending_after(start);
+    while i < regions.len() {
+        let (cur_begin, cur_end) = regions.range(i);
+        if end <= cur_begin {
+            break;
         }
+        let s = cmp::max(start, cur_begin);
+        let e = cmp::min(end, cur_end);
 
-        let mut unmap_start = start;
-        while !cur.is_null() && unmap_start < end {
-            let cur_begin = (*cur).begin;
-            let cur_end = cur_begin + (*cur).npages as usize * PGSIZE;
-            if end <= cur_begin {
-                break;
+        // 从中间打洞时先备好右半部分的节点, 失败时映射保持不变
+        let right = if s > cur_begin && e < cur_end {
+            let right = mmap::region_alloc();
+            if right.is_null() {
+                return Err(UvmError::NoMem);
             }
+            right
+        } else {
+            ptr::null_mut()
+        };
+        if !pt.unmap(s, e - s, true) {
+            mmap::region_free(right);
+            return Err(UvmError::MapFailed);
+        }
 
-            let s = cmp::max(unmap_start, cur_begin);
-            let e = cmp::min(end, cur_end);
-            if e > s {
-                // Unmap [s, e)
-                let sz = e - s;
-                if !pt.unmap(s, sz, true) {
-                    return Err(UvmError::MapFailed);
-                }
-
-                if s == cur_begin && e == cur_end {
-                    // remove whole node
-                    let next = (*cur).next;
-                    if prev.is_null() {
-                        *head = next;
-                    } else {
-                        (*prev).next = next;
-                    }
-                    mmap::region_free(cur);
-                    cur = next;
-                } else if s == cur_begin {
-                    // trim front
-                    (*cur).begin = e;
-                    (*cur).npages = ((cur_end - e) / PGSIZE) as u32;
-                    prev = cur;
-                    cur = (*cur).next;
-                } else if e == cur_end {
-                    // trim back
-                    (*cur).npages = ((s - cur_begin) / PGSIZE) as u32;
-                    prev = cur;
-                    cur = (*cur).next;
-                } else {
-                    // left [cur_begin, s), right [e, cur_end)
-                    let right = mmap::region_alloc();
-                    if right.is_null() {
-                        return Err(UvmError::NoMem);
-                    }
-                    (*right).begin = e;
-                    (*right).npages = ((cur_end - e) / PGSIZE) as u32;
-                    (*right).next = (*cur).next;
-                    (*cur).npages = ((s - cur_begin) / PGSIZE) as u32;
-                    (*cur).next = right;
-                    prev = cur;
-                    cur = (*right).next;
-                }
+        let cur = regions.get(i);
+        unsafe {
+            if s == cur_begin && e == cur_end {
+                // remove whole node
+                mmap::region_free(regions.remove(i));
+                continue;
+            } else if s == cur_begin {
+                // trim front
+                (*cur).begin = e;
+                (*cur).npages = ((cur_end - e) / PGSIZE) as u32;
+            } else if e == cur_end {
+                // trim back
+                (*cur).npages = ((s - cur_begin) / PGSIZE) as u32;
             } else {
-                prev = cur;
-                cur = (*cur).next;
+                // left [cur_begin, s), right [e, cur_end)
+                (*right).begin = e;
+                (*right).npages = ((cur_end - e) / PGSIZE) as u32;
+                (*cur).npages = ((s - cur_begin) / PGSIZE) as u32;
+                regions.insert(i + 1, right);
+                i += 1;
+            }
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+// MADV_DONTNEED: 丢弃区间内的私有物理页, 映射退回共享零页
+// 区域元数据不变, 之后的写入重新走 cow_fault 得到全零页
+pub fn madvise_dontneed(
+    pt: &mut PageTable,
+    regions: &MmapIndex,
+    begin: VirtAddr,
+    len: usize,
+) -> Result<(), UvmError> {
+    if len == 0 || begin & (PGSIZE - 1) != 0 {
+        return Err(UvmError::OutOfRange);
+    }
+    let end = align_up(begin.checked_add(len).ok_or(UvmError::OutOfRange)?);
+    if !regions.covers(begin, end) {
+        return Err(UvmError::OutOfRange);
+    }
+    let zero = pmem::zero_frame();
+    let mut a = begin;
+    while a < end {
+        if let Some(pte_ptr) = pt.lookup(a) {
+            let old = unsafe { *pte_ptr };
+            let old_pa = pte_to_pa(old);
+            if pte::is_valid(old) && !pmem::is_zero_frame(old_pa) {
+                unsafe { *pte_ptr = pa_to_pte(zero, PTE_V | PTE_U | PTE_R | PTE_A | PTE_COW) };
+                vm::sfence_user_va(a);
+                pmem::free(old_pa, false);
             }
-            unmap_start = s + (e - s);
         }
+        a += PGSIZE;
     }
     Ok(())
 }
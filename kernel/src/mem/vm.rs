@@ -3,14 +3,15 @@ use core::panic;
 use super::PGSIZE;
 use super::addr::{align_down, align_up};
 use super::pmem::{self, kernel_region_info, user_region_info};
-use super::pte::{PTE_A, PTE_D, PTE_R, PTE_W, PTE_X, Pte};
+use super::pte::{self, PTE_A, PTE_D, PTE_G, PTE_R, PTE_U, PTE_W, PTE_X, Pte};
 use super::{PageTable, PhysAddr, VirtAddr};
 use crate::drivers;
 use crate::dtb;
 use crate::irq::vector;
 use crate::printk;
 use crate::printk::{ANSI_RESET, ANSI_YELLOW};
-use riscv::asm::sfence_vma_all;
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv::asm::{sfence_vma, sfence_vma_all};
 use riscv::register::satp;
 use spin::{Mutex, Once};
 
@@ -34,36 +35,102 @@ pub const KSTACK_VA_BASE: usize = super::VA_MAX - KSTACK_REGION_SIZE;
 
 // Increase kernel stack to 4 pages (16KB)
 pub const KSTACK_SIZE: usize = super::PGSIZE * 4;
+// 每个内核栈槽位最低一页是不映射的保护页, 栈溢出时触发缺页而不是改写相邻进程的栈
+pub const KSTACK_SLOT_SIZE: usize = KSTACK_SIZE + super::PGSIZE;
+// 区域最高一页是跳板页, 不分给槽位
+pub const KSTACK_SLOTS: usize = (KSTACK_REGION_SIZE - super::PGSIZE) / KSTACK_SLOT_SIZE;
+
+// 跳板页在内核页表和每个用户页表里映射到同一物理页, 权限也必须相同:
+// 内核和用户都不写它, 地址空间切换前后同一条全局映射都有效
+pub const TRAMPOLINE_VA: VirtAddr = super::VA_MAX - super::PGSIZE;
+pub const TRAMPOLINE_FLAGS: usize = PTE_R | PTE_X | PTE_A | PTE_G;
+
+// 临时映射窗口, 紧挨在内核栈区域下方, 每个槽位一页
+pub const KMAP_SLOTS: usize = 64;
+pub const KMAP_VA_BASE: usize = KSTACK_VA_BASE - KMAP_SLOTS * super::PGSIZE;
+
+// SATP 字段: MODE [63:60], ASID [59:44], PPN [43:0]
+#[cfg(not(feature = "sv48"))]
+pub const SATP_MODE: satp::Mode = satp::Mode::Sv39;
+#[cfg(feature = "sv48")]
+pub const SATP_MODE: satp::Mode = satp::Mode::Sv48;
+pub const SATP_ASID_BITS: usize = 16;
+pub const SATP_ASID_MASK: usize = (1 << SATP_ASID_BITS) - 1;
+pub const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+// 由根页表物理地址和 ASID 组成 SATP, ASID 超出硬件宽度的部分被截掉
+#[inline(always)]
+pub fn make_satp(root_pa: PhysAddr, asid: usize) -> usize {
+    debug_assert!(root_pa & (PGSIZE - 1) == 0, "make_satp: root 0x{:x} not page aligned", root_pa);
+    let ppn = (root_pa >> 12) & SATP_PPN_MASK;
+    ((SATP_MODE as usize) << 60) | ((asid & SATP_ASID_MASK) << 44) | ppn
+}
+
+#[allow(dead_code)]
+#[inline(always)]
+pub fn satp_root(bits: usize) -> PhysAddr {
+    (bits & SATP_PPN_MASK) << 12
+}
+
+#[allow(dead_code)]
+#[inline(always)]
+pub fn satp_asid(bits: usize) -> usize {
+    (bits >> 44) & SATP_ASID_MASK
+}
 
 pub fn map_kstack0() {
-    let _top = alloc_kstack(0);
+    alloc_kstack(0).expect("VM: no memory for KSTACK(0)");
     printk!("VM: KSTACK(0) allocated at VA={:p}\n", kstack_base(0) as *const u8);
 }
 
+// 槽位的保护页, 紧挨在栈底下方
+// 槽位 0 是 KSTACK(0), 进程的内核栈按进程表下标使用之后的槽位, 见 proc::process; 再往后是各 hart 的调度器栈
+#[inline(always)]
+pub fn kstack_guard(slot: usize) -> VirtAddr {
+    KSTACK_VA_BASE + slot * KSTACK_SLOT_SIZE
+}
+
+// 栈的最低地址, 即保护页之上第一页
 #[inline(always)]
-pub fn kstack_base(procid: usize) -> VirtAddr {
-    KSTACK_VA_BASE + procid * KSTACK_SIZE
+pub fn kstack_base(slot: usize) -> VirtAddr {
+    kstack_guard(slot) + super::PGSIZE
 }
 
 #[inline(always)]
-pub fn kstack_top(procid: usize) -> VirtAddr {
-    kstack_base(procid) + KSTACK_SIZE
+pub fn kstack_top(slot: usize) -> VirtAddr {
+    kstack_base(slot) + KSTACK_SIZE
 }
 
-pub fn alloc_kstack(pid: usize) -> VirtAddr {
-    let base = kstack_base(pid);
+// hart 离开启动栈后运行调度器和空闲循环的内核栈, 见 init::hart::leave_boot_stack
+#[inline(always)]
+pub fn hart_kstack_slot(hartid: usize) -> usize {
+    crate::proc::table::NPROC + 1 + hartid
+}
+
+// 映射槽位的栈页, 保护页保持不映射; 槽位超出内核栈区域或物理页不足时返回 None, 不留下已映射的页
+pub fn alloc_kstack(slot: usize) -> Option<VirtAddr> {
+    if slot >= KSTACK_SLOTS {
+        return None;
+    }
+    let base = kstack_base(slot);
     let mut kpt = KERNEL_PAGE_TABLE.lock();
     for i in 0..(KSTACK_SIZE / super::PGSIZE) {
         let va = base + i * super::PGSIZE;
-        let pa = pmem::alloc(true) as PhysAddr;
-        mappages(&mut kpt, va, pa, super::PGSIZE, PTE_R | PTE_W | PTE_A | PTE_D);
+        let Some(pa) = pmem::try_alloc(true) else {
+            if i > 0 {
+                unmappages(&mut kpt, base, i * super::PGSIZE, true);
+            }
+            sfence_vma_all();
+            return None;
+        };
+        mappages(&mut kpt, va, pa as PhysAddr, super::PGSIZE, PTE_R | PTE_W | PTE_A | PTE_D);
     }
     sfence_vma_all();
-    base + KSTACK_SIZE
+    Some(base + KSTACK_SIZE)
 }
 
-pub fn free_kstack(pid: usize) {
-    let base = kstack_base(pid);
+pub fn free_kstack(slot: usize) {
+    let base = kstack_base(slot);
     let mut kpt = KERNEL_PAGE_TABLE.lock();
     for i in 0..(KSTACK_SIZE / super::PGSIZE) {
         let va = base + i * super::PGSIZE;
@@ -73,27 +140,113 @@ pub fn free_kstack(pid: usize) {
 }
 
 pub struct KernelStack {
-    pid: usize,
+    slot: usize,
     top: VirtAddr,
 }
 
 impl KernelStack {
-    pub fn new(pid: usize) -> Self {
-        let top = alloc_kstack(pid);
-        Self { pid, top }
+    /// 在槽位上分配内核栈, 内存不足时返回 None
+    pub fn alloc(slot: usize) -> Option<Self> {
+        let top = alloc_kstack(slot)?;
+        Some(Self { slot, top })
     }
 
     pub fn top(&self) -> VirtAddr {
         self.top
     }
+
+    /// 栈的最低可用地址, 再往下一页是保护页
+    #[allow(dead_code)]
+    pub fn base(&self) -> VirtAddr {
+        kstack_base(self.slot)
+    }
+
+    #[allow(dead_code)]
+    pub fn guard(&self) -> VirtAddr {
+        kstack_guard(self.slot)
+    }
 }
 
 impl Drop for KernelStack {
     fn drop(&mut self) {
-        free_kstack(self.pid);
+        free_kstack(self.slot);
     }
 }
 
+static KMAP_USED: AtomicU64 = AtomicU64::new(0);
+
+/// 把一个物理页临时映射到内核窗口, guard 释放时解除映射
+/// 映射是全局的, 但只在当前 hart 上刷新 TLB, 持有期间不能睡眠 (内核态不会被抢占)
+/// 分页未开启时 (启动早期或测试) 直接使用物理地址
+pub struct KMapGuard {
+    slot: Option<usize>,
+    va: VirtAddr,
+}
+
+impl KMapGuard {
+    #[allow(dead_code)]
+    pub fn va(&self) -> VirtAddr {
+        self.va
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.va as *const T
+    }
+
+    pub fn as_mut_ptr<T>(&mut self) -> *mut T {
+        self.va as *mut T
+    }
+}
+
+impl Drop for KMapGuard {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            {
+                let mut kpt = KERNEL_PAGE_TABLE.lock();
+                unmappages(&mut kpt, self.va, PGSIZE, false);
+            }
+            sfence_vma(0, self.va);
+            KMAP_USED.fetch_and(!(1u64 << slot), Ordering::Release);
+        }
+    }
+}
+
+pub fn kmap_temporary(pa: PhysAddr) -> KMapGuard {
+    debug_assert!(pa & (PGSIZE - 1) == 0, "kmap_temporary: pa 0x{:x} not page aligned", pa);
+    if satp::read().mode() == satp::Mode::Bare {
+        return KMapGuard { slot: None, va: pa };
+    }
+
+    let slot = loop {
+        let used = KMAP_USED.load(Ordering::Acquire);
+        if used == u64::MAX {
+            panic!("kmap_temporary: all {} slots in use", KMAP_SLOTS);
+        }
+        let slot = (!used).trailing_zeros() as usize;
+        if KMAP_USED
+            .compare_exchange(used, used | (1u64 << slot), Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            break slot;
+        }
+    };
+
+    let va = KMAP_VA_BASE + slot * PGSIZE;
+    {
+        let mut kpt = KERNEL_PAGE_TABLE.lock();
+        mappages(&mut kpt, va, pa, PGSIZE, PTE_R | PTE_W | PTE_A | PTE_D);
+    }
+    sfence_vma(0, va);
+    KMapGuard { slot: Some(slot), va }
+}
+
+/// 改动用户页表中 va 的叶子项后, 在本 hart 上丢弃它的旧 TLB 项
+/// rs2 为 x0, 覆盖所有 ASID, 调用者不必知道页表属于哪个进程
+#[inline(always)]
+pub fn sfence_user_va(va: VirtAddr) {
+    unsafe { core::arch::asm!("sfence.vma {0}, zero", in(reg) va, options(nostack)) };
+}
+
 pub fn getpte(table: &PageTable, va: VirtAddr) -> *mut Pte {
     match table.lookup(va) {
         Some(p) => p,
@@ -120,6 +273,80 @@ pub fn map_kernel_pages(va: VirtAddr, pa: PhysAddr, size: usize, perm: usize) {
     sfence_vma_all();
 }
 
+#[allow(dead_code)]
+pub fn unmap_kernel_pages(va: VirtAddr, size: usize) {
+    let mut kpt = KERNEL_PAGE_TABLE.lock();
+    unmappages(&mut kpt, va, size, false);
+    sfence_vma_all();
+}
+
+pub fn map_trampoline(table: &mut PageTable) {
+    let tramp_pa = align_down(vector::trampoline as usize);
+    mappages(table, TRAMPOLINE_VA, tramp_pa, PGSIZE, TRAMPOLINE_FLAGS);
+}
+
+// 内核页表中跳板页的 PTE, 用来核对用户页表里的映射
+#[allow(dead_code)]
+pub fn kernel_trampoline_pte() -> Pte {
+    let kpt = KERNEL_PAGE_TABLE.lock();
+    unsafe { *getpte(&kpt, TRAMPOLINE_VA) }
+}
+
+// 内核页表中 va 的有效 PTE, 没有映射时返回 None
+#[allow(dead_code)]
+pub fn kernel_pte(va: VirtAddr) -> Option<Pte> {
+    let kpt = KERNEL_PAGE_TABLE.lock();
+    kpt.lookup(va).map(|p| unsafe { *p }).filter(|&pte| pte::is_valid(pte))
+}
+
+// 内核页表中必须存在且权限固定的区域: (名字, 起始 VA, 结束 VA, 起始 PA, R/W/X 权限)
+fn critical_regions() -> [(&'static str, VirtAddr, VirtAddr, PhysAddr, usize); 4] {
+    let text_start = unsafe { &__text_start as *const u8 as usize };
+    let text_end = unsafe { &__text_end as *const u8 as usize };
+    let rodata_start = unsafe { &__rodata_start as *const u8 as usize };
+    let rodata_end = unsafe { &__rodata_end as *const u8 as usize };
+    let uart_base = dtb::uart_config().unwrap_or(drivers::uart::DEFAULT_QEMU_VIRT).base;
+    let tramp_pa = align_down(vector::trampoline as usize);
+    [
+        (".text", align_down(text_start), align_up(text_end), align_down(text_start), PTE_R | PTE_X),
+        (".rodata", align_down(rodata_start), align_up(rodata_end), align_down(rodata_start), PTE_R),
+        ("UART", align_down(uart_base), align_down(uart_base) + PGSIZE, align_down(uart_base), PTE_R | PTE_W),
+        ("trampoline", TRAMPOLINE_VA, TRAMPOLINE_VA + PGSIZE, tramp_pa, PTE_R | PTE_X),
+    ]
+}
+
+// 逐页核对关键区域: 映射存在, 指向期望的物理页, R/W/X 与期望完全一致且没有 U 位
+// 不一致时返回 (区域名, 出错的 VA, 该 VA 的 PTE), 没有映射时 PTE 为 0
+pub fn check_kernel_mappings(kpt: &PageTable) -> Result<(), (&'static str, VirtAddr, Pte)> {
+    for (name, start, end, pa, perm) in critical_regions() {
+        for va in (start..end).step_by(PGSIZE) {
+            let pte = kpt.lookup(va).map(|p| unsafe { *p }).unwrap_or(0);
+            let ok = pte::is_valid(pte)
+                && pte::is_leaf(pte)
+                && pte::pte_to_pa(pte) == pa + (va - start)
+                && pte::get_flags(pte) & (PTE_R | PTE_W | PTE_X | PTE_U) == perm;
+            if !ok {
+                return Err((name, va, pte));
+            }
+        }
+    }
+    Ok(())
+}
+
+// 内核页表建好后立即调用, 映射顺序或权限写错时在启用分页前就停下
+pub fn verify_kernel_mappings() {
+    let kpt = KERNEL_PAGE_TABLE.lock();
+    if let Err((name, va, pte)) = check_kernel_mappings(&kpt) {
+        panic!("VM: bad kernel mapping of {} at VA {:#x}: {}", name, va, pte::display(pte));
+    }
+}
+
+// 测试用: 在持有内核页表锁的情况下执行 f
+#[allow(dead_code)]
+pub fn with_kernel_page_table<R>(f: impl FnOnce(&mut PageTable) -> R) -> R {
+    f(&mut KERNEL_PAGE_TABLE.lock())
+}
+
 #[cfg(debug_assertions)]
 pub fn print(table: &PageTable) {
     table.print();
@@ -191,14 +418,12 @@ pub fn init_kernel_vm(hartid: usize) {
         );
 
         // TRAMPOLINE 映射
-        let tramp_pa = align_down(vector::trampoline as usize);
-        let tramp_va = super::VA_MAX - super::PGSIZE;
         printk!(
             "VM: Map TRAMPOLINE VA={:p} -> PA={:p}\n",
-            tramp_va as *const u8,
-            tramp_pa as *const u8
+            TRAMPOLINE_VA as *const u8,
+            align_down(vector::trampoline as usize) as *const u8
         );
-        mappages(kpt, tramp_va, tramp_pa, PGSIZE, PTE_R | PTE_X | PTE_A);
+        map_trampoline(kpt);
 
         // MMIO 映射
         let uart_base = dtb::uart_config().unwrap_or(drivers::uart::DEFAULT_QEMU_VIRT).base;
@@ -206,11 +431,13 @@ pub fn init_kernel_vm(hartid: usize) {
         printk!("VM: Map UART @ {:p}\n", uart_base as *const u8);
         mappages(kpt, uart_base, uart_base, uart_size, PTE_R | PTE_W | PTE_A | PTE_D);
 
-        // VirtIO Block Device
-        let virtio_base = 0x10001000;
-        let virtio_size = PGSIZE;
-        printk!("VM: Map VirtIO @ {:p}", virtio_base as *const u8);
-        mappages(kpt, virtio_base, virtio_base, virtio_size, PTE_R | PTE_W | PTE_A | PTE_D);
+        // VirtIO: 映射全部槽位, 块设备在哪个槽位要等驱动初始化时探测
+        for r in drivers::virtio::mmio_regions() {
+            let start = align_down(r.start);
+            let end = align_up(r.start + r.size.max(1));
+            printk!("VM: Map VirtIO @ {:p}\n", r.start as *const u8);
+            mappages(kpt, start, start, end - start, PTE_R | PTE_W | PTE_A | PTE_D);
+        }
 
         // PLIC 映射
         let plic_base = match dtb::plic_base() {
@@ -293,20 +520,28 @@ pub fn init_kernel_vm(hartid: usize) {
         }
         printk!("VM: Root page table built by hart {}\n", hartid);
     });
+    verify_kernel_mappings();
     map_kstack0();
 }
 
 pub fn switch_to_kernel(hartid: usize) {
     let root_ppn = {
         let kpt = KERNEL_PAGE_TABLE.lock();
-        (&*kpt as *const PageTable as usize) >> 12
+        let root_pa = &*kpt as *const PageTable as usize;
+        debug_assert!(root_pa & (PGSIZE - 1) == 0, "switch_to_kernel: root 0x{:x} not page aligned", root_pa);
+        root_pa >> 12
     };
-    // set SATP to the new page table in Sv39 mode (ASID=0)
+    // set SATP to the new page table (ASID=0)
     unsafe {
-        satp::set(satp::Mode::Sv39, 0, root_ppn);
+        satp::set(SATP_MODE, 0, root_ppn);
         // flush all TLB entries
         sfence_vma_all();
     }
+    // MODE 字段是 WARL: 写入不支持的模式时 satp 整体保持不变, 读回即可判断硬件是否支持
+    // 此时仍处于 Bare 模式, 可以安全地报错
+    if satp::read().mode() != SATP_MODE {
+        panic!("VM: hart {} does not support {:?} paging", hartid, SATP_MODE);
+    }
     printk!("VM: Hart {} switched to kernel page table\n", hartid);
 }
 
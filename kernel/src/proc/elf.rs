@@ -0,0 +1,96 @@
+use crate::fs::inode::{self, Inode};
+use crate::mem::addr::{align_down, align_up};
+use crate::mem::pte::{PTE_A, PTE_D, PTE_R, PTE_U, PTE_W, PTE_X};
+use crate::mem::{MMAP_BEGIN, PGSIZE, VirtAddr};
+
+pub const MAX_SEGMENTS: usize = 8;
+// PT_LOAD 段允许的范围 [USER_SEG_BEGIN, MMAP_BEGIN)
+// 第 0 页保留不可访问, MMAP_BEGIN 以上依次是 mmap 区, TrapFrame 与跳板页
+pub const USER_SEG_BEGIN: VirtAddr = PGSIZE;
+
+// p_type
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3; // 需要动态链接器, 内核不支持
+
+// p_flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// exec 时记录的 PT_LOAD 段
+/// 含文件数据的页在首次访问时由缺页处理从可执行文件装入, 纯 BSS 页在 exec 时直接映射共享零页
+#[derive(Debug, Clone, Copy)]
+pub struct ElfSegment {
+    pub vaddr: VirtAddr,
+    pub file_off: usize,
+    pub filesz: usize,
+    pub flags: u32,
+}
+
+impl ElfSegment {
+    pub const fn empty() -> Self {
+        Self { vaddr: 0, file_off: 0, filesz: 0, flags: 0 }
+    }
+
+    // 含文件数据的页范围 [start, end), 纯 BSS 段为空区间
+    pub fn file_pages(&self) -> (VirtAddr, VirtAddr) {
+        let start = align_down(self.vaddr);
+        if self.filesz == 0 {
+            return (start, start);
+        }
+        (start, align_up(self.vaddr + self.filesz))
+    }
+
+    pub fn contains_page(&self, page: VirtAddr) -> bool {
+        let (start, end) = self.file_pages();
+        page >= start && page < end
+    }
+
+    pub fn perm(&self) -> usize {
+        let mut perm = PTE_U | PTE_A | PTE_D;
+        if self.flags & PF_R != 0 {
+            perm |= PTE_R;
+        }
+        if self.flags & PF_W != 0 {
+            perm |= PTE_W;
+        }
+        if self.flags & PF_X != 0 {
+            perm |= PTE_X;
+        }
+        perm
+    }
+}
+
+/// 检查 PT_LOAD 段是否完全落在用户段区间内, 拒绝覆盖跳板页/TrapFrame/mmap 区或回绕的段
+pub fn load_range_valid(vaddr: VirtAddr, filesz: usize, memsz: usize) -> bool {
+    if filesz > memsz {
+        return false;
+    }
+    match vaddr.checked_add(memsz) {
+        Some(end) => vaddr >= USER_SEG_BEGIN && end <= MMAP_BEGIN,
+        None => false,
+    }
+}
+
+/// 把 segs 中落在 page 上的文件数据交给 dst(页内偏移, 数据), 返回合并后的页权限
+/// 相邻段可能共用一页, 因此逐段拷贝并合并权限; 没有段覆盖该页时返回 None
+/// 读盘可能睡眠, 数据经栈上的缓冲区分块交出, dst 自己决定怎样写进页里
+pub fn fill_page(ip: &mut Inode, segs: &[ElfSegment], page: VirtAddr, mut dst: impl FnMut(usize, &[u8])) -> Option<usize> {
+    let mut perm = 0;
+    let mut buf = [0u8; 512];
+    for seg in segs.iter().filter(|s| s.contains_page(page)) {
+        let mut start = core::cmp::max(page, seg.vaddr);
+        let end = core::cmp::min(page + PGSIZE, seg.vaddr + seg.filesz);
+        while start < end {
+            let len = core::cmp::min(end - start, buf.len());
+            let off = (seg.file_off + (start - seg.vaddr)) as u32;
+            if inode::inode_read_data(ip, off, len as u32, &mut buf[..len]) != len as u32 {
+                return None;
+            }
+            dst(start - page, &buf[..len]);
+            start += len;
+        }
+        perm |= seg.perm();
+    }
+    if perm == 0 { None } else { Some(perm) }
+}
@@ -1,4 +1,5 @@
 pub mod context;
+pub mod elf;
 pub mod process;
 pub mod runnable_queue;
 pub mod scheduler;
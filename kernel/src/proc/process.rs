@@ -1,24 +1,24 @@
 use super::ProcContext;
+use super::elf::{self, ElfSegment, MAX_SEGMENTS};
 use super::set_current_user_satp;
 use super::runnable_queue;
 use super::table::{GLOBAL_PID, NPROC, PROC_TABLE};
-use crate::fs::inode;
+use crate::fs::inode::{self, Inode};
 use crate::hart;
-use crate::irq::TrapFrame;
-use crate::irq::vector;
+use crate::irq::{self, TrapFrame};
 use crate::mem::addr::align_down;
 use crate::mem::frame::PhysFrame;
-use crate::mem::mmap::{self, MmapRegion};
+use crate::mem::mmap::MmapIndex;
 use crate::mem::pmem;
-use crate::mem::pte::{PTE_A, PTE_D, PTE_R, PTE_U, PTE_W, PTE_X};
+use crate::mem::pte::{self, PTE_A, PTE_COW, PTE_D, PTE_R, PTE_U, PTE_W, PTE_X};
 use crate::mem::uvm;
 use crate::mem::vm::{self, KernelStack};
-use crate::mem::{PGSIZE, PageTable, PhysAddr, VA_MAX, VirtAddr};
+use crate::mem::{PGSIZE, PageTable, PhysAddr, VirtAddr};
 use crate::printk;
 use crate::proc::scheduler::wakeup;
 use core::sync::atomic::Ordering;
 use riscv::asm::wfi;
-use riscv::register::{satp, sscratch, sstatus};
+use riscv::register::{sscratch, sstatus};
 
 unsafe extern "C" {
     pub fn switch_context(old_ctx: &mut ProcContext, new_ctx: &mut ProcContext);
@@ -51,7 +51,7 @@ pub struct Process {
     pub name: [u8; 16],                     // 进程名称
     pub state: ProcState,                   // 进程状态
     pub parent: *mut Process,               // 父进程指针
-    pub exit_code: i32,                     // 退出码
+    pub exit_code: i32,                     // wait 状态, 见 exit_status/signal_status
     pub sleep_chan: usize,                  // 睡眠通道
     pub pid: usize,                         // 进程ID
     pub root_pt_pa: PhysAddr,               // 根页表物理地址
@@ -66,15 +66,62 @@ pub struct Process {
     pub kstack: Option<KernelStack>,        // 内核栈 RAII
     pub entry_va: VirtAddr,                 // 用户入口地址
     pub user_sp_va: VirtAddr,               // 用户栈顶 VA
-    pub mmap_head: *mut MmapRegion,         // mmap 链表头
+    pub mmap: MmapIndex,                    // mmap 区域, 按起始地址有序
     pub open_files: [Option<usize>; NOFILE], // 打开的文件表索引
+    pub cloexec: [bool; NOFILE],            // 按 fd 记录的 O_CLOEXEC, exec 成功时关闭
     pub cwd: u32,                           // 当前工作目录 inode 号
+    pub vfork_parent: *mut Process,         // 非空表示 vfork 子进程, 仍借用该父进程的地址空间
+    pub segments: [ElfSegment; MAX_SEGMENTS], // exec 记录的 PT_LOAD 段, 缺页时按需装入
+    pub nsegments: usize,                   // segments 中有效项数
+    pub exe_inode: *mut Inode,              // 可执行文件 inode, 持有一个引用直到 exit 或下一次 exec
+    pub utime: usize,                       // 在用户态收到的时钟中断数
+    pub stime: usize,                       // 在系统调用中收到的时钟中断数
+    pub wake_at: u64,                       // sleep_until 的截止时间 (time CSR), 0 表示没有
+}
+
+// 退出状态编码与 POSIX wait 一致: 正常退出为 (code & 0xff) << 8, 被信号杀死时低 7 位为信号号
+pub const SIGILL: i32 = 4;
+pub const SIGBUS: i32 = 7;
+pub const SIGSEGV: i32 = 11;
+
+pub const fn exit_status(code: i32) -> i32 {
+    (code & 0xff) << 8
+}
+
+pub const fn signal_status(sig: i32) -> i32 {
+    sig & 0x7f
 }
 
 unsafe impl Send for Process {}
 unsafe impl Sync for Process {}
 
 impl Process {
+    // root_pt_pa 的不变式: 已分配 (非 0) 且页对齐; 不满足时返回原因
+    pub fn root_pt_invalid(&self) -> Option<&'static str> {
+        if self.root_pt_pa == 0 {
+            Some("page table not allocated (or already released)")
+        } else if self.root_pt_pa & (PGSIZE - 1) != 0 {
+            Some("root_pt_pa not page aligned")
+        } else {
+            None
+        }
+    }
+
+    /// 根页表. 它在单独的物理页里, 引用不借用 Process 本身, 调用者可以同时修改进程的其他字段
+    pub fn page_table(&self) -> &'static PageTable {
+        if let Some(why) = self.root_pt_invalid() {
+            debug_assert!(false, "process {}: {} (root_pt_pa 0x{:x})", self.pid, why, self.root_pt_pa);
+        }
+        unsafe { &*(self.root_pt_pa as *const PageTable) }
+    }
+
+    pub fn page_table_mut(&mut self) -> &'static mut PageTable {
+        if let Some(why) = self.root_pt_invalid() {
+            debug_assert!(false, "process {}: {} (root_pt_pa 0x{:x})", self.pid, why, self.root_pt_pa);
+        }
+        unsafe { &mut *(self.root_pt_pa as *mut PageTable) }
+    }
+
     pub const fn new() -> Self {
         Self {
             name: [0; 16],
@@ -96,25 +143,99 @@ impl Process {
             kstack: None,
             entry_va: 0,
             user_sp_va: 0,
-            mmap_head: core::ptr::null_mut(),
+            mmap: MmapIndex::new(),
             open_files: [None; NOFILE],
+            cloexec: [false; NOFILE],
             cwd: crate::fs::inode::ROOT_INODE,
+            vfork_parent: core::ptr::null_mut(),
+            segments: [ElfSegment::empty(); MAX_SEGMENTS],
+            nsegments: 0,
+            exe_inode: core::ptr::null_mut(),
+            utime: 0,
+            stime: 0,
+            wake_at: 0,
         }
     }
 
     pub fn ustack_grow(&mut self, fault_va: VirtAddr) -> Result<(), ()> {
-        let pt = unsafe { &mut *(self.root_pt_pa as *mut PageTable) };
+        let pt = self.page_table_mut();
         match uvm::ustack_grow(pt, &mut self.stack_pages, self.trapframe_va, fault_va) {
             Ok(_) => Ok(()),
             Err(_) => Err(()),
         }
     }
 
+    pub fn cow_fault(&mut self, fault_va: VirtAddr) -> Result<(), ()> {
+        let pt = self.page_table();
+        uvm::cow_fault(pt, fault_va).map_err(|_| ())
+    }
+
+    // 按需装入 ELF 段中含文件数据的页; 页已映射或不属于任何段时返回 Err
+    pub fn load_segment_page(&mut self, fault_va: VirtAddr) -> Result<(), ()> {
+        if self.exe_inode.is_null() {
+            return Err(());
+        }
+        let page = align_down(fault_va);
+        let pt = self.page_table_mut();
+        let segs = &self.segments[..self.nsegments];
+        if !segs.iter().any(|s| s.contains_page(page)) {
+            return Err(());
+        }
+        if let Some(pte_ptr) = pt.lookup(page) {
+            if pte::is_valid(unsafe { *pte_ptr }) {
+                return Err(());
+            }
+        }
+
+        let pa = pmem::try_alloc(false).ok_or(())? as PhysAddr;
+        let ip = unsafe { &mut *self.exe_inode };
+        // kmap 窗口持有期间不能睡眠, 只在清零和拷贝每块数据时短暂映射, 读盘时不持有
+        {
+            let mut kmap = vm::kmap_temporary(pa);
+            unsafe { core::ptr::write_bytes(kmap.as_mut_ptr::<u8>(), 0, PGSIZE) };
+        }
+        let perm = elf::fill_page(ip, segs, page, |off, data| {
+            let mut kmap = vm::kmap_temporary(pa);
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), kmap.as_mut_ptr::<u8>().add(off), data.len()) };
+        });
+        match perm {
+            Some(perm) => {
+                vm::mappages(pt, page, pa, PGSIZE, perm);
+                // 刚写入的代码页需要让取指看到, 进程之后可能在别的 hart 上运行
+                if perm & PTE_X != 0 {
+                    hart::flush_icache();
+                }
+                Ok(())
+            }
+            None => {
+                pmem::free(pa, false);
+                Err(())
+            }
+        }
+    }
+
+    // 归还可执行文件 inode 的引用; 文件若已被删除, 最后一个引用释放时才回收其数据
+    pub fn release_exe(&mut self) {
+        if !self.exe_inode.is_null() {
+            inode::inode_put(unsafe { &mut *self.exe_inode });
+            self.exe_inode = core::ptr::null_mut();
+        }
+        self.nsegments = 0;
+    }
+
+    // fork/vfork 子进程继承段表, 并各自持有可执行文件的引用
+    fn inherit_exe(&self, child: &mut Process) {
+        child.segments = self.segments;
+        child.nsegments = self.nsegments;
+        if !self.exe_inode.is_null() {
+            inode::inode_dup(unsafe { &mut *self.exe_inode });
+        }
+        child.exe_inode = self.exe_inode;
+    }
+
     pub fn root_satp(&self) -> usize {
-        // 根页表物理页号
-        let ppn = (self.root_pt_pa >> 12) & ((1usize << (usize::BITS as usize - 12)) - 1);
-        // Compose SATP value for Sv39: MODE in bits [63:60], ASID=pid, PPN in [43:0]
-        ((satp::Mode::Sv39 as usize) << 60) | (self.pid << 44) | ppn
+        // Compose SATP value: MODE in bits [63:60], ASID=pid, PPN in [43:0]
+        vm::make_satp(self.root_pt_pa, self.pid)
     }
 
     #[cfg(debug_assertions)]
@@ -134,22 +255,31 @@ impl Process {
             self.entry_va,
             self.user_sp_va,
         );
-        let page_table = unsafe { &*(self.root_pt_pa as *const PageTable) };
+        let page_table = self.page_table();
         page_table.print();
         let tf = unsafe { &*(self.trapframe) };
         tf.print();
         self.context.print();
     }
 
-    pub fn free(&mut self) {
-        let page_table = unsafe { &mut *(self.root_pt_pa as *mut PageTable) };
+    // 释放自有的用户地址空间: 用户页, 页表页与 mmap 节点, 并重置布局字段
+    // 跳板页不在用户区, TrapFrame 由 trapframe_frame 单独持有, 都不会在这里释放
+    pub fn release_address_space(&mut self) {
+        // 未 exec 就退出的 vfork 子进程不拥有页表, 不能销毁父进程的地址空间
+        if let Some(frame) = self.root_pt_frame.take() {
+            let page_table = unsafe { &mut *(frame.addr() as *mut PageTable) };
+            page_table.destroy();
+        }
+
+        self.mmap.free_all();
 
-        // Destory page table first (frees TrapFrame and children)
-        page_table.destroy();
-        self.root_pt_frame = None;
+        self.heap_base = 0;
+        self.heap_top = 0;
+        self.stack_pages = 0;
+    }
 
-        // Free mmap regions
-        mmap::region_free(self.mmap_head);
+    pub fn free(&mut self) {
+        self.release_address_space();
 
         // Kernel Stack is freed by Drop of KernelStack in self.kstack
         self.kstack = None;
@@ -161,6 +291,9 @@ impl Process {
         let sie_enabled = sstatus_val.sie();
         unsafe { sstatus::clear_sie(); }
 
+        // 归还借用的地址空间; 挂起在 vfork 中的父进程与 wait() 睡在同一通道上
+        self.vfork_parent = core::ptr::null_mut();
+
         // Wake up parent if sleeping in wait()
         if !self.parent.is_null() {
             let parent = self.parent as usize;
@@ -194,6 +327,8 @@ impl Process {
                 self.open_files[i] = None;
             }
         }
+        self.cloexec = [false; NOFILE];
+        self.release_exe();
 
         if sie_enabled { unsafe { sstatus::set_sie(); } }
     }
@@ -207,8 +342,16 @@ impl Process {
     }
 
     // TODO: Copy-on-write fork
-    pub fn fork(&mut self) -> &'static mut Process {
-        let child = alloc().expect("Failed to allocate process");
+    // 进程表已满, 或内核栈、TrapFrame、页表复制所需的内存不足时返回 None
+    pub fn fork(&mut self) -> Option<&'static mut Process> {
+        let child = alloc()?;
+        // 先分配内核栈: 失败时子进程还没有任何资源, 直接归还槽位
+        let Some(kstack) = KernelStack::alloc(kstack_slot(child)) else {
+            alloc_abort(child);
+            return None;
+        };
+        let kstack_top = kstack.top();
+        child.kstack = Some(kstack);
         // quiet fork path in release
         // Copy process state from parent to child
         child.parent = self as *mut Process;
@@ -216,10 +359,20 @@ impl Process {
         child.user_sp_va = self.user_sp_va;
         child.trapframe_va = self.trapframe_va;
 
+        // TrapFrame 页和页表副本在继承文件之前取得, 失败时只需归还内核栈和槽位
+        let Some(child_tf_frame) = PhysFrame::alloc() else {
+            fork_abort(child);
+            return None;
+        };
         // Copy page table
-        let parent_pt = unsafe { &*(self.root_pt_pa as *const PageTable) };
+        let parent_pt = self.page_table();
         // Alloc RAII frame for child root pt
-        let child_pt_pa_raw = parent_pt.copy().expect("Failed to copy page table");
+        let Ok(child_pt_pa_raw) = parent_pt.copy() else {
+            fork_abort(child);
+            return None;
+        };
+        // 代码页也复制到了新的物理页上
+        hart::flush_icache();
         // We must wrap the raw PA from `copy` into a PhysFrame.
         // Since `copy` allocated it using `pmem::alloc`, it has ref_count=1.
         // Wrapping it in PhysFrame is correct ownership transfer.
@@ -235,6 +388,7 @@ impl Process {
 
         // Copy FD table and increment refcnts
         child.open_files = self.open_files;
+        child.cloexec = self.cloexec;
         for i in 0..NOFILE {
             if let Some(f_idx) = child.open_files[i] {
                 let mut table = crate::fs::file::FILE_TABLE.lock();
@@ -242,24 +396,19 @@ impl Process {
             }
         }
         child.cwd = self.cwd;
+        self.inherit_exe(child);
         // Increment refcnt for cwd inode if we track it via file objects? 
         // For now cwd is just an inum. In a full system, we might want to hold an Inode ref.
         // If cwd is just inum, no refcnt to increment here unless we use inode_get/put.
         // The design in STEPS.md says "cwd: u32 (inode_num)".
 
-        // Allocate new TrapFrame page for child
-        let child_tf_frame = PhysFrame::alloc().expect("Failed to alloc trapframe");
+        // New TrapFrame page for child
         let child_tf_pa = child_tf_frame.addr();
         child.trapframe = child_tf_pa as *mut TrapFrame;
         child.trapframe_frame = Some(child_tf_frame);
 
-        // Allocate new Kernel Stack for child
-        let kstack = KernelStack::new(child.pid);
-        let kstack_top = kstack.top();
-        child.kstack = Some(kstack);
-
         // Map new TrapFrame in child's page table (overwrite copied mapping)
-        let child_pt = unsafe { &mut *(child.root_pt_pa as *mut PageTable) };
+        let child_pt = child.page_table_mut();
         // Free the TrapFrame page created by copy() (it was a duplicate of parent's, but we want a fresh one)
         vm::unmappages(child_pt, child.trapframe_va, PGSIZE, true);
         vm::mappages(
@@ -289,11 +438,76 @@ impl Process {
         // Note: child.state is already set to Runnable by alloc(), and bitmap is already updated
         // This line is redundant but kept for clarity
         child.state = ProcState::Runnable;
-        child
+        Some(child)
+    }
+
+    // vfork: 子进程直接借用父进程的页表与 TrapFrame, 父进程挂起直到子进程 exec 或退出。
+    // 父进程的用户寄存器在挂起期间保存在它的内核栈上, 返回时会整体写回 TrapFrame,
+    // 因此子进程复用同一页不会破坏父进程。子进程在此期间只应使用栈并调用 exec/exit。
+    // 进程表已满或内核栈分配失败时返回 usize::MAX
+    pub fn vfork(&mut self) -> usize {
+        let Some(child) = alloc() else {
+            return usize::MAX;
+        };
+        let Some(kstack) = KernelStack::alloc(kstack_slot(child)) else {
+            alloc_abort(child);
+            return usize::MAX;
+        };
+        let kstack_top = kstack.top();
+        child.kstack = Some(kstack);
+        child.parent = self as *mut Process;
+        child.entry_va = self.entry_va;
+        child.user_sp_va = self.user_sp_va;
+
+        // 共享页表和 TrapFrame, 不持有 RAII frame
+        child.root_pt_pa = self.root_pt_pa;
+        child.root_pt_frame = None;
+        child.trapframe = self.trapframe;
+        child.trapframe_frame = None;
+        child.trapframe_va = self.trapframe_va;
+        child.heap_base = self.heap_base;
+        child.heap_top = self.heap_top;
+        child.stack_pages = self.stack_pages;
+
+        // Copy FD table and increment refcnts
+        child.open_files = self.open_files;
+        child.cloexec = self.cloexec;
+        for i in 0..NOFILE {
+            if let Some(f_idx) = child.open_files[i] {
+                let mut table = crate::fs::file::FILE_TABLE.lock();
+                table.files[f_idx].refcnt += 1;
+            }
+        }
+        child.cwd = self.cwd;
+        self.inherit_exe(child);
+
+        // 子进程从 vfork 返回 0; 父进程的返回值由系统调用路径写回
+        let tf = unsafe { &mut *self.trapframe };
+        tf.a0 = 0;
+        tf.kernel_epc = tf.kernel_epc.wrapping_add(4); // skip ecall
+        tf.kernel_sp = kstack_top;
+
+        child.context.sp = kstack_top;
+        child.context.ra = trap_user_return as usize;
+        child.vfork_parent = self as *mut Process;
+        child.state = ProcState::Runnable;
+
+        let pid = child.pid;
+        crate::proc::scheduler::wait_vfork(child);
+        pid
+    }
+
+    // vfork 子进程不再借用父进程地址空间时唤醒父进程
+    fn vfork_release(&mut self) {
+        if !self.vfork_parent.is_null() {
+            let parent = self.vfork_parent as usize;
+            self.vfork_parent = core::ptr::null_mut();
+            wakeup(parent);
+        }
     }
 
     pub fn exec(&mut self, payload: &[u8]) {
-        let page_table = unsafe { &mut *(self.root_pt_pa as *mut PageTable) };
+        let page_table = self.page_table_mut();
         let empty_va = 0usize;
         let code_va = empty_va + PGSIZE;
         let (src_ptr, src_len) = (payload.as_ptr(), payload.len());
@@ -338,6 +552,18 @@ impl Process {
         self.heap_base = self.heap_top;
     }
 
+    // exec 提交新映像时关闭标记了 O_CLOEXEC 的 fd, 失败的 exec 不会走到这里
+    fn close_on_exec(&mut self) {
+        for fd in 0..NOFILE {
+            if self.cloexec[fd] {
+                if let Some(f_idx) = self.open_files[fd].take() {
+                    crate::fs::file::file_close(f_idx);
+                }
+                self.cloexec[fd] = false;
+            }
+        }
+    }
+
     pub fn proc_exec(&mut self, path: &[u8], u_argv: &[usize]) -> Result<(), ()> {
         crate::printk!("proc_exec: path='{}'\n", core::str::from_utf8(path).unwrap_or("?"));
         let fd = crate::syscall::fs::fs_open(self, path, 0).map_err(|_| {
@@ -369,22 +595,34 @@ impl Process {
         }
 
         // Setup NEW page table
-        let root_pt_frame = PhysFrame::alloc().ok_or_else(|| {
-            crate::printk!("proc_exec: failed to alloc root pt\n");
-        })?;
+        let root_pt_frame = match PhysFrame::alloc() {
+            Some(frame) => frame,
+            None => {
+                crate::printk!("proc_exec: failed to alloc root pt\n");
+                inode::inode_put(ip);
+                crate::syscall::fs::fs_close(self, fd)?;
+                return Err(());
+            }
+        };
         let root_pt_pa = root_pt_frame.addr();
         let pt = unsafe { &mut *(root_pt_pa as *mut PageTable) };
         unsafe { core::ptr::write_bytes(pt as *mut PageTable as *mut u8, 0, PGSIZE) };
 
         // Map Trampoline
-        let tramp_pa = align_down(vector::trampoline as usize) as PhysAddr;
-        let tramp_va = VA_MAX - PGSIZE;
-        vm::mappages(pt, tramp_va, tramp_pa, PGSIZE, PTE_R | PTE_X | PTE_A);
+        let tramp_va = vm::TRAMPOLINE_VA;
+        vm::map_trampoline(pt);
 
         // Setup NEW TrapFrame
-        let trapframe_frame = PhysFrame::alloc().ok_or_else(|| {
-            crate::printk!("proc_exec: failed to alloc trapframe\n");
-        })?;
+        let trapframe_frame = match PhysFrame::alloc() {
+            Some(frame) => frame,
+            None => {
+                crate::printk!("proc_exec: failed to alloc trapframe\n");
+                pt.destroy();
+                inode::inode_put(ip);
+                crate::syscall::fs::fs_close(self, fd)?;
+                return Err(());
+            }
+        };
         let trapframe_pa = trapframe_frame.addr();
         let trapframe_va = tramp_va - PGSIZE;
         vm::mappages(pt, trapframe_va, trapframe_pa, PGSIZE, PTE_R | PTE_W | PTE_A | PTE_D);
@@ -395,6 +633,8 @@ impl Process {
         let phentsize = u16::from_le_bytes(elf_header[54..56].try_into().unwrap()) as usize;
 
         let mut max_va = 0;
+        let mut segments = [ElfSegment::empty(); MAX_SEGMENTS];
+        let mut nsegments = 0;
 
         for i in 0..phnum {
             let mut ph = [0u8; 56]; // Size of Phdr
@@ -403,49 +643,47 @@ impl Process {
                 break;
             }
             let p_type = u32::from_le_bytes(ph[0..4].try_into().unwrap());
-            if p_type == 1 { // PT_LOAD
+            // 动态链接的程序没有解释器就无法运行, 与其装入后莫名崩溃不如直接拒绝
+            if p_type == elf::PT_INTERP {
+                crate::printk!("proc_exec: dynamic executables not supported (PT_INTERP)\n");
+                pt.destroy();
+                inode::inode_put(ip);
+                crate::syscall::fs::fs_close(self, fd)?;
+                return Err(());
+            }
+            if p_type == elf::PT_LOAD {
                 let p_offset = u64::from_le_bytes(ph[8..16].try_into().unwrap()) as usize;
                 let p_vaddr = u64::from_le_bytes(ph[16..24].try_into().unwrap()) as usize;
                 let p_filesz = u64::from_le_bytes(ph[32..40].try_into().unwrap()) as usize;
                 let p_memsz = u64::from_le_bytes(ph[40..48].try_into().unwrap()) as usize;
                 let p_flags = u32::from_le_bytes(ph[4..8].try_into().unwrap());
 
-                // IMPORTANT: During loading, we must be able to write to the pages.
-                // We add PTE_W now, and ideally we should set final permissions later.
-                let mut perm = PTE_U | PTE_A | PTE_D | PTE_W; // Always add W for loading
-                if p_flags & 1 != 0 { perm |= PTE_X; }
-                if p_flags & 4 != 0 { perm |= PTE_R; }
+                if !elf::load_range_valid(p_vaddr, p_filesz, p_memsz) || nsegments == MAX_SEGMENTS {
+                    crate::printk!("proc_exec: rejected PT_LOAD at 0x{:x} (memsz 0x{:x})\n", p_vaddr, p_memsz);
+                    pt.destroy();
+                    inode::inode_put(ip);
+                    crate::syscall::fs::fs_close(self, fd)?;
+                    return Err(());
+                }
+                let seg = ElfSegment { vaddr: p_vaddr, file_off: p_offset, filesz: p_filesz, flags: p_flags };
+                segments[nsegments] = seg;
+                nsegments += 1;
 
-                // Map and Load
-                let start_va = align_down(p_vaddr);
+                // 含文件数据的页留到首次访问时再装入, 这里只映射不含文件数据的纯 BSS 页 (共享零页)
                 let end_va = (p_vaddr + p_memsz + PGSIZE - 1) & !(PGSIZE - 1);
-                
-                let mut va = start_va;
+                let (_, file_end_va) = seg.file_pages();
+                let mut bss_perm = seg.perm() & !PTE_W;
+                if p_flags & elf::PF_W != 0 { bss_perm |= PTE_COW; }
+
+                let mut va = file_end_va;
                 while va < end_va {
-                    let pa = pmem::alloc(false) as PhysAddr;
-                    unsafe { core::ptr::write_bytes(pa as *mut u8, 0, PGSIZE) };
-                    vm::mappages(pt, va, pa, PGSIZE, perm);
+                    vm::mappages(pt, va, pmem::zero_frame(), PGSIZE, bss_perm);
                     va += PGSIZE;
                 }
-
-                let mut read_off = 0;
-                while read_off < p_filesz {
-                    let chunk = core::cmp::min(p_filesz - read_off, 512);
-                    let mut kbuf = [0u8; 512];
-                    if inode::inode_read_data(ip, (p_offset + read_off) as u32, chunk as u32, &mut kbuf[..chunk]) != chunk as u32 {
-                        crate::printk!("proc_exec: failed to read segment data\n");
-                        break;
-                    }
-                    if let Err(e) = uvm::copyout(pt, p_vaddr + read_off, &kbuf[..chunk]) {
-                        crate::printk!("proc_exec: copyout segment failed: {:?}\n", e);
-                        return Err(());
-                    }
-                    read_off += chunk;
-                }
                 max_va = core::cmp::max(max_va, end_va);
             }
         }
-        inode::inode_put(ip);
+        // 保留对 ip 的引用供缺页时读取段数据, 提交新映像时转交给进程
         crate::syscall::fs::fs_close(self, fd)?;
 
         // Setup Stack
@@ -462,39 +700,53 @@ impl Process {
         }
 
         // Copy arguments to stack
-        let mut sp = stack_top;
-        let mut stack_argv = [0usize; 16];
-        let old_pt = unsafe { &*(self.root_pt_pa as *const PageTable) };
-
-        for i in (0..u_argv.len()).rev() {
-            let mut arg_buf = [0u8; 128];
-            let len = uvm::copyin_str(old_pt, &mut arg_buf, u_argv[i]).map_err(|_| {
-                crate::printk!("proc_exec: failed to copyin argv[{}]\n", i);
-            })?;
-            sp -= len;
-            sp &= !7; // align 8
-            uvm::copyout(pt, sp, &arg_buf[..len]).map_err(|_| {
-                crate::printk!("proc_exec: failed to copyout argv[{}] string\n", i);
-            })?;
-            stack_argv[i] = sp;
-        }
-        
-        // Push argv pointers
-        sp -= u_argv.len() * 8;
-        sp &= !7;
-        for i in 0..u_argv.len() {
-            let bytes = stack_argv[i].to_ne_bytes();
-            uvm::copyout(pt, sp + i * 8, &bytes).map_err(|_| {
-                crate::printk!("proc_exec: failed to copyout argv[{}] pointer\n", i);
-            })?;
-        }
+        let old_pt = self.page_table();
+        let copy_args = |pt: &mut PageTable| -> Result<usize, ()> {
+            let mut sp = stack_top;
+            let mut stack_argv = [0usize; 16];
+            for i in (0..u_argv.len()).rev() {
+                let mut arg_buf = [0u8; 128];
+                let len = uvm::copyin_str(old_pt, &mut arg_buf, u_argv[i]).map_err(|_| {
+                    crate::printk!("proc_exec: failed to copyin argv[{}]\n", i);
+                })?;
+                sp -= len;
+                sp &= !7; // align 8
+                uvm::copyout(pt, sp, &arg_buf[..len]).map_err(|_| {
+                    crate::printk!("proc_exec: failed to copyout argv[{}] string\n", i);
+                })?;
+                stack_argv[i] = sp;
+            }
+
+            // Push argv pointers
+            sp -= u_argv.len() * 8;
+            sp &= !7;
+            for i in 0..u_argv.len() {
+                let bytes = stack_argv[i].to_ne_bytes();
+                uvm::copyout(pt, sp + i * 8, &bytes).map_err(|_| {
+                    crate::printk!("proc_exec: failed to copyout argv[{}] pointer\n", i);
+                })?;
+            }
+            Ok(sp)
+        };
+        let sp = match copy_args(pt) {
+            Ok(v) => v,
+            Err(()) => {
+                // 旧映像保持不变, 只回收新页表上已经装载的页
+                pt.destroy();
+                inode::inode_put(ip);
+                return Err(());
+            }
+        };
         let argv_ptr = sp;
 
         // Commit NEW state
-        let old_pt_frame = self.root_pt_frame.take();
-        if let Some(mut frame) = old_pt_frame {
-            unsafe { &mut *(frame.addr() as *mut PageTable) }.destroy();
-        }
+        // 新映像装载成功后才拆除旧的地址空间 (代码/堆/栈/mmap), 失败的 exec 不影响调用者
+        self.release_address_space();
+        self.release_exe();
+        self.segments = segments;
+        self.nsegments = nsegments;
+        self.exe_inode = ip as *mut Inode;
+        self.close_on_exec();
         self.root_pt_pa = root_pt_pa;
         self.root_pt_frame = Some(root_pt_frame);
         
@@ -510,19 +762,22 @@ impl Process {
         self.user_sp_va = sp;
 
         // Init trapframe
+        unsafe { self.trapframe.write(TrapFrame::new()) };
         let tf = unsafe { &mut *self.trapframe };
         tf.sp = sp;
         tf.kernel_epc = self.entry_va;
         tf.a0 = u_argv.len();
         tf.a1 = argv_ptr;
-        tf.kernel_satp = satp::read().bits();
-        tf.kernel_hartid = hart::getid();
+        irq::init_trapframe(tf);
         tf.kernel_sp = self.kstack.as_ref().unwrap().top();
 
         let satp_bits = self.root_satp();
         set_current_user_satp(satp_bits);
         unsafe { sscratch::write(self.trapframe_va) };
 
+        // 此后系统调用路径只写新的 TrapFrame (见 trap_user_handler), 可以放父进程运行
+        self.vfork_release();
+
         crate::printk!("proc_exec: success, entry=0x{:x}\n", self.entry_va);
         Ok(())
     }
@@ -558,10 +813,15 @@ pub fn alloc() -> Option<&'static mut Process> {
             p.parent = core::ptr::null_mut();
             p.exit_code = 0;
             p.sleep_chan = 0;
+            p.vfork_parent = core::ptr::null_mut();
+            p.utime = 0;
+            p.stime = 0;
+            p.wake_at = 0;
             p.context = ProcContext::new();
             p.context.ra = proc_return as usize;
             p.context.sp = 0;
             p.state = ProcState::Runnable;
+            runnable_queue::reset_slot(i);
             runnable_queue::mark_runnable(i);
 
             if sie_enabled { unsafe { sstatus::set_sie(); } }
@@ -573,6 +833,38 @@ pub fn alloc() -> Option<&'static mut Process> {
     None
 }
 
+// 内核栈按进程表下标取槽位, 槽位 0 留给 KSTACK(0); pid 只增不减, 不能用来定位槽位
+const _: () = assert!(NPROC < vm::KSTACK_SLOTS, "every process slot needs a kernel stack slot");
+
+fn kstack_slot(p: &Process) -> usize {
+    let sie_enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie(); }
+    let idx = runnable_queue::find_proc_index(p as *const Process).expect("kstack_slot: process not in the table");
+    if sie_enabled { unsafe { sstatus::set_sie(); } }
+    idx + 1
+}
+
+// fork 中途失败: 释放子进程已有的内核栈, 再归还槽位
+fn fork_abort(child: &mut Process) {
+    child.kstack = None;
+    alloc_abort(child);
+}
+
+// 归还 alloc 取得但还没有装配好的槽位, 调用者保证它还没有持有任何资源
+fn alloc_abort(p: &mut Process) {
+    let sie_enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie(); }
+
+    let mut table = PROC_TABLE.lock();
+    if let Some(idx) = (0..NPROC).find(|&i| &mut table[i] as *mut Process == p as *mut Process) {
+        runnable_queue::mark_not_runnable(idx);
+    }
+    p.state = ProcState::Unused;
+    drop(table);
+
+    if sie_enabled { unsafe { sstatus::set_sie(); } }
+}
+
 /*
 用户地址空间布局：
 trampoline  (1 page) 映射在最高地址
@@ -592,12 +884,11 @@ pub fn create(payload: &[u8]) -> &'static mut Process {
     let root_pt_frame = PhysFrame::alloc().expect("Failed to alloc root pt");
     proc.root_pt_pa = root_pt_frame.addr();
     proc.root_pt_frame = Some(root_pt_frame);
-    let page_table = unsafe { &mut *(proc.root_pt_pa as *mut PageTable) };
+    let page_table = proc.page_table_mut();
     unsafe { core::ptr::write_bytes(page_table as *mut PageTable as *mut u8, 0, PGSIZE) };
     // Setup Trampoline
-    let tramp_pa = align_down(vector::trampoline as usize) as PhysAddr; // trampoline 物理地址
-    let tramp_va = VA_MAX - PGSIZE; // trampoline 虚拟地址（最高页）
-    vm::mappages(page_table, tramp_va, tramp_pa, PGSIZE, PTE_R | PTE_X | PTE_A);
+    let tramp_va = vm::TRAMPOLINE_VA; // trampoline 虚拟地址（最高页）
+    vm::map_trampoline(page_table);
     // Setup TrapFrame
     // TrapFrame 放在内核物理页区域，避免占用用户物理页池
     let trapframe_frame = PhysFrame::alloc().expect("Failed to alloc trapframe");
@@ -606,24 +897,24 @@ pub fn create(payload: &[u8]) -> &'static mut Process {
     proc.trapframe_va = trapframe_va;
     proc.trapframe = trapframe_pa as *mut TrapFrame;
     proc.trapframe_frame = Some(trapframe_frame);
+    unsafe { proc.trapframe.write(TrapFrame::new()) };
     vm::mappages(page_table, trapframe_va, trapframe_pa, PGSIZE, PTE_R | PTE_W | PTE_A | PTE_D);
     // Load payload
     proc.exec(payload);
     // Setup Kernel Stack
-    let kstack = KernelStack::new(proc.pid);
+    let kstack = KernelStack::alloc(kstack_slot(proc)).expect("Failed to alloc kernel stack for the first process");
     let kstack_top = kstack.top();
     proc.kstack = Some(kstack);
 
     // Setup initial user stack top (matches service/hello/link.ld)
     proc.user_sp_va = 0x20000 + 24576; // STACK_TOP
-    // Ensure I-cache observes freshly written user code
-    riscv::asm::fence_i();
+    // Ensure I-cache observes freshly written user code, on whichever hart first runs it
+    hart::flush_icache();
     // 初始化 trapframe 的返回地址和用户栈（通过物理地址访问）
     let tf = unsafe { &mut *proc.trapframe };
     tf.sp = proc.user_sp_va;
     tf.kernel_epc = proc.entry_va;
-    tf.kernel_satp = satp::read().bits();
-    tf.kernel_hartid = hart::getid();
+    irq::init_trapframe(tf);
     tf.kernel_sp = kstack_top;
     // 记录当前用户页表 SATP
     let satp_bits = proc.root_satp();
@@ -2,26 +2,58 @@
 //! 
 //! This module provides a bitmap-based queue to quickly find runnable processes,
 //! improving scheduler performance from O(n) to O(1).
+//!
+//! All harts share one bitmap, but each slot remembers the hart it last ran on
+//! (its home). A hart prefers its own and never-scheduled slots, and only pulls
+//! work homed elsewhere when that hart is idle or has a backlog of at least
+//! STEAL_THRESHOLD, so processes do not bounce between harts on every tick.
+//! A per-slot affinity mask limits which harts may run a slot at all.
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use spin::Mutex;
 use super::table::NPROC;
+use crate::hart::{self, MAX_HARTS};
 
 /// Bitmap tracking runnable processes
 /// Each bit represents a process index (0-63)
 /// Bit set = process is runnable, bit clear = process is not runnable
 static RUNNABLE_BITMAP: AtomicU64 = AtomicU64::new(0);
 
+/// Number of runnable processes (== popcount of the bitmap)
+/// Only changed on an actual 0->1 / 1->0 bit transition, so fork/exit/sleep/wakeup
+/// paths that mark the same slot twice keep it consistent
+static RUNNABLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Index returned by the last find_runnable
+/// The next search starts just after it, so a process that yields is only picked
+/// again once every other runnable process has had a turn (round-robin)
+static CURSOR: AtomicUsize = AtomicUsize::new(NPROC - 1);
+
 /// Lock for synchronizing bitmap updates with process table
 static BITMAP_LOCK: Mutex<()> = Mutex::new(());
 
+/// A busy hart keeps up to STEAL_THRESHOLD - 1 waiting slots for itself
+pub const STEAL_THRESHOLD: u32 = 2;
+
+/// Slot has no home hart yet
+const NO_HOME: usize = usize::MAX;
+
+/// HOME[i]: hart slot i last ran on; HOME_MASK[h]: slots homed on hart h
+static HOME: [AtomicUsize; NPROC] = [const { AtomicUsize::new(NO_HOME) }; NPROC];
+static HOME_MASK: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+
+/// ALLOWED[h]: slots whose affinity mask includes hart h
+static ALLOWED: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(!0) }; MAX_HARTS];
+
 /// Mark a process as runnable
 pub fn mark_runnable(proc_idx: usize) {
     if proc_idx >= NPROC {
         return;
     }
     let bit = 1u64 << proc_idx;
-    RUNNABLE_BITMAP.fetch_or(bit, Ordering::Relaxed);
+    if RUNNABLE_BITMAP.fetch_or(bit, Ordering::AcqRel) & bit == 0 {
+        RUNNABLE_COUNT.fetch_add(1, Ordering::AcqRel);
+    }
 }
 
 /// Mark a process as not runnable
@@ -30,21 +62,71 @@ pub fn mark_not_runnable(proc_idx: usize) {
         return;
     }
     let bit = 1u64 << proc_idx;
-    RUNNABLE_BITMAP.fetch_and(!bit, Ordering::Relaxed);
+    if RUNNABLE_BITMAP.fetch_and(!bit, Ordering::AcqRel) & bit != 0 {
+        RUNNABLE_COUNT.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
-/// Find the first runnable process index
-/// Returns None if no runnable process exists
-/// 
-/// This uses trailing_zeros() which is typically implemented as a single CPU instruction
-/// (e.g., TZCNT on x86, CLZ on ARM), making it O(1) in practice.
+/// Find the next runnable process for the current hart
 pub fn find_runnable() -> Option<usize> {
-    let bitmap = RUNNABLE_BITMAP.load(Ordering::Acquire);
+    find_runnable_for(hart::getid())
+}
+
+/// Pick a runnable slot that hart `hartid` may run: its own or unhomed slots first,
+/// then a slot homed on an idle hart or on a busy hart with a backlog
+/// Returns None if there is nothing this hart should take
+pub fn find_runnable_for(hartid: usize) -> Option<usize> {
+    match candidates(hartid) {
+        0 => None,
+        bitmap => pick(bitmap),
+    }
+}
+
+/// Whether find_runnable_for(hartid) would return a slot, without moving the cursor
+pub fn has_runnable_for(hartid: usize) -> bool {
+    candidates(hartid) != 0
+}
+
+/// Slots hart `hartid` should choose from, following the policy in the module docs
+fn candidates(hartid: usize) -> u64 {
+    let runnable = RUNNABLE_BITMAP.load(Ordering::Acquire);
+    let bitmap = runnable & ALLOWED[hartid].load(Ordering::Acquire);
     if bitmap == 0 {
-        return None;
+        return 0;
+    }
+
+    let mut homed = 0u64;
+    for h in 0..MAX_HARTS {
+        homed |= HOME_MASK[h].load(Ordering::Acquire);
     }
-    let idx = bitmap.trailing_zeros() as usize;
+    let local = bitmap & (HOME_MASK[hartid].load(Ordering::Acquire) | !homed);
+    if local != 0 {
+        return local;
+    }
+
+    for victim in 0..MAX_HARTS {
+        if victim == hartid {
+            continue;
+        }
+        let theirs = HOME_MASK[victim].load(Ordering::Acquire);
+        let backlog = (runnable & theirs).count_ones();
+        if (bitmap & theirs) != 0 && (hart::is_idle(victim) || backlog >= STEAL_THRESHOLD) {
+            return bitmap & theirs;
+        }
+    }
+    0
+}
+
+/// Round-robin over `bitmap`, starting just after the cursor
+/// 
+/// This uses trailing_zeros() which is typically implemented as a single CPU instruction
+/// (e.g., TZCNT on x86, CLZ on ARM), making it O(1) in practice.
+fn pick(bitmap: u64) -> Option<usize> {
+    let start = (CURSOR.load(Ordering::Relaxed) + 1) % NPROC;
+    let after = bitmap & (!0u64 << start);
+    let idx = if after != 0 { after.trailing_zeros() } else { bitmap.trailing_zeros() } as usize;
     if idx < NPROC {
+        CURSOR.store(idx, Ordering::Relaxed);
         Some(idx)
     } else {
         None
@@ -57,7 +139,52 @@ pub fn clear_runnable_bit(proc_idx: usize) {
         return;
     }
     let bit = 1u64 << proc_idx;
-    RUNNABLE_BITMAP.fetch_and(!bit, Ordering::Relaxed);
+    if RUNNABLE_BITMAP.fetch_and(!bit, Ordering::AcqRel) & bit != 0 {
+        RUNNABLE_COUNT.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Record that slot `proc_idx` is now running on `hartid`
+pub fn set_home(proc_idx: usize, hartid: usize) {
+    if proc_idx >= NPROC {
+        return;
+    }
+    let bit = 1u64 << proc_idx;
+    let old = HOME[proc_idx].swap(hartid, Ordering::AcqRel);
+    if old == hartid {
+        return;
+    }
+    if old != NO_HOME {
+        HOME_MASK[old].fetch_and(!bit, Ordering::AcqRel);
+    }
+    HOME_MASK[hartid].fetch_or(bit, Ordering::AcqRel);
+}
+
+/// Restrict slot `proc_idx` to the harts set in `mask` (bit h = hart h)
+pub fn set_affinity(proc_idx: usize, mask: usize) {
+    if proc_idx >= NPROC {
+        return;
+    }
+    let bit = 1u64 << proc_idx;
+    for h in 0..MAX_HARTS {
+        if mask & (1 << h) != 0 {
+            ALLOWED[h].fetch_or(bit, Ordering::AcqRel);
+        } else {
+            ALLOWED[h].fetch_and(!bit, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Forget home and affinity of a slot that is being reused
+pub fn reset_slot(proc_idx: usize) {
+    if proc_idx >= NPROC {
+        return;
+    }
+    let old = HOME[proc_idx].swap(NO_HOME, Ordering::AcqRel);
+    if old != NO_HOME {
+        HOME_MASK[old].fetch_and(!(1u64 << proc_idx), Ordering::AcqRel);
+    }
+    set_affinity(proc_idx, !0);
 }
 
 /// Get a lock for synchronizing bitmap updates with process table operations
@@ -70,9 +197,17 @@ pub fn has_runnable() -> bool {
     RUNNABLE_BITMAP.load(Ordering::Acquire) != 0
 }
 
+/// Number of runnable processes across all harts
+/// A power-management layer can treat 0 as "all harts may sleep"
+#[allow(dead_code)]
+pub fn runnable_count() -> usize {
+    RUNNABLE_COUNT.load(Ordering::Acquire)
+}
+
 /// Clear all runnable bits (for initialization or debugging)
 pub fn clear_all() {
     RUNNABLE_BITMAP.store(0, Ordering::Release);
+    RUNNABLE_COUNT.store(0, Ordering::Release);
 }
 
 /// Find the index of a process in the process table
@@ -31,6 +31,7 @@ pub fn scheduler() {
                 if p.state == ProcState::Runnable {
                     p.state = ProcState::Running;
                     runnable_queue::clear_runnable_bit(i);
+                    runnable_queue::set_home(i, hart::getid());
                     p as *mut Process
                 } else {
                     // State changed, clear bit and continue
@@ -63,10 +64,17 @@ pub fn scheduler() {
             }
         } else {
             // No runnable processes found
+            // 先标记空闲再检查: wakeup 要么看到空闲位并发 IPI, 要么这里看到新的就绪进程
+            hart::set_idle(true);
             unsafe {
                 sstatus::set_sie();
             }
-            riscv::asm::wfi();
+            // 开中断后再确认一次, 期间被唤醒的进程不必等到下一次中断
+            // 只看本 hart 能取的: 别的 hart 上未达到偷取阈值或不允许在这里运行的进程不算
+            if !runnable_queue::has_runnable_for(hart::getid()) {
+                riscv::asm::wfi();
+            }
+            hart::set_idle(false);
         }
     }
 }
@@ -74,6 +82,9 @@ pub fn scheduler() {
 pub fn sched() {
     let hart = crate::hart::get();
     let p = unsafe { &mut *hart.proc };
+    // find_proc_index 要取 PROC_TABLE, hart 0 的时钟中断在 wakeup 中也会取它
+    let sie_enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie(); }
     // 避免 exit 把 Zombie 设成 Runnable
     if p.state == ProcState::Running {
         p.state = ProcState::Runnable;
@@ -85,11 +96,16 @@ pub fn sched() {
     unsafe {
         switch_context(&mut p.context, &mut hart.context);
     }
+    if sie_enabled { unsafe { sstatus::set_sie(); } }
 }
 
+// 让出 CPU; find_runnable 从上次选中的槽位之后开始找, 其他就绪进程都轮到后才会再选中自己
+// 系统调用体开中断执行时也会调用这里, 关中断的理由同 sched
 pub fn yield_proc() {
     let hart = crate::hart::get();
     let p = unsafe { &mut *hart.proc };
+    let sie_enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie(); }
     if p.state == ProcState::Running {
         p.state = ProcState::Runnable;
         // Find process index and mark as runnable
@@ -100,6 +116,7 @@ pub fn yield_proc() {
     unsafe {
         switch_context(&mut p.context, &mut hart.context);
     }
+    if sie_enabled { unsafe { sstatus::set_sie(); } }
 }
 
 pub fn stop() {
@@ -168,6 +185,69 @@ pub fn wait() -> Option<(usize, i32)> {
     }
 }
 
+// 不阻塞也不回收地查看子进程状态: pid 为 None 时查看任意子进程。
+// 有已退出的匹配子进程时返回 Ok(Some((pid, exit_code))), 槽位保留给之后的 wait;
+// 匹配的子进程都还在运行 (包括正在 exit 中的 Dying) 时返回 Ok(None), 没有匹配的子进程时返回 Err。
+// 子进程在持有 PROC_TABLE 锁时才变为 Zombie, 锁内看到的状态和退出码是一致的
+pub fn peek_child(pid: Option<usize>) -> Result<Option<(usize, i32)>, ()> {
+    let curr_proc = crate::hart::get().proc;
+
+    let sie_enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie(); }
+    let mut result = Err(());
+    {
+        let table = PROC_TABLE.lock();
+        for p in table.iter() {
+            if p.parent != curr_proc || pid.is_some_and(|pid| p.pid != pid) {
+                continue;
+            }
+            if p.state == ProcState::Zombie {
+                result = Ok(Some((p.pid, p.exit_code)));
+                break;
+            }
+            result = Ok(None);
+        }
+    }
+    if sie_enabled { unsafe { sstatus::set_sie(); } }
+    result
+}
+
+// 挂起当前进程直到 vfork 子进程 exec 或退出
+// 检查与睡眠在 PROC_TABLE 锁内完成, 避免错过子进程的唤醒
+pub fn wait_vfork(child: *const Process) {
+    let hart = crate::hart::get();
+    let curr_proc = unsafe { &mut *hart.proc };
+    let curr_ptr = curr_proc as *mut Process;
+
+    loop {
+        let sstatus_val = sstatus::read();
+        let sie_enabled = sstatus_val.sie();
+        unsafe { sstatus::clear_sie(); }
+
+        let borrowed = {
+            let _table = PROC_TABLE.lock();
+            let borrowed = unsafe { (*child).vfork_parent } == curr_ptr;
+            if borrowed {
+                curr_proc.state = ProcState::Sleeping;
+                curr_proc.sleep_chan = curr_ptr as usize;
+                if let Some(idx) = runnable_queue::find_proc_index(curr_proc as *const Process) {
+                    runnable_queue::mark_not_runnable(idx);
+                }
+            }
+            borrowed
+        };
+
+        if !borrowed {
+            if sie_enabled { unsafe { sstatus::set_sie(); } }
+            return;
+        }
+
+        stop();
+
+        if sie_enabled { unsafe { sstatus::set_sie(); } }
+    }
+}
+
 pub fn sleep(channel: usize) {
     let hart = crate::hart::get();
     let p = unsafe { &mut *hart.proc };
@@ -193,6 +273,76 @@ pub fn sleep(channel: usize) {
     if sie_enabled { unsafe { sstatus::set_sie(); } }
 }
 
+// 与 sleep 相同, 但在 PROC_TABLE 锁内先检查 done, 成立则直接返回
+// 唤醒方在调用 wakeup 之前改变 done 的结果, 因此不会错过唤醒
+pub fn sleep_unless(channel: usize, done: impl Fn() -> bool) {
+    let hart = crate::hart::get();
+    let p = unsafe { &mut *hart.proc };
+
+    let sstatus_val = sstatus::read();
+    let sie_enabled = sstatus_val.sie();
+    unsafe { sstatus::clear_sie(); }
+
+    let sleeping = {
+        let _lock = PROC_TABLE.lock();
+        let sleeping = !done();
+        if sleeping {
+            p.state = ProcState::Sleeping;
+            p.sleep_chan = channel;
+            if let Some(idx) = runnable_queue::find_proc_index(p as *const Process) {
+                runnable_queue::mark_not_runnable(idx);
+            }
+        }
+        sleeping
+    };
+
+    if sleeping {
+        unsafe {
+            switch_context(&mut p.context, &mut hart.context);
+        }
+    }
+
+    if sie_enabled { unsafe { sstatus::set_sie(); } }
+}
+
+// sleep_until 睡眠用的通道, 每个进程各自一个
+pub fn deadline_chan(p: &Process) -> usize {
+    &p.wake_at as *const u64 as usize
+}
+
+// 唤醒截止时间不晚于 now 的 sleep_until 进程, 返回其余进程中最早的截止时间 (没有时为 u64::MAX)
+// 已过截止时间但还没睡下的进程会在 sleep_unless 的检查里看到时间已到, 不必唤醒也不计入
+pub fn wakeup_deadlines(now: u64) -> u64 {
+    let sstatus_val = sstatus::read();
+    let sie_enabled = sstatus_val.sie();
+    unsafe { sstatus::clear_sie(); }
+
+    let _lock = runnable_queue::lock();
+    let mut table = PROC_TABLE.lock();
+    let mut next = u64::MAX;
+    let mut woken = 0;
+    for i in 0..NPROC {
+        let p = &mut table[i];
+        if p.wake_at == 0 {
+            continue;
+        }
+        if p.wake_at > now {
+            next = next.min(p.wake_at);
+        } else if p.state == ProcState::Sleeping && p.sleep_chan == deadline_chan(p) {
+            p.state = ProcState::Runnable;
+            p.sleep_chan = 0;
+            runnable_queue::mark_runnable(i);
+            woken += 1;
+        }
+    }
+    if woken > 0 {
+        hart::kick_idle_harts(woken);
+    }
+
+    if sie_enabled { unsafe { sstatus::set_sie(); } }
+    next
+}
+
 pub fn wakeup(channel: usize) {
     let sstatus_val = sstatus::read();
     let sie_enabled = sstatus_val.sie();
@@ -200,14 +350,20 @@ pub fn wakeup(channel: usize) {
 
     let _lock = runnable_queue::lock();
     let mut table = PROC_TABLE.lock();
+    let mut woken = 0;
     for i in 0..NPROC {
         let p = &mut table[i];
         if p.state == ProcState::Sleeping && p.sleep_chan == channel {
             p.state = ProcState::Runnable;
             p.sleep_chan = 0;
             runnable_queue::mark_runnable(i);
+            woken += 1;
         }
     }
+    // 只有 hart 0 有时钟中断, 其他空闲 hart 需要 IPI 才能醒来运行被唤醒的进程
+    if woken > 0 {
+        hart::kick_idle_harts(woken);
+    }
 
     if sie_enabled { unsafe { sstatus::set_sie(); } }
 }
@@ -4,9 +4,15 @@
 
 unsafe extern "C" {
     fn sbi_set_timer_asm(stime_value: u64) -> isize;
+    fn sbi_send_ipi_asm(hart_mask: usize, hart_mask_base: usize) -> isize;
 }
 
 pub fn set_timer(stime_value: u64) -> Result<(), isize> {
     let error = unsafe { sbi_set_timer_asm(stime_value) };
     if error == 0 { Ok(()) } else { Err(error) }
 }
+
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), isize> {
+    let error = unsafe { sbi_send_ipi_asm(hart_mask, hart_mask_base) };
+    if error == 0 { Ok(()) } else { Err(error) }
+}
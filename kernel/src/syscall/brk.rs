@@ -1,12 +1,16 @@
 use crate::irq::TrapContext;
 use crate::mem::MMAP_BEGIN;
-use crate::mem::PageTable;
-use crate::mem::addr::align_up;
 use crate::mem::uvm;
 use crate::printk;
 use crate::printk::{ANSI_RESET, ANSI_YELLOW};
 use crate::proc::current_proc;
 
+/// brk(addr): 把程序断点 (堆顶) 设为 addr, 语义与 Linux 的 brk 系统调用一致, libc 的 brk/sbrk 据此实现
+/// - 成功时返回新的断点, 即 addr 本身 (不做页对齐, 断点所在的页整页可用);
+/// - 失败时不做任何改动, 返回当前断点, 调用者比较返回值与 addr 判断是否成功;
+/// - addr 为 0 时只返回当前断点, 即 brk(0) 查询。
+/// 堆从 heap_base (exec 时可执行映像的末尾) 向上增长, 断点只能落在 [heap_base, MMAP_BEGIN] 内,
+/// 不会与 mmap 区域重叠; 增长的页先映射到共享零页, 首次写入时才分配, 收缩时立即释放整页。
 pub fn sys_brk(ctx: &mut TrapContext) -> usize {
     let new_top = ctx.a0;
     let p = current_proc();
@@ -14,6 +18,10 @@ pub fn sys_brk(ctx: &mut TrapContext) -> usize {
     if new_top == 0 {
         return old_top;
     }
+    if !p.vfork_parent.is_null() {
+        // vfork 子进程与父进程共享地址空间, 不允许改动
+        return old_top;
+    }
     if new_top > MMAP_BEGIN {
         printk!(
             "{}[WARN] brk: new_top=0x{:x} > MMAP_BEGIN=0x{:x}{}\n",
@@ -22,7 +30,7 @@ pub fn sys_brk(ctx: &mut TrapContext) -> usize {
             MMAP_BEGIN,
             ANSI_RESET
         );
-        return usize::MAX;
+        return old_top;
     }
     if new_top < p.heap_base {
         printk!(
@@ -32,28 +40,28 @@ pub fn sys_brk(ctx: &mut TrapContext) -> usize {
             p.heap_base,
             ANSI_RESET
         );
-        return usize::MAX;
+        return old_top;
     }
 
-    let table = unsafe { &mut *(p.root_pt_pa as *mut PageTable) };
-    let new_heap_top = align_up(new_top);
-    let res = if new_heap_top > old_top {
-        uvm::heap_grow(table, old_top, new_heap_top)
-    } else if new_heap_top < old_top {
-        uvm::heap_ungrow(table, old_top, new_heap_top)
+    // heap_grow/heap_ungrow 按 align_up 后的页边界映射或释放
+    let table = p.page_table_mut();
+    let res = if new_top > old_top {
+        uvm::heap_grow(table, old_top, new_top)
+    } else if new_top < old_top {
+        uvm::heap_ungrow(table, old_top, new_top)
     } else {
         Ok(())
     };
     match res {
         Ok(()) => {
             let proc = current_proc();
-            proc.heap_top = new_heap_top;
+            proc.heap_top = new_top;
             printk!("brk: old=0x{:x} -> new=0x{:x}\n", old_top, proc.heap_top);
             proc.heap_top
         }
         Err(e) => {
             printk!("{}[WARN] brk: failed: {:?}{}\n", ANSI_YELLOW, e, ANSI_RESET);
-            usize::MAX
+            old_top
         }
     }
 }
@@ -1,7 +1,6 @@
 use core::slice;
 
 use crate::irq::TrapContext;
-use crate::mem::PageTable;
 use crate::mem::uvm;
 use crate::printk;
 use crate::printk::{ANSI_RESET, ANSI_YELLOW};
@@ -13,9 +12,7 @@ pub fn sys_copyout(ctx: &mut TrapContext) -> usize {
     let bytes = unsafe {
         slice::from_raw_parts(buf.as_ptr() as *const u8, core::mem::size_of::<u32>() * buf.len())
     };
-    let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
-    match uvm::copyout(pt, u_dst, bytes) {
+    match uvm::copy_to_user(u_dst, bytes) {
         Ok(()) => 0,
         Err(e) => {
             printk!("{}[WARN] sys_copyout failed: {:?}{}\n", ANSI_YELLOW, e, ANSI_RESET);
@@ -32,9 +29,7 @@ pub fn sys_copyin(ctx: &mut TrapContext) -> usize {
     let dst_bytes = unsafe {
         slice::from_raw_parts_mut(tmp.as_mut_ptr() as *mut u8, count * core::mem::size_of::<u32>())
     };
-    let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
-    match uvm::copyin(pt, dst_bytes, u_src) {
+    match uvm::copy_from_user(dst_bytes, u_src) {
         Ok(()) => {
             for i in 0..count {
                 printk!("copyin[{}] = {}\n", i, tmp[i]);
@@ -52,7 +47,7 @@ pub fn sys_copyinstr(ctx: &mut TrapContext) -> usize {
     let u_src = ctx.a0;
     let mut buf: [u8; 256] = [0; 256];
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     match uvm::copyin_str(pt, &mut buf, u_src) {
         Ok(len) => {
             let s = &buf[..len.saturating_sub(1)];
@@ -1,16 +1,26 @@
-use crate::fs::{bitmap, buffer, inode, dentry, path};
+use crate::fs::{bitmap, buffer, inode, dentry, path, pipe};
 use crate::fs::file::{self, FileType, File};
-use crate::fs::inode::{Inode, INODE_TYPE_DIR, INODE_TYPE_DATA};
+use crate::fs::inode::{Inode, INODE_TYPE_DIR, INODE_TYPE_DATA, INODE_TYPE_SYMLINK};
 use crate::irq::TrapContext;
-use crate::mem::{PageTable, uvm};
+use crate::mem::uvm;
 use crate::proc::{current_proc, process::Process};
 
 // --- Core Internal Interfaces (Step 4) ---
 
 pub fn fs_open(p: &mut Process, path: &[u8], flags: u32) -> Result<usize, ()> {
-    // flags: O_RDONLY=0, O_WRONLY=1, O_RDWR=2, O_CREAT=0x40, O_TRUNC=0x200
+    // flags: O_RDONLY=0, O_WRONLY=1, O_RDWR=2, O_CREAT=0x40, O_TRUNC=0x200, O_APPEND=0x400, O_DIRECTORY=0x10000,
+    // O_NOFOLLOW=0x20000, O_CLOEXEC=0x80000
     let o_creat = (flags & 0x40) != 0;
     let o_trunc = (flags & 0x200) != 0;
+    let o_append = (flags & 0x400) != 0;
+    let o_directory = (flags & 0x10000) != 0;
+    let o_nofollow = (flags & 0x20000) != 0;
+    let o_cloexec = (flags & 0x80000) != 0;
+
+    // O_CREAT 只会创建普通文件, 与 O_DIRECTORY 同时出现没有意义
+    if o_creat && o_directory {
+        return Err(());
+    }
 
     let inode_ref = if o_creat {
         let mut name = [0u8; inode::MAXLEN_FILENAME];
@@ -26,11 +36,29 @@ pub fn fs_open(p: &mut Process, path: &[u8], flags: u32) -> Result<usize, ()> {
                 match dentry::dentry_search(parent, &name[..name_len]) {
                     Some(inum) => {
                         inode::inode_put(parent);
-                        inode::inode_get(inum)
+                        let ip = inode::inode_get(inum);
+                        if ip.disk.type_ == INODE_TYPE_SYMLINK && !o_nofollow {
+                            // 已存在的符号链接: 重新按完整路径解析以跟随链接
+                            inode::inode_put(ip);
+                            path::path_to_inode_at(p.cwd, path).ok_or(())?
+                        } else {
+                            ip
+                        }
                     }
                     None => {
-                        let new_inode = inode::inode_create(INODE_TYPE_DATA, 0, 0);
-                        dentry::dentry_create(parent, new_inode.inode_num, &name[..name_len]);
+                        if !dentry::name_valid(&name[..name_len]) {
+                            inode::inode_put(parent);
+                            return Err(());
+                        }
+                        let Ok(new_inode) = inode::inode_create(INODE_TYPE_DATA, 0, 0) else {
+                            inode::inode_put(parent);
+                            return Err(());
+                        };
+                        if dentry::dentry_create(parent, new_inode.inode_num, &name[..name_len]) != 0 {
+                            inode::inode_discard(new_inode);
+                            inode::inode_put(parent);
+                            return Err(());
+                        }
                         inode::inode_put(parent);
                         new_inode
                     }
@@ -38,6 +66,8 @@ pub fn fs_open(p: &mut Process, path: &[u8], flags: u32) -> Result<usize, ()> {
             }
             None => return Err(()),
         }
+    } else if o_nofollow {
+        path::path_to_inode_nofollow_at(p.cwd, path).ok_or(())?
     } else {
         match path::path_to_inode_at(p.cwd, path) {
             Some(ip) => ip,
@@ -45,6 +75,24 @@ pub fn fs_open(p: &mut Process, path: &[u8], flags: u32) -> Result<usize, ()> {
         }
     };
 
+    // 新占用一个缓存槽位时检查上限, 留出余量给路径解析, 超出则返回 ENFILE 而不是让后续 inode_get 等待
+    if inode_ref.refcnt == 1 && inode::inode_in_use() > inode::n_inode() - inode::N_INODE_RESERVED {
+        inode::inode_put(inode_ref);
+        return Err(());
+    }
+
+    if inode_ref.disk.type_ == INODE_TYPE_SYMLINK {
+        // 只有 O_NOFOLLOW 才会走到这里, 与 POSIX 一致拒绝打开链接本身
+        inode::inode_put(inode_ref);
+        return Err(());
+    }
+
+    if o_directory && inode_ref.disk.type_ != INODE_TYPE_DIR {
+        // O_DIRECTORY 只打开目录 (ENOTDIR)
+        inode::inode_put(inode_ref);
+        return Err(());
+    }
+
     if inode_ref.disk.type_ == INODE_TYPE_DIR && (flags & 3) != 0 {
         // Cannot open directory for writing
         inode::inode_put(inode_ref);
@@ -52,22 +100,29 @@ pub fn fs_open(p: &mut Process, path: &[u8], flags: u32) -> Result<usize, ()> {
     }
 
     if o_trunc && inode_ref.disk.type_ == INODE_TYPE_DATA {
-        // inode::inode_trunc(inode_ref); // TODO: implement trunc
-        inode_ref.disk.size = 0;
-        inode::inode_rw(inode_ref, true);
+        inode::inode_touch(inode_ref, inode::TOUCH_MTIME | inode::TOUCH_CTIME);
+        inode::inode_trunc(inode_ref);
     }
 
-    let (f_idx, f) = file::file_alloc().ok_or(())?;
+    let (f_idx, f) = match file::file_alloc() {
+        Some(slot) => slot,
+        None => {
+            inode::inode_put(inode_ref);
+            return Err(());
+        }
+    };
     f.ty = FileType::Inode;
     f.inum = inode_ref.inode_num;
     f.readable = (flags & 3) != 1; // Not WRONLY
     f.writable = (flags & 3) != 0; // Not RDONLY
+    f.append = o_append;
     f.off = 0;
 
     // Find FD
     for fd in 0..crate::proc::process::NOFILE {
         if p.open_files[fd].is_none() {
             p.open_files[fd] = Some(f_idx);
+            p.cloexec[fd] = o_cloexec;
             return Ok(fd);
         }
     }
@@ -81,87 +136,65 @@ pub fn fs_close(p: &mut Process, fd: usize) -> Result<(), ()> {
     let f_idx = p.open_files[fd].ok_or(())?;
     file::file_close(f_idx);
     p.open_files[fd] = None;
+    p.cloexec[fd] = false;
     Ok(())
 }
 
 pub fn fs_read(p: &mut Process, fd: usize, u_dst: usize, len: usize) -> Result<usize, ()> {
     if fd >= crate::proc::process::NOFILE { return Err(()); }
     let f_idx = p.open_files[fd].ok_or(())?;
-
-    // We need to mutate File.off, so we lock table or get ref
-    let mut table = file::FILE_TABLE.lock();
-    let f = &mut table.files[f_idx];
-    if !f.readable { return Err(()); }
-
-    let ip = inode::inode_get(f.inum);
-    let mut total_read = 0;
-    let mut buf = [0u8; 512];
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
-
-    while total_read < len {
-        let chunk_len = core::cmp::min(len - total_read, buf.len());
-        let read = inode::inode_read_data(ip, f.off, chunk_len as u32, &mut buf[..chunk_len]);
-        if read == 0 { break; }
-        if let Err(_) = uvm::copyout(pt, u_dst + total_read, &buf[..read as usize]) {
-            inode::inode_put(ip);
-            return Err(());
-        }
-        total_read += read as usize;
-        f.off += read;
-        if read < chunk_len as u32 { break; }
+    if let Some(id) = pipe_of(f_idx, true)? {
+        return pipe_read(p, id, u_dst, len);
     }
-    inode::inode_put(ip);
-    Ok(total_read)
+
+    let pt = p.page_table();
+    file::file_read(f_idx, len, |data, done| uvm::copyout(pt, u_dst + done, data).map_err(|_| ()))
 }
 
 pub fn fs_write(p: &mut Process, fd: usize, u_src: usize, len: usize) -> Result<usize, ()> {
     if fd >= crate::proc::process::NOFILE { return Err(()); }
     let f_idx = p.open_files[fd].ok_or(())?;
-
-    let mut table = file::FILE_TABLE.lock();
-    let f = &mut table.files[f_idx];
-    if !f.writable { return Err(()); }
-
-    let ip = inode::inode_get(f.inum);
-    let mut total_written = 0;
-    let mut buf = [0u8; 512];
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
-
-    while total_written < len {
-        let chunk_len = core::cmp::min(len - total_written, buf.len());
-        if let Err(_) = uvm::copyin(pt, &mut buf[..chunk_len], u_src + total_written) {
-            inode::inode_put(ip);
-            return Err(());
-        }
-        let written = inode::inode_write_data(ip, f.off, chunk_len as u32, &buf[..chunk_len]);
-        total_written += written as usize;
-        f.off += written;
-        if written < chunk_len as u32 { break; }
+    if let Some(id) = pipe_of(f_idx, false)? {
+        return pipe_write(p, id, u_src, len);
     }
-    inode::inode_put(ip);
-    Ok(total_written)
+
+    let pt = p.page_table();
+    file::file_write(f_idx, len, |buf, done| uvm::copyin(pt, buf, u_src + done).map_err(|_| ()))
 }
 
 pub fn fs_lseek(p: &mut Process, fd: usize, off: i32, whence: u32) -> Result<usize, ()> {
     if fd >= crate::proc::process::NOFILE { return Err(()); }
     let f_idx = p.open_files[fd].ok_or(())?;
-    let mut table = file::FILE_TABLE.lock();
-    let f = &mut table.files[f_idx];
+    let (ty, inum) = {
+        let table = file::FILE_TABLE.lock();
+        let f = &table.files[f_idx];
+        (f.ty, f.inum)
+    };
+    if ty != FileType::Inode { return Err(()); } // 管道不能定位
 
-    let ip = inode::inode_get(f.inum);
+    // inode_get 可能读盘, 不能持有文件表锁; 偏移在 inode 睡眠锁内更新, 与读写互斥
+    let ip = inode::inode_get(inum);
+    inode::inode_lock(ip);
     let size = ip.disk.size as i32;
-    inode::inode_put(ip);
-
+    let mut table = file::FILE_TABLE.lock();
+    let f = &mut table.files[f_idx];
     let new_off = match whence {
-        0 => off,              // SEEK_SET
-        1 => f.off as i32 + off, // SEEK_CUR
-        2 => size + off,       // SEEK_END
-        _ => return Err(()),
+        0 => Some(off),              // SEEK_SET
+        1 => Some(f.off as i32 + off), // SEEK_CUR
+        2 => Some(size + off),       // SEEK_END
+        _ => None,
     };
-
-    if new_off < 0 { return Err(()); }
-    f.off = new_off as u32;
-    Ok(f.off as usize)
+    let result = match new_off {
+        Some(new_off) if new_off >= 0 => {
+            f.off = new_off as u32;
+            Ok(f.off as usize)
+        }
+        _ => Err(()),
+    };
+    drop(table);
+    inode::inode_unlock(ip);
+    inode::inode_put(ip);
+    result
 }
 
 pub fn fs_dup(p: &mut Process, fd: usize) -> Result<usize, ()> {
@@ -178,6 +211,7 @@ pub fn fs_dup(p: &mut Process, fd: usize) -> Result<usize, ()> {
     for new_fd in 0..crate::proc::process::NOFILE {
         if p.open_files[new_fd].is_none() {
             p.open_files[new_fd] = Some(f_idx);
+            p.cloexec[new_fd] = false; // dup 出的 fd 不继承 O_CLOEXEC
             return Ok(new_fd);
         }
     }
@@ -190,6 +224,103 @@ pub fn fs_dup(p: &mut Process, fd: usize) -> Result<usize, ()> {
     Err(())
 }
 
+// 管道读写不持有文件表锁, 阻塞期间其他进程仍能打开和关闭文件
+// 返回 Ok(Some(id)) 表示 f_idx 是管道, 方向不符 (读写端用错) 时返回 Err
+fn pipe_of(f_idx: usize, read: bool) -> Result<Option<usize>, ()> {
+    let table = file::FILE_TABLE.lock();
+    let f = &table.files[f_idx];
+    match f.ty {
+        FileType::Pipe { id } if (read && f.readable) || (!read && f.writable) => Ok(Some(id as usize)),
+        FileType::Pipe { .. } => Err(()),
+        _ => Ok(None),
+    }
+}
+
+fn pipe_read(p: &mut Process, id: usize, u_dst: usize, len: usize) -> Result<usize, ()> {
+    let mut buf = [0u8; pipe::PIPE_SIZE];
+    let n = pipe::pipe_read(id, &mut buf[..len.min(pipe::PIPE_SIZE)]);
+    let pt = p.page_table();
+    uvm::copyout(pt, u_dst, &buf[..n]).map_err(|_| ())?;
+    Ok(n)
+}
+
+fn pipe_write(p: &mut Process, id: usize, u_src: usize, len: usize) -> Result<usize, ()> {
+    let mut buf = [0u8; pipe::PIPE_SIZE];
+    let pt = p.page_table();
+    let mut total_written = 0;
+    while total_written < len {
+        let chunk_len = core::cmp::min(len - total_written, buf.len());
+        if let Err(_) = uvm::copyin(pt, &mut buf[..chunk_len], u_src + total_written) {
+            return Err(());
+        }
+        let written = pipe::pipe_write(id, &buf[..chunk_len]);
+        total_written += written;
+        if written < chunk_len {
+            // 读端已全部关闭: 一个字节都没写出时报错 (EPIPE)
+            if total_written == 0 { return Err(()); }
+            break;
+        }
+    }
+    Ok(total_written)
+}
+
+// 创建管道, 把读端和写端的 fd 依次写入用户数组 int[2]
+pub fn fs_pipe(p: &mut Process, u_fds: usize) -> Result<(), ()> {
+    let id = pipe::pipe_alloc().ok_or(())?;
+    let rf = match file::file_alloc() {
+        Some((f_idx, f)) => {
+            f.ty = FileType::Pipe { id: id as u16 };
+            f.readable = true;
+            f.writable = false;
+            f.append = false;
+            f_idx
+        }
+        None => {
+            pipe::pipe_close(id, false);
+            pipe::pipe_close(id, true);
+            return Err(());
+        }
+    };
+    let wf = match file::file_alloc() {
+        Some((f_idx, f)) => {
+            f.ty = FileType::Pipe { id: id as u16 };
+            f.readable = false;
+            f.writable = true;
+            f.append = false;
+            f_idx
+        }
+        None => {
+            file::file_close(rf);
+            pipe::pipe_close(id, true);
+            return Err(());
+        }
+    };
+
+    let mut fds = [0i32; 2];
+    let mut n = 0;
+    for fd in 0..crate::proc::process::NOFILE {
+        if n == 2 { break; }
+        if p.open_files[fd].is_none() {
+            p.open_files[fd] = Some(if n == 0 { rf } else { wf });
+            p.cloexec[fd] = false;
+            fds[n] = fd as i32;
+            n += 1;
+        }
+    }
+
+    let pt = p.page_table();
+    let src = unsafe { core::slice::from_raw_parts(fds.as_ptr() as *const u8, core::mem::size_of_val(&fds)) };
+    if n < 2 || uvm::copyout(pt, u_fds, src).is_err() {
+        for i in 0..n {
+            p.open_files[fds[i] as usize] = None;
+        }
+        file::file_close(rf);
+        file::file_close(wf);
+        return Err(());
+    }
+    Ok(())
+}
+
 pub fn fs_fstat(p: &mut Process, fd: usize, u_stat: usize) -> Result<(), ()> {
     if fd >= crate::proc::process::NOFILE { return Err(()); }
     let f_idx = p.open_files[fd].ok_or(())?;
@@ -198,6 +329,7 @@ pub fn fs_fstat(p: &mut Process, fd: usize, u_stat: usize) -> Result<(), ()> {
         let f = &table.files[f_idx];
         (f.inum, f.ty)
     };
+    if f.1 != FileType::Inode { return Err(()); }
 
     let ip = inode::inode_get(f.0);
     let stat = file::Stat {
@@ -207,22 +339,74 @@ pub fn fs_fstat(p: &mut Process, fd: usize, u_stat: usize) -> Result<(), ()> {
         major: ip.disk.major,
         minor: ip.disk.minor,
         inum: ip.inode_num,
+        atime: ip.disk.atime,
+        mtime: ip.disk.mtime,
+        ctime: ip.disk.ctime,
     };
     inode::inode_put(ip);
 
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let src = unsafe {
         core::slice::from_raw_parts(&stat as *const file::Stat as *const u8, core::mem::size_of::<file::Stat>())
     };
     uvm::copyout(pt, u_stat, src).map_err(|_| ())
 }
 
+// 只写回 fd 对应文件的脏数据块和 inode, 管道等非 inode 文件不支持
+pub fn fs_fsync(p: &mut Process, fd: usize) -> Result<(), ()> {
+    if fd >= crate::proc::process::NOFILE { return Err(()); }
+    let f_idx = p.open_files[fd].ok_or(())?;
+    let f = {
+        let table = file::FILE_TABLE.lock();
+        let f = &table.files[f_idx];
+        (f.inum, f.ty)
+    };
+    if f.1 != FileType::Inode { return Err(()); }
+
+    let ip = inode::inode_get(f.0);
+    inode::inode_sync(ip);
+    inode::inode_put(ip);
+    Ok(())
+}
+
+pub fn fs_access(p: &mut Process, path: &[u8], mode: u16) -> Result<(), ()> {
+    if mode & !(inode::R_OK | inode::W_OK | inode::X_OK) != 0 {
+        return Err(());
+    }
+    let ip = path::path_to_inode_at(p.cwd, path).ok_or(())?;
+    let ok = inode::inode_permits(ip, mode);
+    inode::inode_put(ip);
+    if ok { Ok(()) } else { Err(()) }
+}
+
+pub fn fs_chmod(p: &mut Process, path: &[u8], mode: u16) -> Result<(), ()> {
+    let ip = path::path_to_inode_at(p.cwd, path).ok_or(())?;
+    ip.disk.mode = mode & inode::MODE_MASK;
+    inode::inode_touch(ip, inode::TOUCH_CTIME);
+    inode::inode_rw(ip, true);
+    inode::inode_put(ip);
+    Ok(())
+}
+
+// 按路径改文件长度, 只对有写权限的普通文件生效; 变长部分是空洞, 不分配块
+pub fn fs_truncate(p: &mut Process, path: &[u8], len: u32) -> Result<(), ()> {
+    let ip = path::path_to_inode_at(p.cwd, path).ok_or(())?;
+    if ip.disk.type_ != INODE_TYPE_DATA || !inode::inode_permits(ip, inode::W_OK) {
+        inode::inode_put(ip);
+        return Err(());
+    }
+    inode::inode_touch(ip, inode::TOUCH_MTIME | inode::TOUCH_CTIME);
+    inode::inode_trunc_to(ip, len);
+    inode::inode_put(ip);
+    Ok(())
+}
+
 pub fn fs_mkdir(p: &mut Process, path: &[u8]) -> Result<(), ()> {
     let mut name = [0u8; inode::MAXLEN_FILENAME];
     match path::path_to_parent_inode_at(p.cwd, path, &mut name) {
         Some(parent) => {
             let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
-            if name_len == 0 {
+            if !dentry::name_valid(&name[..name_len]) {
                 inode::inode_put(parent);
                 return Err(());
             }
@@ -230,10 +414,22 @@ pub fn fs_mkdir(p: &mut Process, path: &[u8]) -> Result<(), ()> {
                 inode::inode_put(parent);
                 return Err(());
             }
-            let new_inode = inode::inode_create(INODE_TYPE_DIR, 0, 0);
+            let Ok(new_inode) = inode::inode_create(INODE_TYPE_DIR, 0, 0) else {
+                inode::inode_put(parent);
+                return Err(());
+            };
             new_inode.disk.nlink = 2; // . and ..
             inode::inode_rw(new_inode, true);
-            dentry::dentry_create(parent, new_inode.inode_num, &name[..name_len]);
+            let linked = dentry::dir_init(new_inode, parent.inode_num) == 0
+                && dentry::dentry_create(parent, new_inode.inode_num, &name[..name_len]) == 0;
+            if !linked {
+                inode::inode_discard(new_inode);
+                inode::inode_put(parent);
+                return Err(());
+            }
+            // 子目录的 .. 也是父目录的一个链接
+            parent.disk.nlink += 1;
+            inode::inode_rw(parent, true);
             inode::inode_put(new_inode);
             inode::inode_put(parent);
             Ok(())
@@ -268,13 +464,14 @@ pub fn fs_link(p: &mut Process, old_path: &[u8], new_path: &[u8]) -> Result<(),
     match path::path_to_parent_inode_at(p.cwd, new_path, &mut name) {
         Some(parent) => {
             let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
-            if name_len == 0 || dentry::dentry_search(parent, &name[..name_len]).is_some() {
+            if !dentry::name_valid(&name[..name_len]) || dentry::dentry_search(parent, &name[..name_len]).is_some() {
                 inode::inode_put(parent);
                 inode::inode_put(old_ip);
                 return Err(());
             }
             dentry::dentry_create(parent, old_ip.inode_num, &name[..name_len]);
             old_ip.disk.nlink += 1;
+            inode::inode_touch(old_ip, inode::TOUCH_CTIME);
             inode::inode_rw(old_ip, true);
             inode::inode_put(parent);
             inode::inode_put(old_ip);
@@ -311,6 +508,7 @@ pub fn fs_unlink(p: &mut Process, path: &[u8]) -> Result<(), ()> {
             }
             dentry::dentry_delete(parent, &name[..name_len]);
             ip.disk.nlink -= 1;
+            inode::inode_touch(ip, inode::TOUCH_CTIME);
             inode::inode_rw(ip, true);
             inode::inode_put(ip);
             inode::inode_put(parent);
@@ -320,6 +518,161 @@ pub fn fs_unlink(p: &mut Process, path: &[u8]) -> Result<(), ()> {
     }
 }
 
+// 删除空目录: 目录中除 . 和 .. 外没有目录项才允许删除, inode 在最后一个引用释放时回收
+pub fn fs_rmdir(p: &mut Process, path: &[u8]) -> Result<(), ()> {
+    let mut name = [0u8; inode::MAXLEN_FILENAME];
+    let parent = path::path_to_parent_inode_at(p.cwd, path, &mut name).ok_or(())?;
+    let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    let result = rmdir_at(parent, &name[..name_len]);
+    inode::inode_put(parent);
+    result
+}
+
+fn rmdir_at(parent: &mut Inode, name: &[u8]) -> Result<(), ()> {
+    if !dentry::name_valid(name) {
+        return Err(());
+    }
+    let inum = dentry::dentry_search(parent, name).ok_or(())?;
+    let ip = inode::inode_get(inum);
+    if ip.disk.type_ != INODE_TYPE_DIR || !dentry::dir_is_empty(ip) {
+        inode::inode_put(ip);
+        return Err(());
+    }
+    dentry::dentry_delete(parent, name);
+    // 旧镜像的目录 nlink 不含子目录的 "..", 可能已经是 1 甚至 0
+    parent.disk.nlink = parent.disk.nlink.saturating_sub(1);
+    inode::inode_touch(parent, inode::TOUCH_MTIME | inode::TOUCH_CTIME);
+    inode::inode_rw(parent, true);
+    ip.disk.nlink = 0;
+    inode::inode_touch(ip, inode::TOUCH_CTIME);
+    inode::inode_rw(ip, true);
+    inode::inode_put(ip);
+    Ok(())
+}
+
+// 把 old_path 的目录项移到 new_path: 先建新目录项再删旧的, inode 本身不动
+// new_path 已存在时替换它: 文件只能替换文件, 目录只能替换空目录;
+// 两个路径指向同一个 inode 时什么也不做; 目录不能移进它自己的子树
+pub fn fs_rename(p: &mut Process, old_path: &[u8], new_path: &[u8]) -> Result<(), ()> {
+    let mut old_name = [0u8; inode::MAXLEN_FILENAME];
+    let old_parent = path::path_to_parent_inode_at(p.cwd, old_path, &mut old_name).ok_or(())?;
+    let old_len = old_name.iter().position(|&b| b == 0).unwrap_or(old_name.len());
+    let mut new_name = [0u8; inode::MAXLEN_FILENAME];
+    let Some(new_parent) = path::path_to_parent_inode_at(p.cwd, new_path, &mut new_name) else {
+        inode::inode_put(old_parent);
+        return Err(());
+    };
+    let new_len = new_name.iter().position(|&b| b == 0).unwrap_or(new_name.len());
+
+    let result = rename_at(old_parent, &old_name[..old_len], new_parent, &new_name[..new_len]);
+    inode::inode_put(new_parent);
+    inode::inode_put(old_parent);
+    result
+}
+
+fn rename_at(old_parent: &mut Inode, old_name: &[u8], new_parent: &mut Inode, new_name: &[u8]) -> Result<(), ()> {
+    if !dentry::name_valid(old_name) || !dentry::name_valid(new_name) {
+        return Err(());
+    }
+    let inum = dentry::dentry_search(old_parent, old_name).ok_or(())?;
+    let target = dentry::dentry_search(new_parent, new_name);
+    if target == Some(inum) {
+        return Ok(());
+    }
+
+    let ip = inode::inode_get(inum);
+    let is_dir = ip.disk.type_ == INODE_TYPE_DIR;
+    if is_dir && new_parent.inode_num != old_parent.inode_num && dentry::dir_is_ancestor(inum, new_parent) {
+        inode::inode_put(ip);
+        return Err(());
+    }
+
+    if let Some(target_inum) = target {
+        let tp = inode::inode_get(target_inum);
+        let target_is_dir = tp.disk.type_ == INODE_TYPE_DIR;
+        if target_is_dir != is_dir || (target_is_dir && !dentry::dir_is_empty(tp)) {
+            inode::inode_put(tp);
+            inode::inode_put(ip);
+            return Err(());
+        }
+        dentry::dentry_delete(new_parent, new_name);
+        // 被替换的空目录没有其他名字, 与文件一样在最后一个引用释放时回收
+        tp.disk.nlink = if target_is_dir { 0 } else { tp.disk.nlink - 1 };
+        if target_is_dir {
+            new_parent.disk.nlink -= 1;
+        }
+        inode::inode_touch(tp, inode::TOUCH_CTIME);
+        inode::inode_rw(tp, true);
+        inode::inode_put(tp);
+    }
+
+    if dentry::dentry_create(new_parent, inum, new_name) != 0 {
+        inode::inode_put(ip);
+        return Err(());
+    }
+    dentry::dentry_delete(old_parent, old_name);
+    // 移动目录时它的 .. 改为指向新的父目录
+    if is_dir && new_parent.inode_num != old_parent.inode_num {
+        dentry::dir_set_parent(ip, new_parent.inode_num);
+        old_parent.disk.nlink -= 1;
+        new_parent.disk.nlink += 1;
+    }
+    inode::inode_rw(old_parent, true);
+    inode::inode_rw(new_parent, true);
+    inode::inode_touch(ip, inode::TOUCH_CTIME);
+    inode::inode_rw(ip, true);
+    inode::inode_put(ip);
+    Ok(())
+}
+
+pub fn fs_symlink(p: &mut Process, target: &[u8], link_path: &[u8]) -> Result<(), ()> {
+    // 目标路径不做解析, 允许悬空链接
+    if target.is_empty() || target.len() > inode::MAXLEN_SYMLINK {
+        return Err(());
+    }
+
+    let mut name = [0u8; inode::MAXLEN_FILENAME];
+    let parent = path::path_to_parent_inode_at(p.cwd, link_path, &mut name).ok_or(())?;
+    let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    if !dentry::name_valid(&name[..name_len]) || dentry::dentry_search(parent, &name[..name_len]).is_some() {
+        inode::inode_put(parent);
+        return Err(());
+    }
+
+    let Ok(ip) = inode::inode_create(INODE_TYPE_SYMLINK, 0, 0) else {
+        inode::inode_put(parent);
+        return Err(());
+    };
+    if inode::inode_write_data(ip, 0, target.len() as u32, target) != target.len() as u32
+        || dentry::dentry_create(parent, ip.inode_num, &name[..name_len]) != 0
+    {
+        inode::inode_discard(ip);
+        inode::inode_put(parent);
+        return Err(());
+    }
+    inode::inode_put(ip);
+    inode::inode_put(parent);
+    Ok(())
+}
+
+pub fn fs_readlink(p: &mut Process, path: &[u8], u_buf: usize, bufsiz: usize) -> Result<usize, ()> {
+    let ip = path::path_to_inode_nofollow_at(p.cwd, path).ok_or(())?;
+    if ip.disk.type_ != INODE_TYPE_SYMLINK {
+        inode::inode_put(ip);
+        return Err(());
+    }
+
+    let mut buf = [0u8; inode::MAXLEN_SYMLINK];
+    let len = inode::inode_read_data(ip, 0, inode::MAXLEN_SYMLINK as u32, &mut buf) as usize;
+    inode::inode_put(ip);
+
+    // 与 readlink(2) 一致: 不补 NUL, 超出 bufsiz 的部分截断
+    let n = core::cmp::min(len, bufsiz);
+    let pt = p.page_table();
+    uvm::copyout(pt, u_buf, &buf[..n]).map_err(|_| ())?;
+    Ok(n)
+}
+
 pub fn fs_get_dentries(p: &mut Process, fd: usize, u_buf: usize, max: usize) -> Result<usize, ()> {
     if fd >= crate::proc::process::NOFILE { return Err(()); }
     let f_idx = p.open_files[fd].ok_or(())?;
@@ -328,6 +681,7 @@ pub fn fs_get_dentries(p: &mut Process, fd: usize, u_buf: usize, max: usize) ->
         let f = &table.files[f_idx];
         (f.inum, f.ty)
     };
+    if f.1 != FileType::Inode { return Err(()); }
 
     let ip = inode::inode_get(f.0);
     if ip.disk.type_ != INODE_TYPE_DIR {
@@ -339,7 +693,7 @@ pub fn fs_get_dentries(p: &mut Process, fd: usize, u_buf: usize, max: usize) ->
     let mut off = 0;
     let dentry_size = core::mem::size_of::<inode::DentryDisk>() as u32;
     let mut buf = [0u8; core::mem::size_of::<inode::DentryDisk>()];
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
 
     while off < ip.disk.size && count < max {
         if inode::inode_read_data(ip, off, dentry_size, &mut buf) != dentry_size {
@@ -374,7 +728,7 @@ pub fn sys_free_block(ctx: &mut TrapContext) -> usize {
 }
 
 pub fn sys_alloc_inode() -> usize {
-    inode::alloc() as usize
+    inode::alloc().map_or(usize::MAX, |inum| inum as usize)
 }
 
 pub fn sys_free_inode(ctx: &mut TrapContext) -> usize {
@@ -389,7 +743,7 @@ pub fn sys_show_bitmap(_ctx: &mut TrapContext) -> usize {
 
 pub fn sys_get_block(ctx: &mut TrapContext) -> usize {
     let block_no = ctx.a0 as u32;
-    buffer::read(0, block_no)
+    buffer::try_read(0, block_no).unwrap_or(usize::MAX)
 }
 
 pub fn sys_read_block(ctx: &mut TrapContext) -> usize {
@@ -398,7 +752,7 @@ pub fn sys_read_block(ctx: &mut TrapContext) -> usize {
     let data_ptr = buffer::get_data_ptr(buf_idx);
     let data_slice = unsafe { core::slice::from_raw_parts(data_ptr, buffer::BLOCK_SIZE) };
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     match uvm::copyout(pt, u_dst, data_slice) {
         Ok(_) => 0,
         Err(_) => usize::MAX,
@@ -411,7 +765,7 @@ pub fn sys_write_block(ctx: &mut TrapContext) -> usize {
     let data_ptr = buffer::get_data_ptr(buf_idx);
     let data_slice = unsafe { core::slice::from_raw_parts_mut(data_ptr, buffer::BLOCK_SIZE) };
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     match uvm::copyin(pt, data_slice, u_src) {
         Ok(_) => {
             buffer::write(buf_idx);
@@ -432,7 +786,14 @@ pub fn sys_show_buffer() -> usize {
     0
 }
 
+// 写回全部脏缓冲区, 返回写盘的块数; 参数保留不用
 pub fn sys_flush_buffer(_ctx: &mut TrapContext) -> usize {
+    buffer::flush_all()
+}
+
+// sync(): 把缓存中全部脏块 (含延迟写的元数据) 与校验和表写回磁盘
+pub fn sys_sync() -> usize {
+    buffer::flush_all();
     0
 }
 
@@ -440,8 +801,10 @@ pub fn sys_inode_create(ctx: &mut TrapContext) -> usize {
     let type_ = (ctx.a0 & 0xFFFF) as u16;
     let major = (ctx.a1 & 0xFFFF) as u16;
     let minor = (ctx.a2 & 0xFFFF) as u16;
-    let inode_ref = inode::inode_create(type_, major, minor);
-    inode_ref.inode_num as usize
+    match inode::inode_create(type_, major, minor) {
+        Ok(inode_ref) => inode_ref.inode_num as usize,
+        Err(()) => usize::MAX,
+    }
 }
 
 pub fn sys_inode_dup(ctx: &mut TrapContext) -> usize {
@@ -465,6 +828,7 @@ pub fn sys_inode_set_nlink(ctx: &mut TrapContext) -> usize {
     let nlink = (ctx.a1 & 0xFFFF) as u16;
     let inode_ref = inode::inode_get(inum);
     inode_ref.disk.nlink = nlink;
+    inode::inode_touch(inode_ref, inode::TOUCH_CTIME);
     inode::inode_rw(inode_ref, true);
     inode::inode_put(inode_ref);
     0
@@ -473,7 +837,7 @@ pub fn sys_inode_set_nlink(ctx: &mut TrapContext) -> usize {
 pub fn sys_inode_get_refcnt(ctx: &mut TrapContext) -> usize {
     let inum = ctx.a0 as u32;
     let cache = inode::INODE_CACHE.lock();
-    for i in 0..inode::N_INODE {
+    for i in 0..cache.inodes.len() {
         let inode_ref = unsafe { &*(&cache.inodes[i] as *const inode::Inode) };
         if inode_ref.refcnt > 0 && inode_ref.inode_num == inum {
             return inode_ref.refcnt as usize;
@@ -496,7 +860,7 @@ pub fn sys_inode_write_data(ctx: &mut TrapContext) -> usize {
     let u_src = ctx.a2;
     let len = ctx.a3 as usize;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let inode_ref = inode::inode_get(inum);
     let mut total_written = 0;
     let mut buf = [0u8; 512];
@@ -523,7 +887,7 @@ pub fn sys_inode_read_data(ctx: &mut TrapContext) -> usize {
     let mut total_read = 0;
     let mut buf = [0u8; 512];
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     while total_read < len {
         let chunk_len = core::cmp::min(len - total_read, buf.len());
         let read = inode::inode_read_data(inode_ref, off + total_read as u32, chunk_len as u32, &mut buf[..chunk_len]);
@@ -544,7 +908,7 @@ pub fn sys_dentry_create(ctx: &mut TrapContext) -> usize {
     let target_inum = ctx.a1 as u32;
     let u_name = ctx.a2;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut name_buf = [0u8; inode::MAXLEN_FILENAME + 1];
     let copied = match uvm::copyin_str(pt, &mut name_buf, u_name) {
         Ok(n) => n,
@@ -561,7 +925,7 @@ pub fn sys_dentry_search(ctx: &mut TrapContext) -> usize {
     let dir_inum = ctx.a0 as u32;
     let u_name = ctx.a1;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut name_buf = [0u8; inode::MAXLEN_FILENAME + 1];
     let copied = match uvm::copyin_str(pt, &mut name_buf, u_name) {
         Ok(n) => n,
@@ -578,7 +942,7 @@ pub fn sys_dentry_delete(ctx: &mut TrapContext) -> usize {
     let dir_inum = ctx.a0 as u32;
     let u_name = ctx.a1;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut name_buf = [0u8; inode::MAXLEN_FILENAME + 1];
     let copied = match uvm::copyin_str(pt, &mut name_buf, u_name) {
         Ok(n) => n,
@@ -602,7 +966,7 @@ pub fn sys_dentry_print(ctx: &mut TrapContext) -> usize {
 pub fn sys_path_to_inode(ctx: &mut TrapContext) -> usize {
     let u_path = ctx.a0;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut path_buf = [0u8; 256];
     let copied = match uvm::copyin_str(pt, &mut path_buf, u_path) {
         Ok(n) => n,
@@ -624,7 +988,7 @@ pub fn sys_path_to_parent_inode(ctx: &mut TrapContext) -> usize {
     let u_path = ctx.a0;
     let u_name_out = ctx.a1;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut path_buf = [0u8; 256];
     let copied = match uvm::copyin_str(pt, &mut path_buf, u_path) {
         Ok(n) => n,
@@ -660,12 +1024,12 @@ pub fn sys_prepare_root_dir() -> usize {
         (val & (1 << bit)) != 0
     };
     if !is_inum_set(inode::ROOT_INODE) {
-        let root_inum = inode::alloc();
-        if root_inum != inode::ROOT_INODE { return usize::MAX; }
+        if inode::alloc() != Ok(inode::ROOT_INODE) { return usize::MAX; }
         let root_init = inode::inode_get(inode::ROOT_INODE);
         root_init.disk.type_ = inode::INODE_TYPE_DIR;
         root_init.disk.nlink = 2;
         root_init.disk.size = 0;
+        root_init.disk.mode = inode::default_mode(inode::INODE_TYPE_DIR);
         inode::inode_rw(root_init, true);
         inode::inode_put(root_init);
     } else {
@@ -676,7 +1040,10 @@ pub fn sys_prepare_root_dir() -> usize {
         if changed { inode::inode_rw(root_init, true); }
         inode::inode_put(root_init);
     }
-    0
+    let root = inode::inode_get(inode::ROOT_INODE);
+    let ret = dentry::dir_init(root, inode::ROOT_INODE);
+    inode::inode_put(root);
+    if ret != 0 { usize::MAX } else { 0 }
 }
 
 // --- LAB-9 Syscalls ---
@@ -685,7 +1052,7 @@ pub fn sys_open(ctx: &mut TrapContext) -> usize {
     let u_path = ctx.a0;
     let flags = ctx.a1 as u32;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut path_buf = [0u8; 256];
     let copied = match uvm::copyin_str(pt, &mut path_buf, u_path) {
         Ok(n) => n,
@@ -740,6 +1107,15 @@ pub fn sys_lseek(ctx: &mut TrapContext) -> usize {
     }
 }
 
+pub fn sys_pipe(ctx: &mut TrapContext) -> usize {
+    let u_fds = ctx.a0;
+    let p = current_proc();
+    match fs_pipe(p, u_fds) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
 pub fn sys_dup(ctx: &mut TrapContext) -> usize {
     let fd = ctx.a0;
     let p = current_proc();
@@ -759,6 +1135,15 @@ pub fn sys_fstat(ctx: &mut TrapContext) -> usize {
     }
 }
 
+pub fn sys_fsync(ctx: &mut TrapContext) -> usize {
+    let fd = ctx.a0;
+    let p = current_proc();
+    match fs_fsync(p, fd) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
 pub fn sys_get_dentries(ctx: &mut TrapContext) -> usize {
     let fd = ctx.a0;
     let u_buf = ctx.a1;
@@ -773,7 +1158,7 @@ pub fn sys_get_dentries(ctx: &mut TrapContext) -> usize {
 pub fn sys_mkdir(ctx: &mut TrapContext) -> usize {
     let u_path = ctx.a0;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut path_buf = [0u8; 256];
     if let Err(_) = uvm::copyin_str(pt, &mut path_buf, u_path) { return usize::MAX; }
     let path_len = path_buf.iter().position(|&b| b == 0).unwrap_or(path_buf.len());
@@ -786,7 +1171,7 @@ pub fn sys_mkdir(ctx: &mut TrapContext) -> usize {
 pub fn sys_chdir(ctx: &mut TrapContext) -> usize {
     let u_path = ctx.a0;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut path_buf = [0u8; 256];
     if let Err(_) = uvm::copyin_str(pt, &mut path_buf, u_path) { return usize::MAX; }
     let path_len = path_buf.iter().position(|&b| b == 0).unwrap_or(path_buf.len());
@@ -800,7 +1185,7 @@ pub fn sys_link(ctx: &mut TrapContext) -> usize {
     let u_old = ctx.a0;
     let u_new = ctx.a1;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut old_buf = [0u8; 256];
     let mut new_buf = [0u8; 256];
     if let Err(_) = uvm::copyin_str(pt, &mut old_buf, u_old) { return usize::MAX; }
@@ -816,7 +1201,7 @@ pub fn sys_link(ctx: &mut TrapContext) -> usize {
 pub fn sys_unlink(ctx: &mut TrapContext) -> usize {
     let u_path = ctx.a0;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     let mut path_buf = [0u8; 256];
     if let Err(_) = uvm::copyin_str(pt, &mut path_buf, u_path) { return usize::MAX; }
     let path_len = path_buf.iter().position(|&b| b == 0).unwrap_or(path_buf.len());
@@ -826,6 +1211,110 @@ pub fn sys_unlink(ctx: &mut TrapContext) -> usize {
     }
 }
 
+pub fn sys_rename(ctx: &mut TrapContext) -> usize {
+    let u_old = ctx.a0;
+    let u_new = ctx.a1;
+    let p = current_proc();
+    let pt = p.page_table();
+    let mut old_buf = [0u8; 256];
+    let mut new_buf = [0u8; 256];
+    if let Err(_) = uvm::copyin_str(pt, &mut old_buf, u_old) { return usize::MAX; }
+    if let Err(_) = uvm::copyin_str(pt, &mut new_buf, u_new) { return usize::MAX; }
+    let old_len = old_buf.iter().position(|&b| b == 0).unwrap_or(old_buf.len());
+    let new_len = new_buf.iter().position(|&b| b == 0).unwrap_or(new_buf.len());
+    match fs_rename(p, &old_buf[..old_len], &new_buf[..new_len]) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+pub fn sys_rmdir(ctx: &mut TrapContext) -> usize {
+    let u_path = ctx.a0;
+    let p = current_proc();
+    let pt = p.page_table();
+    let mut path_buf = [0u8; 256];
+    if let Err(_) = uvm::copyin_str(pt, &mut path_buf, u_path) { return usize::MAX; }
+    let path_len = path_buf.iter().position(|&b| b == 0).unwrap_or(path_buf.len());
+    match fs_rmdir(p, &path_buf[..path_len]) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+pub fn sys_symlink(ctx: &mut TrapContext) -> usize {
+    let u_target = ctx.a0;
+    let u_link = ctx.a1;
+    let p = current_proc();
+    let pt = p.page_table();
+    let mut target_buf = [0u8; 256];
+    let mut link_buf = [0u8; 256];
+    if let Err(_) = uvm::copyin_str(pt, &mut target_buf, u_target) { return usize::MAX; }
+    if let Err(_) = uvm::copyin_str(pt, &mut link_buf, u_link) { return usize::MAX; }
+    let target_len = target_buf.iter().position(|&b| b == 0).unwrap_or(target_buf.len());
+    let link_len = link_buf.iter().position(|&b| b == 0).unwrap_or(link_buf.len());
+    match fs_symlink(p, &target_buf[..target_len], &link_buf[..link_len]) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+pub fn sys_readlink(ctx: &mut TrapContext) -> usize {
+    let u_path = ctx.a0;
+    let u_buf = ctx.a1;
+    let bufsiz = ctx.a2;
+    let p = current_proc();
+    let pt = p.page_table();
+    let mut path_buf = [0u8; 256];
+    if let Err(_) = uvm::copyin_str(pt, &mut path_buf, u_path) { return usize::MAX; }
+    let path_len = path_buf.iter().position(|&b| b == 0).unwrap_or(path_buf.len());
+    match fs_readlink(p, &path_buf[..path_len], u_buf, bufsiz) {
+        Ok(n) => n,
+        Err(_) => usize::MAX,
+    }
+}
+
+pub fn sys_access(ctx: &mut TrapContext) -> usize {
+    let u_path = ctx.a0;
+    let mode = (ctx.a1 & 0xFFFF) as u16;
+    let p = current_proc();
+    let pt = p.page_table();
+    let mut path_buf = [0u8; 256];
+    if let Err(_) = uvm::copyin_str(pt, &mut path_buf, u_path) { return usize::MAX; }
+    let path_len = path_buf.iter().position(|&b| b == 0).unwrap_or(path_buf.len());
+    match fs_access(p, &path_buf[..path_len], mode) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+pub fn sys_chmod(ctx: &mut TrapContext) -> usize {
+    let u_path = ctx.a0;
+    let mode = (ctx.a1 & 0xFFFF) as u16;
+    let p = current_proc();
+    let pt = p.page_table();
+    let mut path_buf = [0u8; 256];
+    if let Err(_) = uvm::copyin_str(pt, &mut path_buf, u_path) { return usize::MAX; }
+    let path_len = path_buf.iter().position(|&b| b == 0).unwrap_or(path_buf.len());
+    match fs_chmod(p, &path_buf[..path_len], mode) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+pub fn sys_truncate(ctx: &mut TrapContext) -> usize {
+    let u_path = ctx.a0;
+    let Ok(len) = u32::try_from(ctx.a1) else { return usize::MAX };
+    let p = current_proc();
+    let pt = p.page_table();
+    let mut path_buf = [0u8; 256];
+    if let Err(_) = uvm::copyin_str(pt, &mut path_buf, u_path) { return usize::MAX; }
+    let path_len = path_buf.iter().position(|&b| b == 0).unwrap_or(path_buf.len());
+    match fs_truncate(p, &path_buf[..path_len], len) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
 pub fn sys_print_cwd() -> usize {
     let p = current_proc();
     crate::printk!("CWD Inode: {}\n", p.cwd);
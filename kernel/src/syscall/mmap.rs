@@ -2,22 +2,26 @@ use crate::irq::TrapContext;
 use crate::mem::mmap;
 use crate::mem::uvm;
 use crate::mem::vm;
-use crate::mem::{MMAP_BEGIN, MMAP_END, PageTable};
+use crate::mem::addr::align_up;
+use crate::mem::{MMAP_BEGIN, MMAP_END, PGSIZE, VA_MAX};
 use crate::printk;
 use crate::proc::current_proc;
 
 pub fn sys_mmap(ctx: &mut TrapContext) -> usize {
-    printk!("sys_mmap: begin=0x{:x}, len=0x{:x}\n", ctx.a0, ctx.a1);
+    printk!("sys_mmap: begin=0x{:x}, len=0x{:x}, flags=0x{:x}\n", ctx.a0, ctx.a1, ctx.a2);
     let begin = ctx.a0;
     let len = ctx.a1;
-    let flags = 0;
+    let flags = ctx.a2 & uvm::MAP_FIXED;
     let p = current_proc();
-    let pt = unsafe { &mut *(p.root_pt_pa as *mut PageTable) };
-    match uvm::mmap(pt, &mut p.mmap_head, begin, len, flags, MMAP_BEGIN, MMAP_END) {
+    if !p.vfork_parent.is_null() {
+        return usize::MAX;
+    }
+    let pt = p.page_table_mut();
+    match uvm::mmap(pt, &mut p.mmap, begin, len, flags, MMAP_BEGIN, MMAP_END) {
         Ok(va) => {
             #[cfg(feature = "tests")]
             {
-                mmap::print_mmaplist(p.mmap_head);
+                mmap::print_mmaplist(p.mmap.head());
                 vm::print(pt);
             }
             va
@@ -30,12 +34,15 @@ pub fn sys_munmap(ctx: &mut TrapContext) -> usize {
     let begin = ctx.a0;
     let len = ctx.a1;
     let p = current_proc();
-    let pt = unsafe { &mut *(p.root_pt_pa as *mut PageTable) };
-    match uvm::munmap(pt, &mut p.mmap_head, begin, len) {
+    if !p.vfork_parent.is_null() {
+        return usize::MAX;
+    }
+    let pt = p.page_table_mut();
+    match uvm::munmap(pt, &mut p.mmap, begin, len) {
         Ok(()) => {
             #[cfg(feature = "tests")]
             {
-                mmap::print_mmaplist(p.mmap_head);
+                mmap::print_mmaplist(p.mmap.head());
                 vm::print(pt);
             }
             0
@@ -43,3 +50,56 @@ pub fn sys_munmap(ctx: &mut TrapContext) -> usize {
         Err(_) => usize::MAX,
     }
 }
+
+// mincore(addr, len, vec): 每页向 vec 写一个字节, 1 表示已有物理页, 0 表示未映射或尚未缺页
+pub fn sys_mincore(ctx: &mut TrapContext) -> usize {
+    let begin = ctx.a0;
+    let len = ctx.a1;
+    let u_vec = ctx.a2;
+    if begin & (PGSIZE - 1) != 0 || len == 0 {
+        return usize::MAX;
+    }
+    let end = match begin.checked_add(len) {
+        Some(e) if e <= VA_MAX => align_up(e),
+        _ => return usize::MAX,
+    };
+    let p = current_proc();
+    let pt = p.page_table_mut();
+
+    let mut chunk = [0u8; 64];
+    let mut va = begin;
+    let mut out = u_vec;
+    while va < end {
+        let n = core::cmp::min(chunk.len(), (end - va) / PGSIZE);
+        for i in 0..n {
+            chunk[i] = uvm::is_resident(pt, va + i * PGSIZE) as u8;
+        }
+        if uvm::copyout(pt, out, &chunk[..n]).is_err() {
+            return usize::MAX;
+        }
+        va += n * PGSIZE;
+        out += n;
+    }
+    0
+}
+
+pub const MADV_DONTNEED: usize = 4;
+
+pub fn sys_madvise(ctx: &mut TrapContext) -> usize {
+    let begin = ctx.a0;
+    let len = ctx.a1;
+    let advice = ctx.a2;
+    let p = current_proc();
+    if !p.vfork_parent.is_null() {
+        return usize::MAX;
+    }
+    let pt = p.page_table_mut();
+    let res = match advice {
+        MADV_DONTNEED => uvm::madvise_dontneed(pt, &p.mmap, begin, len),
+        _ => return usize::MAX,
+    };
+    match res {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
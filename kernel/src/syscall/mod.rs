@@ -70,6 +70,25 @@ pub const SYS_CHDIR: usize = 51;
 pub const SYS_PRINT_CWD: usize = 52;
 pub const SYS_LINK: usize = 53;
 pub const SYS_UNLINK: usize = 54;
+pub const SYS_SYMLINK: usize = 55;
+pub const SYS_READLINK: usize = 56;
+pub const SYS_TRAPSTAT: usize = 57;
+pub const SYS_VFORK: usize = 58;
+pub const SYS_ACCESS: usize = 59;
+pub const SYS_CHMOD: usize = 60;
+pub const SYS_MINCORE: usize = 61;
+pub const SYS_MADVISE: usize = 62;
+pub const SYS_UPTIME: usize = 63;
+pub const SYS_NANOSLEEP: usize = 64;
+pub const SYS_PIPE: usize = 65;
+pub const SYS_TIMES: usize = 66;
+pub const SYS_FSYNC: usize = 67;
+pub const SYS_GETTID: usize = 68;
+pub const SYS_RENAME: usize = 69;
+pub const SYS_TRUNCATE: usize = 70;
+pub const SYS_RMDIR: usize = 71;
+pub const SYS_WAITID: usize = 72;
+pub const SYS_SYNC: usize = 73;
 
 pub fn dispatch(ctx: &mut TrapContext) -> usize {
     match ctx.a7 {
@@ -84,6 +103,7 @@ pub fn dispatch(ctx: &mut TrapContext) -> usize {
         SYS_PRINT_STR => util::sys_print_str(ctx),
         SYS_PRINT_INT => util::sys_print_int(ctx),
         SYS_GETPID => proc::sys_getpid(),
+        SYS_TRAPSTAT => util::sys_trapstat(ctx),
 
         SYS_ALLOC_BLOCK => fs::sys_alloc_block(),
         SYS_FREE_BLOCK => fs::sys_free_block(ctx),
@@ -98,6 +118,7 @@ pub fn dispatch(ctx: &mut TrapContext) -> usize {
         SYS_FLUSH_BUFFER => fs::sys_flush_buffer(ctx),
 
         SYS_FORK => proc::sys_fork(),
+        SYS_VFORK => proc::sys_vfork(),
         SYS_WAIT => proc::sys_wait(ctx),
         SYS_EXIT => proc::sys_exit(ctx),
         SYS_SLEEP => proc::sys_sleep(ctx),
@@ -134,6 +155,23 @@ pub fn dispatch(ctx: &mut TrapContext) -> usize {
         SYS_PRINT_CWD => fs::sys_print_cwd(),
         SYS_LINK => fs::sys_link(ctx),
         SYS_UNLINK => fs::sys_unlink(ctx),
+        SYS_SYMLINK => fs::sys_symlink(ctx),
+        SYS_READLINK => fs::sys_readlink(ctx),
+        SYS_ACCESS => fs::sys_access(ctx),
+        SYS_CHMOD => fs::sys_chmod(ctx),
+        SYS_MINCORE => mmap::sys_mincore(ctx),
+        SYS_MADVISE => mmap::sys_madvise(ctx),
+        SYS_UPTIME => proc::sys_uptime(),
+        SYS_NANOSLEEP => proc::sys_nanosleep(ctx),
+        SYS_PIPE => fs::sys_pipe(ctx),
+        SYS_TIMES => proc::sys_times(ctx),
+        SYS_FSYNC => fs::sys_fsync(ctx),
+        SYS_GETTID => proc::sys_gettid(),
+        SYS_RENAME => fs::sys_rename(ctx),
+        SYS_TRUNCATE => fs::sys_truncate(ctx),
+        SYS_RMDIR => fs::sys_rmdir(ctx),
+        SYS_WAITID => proc::sys_waitid(ctx),
+        SYS_SYNC => fs::sys_sync(),
 
         n => {
             printk!("{}[WARN] SYSCALL: unknown number {}{}\n", ANSI_YELLOW, n, ANSI_RESET);
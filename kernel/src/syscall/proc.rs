@@ -1,22 +1,42 @@
+use crate::fs::buffer;
 use crate::irq::TrapContext;
 use crate::irq::timer;
-use crate::mem::PageTable;
 use crate::mem::uvm;
-use crate::proc::{current_proc, scheduler};
+use crate::proc::{current_proc, process, scheduler};
+use crate::syscall::fs;
 
 pub fn sys_getpid() -> usize {
     current_proc().pid
 }
 
+// 内核还没有线程, 每个进程只有一个执行流, 所以 gettid 就是 getpid;
+// 线程创建落地后才需要每线程独立的 tid
+pub fn sys_gettid() -> usize {
+    current_proc().pid
+}
+
 pub fn sys_fork() -> usize {
-    let child = current_proc().fork();
-    child.pid
+    match current_proc().fork() {
+        Some(child) => child.pid,
+        None => usize::MAX,
+    }
+}
+
+pub fn sys_vfork() -> usize {
+    current_proc().vfork()
 }
 
 pub fn sys_exit(ctx: &mut TrapContext) -> usize {
     let code = ctx.a0 as i32;
     let p = current_proc();
-    p.exit_code = code;
+    p.exit_code = process::exit_status(code);
+    // 内核没有关机路径, 初始进程退出后只剩调度器空转: 先关掉它的文件, 再把延迟写的块落盘
+    if p.parent.is_null() {
+        for fd in 0..process::NOFILE {
+            let _ = fs::fs_close(p, fd);
+        }
+        buffer::flush_all();
+    }
     p.exit();
     scheduler::yield_proc();
     // Should not reach here
@@ -29,7 +49,7 @@ pub fn sys_wait(ctx: &mut TrapContext) -> usize {
         Some((pid, code)) => {
             if addr != 0 {
                 let p = current_proc();
-                let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+                let pt = p.page_table();
                 let bytes = code.to_ne_bytes();
                 let _ = uvm::copyout(pt, addr, &bytes);
             }
@@ -39,17 +59,63 @@ pub fn sys_wait(ctx: &mut TrapContext) -> usize {
     }
 }
 
+// waitid(pid, status): 查看子进程是否已退出但不回收, pid 为 -1 时查看任意子进程。
+// 返回已退出子进程的 pid 并写回退出状态; 子进程都还在运行时返回 0; 没有匹配的子进程时失败
+pub fn sys_waitid(ctx: &mut TrapContext) -> usize {
+    let pid = match ctx.a0 as isize {
+        -1 => None,
+        pid if pid > 0 => Some(pid as usize),
+        _ => return usize::MAX,
+    };
+    let addr = ctx.a1;
+    match scheduler::peek_child(pid) {
+        Ok(Some((pid, code))) => {
+            if addr != 0 {
+                let pt = current_proc().page_table();
+                if uvm::copyout(pt, addr, &code.to_ne_bytes()).is_err() {
+                    return usize::MAX;
+                }
+            }
+            pid
+        }
+        Ok(None) => 0,
+        Err(()) => usize::MAX,
+    }
+}
+
 pub fn sys_sleep(ctx: &mut TrapContext) -> usize {
     let ticks = ctx.a0;
     timer::wait(ticks);
     0
 }
 
+pub fn sys_nanosleep(ctx: &mut TrapContext) -> usize {
+    timer::nanosleep(ctx.a0 as u64);
+    0
+}
+
+// 开机以来的毫秒数
+pub fn sys_uptime() -> usize {
+    timer::uptime_ms()
+}
+
+// 向 a0 写入 {utime, stime} 两个 usize, 单位为时钟节拍
+pub fn sys_times(ctx: &mut TrapContext) -> usize {
+    let p = current_proc();
+    let pt = p.page_table();
+    let times = [p.utime, p.stime];
+    let src = unsafe { core::slice::from_raw_parts(times.as_ptr() as *const u8, core::mem::size_of_val(&times)) };
+    match uvm::copyout(pt, ctx.a0, src) {
+        Ok(_) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
 pub fn sys_exec(ctx: &mut TrapContext) -> usize {
     let u_path = ctx.a0;
     let u_argv = ctx.a1;
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
 
     // Read path
     let mut path_buf = [0u8; 256];
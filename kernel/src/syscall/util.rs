@@ -1,5 +1,5 @@
 use crate::irq::TrapContext;
-use crate::mem::PageTable;
+use crate::irq::trap::stat;
 use crate::mem::uvm;
 use crate::printk;
 use crate::proc::current_proc;
@@ -8,7 +8,7 @@ pub fn sys_print_str(ctx: &mut TrapContext) -> usize {
     let u_src = ctx.a0;
     let mut buf: [u8; 256] = [0; 256];
     let p = current_proc();
-    let pt = unsafe { &*(p.root_pt_pa as *const PageTable) };
+    let pt = p.page_table();
     match uvm::copyin_str(pt, &mut buf, u_src) {
         Ok(len) => {
             let s = &buf[..len.saturating_sub(1)];
@@ -26,3 +26,17 @@ pub fn sys_print_int(ctx: &mut TrapContext) -> usize {
     printk!("{}", val);
     0
 }
+
+// sys_trapstat(buf, n): 拷出前 n 个计数 (u64), 布局见 trap::stat::snapshot
+pub fn sys_trapstat(ctx: &mut TrapContext) -> usize {
+    let u_dst = ctx.a0;
+    let n = core::cmp::min(ctx.a1, 2 * stat::NCAUSE);
+    let counts = stat::snapshot();
+    let p = current_proc();
+    let pt = p.page_table();
+    let src = unsafe { core::slice::from_raw_parts(counts.as_ptr() as *const u8, n * 8) };
+    match uvm::copyout(pt, u_dst, src) {
+        Ok(_) => n,
+        Err(_) => usize::MAX,
+    }
+}
@@ -0,0 +1,308 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::barrier::MultiCoreTestBarrier;
+use crate::drivers::virtio;
+use crate::dtb;
+use crate::fs::buffer::{self, BLOCK_SIZE, DEFAULT_N_BUFFER};
+use crate::fs::fs::SuperBlock;
+use crate::fs::inode::{self, DEFAULT_N_INODE};
+use crate::mem::pmem;
+use crate::printk;
+use crate::printk::{ANSI_GREEN, ANSI_RESET, ANSI_YELLOW};
+
+static BUFFER_BARRIER: MultiCoreTestBarrier = MultiCoreTestBarrier::new();
+
+// 块数多于缓存槽位, 迫使两个 hart 在回收 LRU 的同时读同一批块
+fn hammer_blocks() -> u32 {
+    buffer::nbuf() as u32 + 8
+}
+const HAMMER_ROUNDS: u32 = 200;
+// 两个 hart 各自对计数块加一的次数
+const LOCK_ROUNDS: u32 = 100;
+
+pub fn run(hartid: usize) {
+    BUFFER_BARRIER.ensure_inited(dtb::hart_count());
+    if hartid == 0 {
+        BUFFER_BARRIER.init(dtb::hart_count());
+        printk!("[TEST] Buffer cache test start ({} harts)\n", BUFFER_BARRIER.total());
+        save_test_block();
+        set_counter(0);
+    }
+    BUFFER_BARRIER.wait_start();
+    buffer_hammer_test(hartid);
+    buffer_lock_test(hartid);
+    if BUFFER_BARRIER.finish_and_last() {
+        assert_eq!(buffer::in_use(), 0, "buffer_hammer_test: buffers still referenced");
+        buffer_lock_check();
+        cache_size_test();
+        inflight_read_test();
+        write_back_test();
+        checksum_test();
+        restore_test_block();
+        printk!("{}[PASS]{} Buffer cache test ({} harts)\n", ANSI_GREEN, ANSI_RESET, BUFFER_BARRIER.total());
+    }
+}
+
+// hart 0 与 hart 1 交错读写同一批块的缓存, 只读不写, 不改动磁盘内容
+// 若有 hart 在持有缓存锁时进入磁盘 I/O, disk_rw 的断言会报出来; 锁顺序错误则会卡死在这里
+fn buffer_hammer_test(hartid: usize) {
+    if hartid > 1 {
+        return;
+    }
+    if dtb::hart_count() < 2 {
+        if hartid == 0 {
+            printk!("{}[SKIP]{} buffer hammer: needs at least 2 harts\n", ANSI_YELLOW, ANSI_RESET);
+        }
+        return;
+    }
+    if hartid == 0 {
+        printk!("[TEST] buffer hammer\n");
+    }
+    for round in 0..HAMMER_ROUNDS {
+        // 两个 hart 以相反方向遍历, 提高撞上同一块的概率
+        let step = if hartid == 0 { round } else { HAMMER_ROUNDS - round };
+        let blockno = step % hammer_blocks();
+        let b = buffer::read(0, blockno);
+        buffer::release(b);
+    }
+    if hartid == 0 {
+        printk!("{}[PASS]{} buffer hammer\n", ANSI_GREEN, ANSI_RESET);
+    }
+}
+
+// 计数器等测试使用数据区的最后一块; 磁盘最后几块可能是校验和区, 不经过缓存
+fn test_block() -> u32 {
+    let sb = superblock();
+    sb.data_start() + sb.nblocks - 1
+}
+
+// 本文件的测试会改写 test_block, 这块可能已被文件系统使用:
+// 开始前由 hart 0 保存原内容, 全部测试结束后写回
+static TEST_BLOCK_SAVED: AtomicUsize = AtomicUsize::new(0);
+
+fn save_test_block() {
+    let saved = pmem::alloc(true);
+    let b = buffer::read(0, test_block());
+    unsafe { core::ptr::copy_nonoverlapping(buffer::get_data_ptr(b), saved, BLOCK_SIZE) };
+    buffer::release(b);
+    TEST_BLOCK_SAVED.store(saved as usize, Ordering::Relaxed);
+}
+
+fn restore_test_block() {
+    let saved = TEST_BLOCK_SAVED.swap(0, Ordering::Relaxed) as *mut u8;
+    let b = buffer::read(0, test_block());
+    unsafe { core::ptr::copy_nonoverlapping(saved, buffer::get_data_ptr(b), BLOCK_SIZE) };
+    buffer::write(b);
+    buffer::release(b);
+    buffer::flush_block(0, test_block());
+    pmem::free(saved as usize, true);
+}
+
+fn set_counter(value: u32) {
+    let b = buffer::read(0, test_block());
+    unsafe { *(buffer::get_data_ptr(b) as *mut u32) = value };
+    buffer::write(b);
+    buffer::release(b);
+}
+
+// hart 0 与 hart 1 对同一块中的计数器做读-等待-写, 缓冲区的睡眠锁保证两次加一不会交错
+// 没有互斥时中间的等待让另一个 hart 读到旧值, 最终计数会少于 2 * LOCK_ROUNDS
+fn buffer_lock_test(hartid: usize) {
+    if hartid > 1 || dtb::hart_count() < 2 {
+        return;
+    }
+    let blockno = test_block();
+    for _ in 0..LOCK_ROUNDS {
+        let b = buffer::read(0, blockno);
+        let counter = buffer::get_data_ptr(b) as *mut u32;
+        let value = unsafe { core::ptr::read_volatile(counter) };
+        for _ in 0..100 {
+            core::hint::spin_loop();
+        }
+        unsafe { core::ptr::write_volatile(counter, value + 1) };
+        buffer::write(b);
+        buffer::release(b);
+    }
+}
+
+fn buffer_lock_check() {
+    if dtb::hart_count() < 2 {
+        printk!("{}[SKIP]{} buffer sleep lock: needs at least 2 harts\n", ANSI_YELLOW, ANSI_RESET);
+    } else {
+        let b = buffer::read(0, test_block());
+        let count = unsafe { *(buffer::get_data_ptr(b) as *const u32) };
+        buffer::release(b);
+        assert_eq!(count, 2 * LOCK_ROUNDS, "buffer_lock_test: concurrent updates to one block were lost");
+        printk!("{}[PASS]{} buffer sleep lock ({} updates)\n", ANSI_GREEN, ANSI_RESET, count);
+    }
+}
+
+// xtask test 以 "ninode=128 nbuf=64" 启动, 缓存大小应与启动参数一致
+// 同时持有全部缓冲区, 检查每个槽位都真实可用且互不重叠
+fn cache_size_test() {
+    printk!("[TEST] cache size from bootargs\n");
+    let nbuf = buffer::nbuf();
+    assert_eq!(nbuf, dtb::bootarg("nbuf").unwrap_or(DEFAULT_N_BUFFER), "cache_size_test: nbuf mismatch");
+    assert_eq!(
+        inode::n_inode(),
+        dtb::bootarg("ninode").unwrap_or(DEFAULT_N_INODE),
+        "cache_size_test: ninode mismatch"
+    );
+
+    let mut held = [0usize; 256];
+    let n = core::cmp::min(nbuf, held.len());
+    for i in 0..n {
+        held[i] = buffer::read(0, i as u32);
+        assert!(held[i] < nbuf);
+        assert!(held[..i].iter().all(|&b| b != held[i]), "cache_size_test: slot {} handed out twice", held[i]);
+    }
+    for &b in &held[..n] {
+        buffer::release(b);
+    }
+    assert_eq!(buffer::in_use(), 0);
+    printk!(
+        "{}[PASS]{} cache size ({} buffers, {} inodes)\n",
+        ANSI_GREEN,
+        ANSI_RESET,
+        nbuf,
+        inode::n_inode()
+    );
+}
+
+// 测试在 fs_init 之前运行, 直接读超级块
+fn superblock() -> SuperBlock {
+    let b = buffer::read(0, 0);
+    let sb = unsafe { core::ptr::read_unaligned(buffer::get_data_ptr(b) as *const SuperBlock) };
+    buffer::release(b);
+    sb
+}
+
+fn disk_size() -> u32 {
+    superblock().size
+}
+
+// 先提交几块读再倒序等待, 每个请求独立完成; 绕过缓存使用磁盘末尾几块, 结束后恢复原内容
+fn inflight_read_test() {
+    const N: usize = 4;
+    printk!("[TEST] virtio in-flight reads\n");
+    let first = disk_size() - N as u32;
+    let saved: [*mut u8; N] = core::array::from_fn(|_| pmem::alloc(true));
+    let reads: [*mut u8; N] = core::array::from_fn(|_| pmem::alloc(true));
+    let pattern = |i: usize, j: usize| (i * 37 + j % 251) as u8;
+
+    for i in 0..N {
+        virtio::disk::rw(saved[i], first + i as u32, false);
+        for j in 0..BLOCK_SIZE {
+            unsafe { *reads[i].add(j) = pattern(i, j) };
+        }
+        virtio::disk::rw(reads[i], first + i as u32, true);
+        unsafe { core::ptr::write_bytes(reads[i], 0, BLOCK_SIZE) };
+    }
+
+    let reqs: [virtio::disk::Request; N] =
+        core::array::from_fn(|i| virtio::disk::submit(reads[i], first + i as u32, false));
+    for req in reqs.into_iter().rev() {
+        virtio::disk::wait(req);
+    }
+    for i in 0..N {
+        for j in 0..BLOCK_SIZE {
+            let got = unsafe { *reads[i].add(j) };
+            assert_eq!(got, pattern(i, j), "inflight_read_test: block {} byte {}", first + i as u32, j);
+        }
+    }
+
+    for i in 0..N {
+        virtio::disk::rw(saved[i], first + i as u32, true);
+        pmem::free(saved[i] as usize, true);
+        pmem::free(reads[i] as usize, true);
+    }
+    printk!("{}[PASS]{} virtio in-flight reads ({} requests)\n", ANSI_GREEN, ANSI_RESET, N);
+}
+
+// write 只把缓冲区标记为脏, 不访问磁盘; flush_all 写回后磁盘上才是新内容
+// 使用 test_block, 原内容由 restore_test_block 写回
+fn write_back_test() {
+    printk!("[TEST] buffer write-back\n");
+    let blockno = test_block();
+    buffer::flush_all();
+
+    let b = buffer::read(0, blockno);
+    unsafe { core::ptr::write_bytes(buffer::get_data_ptr(b), 0x3c, BLOCK_SIZE) };
+    let writes = virtio::disk::write_count();
+    buffer::write(b);
+    buffer::release(b);
+    assert_eq!(virtio::disk::write_count(), writes, "write_back_test: write went to disk");
+    assert!(buffer::is_dirty(0, blockno), "write_back_test: block not marked dirty");
+
+    let scratch = pmem::alloc(true);
+    virtio::disk::rw(scratch, blockno, false);
+    assert_ne!(unsafe { *scratch }, 0x3c, "write_back_test: disk changed before flush");
+
+    assert_eq!(buffer::flush_all(), 1, "write_back_test: flush_all wrote the wrong number of blocks");
+    assert!(!buffer::is_dirty(0, blockno), "write_back_test: block still dirty after flush");
+    assert_eq!(buffer::in_use(), 0, "write_back_test: flush_all kept a reference");
+    virtio::disk::rw(scratch, blockno, false);
+    let data = unsafe { core::slice::from_raw_parts(scratch, BLOCK_SIZE) };
+    assert!(data.iter().all(|&x| x == 0x3c), "write_back_test: disk missing flushed data");
+    assert_eq!(buffer::flush_all(), 0, "write_back_test: second flush rewrote clean blocks");
+
+    let b = buffer::read(0, blockno);
+    unsafe { core::ptr::write_bytes(buffer::get_data_ptr(b), 0, BLOCK_SIZE) };
+    buffer::write(b);
+    buffer::release(b);
+    buffer::flush_all();
+    pmem::free(scratch as usize, true);
+    printk!("{}[PASS]{} buffer write-back\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 打开块校验后写一块, 绕过缓存直接改写磁盘上的这一块, 再读回时应报告校验和不一致
+// 使用 test_block, 原内容由 restore_test_block 写回
+fn checksum_test() {
+    printk!("[TEST] block checksum\n");
+    let sb = superblock();
+    let blockno = test_block();
+    let was_enabled = buffer::checksums_enabled();
+    buffer::enable_checksums(sb.size as usize, sb.csum_start);
+
+    let b = buffer::read(0, blockno);
+    let data = buffer::get_data_ptr(b);
+    for i in 0..BLOCK_SIZE {
+        unsafe { *data.add(i) = (i % 251) as u8 };
+    }
+    buffer::write(b);
+    buffer::release(b);
+    buffer::flush_block(0, blockno);
+
+    // 未损坏: 从磁盘重新读入能通过校验
+    buffer::invalidate(0, blockno);
+    let b = buffer::try_read(0, blockno).expect("checksum_test: clean block failed verification");
+    buffer::release(b);
+
+    // 带外翻转一个字节
+    let scratch = pmem::alloc(true);
+    virtio::disk::rw(scratch, blockno, false);
+    unsafe { *scratch.add(100) ^= 0xff };
+    virtio::disk::rw(scratch, blockno, true);
+    buffer::invalidate(0, blockno);
+    assert!(buffer::try_read(0, blockno).is_err(), "checksum_test: corruption not detected");
+    assert_eq!(buffer::in_use(), 0, "checksum_test: failed read kept a reference");
+    // read 只报告不停机, 交出磁盘上的内容; 丢掉这份副本再恢复
+    let b = buffer::read(0, blockno);
+    assert_eq!(unsafe { *buffer::get_data_ptr(b).add(100) }, unsafe { *scratch.add(100) });
+    buffer::release(b);
+    buffer::invalidate(0, blockno);
+
+    // 把字节翻回来后校验重新通过, 再经缓存写回全零
+    unsafe { *scratch.add(100) ^= 0xff };
+    virtio::disk::rw(scratch, blockno, true);
+    let b = buffer::try_read(0, blockno).expect("checksum_test: restored block failed verification");
+    unsafe { core::ptr::write_bytes(buffer::get_data_ptr(b), 0, BLOCK_SIZE) };
+    buffer::write(b);
+    buffer::release(b);
+    buffer::flush_block(0, blockno);
+    pmem::free(scratch as usize, true);
+    if !was_enabled {
+        buffer::disable_checksums();
+    }
+    printk!("{}[PASS]{} block checksum\n", ANSI_GREEN, ANSI_RESET);
+}
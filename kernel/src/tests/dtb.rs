@@ -0,0 +1,185 @@
+use crate::dtb;
+use crate::printk;
+use crate::printk::{ANSI_GREEN, ANSI_RESET};
+
+/// 解析手工构造的设备树
+pub fn run(hartid: usize) {
+    if hartid == 0 {
+        memory_regions_test();
+        virtio_mmio_test();
+    }
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+const HEADER_SIZE: usize = 40;
+const RSVMAP_SIZE: usize = 16; // 只有一个全零的结束条目
+
+#[repr(C, align(8))]
+struct Blob([u8; 1024]);
+
+// 按 FDT v17 格式写出 header、空的保留表、结构块和字符串块
+struct FdtBuilder {
+    blob: Blob,
+    len: usize,
+    strings: [u8; 256],
+    strings_len: usize,
+}
+
+impl FdtBuilder {
+    fn new() -> Self {
+        Self { blob: Blob([0; 1024]), len: HEADER_SIZE + RSVMAP_SIZE, strings: [0; 256], strings_len: 0 }
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        self.blob.0[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+
+    fn word(&mut self, v: u32) {
+        self.bytes(&v.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        self.len = self.len.next_multiple_of(4);
+    }
+
+    fn begin(&mut self, name: &str) {
+        self.word(FDT_BEGIN_NODE);
+        self.bytes(name.as_bytes());
+        self.bytes(&[0]);
+        self.pad();
+    }
+
+    fn end(&mut self) {
+        self.word(FDT_END_NODE);
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8]) {
+        let nameoff = self.strings_len;
+        self.strings[nameoff..nameoff + name.len()].copy_from_slice(name.as_bytes());
+        self.strings_len += name.len() + 1;
+        self.word(FDT_PROP);
+        self.word(value.len() as u32);
+        self.word(nameoff as u32);
+        self.bytes(value);
+        self.pad();
+    }
+
+    fn prop_u32(&mut self, name: &str, v: u32) {
+        self.prop(name, &v.to_be_bytes());
+    }
+
+    // 根节点: 2/2 的地址/长度 cell, 以及 parse_hart_count 需要的空 /cpus
+    fn root(&mut self) {
+        self.begin("");
+        self.prop_u32("#address-cells", 2);
+        self.prop_u32("#size-cells", 2);
+        self.begin("cpus");
+        self.prop_u32("#address-cells", 1);
+        self.prop_u32("#size-cells", 0);
+        self.end();
+    }
+
+    // #address-cells = #size-cells = 2 时的 reg
+    fn reg(&mut self, ranges: &[(u64, u64)]) {
+        let mut value = [0u8; 64];
+        for (i, &(start, size)) in ranges.iter().enumerate() {
+            value[i * 16..i * 16 + 8].copy_from_slice(&start.to_be_bytes());
+            value[i * 16 + 8..i * 16 + 16].copy_from_slice(&size.to_be_bytes());
+        }
+        self.prop("reg", &value[..ranges.len() * 16]);
+    }
+
+    fn finish(mut self) -> Blob {
+        self.word(FDT_END);
+        let off_struct = HEADER_SIZE + RSVMAP_SIZE;
+        let size_struct = self.len - off_struct;
+        let off_strings = self.len;
+        let strings_len = self.strings_len;
+        let strings = self.strings;
+        self.bytes(&strings[..strings_len]);
+        let header = [
+            FDT_MAGIC,
+            self.len as u32,
+            off_struct as u32,
+            off_strings as u32,
+            HEADER_SIZE as u32,
+            17,
+            16,
+            0,
+            strings_len as u32,
+            size_struct as u32,
+        ];
+        for (i, v) in header.iter().enumerate() {
+            self.blob.0[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+        }
+        self.blob
+    }
+}
+
+// 两个内存节点, 第二个节点有两个 reg 条目: 三段都要解析出来, memory() 仍返回第一段
+fn memory_regions_test() {
+    printk!("[TEST] dtb multiple memory regions\n");
+    const BANKS: [(u64, u64); 3] =
+        [(0x8000_0000, 0x0400_0000), (0xc000_0000, 0x0200_0000), (0x1_0000_0000, 0x1000_0000)];
+
+    let mut fdt = FdtBuilder::new();
+    fdt.root();
+    fdt.begin("memory@80000000");
+    fdt.prop("device_type", b"memory\0");
+    fdt.reg(&BANKS[..1]);
+    fdt.end();
+    fdt.begin("memory@c0000000");
+    fdt.prop("device_type", b"memory\0");
+    fdt.reg(&BANKS[1..]);
+    fdt.end();
+    fdt.end();
+    let blob = fdt.finish();
+
+    let info = dtb::parse(blob.0.as_ptr()).expect("memory_regions_test: synthetic FDT rejected");
+    let regions = info.memory_regions();
+    assert_eq!(regions.len(), BANKS.len(), "memory_regions_test: region count");
+    for (r, &(start, size)) in regions.iter().zip(BANKS.iter()) {
+        assert_eq!((r.start, r.size), (start as usize, size as usize), "memory_regions_test: region mismatch");
+    }
+    let first = info.memory().expect("memory_regions_test: no first region");
+    assert_eq!(first.start, BANKS[0].0 as usize, "memory_regions_test: memory() is not the first bank");
+    printk!("{}[PASS]{} dtb multiple memory regions ({} banks)\n", ANSI_GREEN, ANSI_RESET, regions.len());
+}
+
+// 只收集 compatible 为 virtio,mmio 的节点, 其他设备忽略
+fn virtio_mmio_test() {
+    printk!("[TEST] dtb virtio-mmio slots\n");
+    const SLOTS: [(&str, u64); 2] = [("virtio_mmio@10008000", 0x1000_8000), ("virtio_mmio@10001000", 0x1000_1000)];
+
+    let mut fdt = FdtBuilder::new();
+    fdt.root();
+    fdt.begin("memory@80000000");
+    fdt.prop("device_type", b"memory\0");
+    fdt.reg(&[(0x8000_0000, 0x0800_0000)]);
+    fdt.end();
+    for &(name, base) in SLOTS.iter() {
+        fdt.begin(name);
+        fdt.prop("compatible", b"virtio,mmio\0");
+        fdt.reg(&[(base, 0x1000)]);
+        fdt.end();
+    }
+    fdt.begin("serial@10000000");
+    fdt.prop("compatible", b"ns16550a\0");
+    fdt.reg(&[(0x1000_0000, 0x100)]);
+    fdt.end();
+    fdt.end();
+    let blob = fdt.finish();
+
+    let info = dtb::parse(blob.0.as_ptr()).expect("virtio_mmio_test: synthetic FDT rejected");
+    let slots = info.virtio_mmio();
+    assert_eq!(slots.len(), SLOTS.len(), "virtio_mmio_test: slot count");
+    for (r, &(_, base)) in slots.iter().zip(SLOTS.iter()) {
+        assert_eq!((r.start, r.size), (base as usize, 0x1000), "virtio_mmio_test: slot mismatch");
+    }
+    printk!("{}[PASS]{} dtb virtio-mmio slots\n", ANSI_GREEN, ANSI_RESET);
+}
@@ -0,0 +1,263 @@
+use core::mem::size_of;
+
+use crate::drivers::virtio;
+use crate::fs::bitmap;
+use crate::fs::buffer;
+use crate::fs::dentry;
+use crate::fs::file::{self, FileType};
+use crate::fs::fs::{BSIZE, SuperBlock, get_sb};
+use crate::fs::fsck;
+use crate::fs::inode;
+use crate::mem::{PGSIZE, pmem};
+use crate::printk;
+use crate::printk::{ANSI_GREEN, ANSI_RESET};
+
+// 会改写磁盘的文件系统测试, 只在 tests 特性下由 hart 0 运行 (xtask test 每次都重新生成镜像);
+// 只使用临时 inode 和临时分配的块, 测试结束前全部释放
+pub fn run(hartid: usize) {
+    if hartid != 0 {
+        return;
+    }
+    printk!("[TEST] fs tests start\n");
+    fsync_test();
+    append_test();
+    bitmap_geometry_test();
+    create_failure_test();
+    trunc_test();
+    trunc_to_test();
+    fsck_test();
+    printk!("{}[PASS]{} fs tests\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// fsync 只写回目标文件的脏块 (两个数据块和 inode 所在的块), 另一个文件的数据仍留在缓存里
+fn fsync_test() {
+    printk!("[TEST] fsync\n");
+    let a = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("fsync_test: inode_create failed");
+    let b = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("fsync_test: inode_create failed");
+    let buf = [0x5au8; 100];
+    for ip in [&mut *a, &mut *b] {
+        inode::inode_write_data(ip, 0, 100, &buf);
+        inode::inode_write_data(ip, BSIZE as u32, 100, &buf);
+    }
+    let writes = virtio::disk::write_count();
+    inode::inode_sync(a);
+    let synced = virtio::disk::write_count() - writes;
+    assert_eq!(synced, 3, "fsync_test: fsync wrote {} blocks, expected 3", synced);
+    assert!(
+        buffer::is_dirty(0, b.disk.index[0]) && buffer::is_dirty(0, b.disk.index[1]),
+        "fsync_test: fsync flushed another file's blocks"
+    );
+    let writes = virtio::disk::write_count();
+    inode::inode_sync(a);
+    assert_eq!(virtio::disk::write_count(), writes, "fsync_test: second fsync rewrote clean blocks");
+    inode::inode_sync(b);
+    assert!(
+        !buffer::is_dirty(0, b.disk.index[0]) && !buffer::is_dirty(0, b.disk.index[1]),
+        "fsync_test: blocks still dirty after fsync"
+    );
+    for ip in [a, b] {
+        inode::inode_discard(ip);
+    }
+    printk!("{}[PASS]{} fsync\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 两个 O_APPEND 文件交替写同一个 inode: 各自的偏移都从 0 开始, 不追加时后写的会覆盖先写的
+fn append_test() {
+    const ROUNDS: usize = 8;
+    const CHUNK: usize = 5;
+    printk!("[TEST] O_APPEND\n");
+    let ip = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("append_test: inode_create failed");
+    let inum = ip.inode_num;
+    // 两个文件各持有一个引用, 关闭时各自释放
+    inode::inode_dup(ip);
+    let files: [usize; 2] = core::array::from_fn(|_| {
+        let (f_idx, f) = file::file_alloc().expect("append_test: no free file");
+        f.ty = FileType::Inode;
+        f.inum = inum;
+        f.writable = true;
+        f.append = true;
+        f_idx
+    });
+
+    for round in 0..ROUNDS {
+        for (i, &f_idx) in files.iter().enumerate() {
+            let byte = b'A' + (2 * round + i) as u8;
+            let n = file::file_write(f_idx, CHUNK, |buf, _| {
+                buf.fill(byte);
+                Ok(())
+            });
+            assert_eq!(n, Ok(CHUNK), "append_test: short write in round {}", round);
+        }
+    }
+
+    let mut out = [0u8; 2 * ROUNDS * CHUNK];
+    let n = inode::inode_read_data(ip, 0, out.len() as u32, &mut out);
+    assert!(
+        n as usize == out.len() && ip.disk.size as usize == out.len(),
+        "append_test: file is {} bytes, expected {}",
+        ip.disk.size,
+        out.len()
+    );
+    for (k, &b) in out.iter().enumerate() {
+        assert_eq!(b, b'A' + (k / CHUNK) as u8, "append_test: byte {} overwritten", k);
+    }
+
+    ip.disk.nlink = 0;
+    for f_idx in files {
+        file::file_close(f_idx);
+    }
+    printk!("{}[PASS]{} O_APPEND\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 数据位图跨块时块号与位的换算
+fn bitmap_geometry_test() {
+    printk!("[TEST] data bitmap geometry\n");
+    let sb = *get_sb();
+    let big = SuperBlock { nblocks: SuperBlock::BITS_PER_BLOCK + 1, ..sb };
+    assert!(
+        big.bmap_blocks() == 2 && big.data_start() == sb.bmap_start + 2,
+        "bitmap_geometry_test: {} data blocks need 2 bitmap blocks",
+        big.nblocks
+    );
+    let blk = bitmap::alloc();
+    assert!(
+        blk >= sb.data_start() && blk < sb.data_start() + sb.nblocks,
+        "bitmap_geometry_test: allocated block {} outside the data region",
+        blk
+    );
+    bitmap::free(blk);
+    assert_eq!(bitmap::alloc(), blk, "bitmap_geometry_test: freed block {} was not reused", blk);
+    bitmap::free(blk);
+    printk!("{}[PASS]{} data bitmap geometry\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 创建或链接失败后位图中不留下孤儿 inode
+fn create_failure_test() {
+    printk!("[TEST] inode_create failure paths\n");
+    let before = inode::count_allocated();
+    let root = inode::inode_get(inode::ROOT_INODE);
+    let ip = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("create_failure_test: inode_create failed");
+    assert_eq!(inode::count_allocated(), before + 1, "create_failure_test: inode_create did not mark the bitmap");
+    assert_ne!(dentry::dentry_create(root, ip.inode_num, b""), 0, "create_failure_test: dentry_create accepted an empty name");
+    inode::inode_discard(ip);
+    inode::inode_put(root);
+    assert_eq!(inode::count_allocated(), before, "create_failure_test: discarded inode still allocated");
+
+    // 测试期间没有进程, 无法等待槽位: 缓存占满后 inode_create 必须在置位之前失败
+    // 占住的 inode 记在一页里, 空闲槽位多于一页能记下的数量时跳过
+    let hold = PGSIZE / size_of::<usize>();
+    let free_slots = inode::n_inode() - inode::inode_in_use();
+    if free_slots < hold {
+        let held = pmem::alloc(true) as *mut *mut inode::Inode;
+        let mut n = 0;
+        while let Ok(ip) = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0) {
+            unsafe { *held.add(n) = ip };
+            n += 1;
+        }
+        // 位图先用完时停在位图的余量处
+        let room = (get_sb().ninodes - before) as usize;
+        assert_eq!(n, free_slots.min(room), "create_failure_test: inode_create stopped early");
+        assert_eq!(inode::count_allocated(), before + n as u32, "create_failure_test: failed inode_create left an orphan inode");
+        for i in 0..n {
+            inode::inode_discard(unsafe { &mut **held.add(i) });
+        }
+        pmem::free(held as usize, true);
+        assert_eq!(inode::count_allocated(), before, "create_failure_test: inodes leaked after discard");
+        printk!("  creation failed cleanly after {} inodes\n", n);
+    } else {
+        printk!("  {} free inode slots, cache exhaustion not tested\n", free_slots);
+    }
+    printk!("{}[PASS]{} inode_create failure paths\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 写入直接块和一级间接块后的块数: 数据块之外还有一个一级间接索引块
+const TRUNC_BLOCKS: u32 = inode::INODE_INDEX_1 as u32 + 2;
+
+fn fill_scratch(ip: &mut inode::Inode) {
+    let buf = [0xa5u8; 16];
+    for lbn in 0..TRUNC_BLOCKS {
+        inode::inode_write_data(ip, lbn * BSIZE as u32, buf.len() as u32, &buf);
+    }
+}
+
+// 截断释放直接块, 一级间接块及其索引块; 对空文件再截断不改变位图
+fn trunc_test() {
+    printk!("[TEST] inode_trunc\n");
+    let before = bitmap::count_allocated();
+    let ip = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("trunc_test: inode_create failed");
+    fill_scratch(ip);
+    assert_eq!(bitmap::count_allocated(), before + TRUNC_BLOCKS + 1, "trunc_test: wrong number of blocks allocated");
+    inode::inode_trunc(ip);
+    assert!(
+        bitmap::count_allocated() == before && ip.disk.size == 0 && ip.disk.index.iter().all(|&b| b == 0),
+        "trunc_test: {} blocks allocated after truncate, expected {}",
+        bitmap::count_allocated(),
+        before
+    );
+    inode::inode_trunc(ip);
+    assert!(
+        bitmap::count_allocated() == before && ip.disk.size == 0,
+        "trunc_test: truncating an empty file changed the bitmap"
+    );
+    inode::inode_discard(ip);
+    printk!("{}[PASS]{} inode_trunc\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 截断到指定长度; 变长不分配块, 变短释放多余的块和变空的索引块, 保留块的尾部清零
+fn trunc_to_test() {
+    printk!("[TEST] inode_trunc_to\n");
+    let before = bitmap::count_allocated();
+    let ip = inode::inode_create(inode::INODE_TYPE_DATA, 0, 0).expect("trunc_to_test: inode_create failed");
+    fill_scratch(ip);
+    let long = 4 * TRUNC_BLOCKS * BSIZE as u32;
+    inode::inode_trunc_to(ip, long);
+    assert!(
+        bitmap::count_allocated() == before + TRUNC_BLOCKS + 1 && ip.disk.size == long,
+        "trunc_to_test: extending allocated blocks ({} allocated, expected {})",
+        bitmap::count_allocated(),
+        before + TRUNC_BLOCKS + 1
+    );
+    // 只保留全部直接块和第一个间接数据块
+    inode::inode_trunc_to(ip, inode::INODE_INDEX_1 as u32 * BSIZE as u32 + 5);
+    assert_eq!(
+        bitmap::count_allocated(),
+        before + inode::INODE_INDEX_1 as u32 + 2,
+        "trunc_to_test: wrong number of blocks after shrinking into the indirect level"
+    );
+    inode::inode_trunc_to(ip, 5);
+    assert!(
+        bitmap::count_allocated() == before + 1 && ip.disk.index[inode::INODE_INDEX_1] == 0,
+        "trunc_to_test: {} blocks allocated after shrink, expected {}",
+        bitmap::count_allocated(),
+        before + 1
+    );
+    inode::inode_trunc_to(ip, 16);
+    let mut out = [0xffu8; 16];
+    inode::inode_read_data(ip, 0, out.len() as u32, &mut out);
+    assert!(
+        out[..5].iter().all(|&b| b == 0xa5) && out[5..].iter().all(|&b| b == 0),
+        "trunc_to_test: content after shrink and extend is {:?}",
+        out
+    );
+    inode::inode_discard(ip);
+    assert_eq!(bitmap::count_allocated(), before, "trunc_to_test: blocks leaked");
+    printk!("{}[PASS]{} inode_trunc_to\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 新镜像应当没有任何问题; fsck 发现故意泄漏的块并在修复模式下把它还给位图
+fn fsck_test() {
+    printk!("[TEST] fsck\n");
+    let before = fsck::fsck(false);
+    assert_eq!(before.problems(), 0, "fsck_test: fresh image has {} problems", before.problems());
+    let blk = bitmap::alloc();
+    let leaked = fsck::fsck(false);
+    assert_eq!(leaked.leaked, 1, "fsck_test: {} leaked blocks reported, expected 1", leaked.leaked);
+    let repaired = fsck::fsck(true);
+    assert!(
+        repaired.repaired == 1 && !bitmap::is_allocated(blk),
+        "fsck_test: leaked block {} not repaired",
+        blk
+    );
+    assert_eq!(fsck::fsck(false).problems(), 0, "fsck_test: problems remain after repair");
+    printk!("{}[PASS]{} fsck\n", ANSI_GREEN, ANSI_RESET);
+}
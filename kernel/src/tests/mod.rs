@@ -1,8 +1,12 @@
 mod barrier;
+mod buffer;
+mod dtb;
+mod fs;
 mod mmaprepo;
 mod pmem;
 mod printk;
 mod run;
+mod sched;
 mod spinlock;
 mod syscall;
 mod trap;
@@ -12,6 +16,11 @@ pub fn test(hartid: usize) {
     run::run_tests(hartid);
 }
 
+// 所有 hart 都离开启动栈后, 由回收启动栈的 hart 调用
+pub fn test_boot_stacks_reclaimed() {
+    pmem::boot_stacks_reclaimed_test();
+}
+
 pub fn test_user(hartid: usize) {
     run::run_tests_user(hartid);
 }
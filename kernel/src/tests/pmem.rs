@@ -4,6 +4,7 @@ use core::cmp;
 use core::hint::spin_loop;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+use crate::drivers::virtio;
 use crate::dtb;
 use crate::hart::MAX_HARTS;
 use crate::mem::PGSIZE;
@@ -43,9 +44,16 @@ impl HartSlotTable {
 unsafe impl Sync for HartSlotTable {}
 
 static PAGE_SLOTS: HartSlotTable = HartSlotTable::new();
+// 每个 hart 进入测试时的栈指针, 此时仍在启动栈上
+static HART_SP: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
 
 pub fn run(hartid: usize) {
     printk!("{}[TEST]{} PMEM test started on hart {}\n", ANSI_YELLOW, ANSI_RESET, hartid);
+    let sp: usize;
+    unsafe { core::arch::asm!("mv {}, sp", out(reg) sp) };
+    if hartid < MAX_HARTS {
+        HART_SP[hartid].store(sp, Ordering::Release);
+    }
     kernel_concurrent_alloc_test(hartid);
 
     if hartid == 0 {
@@ -55,6 +63,9 @@ pub fn run(hartid: usize) {
         }
         // 再进行 user region 测试，避免与并发阶段重叠
         user_region_validation();
+        boot_stack_reclaim_test();
+        reserved_overlap_test();
+        virtio_ring_oom_test();
         printk!("{}[PASS]{} PMEM test\n", ANSI_GREEN, ANSI_RESET);
         ALL_DONE.store(true, Ordering::Release);
     } else {
@@ -250,3 +261,115 @@ fn exhaust_user_region() -> bool {
 
     count > 0
 }
+
+unsafe extern "C" {
+    static __text_start: u8;
+    static __alloc_start: u8;
+}
+
+// 回收的启动栈不在任何 hart 的栈上, 并且属于内核区
+fn boot_stack_reclaim_test() {
+    let Some((begin, end)) = pmem::reclaimed_boot_stacks() else {
+        printk!("pmem: no boot stacks reclaimed ({} harts)\n", dtb::hart_count());
+        return;
+    };
+    for hart in 0..cmp::min(dtb::hart_count(), MAX_HARTS) {
+        let sp = HART_SP[hart].load(Ordering::Acquire);
+        assert!(sp != 0, "pmem: hart {} did not record its stack", hart);
+        // 栈向下增长, sp 落在 (begin, end] 内就说明它在用回收的内存
+        assert!(
+            sp <= begin || sp > end,
+            "pmem: hart {} sp {:#x} in reclaimed boot stacks [{:#x}, {:#x})",
+            hart, sp, begin, end
+        );
+    }
+    assert_eq!(pmem::get_region(begin), Some(true));
+    assert_eq!(pmem::get_region(end - PGSIZE), Some(true));
+    pmem::audit_refcounts().expect("pmem: refcounts inconsistent after reclaiming boot stacks");
+    printk!("pmem: boot stacks [{:#x}, {:#x}) reclaimed\n", begin, end);
+}
+
+// 所有 hart 都已切到自己的内核栈: 整个启动栈区域并入内核区, 各 hart 测试时所在的启动栈都在其中, 当前栈不在
+pub fn boot_stacks_reclaimed_test() {
+    let Some((begin, end)) = pmem::reclaimed_boot_stacks() else {
+        printk!("pmem: boot stacks kept ({} harts)\n", dtb::hart_count());
+        return;
+    };
+    let sp: usize;
+    unsafe { core::arch::asm!("mv {}, sp", out(reg) sp) };
+    assert!(sp <= begin || sp > end, "pmem: sp {:#x} still in the boot stacks [{:#x}, {:#x})", sp, begin, end);
+    for hart in 0..cmp::min(dtb::hart_count(), MAX_HARTS) {
+        let boot_sp = HART_SP[hart].load(Ordering::Acquire);
+        assert!(
+            boot_sp > begin && boot_sp <= end,
+            "pmem: boot stack of hart {} at {:#x} not reclaimed [{:#x}, {:#x})",
+            hart, boot_sp, begin, end
+        );
+    }
+    assert_eq!(pmem::get_region(begin), Some(true));
+    assert_eq!(pmem::get_region(end - PGSIZE), Some(true));
+    printk!("{}[PASS]{} boot stacks [{:#x}, {:#x}) reclaimed after all harts left them\n", ANSI_GREEN, ANSI_RESET, begin, end);
+}
+
+// 取空两个分区, 确认除回收的启动栈外没有任何页落在内核镜像或设备树上, 回收的页全部能分配到
+fn reserved_overlap_test() {
+    let kstart = unsafe { &__text_start as *const u8 as usize };
+    let kend = unsafe { &__alloc_start as *const u8 as usize };
+    let dtb = dtb::dtb_range();
+    let overlaps = |page: usize, start: usize, end: usize| page < end && start < page + PGSIZE;
+    let (rbegin, rend) = pmem::reclaimed_boot_stacks().unwrap_or((0, 0));
+
+    for for_kernel in [true, false] {
+        let mut head: usize = 0;
+        let mut count = 0usize;
+        let mut reclaimed = 0usize;
+        while let Some(page) = pmem::try_alloc(for_kernel) {
+            let page = page as usize;
+            let in_reclaimed = page >= rbegin && page < rend;
+            reclaimed += in_reclaimed as usize;
+            assert!(
+                in_reclaimed || !overlaps(page, kstart, kend),
+                "pmem: page {:#x} overlaps kernel image [{:#x}, {:#x})",
+                page, kstart, kend
+            );
+            if let Some(r) = dtb {
+                assert!(
+                    !overlaps(page, r.start, r.end()),
+                    "pmem: page {:#x} overlaps DTB [{:#x}, {:#x})",
+                    page, r.start, r.end()
+                );
+            }
+            unsafe { core::ptr::write(page as *mut usize, head) };
+            head = page;
+            count += 1;
+        }
+        let mut node = head;
+        while node != 0 {
+            let next = unsafe { core::ptr::read(node as *const usize) };
+            pmem::free(node, for_kernel);
+            node = next;
+        }
+        if for_kernel {
+            assert_eq!(reclaimed, (rend - rbegin) / PGSIZE, "pmem: reclaimed boot stack pages not all allocable");
+        }
+        printk!("pmem: {} {} pages checked against reserved ranges\n", count, if for_kernel { "kernel" } else { "user" });
+    }
+}
+
+// 取空内核分区后 virtio 环分配应返回 Err 并打印诊断, 而不是在 pmem 中 panic
+fn virtio_ring_oom_test() {
+    let mut head: usize = 0;
+    while let Some(page) = pmem::try_alloc(true) {
+        unsafe { core::ptr::write(page as *mut usize, head) };
+        head = page as usize;
+    }
+    assert!(virtio::disk::alloc_ring().is_err(), "virtio: ring alloc should fail when pmem is exhausted");
+    let mut node = head;
+    while node != 0 {
+        let next = unsafe { core::ptr::read(node as *const usize) };
+        pmem::free(node, true);
+        node = next;
+    }
+    assert!(virtio::disk::alloc_ring().is_ok(), "virtio: ring alloc should succeed after pages are freed");
+    printk!("pmem: virtio ring allocation failure reported\n");
+}
@@ -14,10 +14,14 @@ pub fn run_tests(hartid: usize) {
     vm::switch_off(hartid); // 关闭 VM，确保测试在非分页环境下运行
     super::spinlock::run(hartid);
     super::printk::run(hartid);
+    super::dtb::run(hartid);
     super::pmem::run(hartid);
     super::mmaprepo::run(hartid);
     super::trap::run(hartid);
     super::vm::run(hartid);
+    super::sched::run(hartid);
+    super::buffer::run(hartid);
+    super::fs::run(hartid);
     // 最终同步：所有测试结束后再统一进入 main loop
     // 初始化（任意先到可执行）；如果已经 init 则忽略
     FINAL_BARRIER.ensure_inited(crate::dtb::hart_count());
@@ -0,0 +1,247 @@
+use super::barrier::MultiCoreTestBarrier;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::{sie, sstatus};
+
+use crate::dtb;
+use crate::hart;
+use crate::mem::pmem;
+use crate::printk;
+use crate::printk::{ANSI_GREEN, ANSI_RESET, ANSI_YELLOW};
+use crate::proc::runnable_queue;
+use crate::proc::table::NPROC;
+
+static SCHED_BARRIER: MultiCoreTestBarrier = MultiCoreTestBarrier::new();
+
+pub fn run(hartid: usize) {
+    SCHED_BARRIER.ensure_inited(dtb::hart_count());
+    if hartid == 0 {
+        SCHED_BARRIER.init(dtb::hart_count());
+        printk!("[TEST] Sched test start ({} harts)\n", SCHED_BARRIER.total());
+    }
+    SCHED_BARRIER.wait_start();
+    if hartid == 0 {
+        runnable_count_test();
+        yield_fairness_test();
+        load_balance_test();
+    }
+    ipi_wakeup_test(hartid);
+    icache_sync_test(hartid);
+    if SCHED_BARRIER.finish_and_last() {
+        printk!("{}[PASS]{} Sched test ({} harts)\n", ANSI_GREEN, ANSI_RESET, SCHED_BARRIER.total());
+    }
+}
+
+// 测试在创建 init 进程之前运行, 这里直接操作空闲槽位的 runnable 位
+fn runnable_count_test() {
+    printk!("[TEST] runnable count\n");
+    for i in 0..NPROC {
+        runnable_queue::mark_runnable(i);
+    }
+    assert_eq!(runnable_queue::runnable_count(), NPROC, "all slots runnable");
+
+    // 全部阻塞
+    for i in 0..NPROC {
+        runnable_queue::mark_not_runnable(i);
+    }
+    assert_eq!(runnable_queue::runnable_count(), 0, "count should drop to zero");
+    assert!(!runnable_queue::has_runnable(), "bitmap should be empty");
+
+    // 唤醒一个, 重复标记不能重复计数
+    runnable_queue::mark_runnable(3);
+    runnable_queue::mark_runnable(3);
+    assert_eq!(runnable_queue::runnable_count(), 1, "one woken slot");
+    assert_eq!(runnable_queue::find_runnable(), Some(3));
+
+    // 调度器取走 (Running) 后不再计入
+    runnable_queue::clear_runnable_bit(3);
+    runnable_queue::clear_runnable_bit(3);
+    assert_eq!(runnable_queue::runnable_count(), 0, "running slot not counted");
+    printk!("{}[PASS]{} runnable count\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 模拟三个进程反复 yield: 每次取走的槽位立刻重新标记为就绪, 三者应轮流运行
+fn yield_fairness_test() {
+    printk!("[TEST] yield fairness\n");
+    const SLOTS: [usize; 3] = [5, 6, 40];
+    const ROUNDS: usize = 300;
+    for &i in SLOTS.iter() {
+        runnable_queue::mark_runnable(i);
+    }
+
+    let mut turns = [0usize; 3];
+    let mut last = usize::MAX;
+    for _ in 0..ROUNDS {
+        let idx = runnable_queue::find_runnable().expect("yield fairness: nothing runnable");
+        assert!(idx != last, "yield fairness: slot {} rerun while peers were runnable", idx);
+        last = idx;
+        runnable_queue::clear_runnable_bit(idx);
+        let t = SLOTS.iter().position(|&s| s == idx).expect("yield fairness: unexpected slot");
+        turns[t] += 1;
+        // yield_proc: 仍然就绪, 回到队尾
+        runnable_queue::mark_runnable(idx);
+    }
+
+    let min = *turns.iter().min().unwrap();
+    let max = *turns.iter().max().unwrap();
+    assert!(max - min <= 1, "yield fairness: uneven turns {:?}", turns);
+
+    for &i in SLOTS.iter() {
+        runnable_queue::mark_not_runnable(i);
+    }
+    assert_eq!(runnable_queue::runnable_count(), 0, "yield fairness: slots left runnable");
+    printk!("{}[PASS]{} yield fairness\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 8 个槽位都记在 hart 0 上, 其中一个只允许在 hart 0 运行; 以 hart 1 的身份反复取进程:
+// hart 0 忙碌时只偷到它积压降到阈值以下为止, 且不碰绑定的槽位; hart 0 空闲后剩下的也可以偷
+fn load_balance_test() {
+    printk!("[TEST] load balance\n");
+    const SLOTS: core::ops::Range<usize> = 10..18;
+    const PINNED: usize = 10;
+    const THIEF: usize = 1;
+    assert!(!hart::is_idle(0), "load balance: hart 0 is running this test");
+
+    for i in SLOTS {
+        runnable_queue::set_home(i, 0);
+        runnable_queue::mark_runnable(i);
+    }
+    runnable_queue::set_affinity(PINNED, 1 << 0);
+
+    let mut stolen = 0;
+    while let Some(i) = runnable_queue::find_runnable_for(THIEF) {
+        assert!(SLOTS.contains(&i), "load balance: unexpected slot {}", i);
+        assert!(i != PINNED, "load balance: pinned slot ran on hart {}", THIEF);
+        runnable_queue::clear_runnable_bit(i);
+        runnable_queue::set_home(i, THIEF);
+        stolen += 1;
+    }
+    // 积压降到 1 (只剩绑定的槽位) 时停止
+    assert_eq!(stolen, SLOTS.len() - 1, "load balance: stole {} slots", stolen);
+    assert_eq!(runnable_queue::find_runnable_for(0), Some(PINNED), "load balance: owner lost its slot");
+    runnable_queue::clear_runnable_bit(PINNED);
+
+    // 只积压一个时忙碌的 hart 留着自己跑, 空闲时则让出去
+    runnable_queue::set_home(11, 0);
+    runnable_queue::mark_runnable(11);
+    assert_eq!(runnable_queue::find_runnable_for(THIEF), None, "load balance: stole below threshold");
+    hart::set_idle(true);
+    let taken = runnable_queue::find_runnable_for(THIEF);
+    hart::set_idle(false);
+    assert_eq!(taken, Some(11), "load balance: work left on an idle hart");
+
+    for i in SLOTS {
+        runnable_queue::mark_not_runnable(i);
+        runnable_queue::reset_slot(i);
+    }
+    assert_eq!(runnable_queue::runnable_count(), 0, "load balance: slots left runnable");
+    printk!("{}[PASS]{} load balance ({} stolen)\n", ANSI_GREEN, ANSI_RESET, stolen);
+}
+
+static IPI_READY: AtomicBool = AtomicBool::new(false);
+static IPI_WOKEN: AtomicBool = AtomicBool::new(false);
+
+// hart 1 在 wfi 中空闲, hart 0 通过 kick_idle_harts 发 IPI, 确认 hart 1 及时醒来
+fn ipi_wakeup_test(hartid: usize) {
+    if dtb::hart_count() < 2 {
+        if hartid == 0 {
+            printk!("{}[SKIP]{} ipi wakeup: needs at least 2 harts\n", ANSI_YELLOW, ANSI_RESET);
+        }
+        return;
+    }
+    match hartid {
+        0 => {
+            printk!("[TEST] ipi wakeup\n");
+            while !IPI_READY.load(Ordering::Acquire) {
+                spin_loop();
+            }
+            hart::kick_idle_harts(1);
+            let mut spins = 0usize;
+            while !IPI_WOKEN.load(Ordering::Acquire) {
+                spins += 1;
+                assert!(spins < 100_000_000, "ipi wakeup: hart 1 did not wake up");
+                spin_loop();
+            }
+            printk!("{}[PASS]{} ipi wakeup\n", ANSI_GREEN, ANSI_RESET);
+        }
+        1 => {
+            let base = hart::ipi_count(1);
+            unsafe {
+                sie::set_ssoft();
+                sstatus::set_sie();
+            }
+            hart::set_idle(true);
+            IPI_READY.store(true, Ordering::Release);
+            while hart::ipi_count(1) == base {
+                riscv::asm::wfi();
+            }
+            hart::set_idle(false);
+            unsafe {
+                sstatus::clear_sie();
+                sie::clear_ssoft();
+            }
+            IPI_WOKEN.store(true, Ordering::Release);
+        }
+        _ => {}
+    }
+}
+
+static CODE_PA: AtomicUsize = AtomicUsize::new(0);
+static CODE_ROUND: AtomicUsize = AtomicUsize::new(0);
+static CODE_RESULT: AtomicUsize = AtomicUsize::new(0);
+
+// addi a0, zero, imm; ret
+fn write_return_imm(pa: usize, imm: u32) {
+    let code = pa as *mut u32;
+    unsafe {
+        code.write_volatile((imm << 20) | (10 << 7) | 0x13);
+        code.add(1).write_volatile(0x0000_8067);
+    }
+}
+
+// hart 0 写代码页, hart 1 执行: 改写后 hart 1 在 sync_icache 之后必须执行到新指令。
+// 测试时未开分页, 物理页可以直接执行
+fn icache_sync_test(hartid: usize) {
+    if dtb::hart_count() < 2 {
+        if hartid == 0 {
+            printk!("{}[SKIP]{} icache sync: needs at least 2 harts\n", ANSI_YELLOW, ANSI_RESET);
+        }
+        return;
+    }
+    let wait_for = |atomic: &AtomicUsize, value: usize, what: &str| {
+        let mut spins = 0usize;
+        while atomic.load(Ordering::Acquire) != value {
+            spins += 1;
+            assert!(spins < 100_000_000, "icache sync: timed out waiting for {}", what);
+            spin_loop();
+        }
+    };
+    match hartid {
+        0 => {
+            printk!("[TEST] icache sync\n");
+            let pa = pmem::alloc(true) as usize;
+            for (round, imm) in [(1, 42), (2, 43)] {
+                write_return_imm(pa, imm);
+                hart::flush_icache();
+                CODE_PA.store(pa, Ordering::Release);
+                CODE_ROUND.store(round, Ordering::Release);
+                wait_for(&CODE_RESULT, imm as usize, "hart 1 to run the new code");
+            }
+            CODE_ROUND.store(0, Ordering::Release);
+            pmem::free(pa, true);
+            printk!("{}[PASS]{} icache sync\n", ANSI_GREEN, ANSI_RESET);
+        }
+        1 => {
+            for round in [1, 2] {
+                wait_for(&CODE_ROUND, round, "hart 0 to write code");
+                let before = hart::get().icache_gen;
+                hart::sync_icache();
+                assert!(hart::get().icache_gen > before, "icache sync: hart 1 did not see the new generation");
+                let f: extern "C" fn() -> usize = unsafe { core::mem::transmute(CODE_PA.load(Ordering::Acquire)) };
+                CODE_RESULT.store(f(), Ordering::Release);
+            }
+            wait_for(&CODE_ROUND, 0, "hart 0 to finish");
+        }
+        _ => {}
+    }
+}
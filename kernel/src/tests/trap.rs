@@ -1,14 +1,136 @@
 use super::barrier::MultiCoreTestBarrier;
 use crate::dtb;
 use crate::irq::timer;
+#[cfg(debug_assertions)]
+use crate::irq::TrapFrame;
 use crate::printk;
 use crate::printk::{ANSI_GREEN, ANSI_RESET, ANSI_YELLOW};
-use riscv::register::sie;
+use riscv::register::{sie, time};
 
 /// 运行时钟滴答测试和 UART 输出测试
 pub fn run(hartid: usize) {
+    // 必须在 timer_tick_test 之前: 它结束时会关闭 sie.STIE
+    interrupt_state_test(hartid);
     timer_tick_test(hartid);
     uart_output_test(hartid);
+    if hartid == 0 {
+        breakpoint_resume_test();
+        nanosleep_test();
+        #[cfg(debug_assertions)]
+        trapframe_canary_test();
+        #[cfg(feature = "vectored-trap")]
+        vectored_trap_test();
+    }
+}
+
+// 向量模式: 时钟中断经 kernel_stip_entry 直达处理函数, 节拍照常前进;
+// 异常仍从表的第 0 项进入 kernel_vector, ebreak 被计数并跳过
+#[cfg(feature = "vectored-trap")]
+fn vectored_trap_test() {
+    use crate::irq::trap::stat::{self, NCAUSE};
+    use crate::irq::vector;
+    use riscv::register::stvec::{self, TrapMode};
+
+    printk!("[TEST] vectored trap dispatch\n");
+    let vec = stvec::read();
+    assert!(matches!(vec.trap_mode(), TrapMode::Vectored), "stvec not in vectored mode");
+    assert_eq!(vec.address(), vector::kernel_vector_table as *const () as usize, "stvec points elsewhere");
+
+    let before = stat::snapshot();
+    unsafe {
+        sie::set_stimer();
+    }
+    let start = timer::uptime_ticks();
+    while timer::uptime_ticks() < start + 2 {
+        core::hint::spin_loop();
+    }
+    unsafe {
+        sie::clear_stimer();
+        core::arch::asm!(".4byte 0x00100073"); // ebreak
+    }
+    let after = stat::snapshot();
+    let timer_irqs = after[NCAUSE + 5] - before[NCAUSE + 5];
+    assert!(timer_irqs >= 2, "only {} timer interrupts counted", timer_irqs);
+    assert_eq!(after[3] - before[3], 1, "breakpoint did not reach the exception path");
+    printk!("{}[PASS]{} vectored trap dispatch ({} timer interrupts)\n", ANSI_GREEN, ANSI_RESET, timer_irqs);
+}
+
+// ebreak (4 字节) 与 c.ebreak (2 字节) 之后都必须恰好回到下一条指令
+fn breakpoint_resume_test() {
+    printk!("[TEST] breakpoint resume\n");
+    let hits: usize;
+    unsafe {
+        core::arch::asm!(
+            ".option push",
+            ".option norvc",
+            "li {0}, 0",
+            ".4byte 0x00100073", // ebreak
+            "addi {0}, {0}, 1",
+            ".2byte 0x9002", // c.ebreak
+            "addi {0}, {0}, 2",
+            ".option pop",
+            out(reg) hits,
+        );
+    }
+    assert_eq!(hits, 3, "breakpoint resumed at the wrong instruction");
+    printk!("{}[PASS]{} breakpoint resume\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 启动阶段没有进程, 不足一个节拍的睡眠走忙等路径, 用 time CSR 检查至少过了 500µs
+fn nanosleep_test() {
+    printk!("[TEST] nanosleep\n");
+    let freq = dtb::timebase_frequency();
+    let cycles = timer::ns_to_cycles(500_000);
+    assert_eq!(cycles, freq / 2000, "500us at {} Hz", freq);
+    let start = time::read();
+    timer::nanosleep(500_000);
+    let elapsed = time::read() - start;
+    assert!(elapsed >= cycles as usize, "nanosleep returned after {} cycles", elapsed);
+    // 超长睡眠的换算不能溢出
+    let huge = timer::ns_to_cycles(u64::MAX);
+    assert!(huge >= u64::MAX / 1_000_000_000 * freq, "ns_to_cycles overflowed: {}", huge);
+    // 节拍长度按 timebase 换算, 不再是写死的计数
+    assert_eq!(timer::interval(), freq * timer::TICK_MS as u64 / 1000);
+    printk!("{}[PASS]{} nanosleep ({} cycles)\n", ANSI_GREEN, ANSI_RESET, elapsed);
+}
+
+// 模拟汇编少算了 canary 一项: 按旧偏移 40 写 ra 会正好落在 canary 上
+#[cfg(debug_assertions)]
+fn trapframe_canary_test() {
+    printk!("[TEST] trapframe canary\n");
+    let mut tf = TrapFrame::new();
+    assert!(tf.canary_intact(), "fresh TrapFrame must carry the canary");
+    assert_eq!(tf.canary_overwrite(), None);
+    unsafe {
+        let base = &mut tf as *mut TrapFrame as *mut u8;
+        (base.add(40) as *mut usize).write(0x1234);
+    }
+    assert!(!tf.canary_intact(), "overwritten canary was not detected");
+    // check_canary 据此 panic, 报告的正是越界写入的值
+    assert_eq!(tf.canary_overwrite(), Some(0x1234), "overwrite not reported");
+    assert_eq!(tf.ra, 0, "stray write must hit the canary, not ra");
+    printk!("{}[PASS]{} trapframe canary\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// init_hart 之后每个 hart 都应打开时钟/外部中断, 并在 PLIC 上使能 UART 和 virtio 中断源
+fn interrupt_state_test(hartid: usize) {
+    use crate::drivers::virtio;
+    use crate::irq::trap;
+
+    if hartid == 0 {
+        printk!("[TEST] interrupt state after init_hart\n");
+    }
+    let st = trap::dump_interrupt_state(hartid);
+    assert!(st.irq_enabled(), "hart {}: sstatus.SIE clear", hartid);
+    assert!(st.timer_enabled(), "hart {}: sie.STIE clear", hartid);
+    assert!(st.external_enabled(), "hart {}: sie.SEIE clear", hartid);
+    assert_eq!(st.plic_threshold, 0, "hart {}: PLIC threshold masks all sources", hartid);
+    assert!(st.plic_source_enabled(10), "hart {}: UART source not enabled", hartid);
+    assert!(st.plic_source_enabled(virtio::irq()), "hart {}: virtio disk source not enabled", hartid);
+    assert_ne!(st.stvec & !0b11, 0, "hart {}: stvec not set", hartid);
+    if hartid == 0 {
+        printk!("{}[PASS]{} interrupt state after init_hart\n", ANSI_GREEN, ANSI_RESET);
+    }
 }
 
 fn timer_tick_test(hartid: usize) {
@@ -31,13 +153,15 @@ fn timer_tick_test(hartid: usize) {
     while TIMER_BARRIER.total() == 0 {}
     TIMER_BARRIER.wait_start();
 
-    let base = timer::get_ticks();
+    let base = timer::uptime_ticks();
     let mut last = base;
+    let ms_base = timer::uptime_ms();
+    let time_base = time::read();
 
     const TICKS_TO_WAIT: usize = 10;
     for _ in 0..TICKS_TO_WAIT {
         loop {
-            let cur = timer::get_ticks();
+            let cur = timer::uptime_ticks();
             if cur > last {
                 last = cur;
                 break;
@@ -48,6 +172,14 @@ fn timer_tick_test(hartid: usize) {
         printk!("[hart {}] di da, ticks={}\n", hartid, delta);
     }
 
+    // 节拍换算出的毫秒数应与 time CSR 的实际流逝大致一致, 允许一个多节拍的误差
+    if hartid == 0 {
+        let uptime = timer::uptime_ms() - ms_base;
+        let elapsed = (time::read() - time_base) / (dtb::timebase_frequency() / 1000) as usize;
+        assert!(uptime >= TICKS_TO_WAIT * 100, "uptime advanced only {} ms over {} ticks", uptime, TICKS_TO_WAIT);
+        assert!(uptime.abs_diff(elapsed) <= 150, "uptime {} ms vs time CSR {} ms", uptime, elapsed);
+    }
+
     if TIMER_BARRIER.finish_and_last() {
         printk!("{}[PASS]{} Timer tick test", ANSI_GREEN, ANSI_RESET);
         unsafe {
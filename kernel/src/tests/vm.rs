@@ -1,11 +1,17 @@
 use super::barrier::MultiCoreTestBarrier;
 use crate::dtb;
+use crate::hart;
 use crate::mem::addr::PhysAddr;
 use crate::mem::pagetable::PageTable;
+use crate::mem::mmap::MmapIndex;
 use crate::mem::pmem;
-use crate::mem::pte::{self, PTE_R, PTE_W, PTE_X, pte_to_pa};
-use crate::mem::vm;
-use crate::mem::{PGSIZE, VA_MAX};
+use crate::mem::pte::{self, PTE_A, PTE_COW, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X, pte_to_pa};
+use crate::mem::uvm;
+use crate::mem::vm::{self, KernelStack};
+use crate::mem::frame::PhysFrame;
+use crate::proc::Process;
+use crate::proc::elf;
+use crate::mem::{MMAP_BEGIN, MMAP_END, PGSIZE, VA_MAX};
 use crate::printk;
 use crate::printk::{ANSI_GREEN, ANSI_RESET, ANSI_YELLOW};
 
@@ -21,6 +27,27 @@ pub fn run(hartid: usize) {
     if hartid == 0 {
         //vm_func_test();
         vm_mapping_test();
+        vm_zero_page_test();
+        copyout_cow_test();
+        vm_satp_test();
+        vm_madvise_test();
+        vm_mmap_index_test();
+        vm_mmap_hint_test();
+        pte_display_test();
+        vm_exec_release_test();
+        fork_refcount_audit_test();
+        #[cfg(debug_assertions)]
+        page_table_accessor_test();
+        kmap_temporary_test(hartid);
+        uaccess_fault_test();
+        elf_load_range_test();
+        trampoline_mapping_test();
+        kernel_mappings_test();
+        #[cfg(debug_assertions)]
+        kernel_mappings_corrupt_test();
+        kstack_guard_test(hartid);
+        #[cfg(feature = "sv48")]
+        sv48_high_va_test(hartid);
     }
     if VM_BARRIER.finish_and_last() {
         printk!("{}[PASS]{} VM test ({} harts)\n", ANSI_GREEN, ANSI_RESET, VM_BARRIER.total());
@@ -150,3 +177,641 @@ fn vm_mapping_test() {
 
     printk!("vm_mapping_test passed!\n");
 }
+
+fn vm_zero_page_test() {
+    printk!("--- vm_zero_page_test ---\n");
+
+    const NPAGES: usize = 1000;
+    let pgtbl = pmem::alloc(true) as *mut PageTable;
+    let table = unsafe { &mut *pgtbl };
+    let mut regions = MmapIndex::new();
+    let zero = pmem::zero_frame();
+
+    // 1. 映射 1000 页但不写入: 用户区不应消耗任何物理页
+    let before = pmem::user_region_info().allocable;
+    let va = uvm::mmap(table, &mut regions, 0, NPAGES * PGSIZE, 0, MMAP_BEGIN, MMAP_END)
+        .expect("vm_zero_page_test: mmap failed");
+    assert_eq!(pmem::user_region_info().allocable, before, "vm_zero_page_test: mmap consumed user pages");
+    for i in 0..NPAGES {
+        let pte = unsafe { *table.lookup(va + i * PGSIZE).expect("vm_zero_page_test: pte missing") };
+        assert_eq!(pte_to_pa(pte), zero, "vm_zero_page_test: page {} not on zero frame", i);
+        assert!(pte::get_flags(pte) & PTE_W == 0, "vm_zero_page_test: zero frame mapped writable");
+    }
+
+    // 2. 写一页: 只分配一个私有页, 且内容为零
+    let target = va + 7 * PGSIZE;
+    uvm::cow_fault(table, target).expect("vm_zero_page_test: cow fault failed");
+    assert_eq!(pmem::user_region_info().allocable, before - 1, "vm_zero_page_test: cow allocated wrong count");
+    let pte = unsafe { *table.lookup(target).unwrap() };
+    let pa = pte_to_pa(pte);
+    assert!(pa != zero, "vm_zero_page_test: page still on zero frame");
+    assert!(pte::get_flags(pte) & PTE_W != 0 && pte::get_flags(pte) & PTE_COW == 0);
+    assert!(is_zeroed(pa), "vm_zero_page_test: private page not zeroed");
+    assert!(is_zeroed(zero), "vm_zero_page_test: zero frame dirtied");
+
+    // 3. fork 复制页表: 零页上的页 (含不带写时复制标记的只读 BSS 页) 继续共享零页, 只复制写过的那一页
+    let bss = PGSIZE;
+    vm::mappages(table, bss, zero, PGSIZE, PTE_U | PTE_R | PTE_A);
+    let child_pa = table.copy().expect("vm_zero_page_test: copy failed");
+    let child = unsafe { &mut *(child_pa as *mut PageTable) };
+    assert_eq!(pmem::user_region_info().allocable, before - 2, "vm_zero_page_test: fork copied zero-frame pages");
+    for a in [bss, va, va + (NPAGES - 1) * PGSIZE] {
+        let pte = unsafe { *child.lookup(a).expect("vm_zero_page_test: child pte missing") };
+        assert_eq!(pte_to_pa(pte), zero, "vm_zero_page_test: child page 0x{:x} not on zero frame", a);
+    }
+    assert!(pte_to_pa(unsafe { *child.lookup(target).unwrap() }) != pa, "vm_zero_page_test: private page shared");
+    child.destroy();
+    pmem::free(child_pa, true);
+    vm::unmappages(table, bss, PGSIZE, false);
+    assert_eq!(pmem::user_region_info().allocable, before - 1, "vm_zero_page_test: child pages leaked");
+
+    // 4. 解除映射后物理页全部归还, 零页保持存活
+    uvm::munmap(table, &mut regions, va, NPAGES * PGSIZE).expect("vm_zero_page_test: munmap failed");
+    assert_eq!(pmem::user_region_info().allocable, before, "vm_zero_page_test: leak after munmap");
+    assert!(regions.head().is_null());
+
+    table.destroy();
+    pmem::free(pgtbl as usize, true);
+    printk!("vm_zero_page_test passed!\n");
+}
+
+// 内核代替进程写入 (copyout) 一个仍在共享零页上的写时复制页: 先拆分出私有页再写, 不会改动零页
+fn copyout_cow_test() {
+    printk!("--- copyout_cow_test ---\n");
+    let pgtbl = pmem::alloc(true) as *mut PageTable;
+    let table = unsafe { &mut *pgtbl };
+    let mut regions = MmapIndex::new();
+    let zero = pmem::zero_frame();
+
+    let va = uvm::mmap(table, &mut regions, 0, 2 * PGSIZE, 0, MMAP_BEGIN, MMAP_END)
+        .expect("copyout_cow_test: mmap failed");
+    let before = pmem::user_region_info().allocable;
+    // 跨页写入, 两页都要拆分
+    let src = [0x7eu8; 32];
+    uvm::copyout(table, va + PGSIZE - 16, &src).expect("copyout_cow_test: copyout into a cow page failed");
+    assert_eq!(pmem::user_region_info().allocable, before - 2, "copyout_cow_test: wrong number of private pages");
+    for page in [va, va + PGSIZE] {
+        let pte = unsafe { *table.lookup(page).unwrap() };
+        assert!(pte_to_pa(pte) != zero, "copyout_cow_test: page 0x{:x} still on the zero frame", page);
+        assert!(pte::get_flags(pte) & PTE_W != 0 && pte::get_flags(pte) & PTE_COW == 0);
+    }
+    let mut back = [0u8; 32];
+    uvm::copyin(table, &mut back, va + PGSIZE - 16).expect("copyout_cow_test: copyin failed");
+    assert_eq!(back, src);
+    assert!(is_zeroed(zero), "copyout_cow_test: zero frame dirtied");
+
+    uvm::munmap(table, &mut regions, va, 2 * PGSIZE).expect("copyout_cow_test: munmap failed");
+    table.destroy();
+    pmem::free(pgtbl as usize, true);
+    printk!("copyout_cow_test passed!\n");
+}
+
+fn is_zeroed(pa: PhysAddr) -> bool {
+    let words = unsafe { core::slice::from_raw_parts(pa as *const usize, PGSIZE / 8) };
+    words.iter().all(|&w| w == 0)
+}
+
+fn vm_satp_test() {
+    printk!("[TEST] SATP encode/decode\n");
+    let root: PhysAddr = 0x8765_4000;
+    let bits = vm::make_satp(root, 0x1234);
+    assert_eq!(bits >> 60, vm::SATP_MODE as usize, "SATP mode mismatch");
+    assert_eq!(vm::satp_root(bits), root, "SATP root mismatch");
+    assert_eq!(vm::satp_asid(bits), 0x1234, "SATP asid mismatch");
+
+    // ASID 超出 16 位时被截断, 不能污染 MODE 字段
+    let bits = vm::make_satp(root, 0x1_ffff);
+    assert_eq!(bits >> 60, vm::SATP_MODE as usize, "ASID overflowed into SATP mode");
+    assert_eq!(vm::satp_asid(bits), 0xffff, "ASID not masked");
+    assert_eq!(vm::satp_root(bits), root, "SATP root corrupted by ASID");
+    printk!("{}[PASS]{} SATP encode/decode\n", ANSI_GREEN, ANSI_RESET);
+}
+
+fn vm_madvise_test() {
+    printk!("--- vm_madvise_test ---\n");
+
+    const NPAGES: usize = 8;
+    let pgtbl = pmem::alloc(true) as *mut PageTable;
+    let table = unsafe { &mut *pgtbl };
+    let mut regions = MmapIndex::new();
+    let zero = pmem::zero_frame();
+
+    let before = pmem::user_region_info().allocable;
+    let va = uvm::mmap(table, &mut regions, 0, NPAGES * PGSIZE, 0, MMAP_BEGIN, MMAP_END)
+        .expect("vm_madvise_test: mmap failed");
+
+    // 写满所有页
+    for i in 0..NPAGES {
+        uvm::cow_fault(table, va + i * PGSIZE).expect("vm_madvise_test: cow fault failed");
+        let pa = pte_to_pa(unsafe { *table.lookup(va + i * PGSIZE).unwrap() });
+        unsafe { core::ptr::write_bytes(pa as *mut u8, 0x5a, PGSIZE) };
+    }
+    assert_eq!(pmem::user_region_info().allocable, before - NPAGES);
+
+    // 超出 mmap 区域的请求被拒绝
+    assert!(uvm::madvise_dontneed(table, &regions, va, (NPAGES + 1) * PGSIZE).is_err());
+
+    // 丢弃后物理页全部归还, 再读为零
+    uvm::madvise_dontneed(table, &regions, va, NPAGES * PGSIZE).expect("vm_madvise_test: madvise failed");
+    assert_eq!(pmem::user_region_info().allocable, before, "vm_madvise_test: frames not returned");
+    for i in 0..NPAGES {
+        let pte = unsafe { *table.lookup(va + i * PGSIZE).expect("vm_madvise_test: mapping dropped") };
+        assert_eq!(pte_to_pa(pte), zero, "vm_madvise_test: page {} not back on zero frame", i);
+        assert!(pte::get_flags(pte) & PTE_COW != 0);
+    }
+    assert!(is_zeroed(zero), "vm_madvise_test: zero frame dirtied");
+
+    // 区域仍在, 可以再次写入
+    uvm::cow_fault(table, va).expect("vm_madvise_test: re-fault failed");
+    assert!(is_zeroed(pte_to_pa(unsafe { *table.lookup(va).unwrap() })), "vm_madvise_test: re-fault not zeroed");
+
+    uvm::munmap(table, &mut regions, va, NPAGES * PGSIZE).expect("vm_madvise_test: munmap failed");
+    assert_eq!(pmem::user_region_info().allocable, before, "vm_madvise_test: leak after munmap");
+
+    table.destroy();
+    pmem::free(pgtbl as usize, true);
+    printk!("vm_madvise_test passed!\n");
+}
+
+fn vm_mmap_index_test() {
+    printk!("--- vm_mmap_index_test ---\n");
+
+    const NREGION: usize = 100;
+    const STRIDE: usize = 3; // 每个区域 2 页 + 1 页空洞, 避免相邻合并
+    let pgtbl = pmem::alloc(true) as *mut PageTable;
+    let table = unsafe { &mut *pgtbl };
+    let mut regions = MmapIndex::new();
+    let region_at = |regions: &MmapIndex, va| regions.find(va).map(|r| unsafe { (*r).begin });
+
+    // 倒序插入, 确认索引与插入顺序无关
+    for i in (0..NREGION).rev() {
+        let begin = MMAP_BEGIN + i * STRIDE * PGSIZE;
+        let va = uvm::mmap(table, &mut regions, begin, 2 * PGSIZE, uvm::MAP_FIXED, MMAP_BEGIN, MMAP_END)
+            .expect("vm_mmap_index_test: mmap failed");
+        assert_eq!(va, begin);
+    }
+    assert_eq!(regions.len(), NREGION);
+
+    // 按缺页地址查区域, 并确认链表与索引顺序一致
+    let mut cur = regions.head();
+    for i in 0..NREGION {
+        let begin = MMAP_BEGIN + i * STRIDE * PGSIZE;
+        for va in [begin, begin + PGSIZE + 123, begin + 2 * PGSIZE - 1] {
+            assert_eq!(region_at(&regions, va), Some(begin), "vm_mmap_index_test: wrong region for 0x{:x}", va);
+        }
+        for va in [begin, begin + PGSIZE + 123] {
+            uvm::cow_fault(table, va).expect("vm_mmap_index_test: fault-in failed");
+        }
+        assert!(region_at(&regions, begin + 2 * PGSIZE).is_none(), "vm_mmap_index_test: hole reported mapped");
+        assert_eq!(unsafe { (*cur).begin }, begin, "vm_mmap_index_test: list out of order");
+        cur = unsafe { (*cur).next };
+    }
+    assert!(cur.is_null());
+    assert!(region_at(&regions, MMAP_BEGIN - 1).is_none());
+    assert!(regions.covers(MMAP_BEGIN, MMAP_BEGIN + 2 * PGSIZE));
+    assert!(!regions.covers(MMAP_BEGIN, MMAP_BEGIN + 3 * PGSIZE));
+
+    // 填上第 50 个区域后的空洞: 50 与 51 合并成一个区域
+    let mid = MMAP_BEGIN + 50 * STRIDE * PGSIZE;
+    uvm::mmap(table, &mut regions, mid + 2 * PGSIZE, PGSIZE, uvm::MAP_FIXED, MMAP_BEGIN, MMAP_END)
+        .expect("vm_mmap_index_test: fill hole failed");
+    assert_eq!(regions.len(), NREGION - 1);
+    assert_eq!(region_at(&regions, mid + 4 * PGSIZE), Some(mid));
+    assert!(regions.covers(mid, mid + 5 * PGSIZE));
+
+    // 再在合并后的区域中间打洞: 区域被拆成两半
+    uvm::munmap(table, &mut regions, mid + PGSIZE, PGSIZE).expect("vm_mmap_index_test: munmap failed");
+    assert_eq!(regions.len(), NREGION);
+    assert_eq!(region_at(&regions, mid), Some(mid));
+    assert!(region_at(&regions, mid + PGSIZE).is_none());
+    assert_eq!(region_at(&regions, mid + 2 * PGSIZE), Some(mid + 2 * PGSIZE));
+    assert_eq!(unsafe { (*(*regions.find(mid).unwrap()).next).begin }, mid + 2 * PGSIZE);
+
+    // 拆掉第一个区域: 链表头随之前移
+    uvm::munmap(table, &mut regions, MMAP_BEGIN, 2 * PGSIZE).expect("vm_mmap_index_test: munmap head failed");
+    assert_eq!(unsafe { (*regions.head()).begin }, MMAP_BEGIN + STRIDE * PGSIZE);
+
+    uvm::munmap(table, &mut regions, MMAP_BEGIN, NREGION * STRIDE * PGSIZE)
+        .expect("vm_mmap_index_test: munmap all failed");
+    assert_eq!(regions.len(), 0);
+    assert!(regions.head().is_null());
+
+    table.destroy();
+    pmem::free(pgtbl as usize, true);
+    printk!("vm_mmap_index_test passed!\n");
+}
+
+fn vm_mmap_hint_test() {
+    printk!("--- vm_mmap_hint_test ---\n");
+
+    let pgtbl = pmem::alloc(true) as *mut PageTable;
+    let table = unsafe { &mut *pgtbl };
+    let mut regions = MmapIndex::new();
+    let mut map = |regions: &mut _, hint, npages: usize, flags| {
+        uvm::mmap(table, regions, hint, npages * PGSIZE, flags, MMAP_BEGIN, MMAP_END)
+    };
+
+    // 空闲的提示地址: 就放在那里
+    let hint = MMAP_BEGIN + 8 * PGSIZE;
+    let a = map(&mut regions, hint, 2, 0).expect("vm_mmap_hint_test: free hint failed");
+    assert_eq!(a, hint);
+
+    // 被占用的提示地址: 放到最低的空洞 [MMAP_BEGIN, hint)
+    let b = map(&mut regions, hint + PGSIZE, 2, 0).expect("vm_mmap_hint_test: busy hint failed");
+    assert_eq!(b, MMAP_BEGIN);
+
+    // 空洞 [MMAP_BEGIN + 2 页, hint) 只有 6 页, 放不下 8 页: 放到 a 之后
+    let c = map(&mut regions, hint, 8, 0).expect("vm_mmap_hint_test: large busy hint failed");
+    assert_eq!(c, hint + 2 * PGSIZE);
+
+    // 未对齐的提示地址不可用, 退回自动选址
+    let d = map(&mut regions, MMAP_BEGIN + 123, 1, 0).expect("vm_mmap_hint_test: unaligned hint failed");
+    assert_eq!(d, MMAP_BEGIN + 2 * PGSIZE);
+
+    // MAP_FIXED 不退让
+    assert!(map(&mut regions, hint, 1, uvm::MAP_FIXED).is_err());
+    assert!(map(&mut regions, MMAP_BEGIN + 123, 1, uvm::MAP_FIXED).is_err());
+
+    // 各次映射互不重叠: 合并后的区域恰好覆盖 [MMAP_BEGIN, MMAP_BEGIN + 3 页) 和 [hint, hint + 10 页)
+    let ranges = [(a, 2), (b, 2), (c, 8), (d, 1)];
+    for (i, &(x, n)) in ranges.iter().enumerate() {
+        for &(y, m) in &ranges[i + 1..] {
+            assert!(x + n * PGSIZE <= y || y + m * PGSIZE <= x, "vm_mmap_hint_test: 0x{:x} overlaps 0x{:x}", x, y);
+        }
+    }
+    assert!(regions.covers(MMAP_BEGIN, MMAP_BEGIN + 3 * PGSIZE));
+    assert!(regions.covers(hint, hint + 10 * PGSIZE));
+    assert!(!regions.covers(MMAP_BEGIN, hint + 10 * PGSIZE));
+
+    uvm::munmap(table, &mut regions, MMAP_BEGIN, 18 * PGSIZE).expect("vm_mmap_hint_test: munmap failed");
+    assert!(regions.head().is_null());
+    table.destroy();
+    pmem::free(pgtbl as usize, true);
+    printk!("vm_mmap_hint_test passed!\n");
+}
+
+// 固定大小的格式化缓冲区, 只用于比较 Display 输出
+struct FmtBuf {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl core::fmt::Write for FmtBuf {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        if self.len + bytes.len() > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+fn pte_display_test() {
+    use core::fmt::Write;
+    printk!("[TEST] pte display\n");
+    let cases: [(usize, &str); 4] = [
+        (pte::pa_to_pte(0x8020_0000, PTE_V | PTE_R | PTE_X | PTE_A), "V R-X --- A- ppn=0x80200"),
+        (
+            pte::pa_to_pte(0x8765_4000, PTE_V | PTE_R | PTE_W | PTE_U | PTE_A | PTE_D),
+            "V RW- U-- AD ppn=0x87654",
+        ),
+        (pte::pa_to_pte(0x1000, PTE_V | PTE_R | PTE_U | PTE_COW), "V R-- U-C -- ppn=0x1"),
+        (0, "- --- --- -- ppn=0x0"),
+    ];
+    for (value, expect) in cases {
+        let mut out = FmtBuf { buf: [0; 64], len: 0 };
+        write!(out, "{}", pte::display(value)).expect("pte_display_test: buffer too small");
+        let got = core::str::from_utf8(&out.buf[..out.len]).unwrap();
+        assert_eq!(got, expect, "pte_display_test: pte={:#x}", value);
+    }
+
+    let user_rw = pte::pa_to_pte(0x8765_4000, PTE_V | PTE_R | PTE_W | PTE_U);
+    assert!(pte::is_user(user_rw) && pte::is_writable(user_rw) && !pte::is_executable(user_rw));
+    let kernel_text = pte::pa_to_pte(0x8020_0000, PTE_V | PTE_R | PTE_X);
+    assert!(!pte::is_user(kernel_text) && !pte::is_writable(kernel_text) && pte::is_executable(kernel_text));
+    printk!("{}[PASS]{} pte display\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 内核页表, 新建的用户页表和 fork 复制出的页表里, 跳板页的 PTE 必须逐位相同
+fn trampoline_mapping_test() {
+    printk!("--- trampoline_mapping_test ---\n");
+    let kpte = vm::kernel_trampoline_pte();
+    assert_eq!(pte::get_flags(kpte), vm::TRAMPOLINE_FLAGS | PTE_V, "trampoline_mapping_test: kernel flags");
+    assert_eq!(pte_to_pa(kpte), crate::irq::vector::trampoline as *const () as usize & !(PGSIZE - 1));
+
+    let frame = PhysFrame::alloc().expect("trampoline_mapping_test: no page for root pt");
+    unsafe { core::ptr::write_bytes(frame.addr() as *mut u8, 0, PGSIZE) };
+    let table = unsafe { &mut *(frame.addr() as *mut PageTable) };
+    vm::map_trampoline(table);
+    let upte = unsafe { *table.lookup(vm::TRAMPOLINE_VA).expect("trampoline_mapping_test: not mapped") };
+    assert_eq!(upte, kpte, "trampoline_mapping_test: user table differs from kernel");
+
+    let copy_pa = table.copy().expect("trampoline_mapping_test: copy failed");
+    let copy = unsafe { &mut *(copy_pa as *mut PageTable) };
+    let cpte = unsafe { *copy.lookup(vm::TRAMPOLINE_VA).expect("trampoline_mapping_test: copy not mapped") };
+    assert_eq!(cpte, kpte, "trampoline_mapping_test: copied table differs from kernel");
+
+    copy.destroy();
+    pmem::free(copy_pa, true);
+    table.destroy();
+    printk!("{}[PASS]{} trampoline_mapping_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+fn kernel_mappings_test() {
+    printk!("--- kernel_mappings_test ---\n");
+    vm::with_kernel_page_table(|kpt| {
+        if let Err((name, va, pte)) = vm::check_kernel_mappings(kpt) {
+            panic!("kernel_mappings_test: {} at {:#x}: {}", name, va, pte::display(pte));
+        }
+    });
+    printk!("{}[PASS]{} kernel_mappings_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 测试期间分页已关闭, 可以临时改坏内核页表中的 PTE 再恢复; 改动全程持有页表锁
+#[cfg(debug_assertions)]
+fn kernel_mappings_corrupt_test() {
+    printk!("--- kernel_mappings_corrupt_test ---\n");
+    let text_va = kernel_mappings_corrupt_test as fn() as usize & !(PGSIZE - 1);
+    vm::with_kernel_page_table(|kpt| {
+        let cases = [(text_va, PTE_W, ".text"), (vm::TRAMPOLINE_VA, PTE_X, "trampoline")];
+        for (va, bit, name) in cases {
+            let p = kpt.lookup(va).expect("kernel_mappings_corrupt_test: not mapped");
+            let orig = unsafe { *p };
+            unsafe { *p = orig ^ bit };
+            let result = vm::check_kernel_mappings(kpt);
+            unsafe { *p = orig };
+            match result {
+                Err((got, bad_va, pte)) => {
+                    assert_eq!(got, name, "kernel_mappings_corrupt_test: wrong region");
+                    assert_eq!(bad_va, va, "kernel_mappings_corrupt_test: wrong VA");
+                    assert_eq!(pte, orig ^ bit, "kernel_mappings_corrupt_test: wrong PTE");
+                }
+                Ok(()) => panic!("kernel_mappings_corrupt_test: flipping {:#x} at {:#x} not detected", bit, va),
+            }
+        }
+        assert!(vm::check_kernel_mappings(kpt).is_ok(), "kernel_mappings_corrupt_test: not restored");
+    });
+    printk!("{}[PASS]{} kernel_mappings_corrupt_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// Process::page_table 在 root_pt_pa 为 0 或未对齐时 debug_assert 失败, 测试里无法捕获 panic,
+// 这里检查它依据的 root_pt_invalid 给出的原因, 以及合法根页表时访问器返回同一张表
+#[cfg(debug_assertions)]
+fn page_table_accessor_test() {
+    printk!("--- page_table_accessor_test ---\n");
+    let mut p = Process::new();
+    assert_eq!(
+        p.root_pt_invalid(),
+        Some("page table not allocated (or already released)"),
+        "page_table_accessor_test: zero root_pt_pa not rejected"
+    );
+
+    let frame = PhysFrame::alloc().expect("page_table_accessor_test: no page for root pt");
+    unsafe { core::ptr::write_bytes(frame.addr() as *mut u8, 0, PGSIZE) };
+    p.root_pt_pa = frame.addr() + 8;
+    assert_eq!(
+        p.root_pt_invalid(),
+        Some("root_pt_pa not page aligned"),
+        "page_table_accessor_test: misaligned root_pt_pa not rejected"
+    );
+
+    p.root_pt_pa = frame.addr();
+    assert_eq!(p.root_pt_invalid(), None);
+    assert_eq!(p.page_table() as *const PageTable as usize, frame.addr());
+    assert_eq!(p.page_table_mut() as *mut PageTable as usize, frame.addr());
+    p.root_pt_pa = 0;
+    drop(frame);
+    printk!("page_table_accessor_test passed!\n");
+}
+
+// 内核栈槽位底部的保护页不映射: 从栈底往下越界的写入触发缺页, 不会落到相邻槽位的栈上
+// 使用最高的槽位, 进程表用不到它; 内核栈只在内核页表里, 越界写入要开启分页后做
+fn kstack_guard_test(hartid: usize) {
+    printk!("--- kstack_guard_test ---\n");
+    let slot = vm::KSTACK_SLOTS - 1;
+    assert!(KernelStack::alloc(vm::KSTACK_SLOTS).is_none(), "kstack_guard_test: slot beyond the region");
+    let kstack = KernelStack::alloc(slot).expect("kstack_guard_test: no memory for the kernel stack");
+    assert_eq!(kstack.top() - kstack.base(), vm::KSTACK_SIZE);
+    assert_eq!(kstack.base() - kstack.guard(), PGSIZE);
+    assert!(kstack.top() < vm::TRAMPOLINE_VA, "kstack_guard_test: stack overlaps the trampoline");
+    assert_eq!(vm::kstack_top(slot - 1), kstack.guard(), "kstack_guard_test: guard not between slots");
+    for va in (kstack.base()..kstack.top()).step_by(PGSIZE) {
+        assert!(vm::kernel_pte(va).is_some(), "kstack_guard_test: stack page 0x{:x} not mapped", va);
+    }
+    assert!(vm::kernel_pte(kstack.guard()).is_none(), "kstack_guard_test: guard page mapped");
+
+    // 栈内写入正常, 越过栈底的写入落在保护页上
+    let src = [0xa5u8; 16];
+    vm::switch_to_kernel(hartid);
+    let inside = uvm::copy_guarded(kstack.base() as *mut u8, src.as_ptr(), src.len());
+    let overflow = uvm::copy_guarded((kstack.base() - 8) as *mut u8, src.as_ptr(), src.len());
+    vm::switch_off(hartid);
+    assert_eq!(inside, Ok(()), "kstack_guard_test: write to stack failed");
+    assert_eq!(overflow, Err(uvm::CopyError::Fault), "kstack_guard_test: overflow did not hit the guard page");
+
+    let base = kstack.base();
+    drop(kstack);
+    assert!(vm::kernel_pte(base).is_none(), "kstack_guard_test: stack still mapped after drop");
+    printk!("{}[PASS]{} kstack_guard_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 模拟 exec 前后两个映像: 先装一个大映像再换成小映像, 每次拆除后用户区与内核区的空闲页数都应回到基线
+fn vm_exec_release_test() {
+    printk!("--- vm_exec_release_test ---\n");
+    let user_before = pmem::user_region_info().allocable;
+    let kernel_before = pmem::kernel_region_info().allocable;
+
+    let mut p = Process::new();
+    for (image_pages, mmap_pages) in [(64usize, 8usize), (4, 1)] {
+        let frame = PhysFrame::alloc().expect("vm_exec_release_test: no page for root pt");
+        unsafe { core::ptr::write_bytes(frame.addr() as *mut u8, 0, PGSIZE) };
+        let table = unsafe { &mut *(frame.addr() as *mut PageTable) };
+
+        // 代码 + 堆
+        for i in 0..image_pages {
+            let pa = pmem::alloc(false) as usize;
+            vm::mappages(table, PGSIZE * (i + 1), pa, PGSIZE, PTE_U | PTE_R | PTE_W | PTE_A | PTE_D);
+        }
+        // 写入过的 mmap 页
+        let va = uvm::mmap(table, &mut p.mmap, 0, mmap_pages * PGSIZE, 0, MMAP_BEGIN, MMAP_END)
+            .expect("vm_exec_release_test: mmap failed");
+        for i in 0..mmap_pages {
+            uvm::cow_fault(table, va + i * PGSIZE).expect("vm_exec_release_test: cow fault failed");
+        }
+        p.root_pt_pa = frame.addr();
+        p.root_pt_frame = Some(frame);
+        p.heap_base = PGSIZE * (image_pages + 1);
+        p.heap_top = p.heap_base;
+        assert!(pmem::user_region_info().allocable <= user_before - image_pages - mmap_pages);
+
+        p.release_address_space();
+        assert_eq!(pmem::user_region_info().allocable, user_before, "vm_exec_release_test: user frames leaked");
+        assert_eq!(pmem::kernel_region_info().allocable, kernel_before, "vm_exec_release_test: page table frames leaked");
+        assert!(p.mmap.len() == 0 && p.root_pt_frame.is_none());
+        assert_eq!(p.heap_top, 0);
+    }
+    printk!("{}[PASS]{} vm_exec_release_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 按 fork 的做法复制父进程页表 (copy 后经 from_raw 交给子进程), 父子先后退出后
+// 引用计数与空闲链表仍然一致, 两个区域的可用页数回到原值
+fn fork_refcount_audit_test() {
+    printk!("--- fork_refcount_audit_test ---\n");
+    let audit = || {
+        if let Err((region, expected, actual)) = pmem::audit_refcounts() {
+            panic!("fork_refcount_audit_test: {} region expected {:#x}, found {:#x}", region, expected, actual);
+        }
+    };
+    audit();
+    let user_before = pmem::user_region_info().allocable;
+    let kernel_before = pmem::kernel_region_info().allocable;
+
+    let mut parent = Process::new();
+    let frame = PhysFrame::alloc().expect("fork_refcount_audit_test: no page for root pt");
+    unsafe { core::ptr::write_bytes(frame.addr() as *mut u8, 0, PGSIZE) };
+    let table = unsafe { &mut *(frame.addr() as *mut PageTable) };
+    for i in 0..4 {
+        let pa = pmem::alloc(false) as usize;
+        vm::mappages(table, PGSIZE * (i + 1), pa, PGSIZE, PTE_U | PTE_R | PTE_W | PTE_A | PTE_D);
+    }
+    parent.root_pt_pa = frame.addr();
+    parent.root_pt_frame = Some(frame);
+    parent.heap_base = PGSIZE * 5;
+    parent.heap_top = parent.heap_base;
+
+    let mut child = Process::new();
+    let child_pt_pa = parent.page_table().copy().expect("fork_refcount_audit_test: copy failed");
+    child.root_pt_pa = child_pt_pa;
+    child.root_pt_frame = Some(unsafe { PhysFrame::from_raw(child_pt_pa) });
+    child.heap_base = parent.heap_base;
+    child.heap_top = parent.heap_top;
+    audit();
+
+    child.release_address_space();
+    audit();
+
+    // 用户页只剩 2 页: 复制在第 3 个叶子页上失败, 已建好的页表和叶子页全部归还
+    let mut held = 0usize;
+    while pmem::user_region_info().allocable > 2 {
+        let page = pmem::try_alloc(false).expect("fork_refcount_audit_test: user region drained early") as usize;
+        unsafe { core::ptr::write(page as *mut usize, held) };
+        held = page;
+    }
+    let kernel_mid = pmem::kernel_region_info().allocable;
+    assert!(parent.page_table().copy().is_err(), "fork_refcount_audit_test: copy should run out of user pages");
+    assert_eq!(pmem::user_region_info().allocable, 2, "fork_refcount_audit_test: failed copy leaked user frames");
+    assert_eq!(pmem::kernel_region_info().allocable, kernel_mid, "fork_refcount_audit_test: failed copy leaked page tables");
+    while held != 0 {
+        let next = unsafe { core::ptr::read(held as *const usize) };
+        pmem::free(held, false);
+        held = next;
+    }
+    audit();
+
+    parent.release_address_space();
+    audit();
+    assert_eq!(pmem::user_region_info().allocable, user_before, "fork_refcount_audit_test: user frames leaked");
+    assert_eq!(pmem::kernel_region_info().allocable, kernel_before, "fork_refcount_audit_test: kernel frames leaked");
+    printk!("{}[PASS]{} fork_refcount_audit_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 测试默认在分页关闭时运行, 这里临时切回内核页表以经由窗口访问物理页
+fn kmap_temporary_test(hartid: usize) {
+    printk!("--- kmap_temporary_test ---\n");
+    let pa = pmem::alloc(true) as PhysAddr;
+    unsafe { core::ptr::write_bytes(pa as *mut u8, 0, PGSIZE) };
+
+    vm::switch_to_kernel(hartid);
+    {
+        let mut guard = vm::kmap_temporary(pa);
+        assert_ne!(guard.va(), pa, "kmap_temporary_test: window aliases identity map");
+        unsafe { core::ptr::write_bytes(guard.as_mut_ptr::<u8>(), 0xa5, PGSIZE) };
+        // 同一物理页可以同时占用两个槽位
+        let second = vm::kmap_temporary(pa);
+        assert_ne!(second.va(), guard.va());
+        assert_eq!(unsafe { *second.as_ptr::<u8>().add(PGSIZE - 1) }, 0xa5);
+    }
+    vm::switch_off(hartid);
+
+    for i in 0..PGSIZE {
+        assert_eq!(unsafe { *(pa as *const u8).add(i) }, 0xa5, "kmap_temporary_test: byte {} not written", i);
+    }
+    // 分页关闭时直接返回物理地址
+    let guard = vm::kmap_temporary(pa);
+    assert_eq!(guard.va(), pa);
+    drop(guard);
+
+    pmem::free(pa, true);
+    printk!("{}[PASS]{} kmap_temporary_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// 未映射的用户地址直接返回 NotMapped; PTE 指向内存之外的物理地址时, 拷贝中的访存异常由恢复点接住并返回 Fault
+fn uaccess_fault_test() {
+    printk!("--- uaccess_fault_test ---\n");
+    const BAD_PA: PhysAddr = 0x10_0000_0000;
+    let frame = PhysFrame::alloc().expect("uaccess_fault_test: no page for root pt");
+    unsafe { core::ptr::write_bytes(frame.addr() as *mut u8, 0, PGSIZE) };
+    let table = unsafe { &mut *(frame.addr() as *mut PageTable) };
+
+    let mut buf = [0u8; 16];
+    assert_eq!(uvm::copyin(table, &mut buf, 0x4000), Err(uvm::CopyError::NotMapped));
+    // 没有当前进程时包装函数不会去碰任何页表
+    assert_eq!(uvm::copy_from_user(&mut buf, 0x4000), Err(uvm::CopyError::NotMapped));
+    assert_eq!(uvm::copy_to_user(0x4000, &buf), Err(uvm::CopyError::NotMapped));
+
+    let va = 0x5000;
+    vm::mappages(table, va, BAD_PA, PGSIZE, PTE_U | PTE_R | PTE_W | PTE_A | PTE_D);
+    assert_eq!(uvm::copyin(table, &mut buf, va + 8), Err(uvm::CopyError::Fault));
+    assert_eq!(uvm::copyout(table, va, &buf), Err(uvm::CopyError::Fault));
+    assert_eq!(hart::get().fault_fixup, 0, "uaccess_fault_test: recovery point left armed");
+
+    // 恢复后正常拷贝仍然可用
+    let pa = pmem::alloc(false) as PhysAddr;
+    vm::mappages(table, va + PGSIZE, pa, PGSIZE, PTE_U | PTE_R | PTE_W | PTE_A | PTE_D);
+    let src = [0x5au8; 16];
+    uvm::copyout(table, va + PGSIZE, &src).expect("uaccess_fault_test: copyout to good page failed");
+    uvm::copyin(table, &mut buf, va + PGSIZE).expect("uaccess_fault_test: copyin from good page failed");
+    assert_eq!(buf, src);
+
+    // destroy 只回收用户区内的页, BAD_PA 不会被当作物理页释放
+    table.destroy();
+    printk!("{}[PASS]{} uaccess_fault_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+fn elf_load_range_test() {
+    printk!("--- elf_load_range_test ---\n");
+    let tramp_va = VA_MAX - PGSIZE;
+    assert!(elf::load_range_valid(0x1000, 0x200, 0x3000));
+    assert!(elf::load_range_valid(MMAP_BEGIN - PGSIZE, PGSIZE, PGSIZE), "segment ending at MMAP_BEGIN");
+    assert!(!elf::load_range_valid(tramp_va, PGSIZE, PGSIZE), "segment over the trampoline");
+    assert!(!elf::load_range_valid(tramp_va - PGSIZE, 0, PGSIZE), "bss over the trapframe");
+    assert!(!elf::load_range_valid(MMAP_BEGIN - PGSIZE, 0, 2 * PGSIZE), "segment running into the mmap region");
+    assert!(!elf::load_range_valid(0, 0x10, 0x10), "segment on page 0");
+    assert!(!elf::load_range_valid(usize::MAX - 0xfff, 0, 0x2000), "wrapping segment");
+    assert!(!elf::load_range_valid(0x1000, 0x2000, 0x1000), "filesz larger than memsz");
+    printk!("{}[PASS]{} elf_load_range_test\n", ANSI_GREEN, ANSI_RESET);
+}
+
+// Sv39 下不可达的地址 (第 39 位以上) 在 Sv48 下经由第四级页表映射, 写入后从物理地址读回
+#[cfg(feature = "sv48")]
+fn sv48_high_va_test(hartid: usize) {
+    printk!("--- sv48_high_va_test ---\n");
+    const HIGH_VA: usize = 1 << 40;
+    assert!(HIGH_VA < VA_MAX && HIGH_VA >= 1 << 38);
+    let pa = pmem::alloc(true) as PhysAddr;
+    unsafe { core::ptr::write_bytes(pa as *mut u8, 0, PGSIZE) };
+    vm::map_kernel_pages(HIGH_VA, pa, PGSIZE, PTE_R | PTE_W | PTE_A | PTE_D);
+
+    vm::switch_to_kernel(hartid);
+    unsafe {
+        core::ptr::write_volatile((HIGH_VA + 8) as *mut u64, 0x5a5a_48_48);
+    }
+    vm::switch_off(hartid);
+
+    assert_eq!(unsafe { *((pa + 8) as *const u64) }, 0x5a5a_48_48, "sv48_high_va_test: write did not reach PA");
+    vm::unmap_kernel_pages(HIGH_VA, PGSIZE);
+    pmem::free(pa, true);
+    printk!("{}[PASS]{} sv48_high_va_test\n", ANSI_GREEN, ANSI_RESET);
+}
@@ -6,6 +6,7 @@
 #define BLOCK_BASE 5000
 #define INODE_TYPE_DIR  1
 #define INODE_TYPE_DATA 2
+#define INODE_TYPE_SYMLINK 3
 #define MAXLEN_FILENAME 60
 
 #define O_RDONLY  0x000
@@ -13,6 +14,24 @@
 #define O_RDWR    0x002
 #define O_CREAT   0x040
 #define O_TRUNC   0x200
+#define O_DIRECTORY 0x10000
+#define O_NOFOLLOW 0x20000
+#define O_CLOEXEC 0x80000
+
+#define F_OK 0
+#define X_OK 1
+#define W_OK 2
+#define R_OK 4
+
+#define MADV_DONTNEED 4
+#define MAP_FIXED 0x10
+
+// wait 状态解码, 与内核 exit_status/signal_status 对应
+#define WIFEXITED(s)   (((s) & 0x7f) == 0)
+#define WEXITSTATUS(s) (((s) >> 8) & 0xff)
+#define WIFSIGNALED(s) (((s) & 0x7f) != 0)
+#define WTERMSIG(s)    ((s) & 0x7f)
+#define SIGSEGV 11
 
 struct stat {
     unsigned short type;
@@ -21,6 +40,9 @@ struct stat {
     unsigned short major;
     unsigned short minor;
     unsigned int inum;
+    unsigned int atime;
+    unsigned int mtime;
+    unsigned int ctime;
 };
 
 struct dirent {
@@ -40,13 +62,26 @@ static void test_copy(void) {
     syscall(SYS_copyinstr, (long)s);
 }
 
+// brk(0) 查询; 成功返回新断点 (不对齐); 越界时失败并返回当前断点
 static void test_brk(void) {
-    long heap_top = 0;
-    heap_top = syscall(SYS_brk, 0);
-    heap_top = syscall(SYS_brk, heap_top + PGSIZE * 9);
-    heap_top = syscall(SYS_brk, heap_top);
-    heap_top = syscall(SYS_brk, heap_top - PGSIZE * 5);
-    (void)heap_top;
+    syscall(SYS_copyinstr, (long)"[TEST] brk");
+    long base = syscall(SYS_brk, 0);
+    long want = base + PGSIZE * 9 + 17;
+    long top = syscall(SYS_brk, want);
+    if (top != want || syscall(SYS_brk, 0) != want) {
+        syscall(SYS_copyinstr, (long)"[FAIL] brk did not return the new break");
+        return;
+    }
+    ((volatile char *)top)[-1] = 'x'; // 断点所在页可写
+    if (syscall(SYS_brk, base - PGSIZE) != top || syscall(SYS_brk, 1L << 40) != top) {
+        syscall(SYS_copyinstr, (long)"[FAIL] out-of-range brk changed the break");
+        return;
+    }
+    top = syscall(SYS_brk, base + PGSIZE * 4);
+    if (top != base + PGSIZE * 4 || syscall(SYS_brk, base) != base) {
+        syscall(SYS_copyinstr, (long)"[FAIL] brk shrink");
+        return;
+    }
     syscall(SYS_copyinstr, (long)"[PASS] brk test passed");
 }
 
@@ -77,13 +112,13 @@ static void test_mmap(void) {
 
   syscall(SYS_copyinstr, (long)"[TEST] mmap/munmap begin");
 
-  syscall(SYS_mmap, MMAP_BEGIN + 4 * PGSIZE, 3 * PGSIZE);
-  syscall(SYS_mmap, MMAP_BEGIN + 10 * PGSIZE, 2 * PGSIZE);
-  syscall(SYS_mmap, MMAP_BEGIN + 2 * PGSIZE, 2 * PGSIZE);
-  syscall(SYS_mmap, MMAP_BEGIN + 12 * PGSIZE, 1 * PGSIZE);
-  syscall(SYS_mmap, MMAP_BEGIN + 7 * PGSIZE, 3 * PGSIZE);
-  syscall(SYS_mmap, MMAP_BEGIN + 0 * PGSIZE, 2 * PGSIZE);
-  syscall(SYS_mmap, 0, 10 * PGSIZE);
+  syscall(SYS_mmap, MMAP_BEGIN + 4 * PGSIZE, 3 * PGSIZE, MAP_FIXED);
+  syscall(SYS_mmap, MMAP_BEGIN + 10 * PGSIZE, 2 * PGSIZE, MAP_FIXED);
+  syscall(SYS_mmap, MMAP_BEGIN + 2 * PGSIZE, 2 * PGSIZE, MAP_FIXED);
+  syscall(SYS_mmap, MMAP_BEGIN + 12 * PGSIZE, 1 * PGSIZE, MAP_FIXED);
+  syscall(SYS_mmap, MMAP_BEGIN + 7 * PGSIZE, 3 * PGSIZE, MAP_FIXED);
+  syscall(SYS_mmap, MMAP_BEGIN + 0 * PGSIZE, 2 * PGSIZE, MAP_FIXED);
+  syscall(SYS_mmap, 0, 10 * PGSIZE, 0);
 
   syscall(SYS_munmap, MMAP_BEGIN + 10 * PGSIZE, 5 * PGSIZE);
   syscall(SYS_munmap, MMAP_BEGIN + 0 * PGSIZE, 10 * PGSIZE);
@@ -94,15 +129,25 @@ static void test_mmap(void) {
   syscall(SYS_munmap, MMAP_BEGIN + 21 * PGSIZE, 1 * PGSIZE);
 
   syscall(SYS_copyinstr, (long)"[TEST] mmap: overlap should fail");
-  (void)syscall(SYS_mmap, MMAP_BEGIN + 0 * PGSIZE, 2 * PGSIZE);
-  long rv = syscall(SYS_mmap, MMAP_BEGIN + 1 * PGSIZE, 2 * PGSIZE);
+  (void)syscall(SYS_mmap, MMAP_BEGIN + 0 * PGSIZE, 2 * PGSIZE, MAP_FIXED);
+  long rv = syscall(SYS_mmap, MMAP_BEGIN + 1 * PGSIZE, 2 * PGSIZE, MAP_FIXED);
   if (rv != -1) { syscall(SYS_copyinstr, (long)"[WARN] overlap not rejected"); }
   syscall(SYS_munmap, MMAP_BEGIN + 0 * PGSIZE, 2 * PGSIZE);
 
   syscall(SYS_copyinstr, (long)"[TEST] mmap: unaligned should fail");
-  rv = syscall(SYS_mmap, MMAP_BEGIN + 123, 2 * PGSIZE);
+  rv = syscall(SYS_mmap, MMAP_BEGIN + 123, 2 * PGSIZE, MAP_FIXED);
   if (rv != -1) { syscall(SYS_copyinstr, (long)"[WARN] unaligned begin not rejected"); }
 
+  syscall(SYS_copyinstr, (long)"[TEST] mmap: hint address");
+  long a = syscall(SYS_mmap, MMAP_BEGIN + 4 * PGSIZE, 2 * PGSIZE, 0);
+  long b = syscall(SYS_mmap, MMAP_BEGIN + 5 * PGSIZE, 2 * PGSIZE, 0);
+  if (a != (long)(MMAP_BEGIN + 4 * PGSIZE)) { syscall(SYS_copyinstr, (long)"[WARN] free hint not used"); }
+  if (b == -1 || (b < a + 2 * PGSIZE && a < b + 2 * PGSIZE)) {
+    syscall(SYS_copyinstr, (long)"[WARN] busy hint overlaps");
+  }
+  syscall(SYS_munmap, a, 2 * PGSIZE);
+  syscall(SYS_munmap, b, 2 * PGSIZE);
+
   syscall(SYS_copyinstr, (long)"[TEST] munmap: unmapped range is no-op");
   syscall(SYS_munmap, MMAP_BEGIN + 8 * PGSIZE, 3 * PGSIZE);
 
@@ -135,7 +180,7 @@ void test_memory_fork() {
     char *str1, *str2, *str3 = "STACK_REGION\n\n";
     char *tmp1 = "MMAP_REGION\n", *tmp2 = "HEAP_REGION\n";
 
-    str1 = (char*)syscall(SYS_mmap, MMAP_BEGIN, PGSIZE);
+    str1 = (char*)syscall(SYS_mmap, MMAP_BEGIN, PGSIZE, 0);
     for (i = 0; tmp1[i] != '\0'; i++)
         str1[i] = tmp1[i];
     str1[i] = '\0';
@@ -160,7 +205,7 @@ void test_memory_fork() {
       syscall(SYS_wait, (long)&exit_state);
       syscall(SYS_print_str, (long)"parent proc: hello\n");
       syscall(SYS_print_int, pid);
-      if (exit_state == 1234)
+      if (WIFEXITED(exit_state) && WEXITSTATUS(exit_state) == (1234 & 0xff))
         syscall(SYS_print_str, (long)"good boy!\n");
       else
         syscall(SYS_print_str, (long)"bad boy!\n");
@@ -388,6 +433,134 @@ void lab9_test_3(void) {
     syscall(SYS_copyinstr, (long)"\n[PASS] LAB9-3 done.");
 }
 
+void test_symlink(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] symlink/readlink");
+
+    const char *data = "via symlink";
+    char buf[64] = {0};
+
+    int fd = syscall(SYS_open, (long)"sl_target.txt", O_CREAT | O_RDWR);
+    syscall(SYS_write, fd, (long)data, 11);
+    syscall(SYS_close, fd);
+
+    // 相对目标 + 链接的链接
+    if (syscall(SYS_symlink, (long)"sl_target.txt", (long)"sl_link") < 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] symlink create failed");
+    syscall(SYS_symlink, (long)"sl_link", (long)"sl_link2");
+    // 绝对目标
+    syscall(SYS_symlink, (long)"/sl_target.txt", (long)"sl_abs");
+
+    fd = syscall(SYS_open, (long)"sl_link2", O_RDONLY);
+    if (fd < 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] open through symlink chain failed");
+    } else {
+        int n = syscall(SYS_read, fd, (long)buf, 11);
+        int match = n == 11;
+        for (int i = 0; i < 11; i++) if (buf[i] != data[i]) match = 0;
+        if (!match) syscall(SYS_copyinstr, (long)"[FAIL] data mismatch through symlink");
+        syscall(SYS_close, fd);
+    }
+
+    fd = syscall(SYS_open, (long)"sl_abs", O_RDONLY);
+    if (fd < 0) syscall(SYS_copyinstr, (long)"[FAIL] open absolute symlink failed");
+    else syscall(SYS_close, fd);
+
+    int n = syscall(SYS_readlink, (long)"sl_link", (long)buf, sizeof(buf) - 1);
+    if (n != 13) {
+        syscall(SYS_copyinstr, (long)"[FAIL] readlink length mismatch");
+    } else {
+        buf[n] = 0;
+        syscall(SYS_copyinstr, (long)"[INFO] readlink sl_link:");
+        syscall(SYS_copyinstr, (long)buf);
+    }
+
+    if (syscall(SYS_open, (long)"sl_link", O_RDONLY | O_NOFOLLOW) >= 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] O_NOFOLLOW opened a symlink");
+
+    // 悬空链接与环路
+    syscall(SYS_symlink, (long)"no_such_file", (long)"sl_dangling");
+    if (syscall(SYS_open, (long)"sl_dangling", O_RDONLY) >= 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] dangling symlink opened");
+    syscall(SYS_symlink, (long)"sl_loop", (long)"sl_loop");
+    if (syscall(SYS_open, (long)"sl_loop", O_RDONLY) >= 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] symlink loop opened");
+
+    syscall(SYS_unlink, (long)"sl_loop");
+    syscall(SYS_unlink, (long)"sl_dangling");
+    syscall(SYS_unlink, (long)"sl_abs");
+    syscall(SYS_unlink, (long)"sl_link2");
+    syscall(SYS_unlink, (long)"sl_link");
+    syscall(SYS_unlink, (long)"sl_target.txt");
+
+    syscall(SYS_copyinstr, (long)"[PASS] symlink test done.");
+}
+
+// trapstat 布局: [0,16) 异常计数, [16,32) 中断计数, 下标为 scause 编码
+#define TRAPSTAT_N 32
+#define EXC_ECALL_U 8
+#define EXC_LOAD_PF 13
+#define EXC_STORE_PF 15
+#define INTR_S_SOFT (16 + 1)
+#define INTR_S_TIMER (16 + 5)
+
+void test_trapstat(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] trapstat counters");
+
+    unsigned long before[TRAPSTAT_N], after[TRAPSTAT_N];
+    syscall(SYS_trapstat, (long)before, TRAPSTAT_N);
+    for (int i = 0; i < 10; i++) syscall(SYS_getpid);
+    syscall(SYS_trapstat, (long)after, TRAPSTAT_N);
+
+    // 10 次 getpid + 第二次 trapstat 本身
+    if (after[EXC_ECALL_U] - before[EXC_ECALL_U] != 11)
+        syscall(SYS_copyinstr, (long)"[FAIL] ecall counter mismatch");
+
+    syscall(SYS_trapstat, (long)before, TRAPSTAT_N);
+    syscall(SYS_sleep, 2);
+    syscall(SYS_trapstat, (long)after, TRAPSTAT_N);
+    if (after[INTR_S_SOFT] + after[INTR_S_TIMER] <= before[INTR_S_SOFT] + before[INTR_S_TIMER])
+        syscall(SYS_copyinstr, (long)"[FAIL] timer counter did not advance");
+
+    syscall(SYS_copyinstr, (long)"[INFO] page faults (load/store):");
+    syscall(SYS_print_int, after[EXC_LOAD_PF]);
+    syscall(SYS_print_str, (long)" ");
+    syscall(SYS_print_int, after[EXC_STORE_PF]);
+
+    syscall(SYS_copyinstr, (long)"\n[PASS] trapstat test done.");
+}
+
+static volatile int vfork_shared;
+
+void test_vfork(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] vfork");
+
+    int marker = 1234;
+    vfork_shared = 0;
+    int pid = syscall(SYS_vfork);
+    if (pid == 0) {
+        // 子进程借用父进程的地址空间: 写入对父进程可见
+        vfork_shared = 1;
+        char *argv[] = {"nonexistent", 0};
+        syscall(SYS_exec, (long)"/no_such_program", (long)argv);
+        syscall(SYS_exit, 42);
+    }
+
+    if (pid < 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] vfork failed");
+        return;
+    }
+    // 父进程恢复时子进程必须已经退出或 exec
+    if (vfork_shared != 1) syscall(SYS_copyinstr, (long)"[FAIL] vfork child did not share memory");
+    if (marker != 1234) syscall(SYS_copyinstr, (long)"[FAIL] parent stack clobbered");
+
+    int code = 0;
+    int wpid = syscall(SYS_wait, (long)&code);
+    if (wpid != pid || !WIFEXITED(code) || WEXITSTATUS(code) != 42)
+        syscall(SYS_copyinstr, (long)"[FAIL] vfork child exit status");
+
+    syscall(SYS_copyinstr, (long)"[PASS] vfork test done.");
+}
+
 void lab9_test_4(void) {
     syscall(SYS_copyinstr, (long)"[TEST] LAB9-4: Exec ELF from disk");
     char *argv[] = {"hello", "world", 0};
@@ -396,13 +569,1064 @@ void lab9_test_4(void) {
     syscall(SYS_copyinstr, (long)"[FAIL] exec failed");
 }
 
-int main(void)
+void test_access(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] access/chmod");
+
+    int fd = syscall(SYS_open, (long)"acc_file.txt", O_CREAT | O_RDWR);
+    syscall(SYS_close, fd);
+
+    // 新建文件默认 0644
+    if (syscall(SYS_access, (long)"acc_file.txt", R_OK | W_OK) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] new file not readable/writable");
+    if (syscall(SYS_access, (long)"acc_file.txt", X_OK) == 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] new file should not be executable");
+
+    syscall(SYS_chmod, (long)"acc_file.txt", 0444);
+    if (syscall(SYS_access, (long)"acc_file.txt", W_OK) == 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] access(W) passed on read-only file");
+    if (syscall(SYS_access, (long)"acc_file.txt", R_OK) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] read-only file not readable");
+    // mode 0 什么都不允许, 只剩存在性检查
+    syscall(SYS_chmod, (long)"acc_file.txt", 0);
+    if (syscall(SYS_access, (long)"acc_file.txt", R_OK) == 0 || syscall(SYS_access, (long)"acc_file.txt", W_OK) == 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] access passed on a mode 0 file");
+    if (syscall(SYS_access, (long)"acc_file.txt", F_OK) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] access(F_OK) failed on a mode 0 file");
+    if (syscall(SYS_access, (long)"no_such_file", F_OK) == 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] access on missing file passed");
+
+    syscall(SYS_unlink, (long)"acc_file.txt");
+    syscall(SYS_copyinstr, (long)"[PASS] access/chmod");
+}
+
+void test_mincore(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] mincore");
+
+    const int npages = 8;
+    unsigned char vec[8];
+    char *base = (char *)syscall(SYS_mmap, 0, npages * PGSIZE, 0);
+    if ((long)base == -1) {
+        syscall(SYS_copyinstr, (long)"[FAIL] mincore: mmap failed");
+        return;
+    }
+
+    // 偶数页写入触发缺页, 奇数页只读 (仍指向零页)
+    for (int i = 0; i < npages; i++) {
+        if (i % 2 == 0) base[i * PGSIZE] = (char)i;
+        else (void)*(volatile char *)(base + i * PGSIZE);
+    }
+
+    if (syscall(SYS_mincore, (long)base, npages * PGSIZE, (long)vec) != 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] mincore returned error");
+    } else {
+        int ok = 1;
+        for (int i = 0; i < npages; i++)
+            if (vec[i] != (i % 2 == 0)) ok = 0;
+        if (!ok) syscall(SYS_copyinstr, (long)"[FAIL] mincore resident pattern mismatch");
+    }
+
+    syscall(SYS_munmap, (long)base, npages * PGSIZE);
+    syscall(SYS_copyinstr, (long)"[PASS] mincore");
+}
+
+void test_ebreak(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] ebreak resume");
+    long hits;
+    // c.ebreak 只能前进 2 字节, ebreak 前进 4 字节, 之后的 ecall 也要正常返回
+    asm volatile(
+        ".option push\n"
+        ".option norvc\n"
+        "li %0, 0\n"
+        ".2byte 0x9002\n"
+        "addi %0, %0, 1\n"
+        ".4byte 0x00100073\n"
+        "addi %0, %0, 2\n"
+        ".option pop\n"
+        : "=r"(hits));
+    long pid = syscall(SYS_getpid);
+    if (hits != 3 || pid <= 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] ebreak resumed at the wrong instruction");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] ebreak resume");
+}
+
+static void put_le(unsigned char *p, unsigned long v, int n) {
+    for (int i = 0; i < n; i++) p[i] = (unsigned char)(v >> (8 * i));
+}
+
+// 构造一个 PT_LOAD 指向跳板页的 ELF, exec 必须拒绝而不是把它映射上去
+void test_exec_reserved_va(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] exec rejects reserved VAs");
+
+    const unsigned long tramp_va = (1UL << 38) - PGSIZE;
+    unsigned char img[64 + 56] = {0};
+    img[0] = 0x7f; img[1] = 'E'; img[2] = 'L'; img[3] = 'F';
+    img[4] = 2; img[5] = 1; img[6] = 1;   // ELFCLASS64, little endian, EV_CURRENT
+    put_le(img + 16, 2, 2);               // ET_EXEC
+    put_le(img + 18, 243, 2);             // EM_RISCV
+    put_le(img + 24, tramp_va, 8);        // e_entry
+    put_le(img + 32, 64, 8);              // e_phoff
+    put_le(img + 54, 56, 2);              // e_phentsize
+    put_le(img + 56, 1, 2);               // e_phnum
+    unsigned char *ph = img + 64;
+    put_le(ph + 0, 1, 4);                 // PT_LOAD
+    put_le(ph + 4, 5, 4);                 // R + X
+    put_le(ph + 8, 0, 8);                 // p_offset
+    put_le(ph + 16, tramp_va, 8);         // p_vaddr
+    put_le(ph + 32, sizeof(img), 8);      // p_filesz
+    put_le(ph + 40, PGSIZE, 8);           // p_memsz
+
+    int fd = syscall(SYS_open, (long)"bad.elf", O_CREAT | O_RDWR | O_TRUNC);
+    syscall(SYS_write, fd, (long)img, sizeof(img));
+    syscall(SYS_close, fd);
+
+    char *argv[] = {"bad.elf", 0};
+    long ret = syscall(SYS_exec, (long)"bad.elf", (long)argv);
+    syscall(SYS_unlink, (long)"bad.elf");
+
+    // 失败的 exec 不影响当前映像, 系统调用 (经由跳板页) 仍然可用
+    if (ret != -1)
+        syscall(SYS_copyinstr, (long)"[FAIL] exec accepted a segment over the trampoline");
+    else if (syscall(SYS_getpid) <= 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] process broken after rejected exec");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] exec rejects reserved VAs");
+}
+
+// 同一个静态映像: 带 PT_INTERP 时 exec 失败且当前进程不受影响;
+// 把该项改成 PT_NULL 后在子进程里 exec 成功, 映像里的三条指令以 exit(7) 结束
+void test_exec_interp(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] exec rejects PT_INTERP");
+
+    const unsigned long base = 0x10000;
+    unsigned char img[64 + 2 * 56 + 12] = {0};
+    unsigned char *code = img + 64 + 2 * 56;
+    img[0] = 0x7f; img[1] = 'E'; img[2] = 'L'; img[3] = 'F';
+    img[4] = 2; img[5] = 1; img[6] = 1;   // ELFCLASS64, little endian, EV_CURRENT
+    put_le(img + 16, 2, 2);               // ET_EXEC
+    put_le(img + 18, 243, 2);             // EM_RISCV
+    put_le(img + 24, base + (code - img), 8); // e_entry
+    put_le(img + 32, 64, 8);              // e_phoff
+    put_le(img + 54, 56, 2);              // e_phentsize
+    put_le(img + 56, 2, 2);               // e_phnum
+    unsigned char *interp = img + 64, *load = img + 64 + 56;
+    put_le(interp + 0, 3, 4);             // PT_INTERP
+    put_le(load + 0, 1, 4);               // PT_LOAD
+    put_le(load + 4, 5, 4);               // R + X
+    put_le(load + 16, base, 8);           // p_vaddr
+    put_le(load + 32, sizeof(img), 8);    // p_filesz
+    put_le(load + 40, PGSIZE, 8);         // p_memsz
+    put_le(code + 0, 0x00700513, 4);      // li a0, 7
+    put_le(code + 4, (SYS_exit << 20) | 0x893, 4); // li a7, SYS_exit
+    put_le(code + 8, 0x00000073, 4);      // ecall
+
+    char *argv[] = {"interp.elf", 0};
+    int fd = syscall(SYS_open, (long)"interp.elf", O_CREAT | O_RDWR | O_TRUNC);
+    syscall(SYS_write, fd, (long)img, sizeof(img));
+    syscall(SYS_close, fd);
+    long ret = syscall(SYS_exec, (long)"interp.elf", (long)argv);
+
+    put_le(interp + 0, 0, 4);             // PT_NULL
+    fd = syscall(SYS_open, (long)"interp.elf", O_CREAT | O_RDWR | O_TRUNC);
+    syscall(SYS_write, fd, (long)img, sizeof(img));
+    syscall(SYS_close, fd);
+    int status = 0;
+    int pid = syscall(SYS_fork);
+    if (pid == 0) {
+        syscall(SYS_exec, (long)"interp.elf", (long)argv);
+        syscall(SYS_exit, 9);
+    }
+    syscall(SYS_wait, (long)&status);
+    syscall(SYS_unlink, (long)"interp.elf");
+
+    if (ret != -1)
+        syscall(SYS_copyinstr, (long)"[FAIL] exec accepted an ELF with PT_INTERP");
+    else if (!WIFEXITED(status) || WEXITSTATUS(status) != 7)
+        syscall(SYS_copyinstr, (long)"[FAIL] static ELF without PT_INTERP did not run");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] exec rejects PT_INTERP");
+}
+
+// 睡眠登记截止时间, 时钟按截止时间提前触发, 亚节拍的睡眠也不必轮询
+void test_nanosleep(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] nanosleep");
+
+    if (syscall(SYS_nanosleep, 500000L) != 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] nanosleep(500us) failed");
+        return;
+    }
+
+    long t0 = syscall(SYS_uptime);
+    syscall(SYS_nanosleep, 300000000L);
+    long t1 = syscall(SYS_uptime);
+
+    // uptime 按 100ms 节拍计, 至少要跨过两个节拍
+    if (t1 - t0 < 200)
+        syscall(SYS_copyinstr, (long)"[FAIL] nanosleep(300ms) returned early");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] nanosleep");
+}
+
+// 系统调用体开中断执行: 逐页检查一大段地址的 mincore 在内核里一直占着 CPU,
+// 期间到来的时钟中断记在 stime 上; 关中断执行时 stime 永远不会增长
+static unsigned char irq_vec[16384];
+
+void test_syscall_irq(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] timer interrupts during syscalls");
+
+    unsigned long before[2], after[2];
+    if (syscall(SYS_times, (long)before) != 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] times() failed");
+        return;
+    }
+    for (int i = 0; i < 200; i++) {
+        syscall(SYS_mincore, 0, sizeof(irq_vec) * PGSIZE, (long)irq_vec);
+        syscall(SYS_times, (long)after);
+        if (after[1] > before[1]) {
+            syscall(SYS_copyinstr, (long)"[PASS] timer interrupts during syscalls");
+            return;
+        }
+    }
+    syscall(SYS_copyinstr, (long)"[FAIL] no timer interrupt taken inside a syscall");
+}
+
+void test_fsync(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] fsync");
+
+    int fd = syscall(SYS_open, (long)"fsync_file", O_CREAT | O_RDWR | O_TRUNC);
+    if (fd < 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] open fsync_file failed");
+        return;
+    }
+    if (syscall(SYS_write, fd, (long)"durable", 7) != 7 || syscall(SYS_fsync, fd) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] fsync on a regular file failed");
+    syscall(SYS_close, fd);
+    syscall(SYS_unlink, (long)"fsync_file");
+    // sync 写回全部脏块 (包括 unlink 留下的元数据), 之后再 flush 没有块可写
+    if (syscall(SYS_sync) != 0 || syscall(SYS_flush_buffer, 0) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] sync left dirty blocks");
+
+    // 管道和未打开的 fd 没有可写回的块
+    int fds[2];
+    if (syscall(SYS_pipe, (long)fds) == 0) {
+        if (syscall(SYS_fsync, fds[0]) != -1)
+            syscall(SYS_copyinstr, (long)"[FAIL] fsync on a pipe succeeded");
+        syscall(SYS_close, fds[0]);
+        syscall(SYS_close, fds[1]);
+    }
+    if (syscall(SYS_fsync, 15) != -1)
+        syscall(SYS_copyinstr, (long)"[FAIL] fsync on a closed fd succeeded");
+
+    syscall(SYS_copyinstr, (long)"[PASS] fsync");
+}
+
+// 内核还没有线程, gettid 与 getpid 返回同一个值; 这里只检查系统调用号可用且 fork 出的子进程取到自己的 pid.
+// 同一进程两个线程 pid 相同而 tid 不同的测试要等线程创建落地
+void test_gettid(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] gettid");
+
+    long pid = syscall(SYS_getpid);
+    if (syscall(SYS_gettid) != pid) {
+        syscall(SYS_copyinstr, (long)"[FAIL] main thread tid differs from pid");
+        return;
+    }
+    int status = 0;
+    int child = syscall(SYS_fork);
+    if (child == 0) {
+        long tid = syscall(SYS_gettid);
+        syscall(SYS_exit, tid == syscall(SYS_getpid) && tid != pid ? 0 : 1);
+    }
+    syscall(SYS_wait, (long)&status);
+    if (!WIFEXITED(status) || WEXITSTATUS(status) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] child tid does not follow its own pid");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] gettid");
+}
+
+static unsigned long rdtime(void) {
+    unsigned long t;
+    asm volatile("rdtime %0" : "=r"(t));
+    return t;
+}
+
+// 连续发起大量系统调用: 每次返回都要回到正确的进程和 hart, 返回值和被调用者保存的寄存器不变;
+// 子进程同时进行, 可能在别的 hart 上返回。最后打印每次调用平均的 time 计数
+static int syscall_storm(long n) {
+    long pid = syscall(SYS_getpid);
+    long sum = 0;
+    for (long i = 0; i < n; i++) {
+        if (syscall(SYS_getpid) != pid) return 1;
+        sum += i;
+    }
+    return sum == n * (n - 1) / 2 ? 0 : 2;
+}
+
+void test_syscall_storm(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] syscall storm");
+
+    const long n = 20000;
+    int status = 0;
+    int child = syscall(SYS_fork);
+    if (child == 0)
+        syscall(SYS_exit, syscall_storm(n));
+
+    unsigned long t0 = rdtime();
+    int rv = syscall_storm(n);
+    unsigned long t1 = rdtime();
+    syscall(SYS_wait, (long)&status);
+    if (rv != 0 || !WIFEXITED(status) || WEXITSTATUS(status) != 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] wrong result after syscall return");
+        return;
+    }
+    syscall(SYS_copyinstr, (long)"[INFO] time ticks per getpid:");
+    syscall(SYS_print_int, (t1 - t0) / n);
+    syscall(SYS_copyinstr, (long)"\n[PASS] syscall storm");
+}
+
+// 父进程经管道写入多段数据, 子进程读到 EOF 后比对内容并用退出码汇报结果
+void test_pipe(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] pipe");
+
+    int fds[2];
+    if (syscall(SYS_pipe, (long)fds) != 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] pipe() failed");
+        return;
+    }
+
+    // 超过管道容量, 写者必须在管道满时阻塞等待读者
+    static char msg[1500];
+    for (int i = 0; i < (int)sizeof(msg); i++) msg[i] = (char)('a' + i % 26);
+
+    int pid = syscall(SYS_fork);
+    if (pid == 0) {
+        syscall(SYS_close, fds[1]);
+        static char got[sizeof(msg) + 16];
+        int total = 0, n;
+        while ((n = syscall(SYS_read, fds[0], (long)(got + total), sizeof(got) - total)) > 0)
+            total += n;
+        int ok = n == 0 && total == (int)sizeof(msg);
+        for (int i = 0; ok && i < total; i++) ok = got[i] == msg[i];
+        syscall(SYS_close, fds[0]);
+        syscall(SYS_exit, ok ? 0 : 1);
+    }
+
+    syscall(SYS_close, fds[0]);
+    long w1 = syscall(SYS_write, fds[1], (long)msg, 100);
+    long w2 = syscall(SYS_write, fds[1], (long)(msg + 100), sizeof(msg) - 100);
+    syscall(SYS_close, fds[1]);
+
+    int code = 0;
+    syscall(SYS_wait, (long)&code);
+    if (w1 != 100 || w2 != (long)sizeof(msg) - 100)
+        syscall(SYS_copyinstr, (long)"[FAIL] pipe write was short");
+    else if (!WIFEXITED(code) || WEXITSTATUS(code) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] pipe reader saw wrong data or no EOF");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] pipe");
+
+    // 读端全部关闭后写入报错
+    if (syscall(SYS_pipe, (long)fds) == 0) {
+        syscall(SYS_close, fds[0]);
+        if (syscall(SYS_write, fds[1], (long)"x", 1) != -1)
+            syscall(SYS_copyinstr, (long)"[FAIL] write to pipe without readers succeeded");
+        syscall(SYS_close, fds[1]);
+    }
+}
+
+// 双向通信: 两个管道各自只保留一端, 关闭不用的一端后对方才能看到 EOF
+void test_pipe_fork(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] pipe across fork");
+
+    int down[2], up[2];
+    if (syscall(SYS_pipe, (long)down) != 0 || syscall(SYS_pipe, (long)up) != 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] pipe() failed");
+        return;
+    }
+
+    int pid = syscall(SYS_fork);
+    if (pid == 0) {
+        syscall(SYS_close, down[1]);
+        syscall(SYS_close, up[0]);
+        char buf[8];
+        int n = syscall(SYS_read, down[0], (long)buf, sizeof(buf));
+        if (n == 4 && buf[0] == 'p' && buf[1] == 'i' && buf[2] == 'n' && buf[3] == 'g')
+            syscall(SYS_write, up[1], (long)"pong", 4);
+        // 父进程关闭写端后这里读到 EOF
+        n = syscall(SYS_read, down[0], (long)buf, sizeof(buf));
+        syscall(SYS_exit, n == 0 ? 0 : 1);
+    }
+
+    syscall(SYS_close, down[0]);
+    syscall(SYS_close, up[1]);
+    syscall(SYS_write, down[1], (long)"ping", 4);
+    char buf[8];
+    int n = syscall(SYS_read, up[0], (long)buf, sizeof(buf));
+    syscall(SYS_close, down[1]);
+    // 子进程退出后写端全部关闭
+    int code = 0;
+    syscall(SYS_wait, (long)&code);
+    int eof = syscall(SYS_read, up[0], (long)buf, sizeof(buf));
+    syscall(SYS_close, up[0]);
+
+    if (n != 4 || buf[0] != 'p' || buf[1] != 'o' || buf[2] != 'n' || buf[3] != 'g')
+        syscall(SYS_copyinstr, (long)"[FAIL] parent did not receive pong");
+    else if (!WIFEXITED(code) || WEXITSTATUS(code) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] child did not see EOF on its pipe");
+    else if (eof != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] parent did not see EOF after child exit");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] pipe across fork");
+}
+
+static int streq(const char *a, const char *b) {
+    while (*a && *a == *b) { a++; b++; }
+    return *a == *b;
+}
+
+// exec 后的检查: argv[2] 是带 O_CLOEXEC 的 fd, argv[3] 是普通 fd (单个字符 'A' + fd)
+static int cloexec_check(char **argv) {
+    struct stat st;
+    int closed_fd = argv[2][0] - 'A', kept_fd = argv[3][0] - 'A';
+    if (syscall(SYS_fstat, closed_fd, (long)&st) != -1) return 1;
+    if (syscall(SYS_fstat, kept_fd, (long)&st) != 0) return 2;
+    if (syscall(SYS_write, kept_fd, (long)"ok", 2) != 2) return 3;
+    return 0;
+}
+
+void test_cloexec(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] O_CLOEXEC");
+
+    int status = 0;
+    int pid = syscall(SYS_fork);
+    if (pid == 0) {
+        int closed_fd = syscall(SYS_open, (long)"cloexec_a", O_CREAT | O_RDWR | O_CLOEXEC);
+        int kept_fd = syscall(SYS_open, (long)"cloexec_b", O_CREAT | O_RDWR | O_TRUNC);
+        if (closed_fd < 0 || kept_fd < 0) syscall(SYS_exit, 8);
+        char a[2] = {(char)('A' + closed_fd), 0}, b[2] = {(char)('A' + kept_fd), 0};
+        char *argv[] = {"hello", "cloexec", a, b, 0};
+        syscall(SYS_exec, (long)"/hello", (long)argv);
+        syscall(SYS_exit, 9);
+    }
+    syscall(SYS_wait, (long)&status);
+
+    struct stat st;
+    int fd = syscall(SYS_open, (long)"cloexec_b", O_RDONLY);
+    int ok = fd >= 0 && syscall(SYS_fstat, fd, (long)&st) == 0 && st.size == 2;
+    if (fd >= 0) syscall(SYS_close, fd);
+    syscall(SYS_unlink, (long)"cloexec_a");
+    syscall(SYS_unlink, (long)"cloexec_b");
+
+    if (!WIFEXITED(status) || WEXITSTATUS(status) == 9)
+        syscall(SYS_copyinstr, (long)"[FAIL] exec of /hello failed");
+    else if (WEXITSTATUS(status) == 1)
+        syscall(SYS_copyinstr, (long)"[FAIL] O_CLOEXEC fd survived exec");
+    else if (WEXITSTATUS(status) != 0 || !ok)
+        syscall(SYS_copyinstr, (long)"[FAIL] plain fd was not kept across exec");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] O_CLOEXEC");
+}
+
+void test_uptime(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] uptime");
+
+    long t0 = syscall(SYS_uptime);
+    syscall(SYS_sleep, 5);
+    long t1 = syscall(SYS_uptime);
+
+    // 5 个 100ms 节拍, 调度延迟留出余量
+    if (t1 - t0 < 400 || t1 - t0 > 1500)
+        syscall(SYS_copyinstr, (long)"[FAIL] uptime did not advance by about 500ms");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] uptime");
+}
+
+// 时间戳以秒计, 时钟节拍为 100ms, 睡 15 个节拍保证跨过一秒
+void test_timestamps(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] inode timestamps");
+
+    struct stat st1, st2;
+    int fd = syscall(SYS_open, (long)"ts_file", O_CREAT | O_RDWR | O_TRUNC);
+    if (fd < 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] timestamps: open failed");
+        return;
+    }
+    syscall(SYS_write, fd, (long)"v1", 2);
+    syscall(SYS_fstat, fd, (long)&st1);
+
+    syscall(SYS_sleep, 15);
+    syscall(SYS_write, fd, (long)"v2", 2);
+    syscall(SYS_fstat, fd, (long)&st2);
+    syscall(SYS_close, fd);
+    syscall(SYS_unlink, (long)"ts_file");
+
+    if (st2.mtime <= st1.mtime)
+        syscall(SYS_copyinstr, (long)"[FAIL] mtime did not advance after write");
+    else if (st2.ctime < st2.mtime)
+        syscall(SYS_copyinstr, (long)"[FAIL] ctime older than mtime");
+    else if (st1.atime > st1.mtime)
+        syscall(SYS_copyinstr, (long)"[FAIL] atime ahead of mtime on an unread file");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] inode timestamps");
+}
+
+void test_madvise(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] madvise(DONTNEED)");
+
+    const int npages = 4;
+    unsigned char vec[4];
+    char *base = (char *)syscall(SYS_mmap, 0, npages * PGSIZE, 0);
+    if ((long)base == -1) {
+        syscall(SYS_copyinstr, (long)"[FAIL] madvise: mmap failed");
+        return;
+    }
+    for (int i = 0; i < npages * PGSIZE; i++) base[i] = 0x5a;
+
+    if (syscall(SYS_madvise, (long)base, npages * PGSIZE, MADV_DONTNEED) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] madvise returned error");
+
+    // 物理页已经归还, 重新读取得到零
+    syscall(SYS_mincore, (long)base, npages * PGSIZE, (long)vec);
+    int ok = 1;
+    for (int i = 0; i < npages; i++) if (vec[i] != 0) ok = 0;
+    for (int i = 0; i < npages * PGSIZE; i++) if (base[i] != 0) ok = 0;
+    if (!ok) syscall(SYS_copyinstr, (long)"[FAIL] madvise: pages not dropped");
+
+    // 不属于任何 mmap 区域的范围 (栈) 应被拒绝
+    if (syscall(SYS_madvise, (long)vec & ~(long)(PGSIZE - 1), PGSIZE, MADV_DONTNEED) == 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] madvise accepted a range outside the mapping");
+
+    syscall(SYS_munmap, (long)base, npages * PGSIZE);
+    if (ok) syscall(SYS_copyinstr, (long)"[PASS] madvise(DONTNEED)");
+}
+
+// 系统调用把结果写进一个还没碰过的 mmap 页 (共享零页上的写时复制页), 内核应先拆分再写入
+void test_copyout_cow(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] copyout into a copy-on-write page");
+
+    unsigned char vec[1] = {1};
+    int *fds = (int *)syscall(SYS_mmap, 0, PGSIZE, 0);
+    if ((long)fds == -1) {
+        syscall(SYS_copyinstr, (long)"[FAIL] copyout cow: mmap failed");
+        return;
+    }
+    if (syscall(SYS_mincore, (long)fds, PGSIZE, (long)vec) != 0 || vec[0] != 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] copyout cow: page resident before the syscall");
+        syscall(SYS_munmap, (long)fds, PGSIZE);
+        return;
+    }
+    int ok = syscall(SYS_pipe, (long)fds) == 0 && fds[0] >= 0 && fds[1] >= 0;
+    ok = ok && syscall(SYS_mincore, (long)fds, PGSIZE, (long)vec) == 0 && vec[0] == 1;
+    if (ok) {
+        syscall(SYS_close, fds[0]);
+        syscall(SYS_close, fds[1]);
+    }
+    syscall(SYS_munmap, (long)fds, PGSIZE);
+    if (ok)
+        syscall(SYS_copyinstr, (long)"[PASS] copyout into a copy-on-write page");
+    else
+        syscall(SYS_copyinstr, (long)"[FAIL] copyout cow: pipe fds not written to a private page");
+}
+
+void test_exit_status(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] exit status encoding");
+
+    int status = 0;
+    int pid = syscall(SYS_fork);
+    if (pid == 0) syscall(SYS_exit, 42);
+    syscall(SYS_wait, (long)&status);
+    if (!WIFEXITED(status) || WEXITSTATUS(status) != 42)
+        syscall(SYS_copyinstr, (long)"[FAIL] exit(42) not decoded by WEXITSTATUS");
+
+    // 访问未映射地址: 子进程被 SIGSEGV 杀死, 内核继续运行
+    pid = syscall(SYS_fork);
+    if (pid == 0) {
+        *(volatile int *)0x1000000000UL = 1;
+        syscall(SYS_exit, 0);
+    }
+    syscall(SYS_wait, (long)&status);
+    if (!WIFSIGNALED(status) || WTERMSIG(status) != SIGSEGV)
+        syscall(SYS_copyinstr, (long)"[FAIL] faulting child not reported as SIGSEGV");
+
+    syscall(SYS_copyinstr, (long)"[PASS] exit status encoding");
+}
+
+// waitid 只查看不回收: 子进程退出前返回 0, 退出后多次查看都是同一个状态, 之后 wait 照常回收
+void test_waitid(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] waitid");
+
+    int fds[2];
+    if (syscall(SYS_pipe, (long)fds) != 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] waitid: pipe failed");
+        return;
+    }
+    int pid = syscall(SYS_fork);
+    if (pid == 0) {
+        char c;
+        syscall(SYS_close, fds[1]);
+        syscall(SYS_read, fds[0], (long)&c, 1);
+        syscall(SYS_exit, 5);
+    }
+    syscall(SYS_close, fds[0]);
+
+    int status = 0;
+    int ok = syscall(SYS_waitid, pid, (long)&status) == 0;
+    ok = ok && syscall(SYS_waitid, syscall(SYS_getpid), (long)&status) == -1;
+    syscall(SYS_write, fds[1], (long)"x", 1);
+    syscall(SYS_close, fds[1]);
+
+    long rv = 0;
+    for (int i = 0; i < 100 && rv == 0; i++) {
+        rv = syscall(SYS_waitid, pid, (long)&status);
+        if (rv == 0) syscall(SYS_sleep, 1);
+    }
+    ok = ok && rv == pid && WIFEXITED(status) && WEXITSTATUS(status) == 5;
+    // 槽位没有被回收: 再看一次还是同一个状态; 之前的测试可能留下别的子进程, wait 到它为止
+    status = 0;
+    ok = ok && syscall(SYS_waitid, pid, (long)&status) == pid && WEXITSTATUS(status) == 5;
+    status = 0;
+    do rv = syscall(SYS_wait, (long)&status); while (rv != pid && rv != -1);
+    ok = ok && rv == pid && WEXITSTATUS(status) == 5;
+    ok = ok && syscall(SYS_waitid, pid, 0) == -1;
+    if (!ok)
+        syscall(SYS_copyinstr, (long)"[FAIL] waitid");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] waitid");
+}
+
+// 读地址 4: 内核打印 "null pointer dereference at 0x4" 并以 SIGSEGV 结束子进程, 父进程照常运行
+void test_null_deref(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] null pointer dereference");
+
+    int status = 0;
+    int pid = syscall(SYS_fork);
+    if (pid == 0) {
+        int v = *(volatile int *)4UL;
+        syscall(SYS_exit, v == 0 ? 1 : 2);
+    }
+    syscall(SYS_wait, (long)&status);
+    if (!WIFSIGNALED(status) || WTERMSIG(status) != SIGSEGV)
+        syscall(SYS_copyinstr, (long)"[FAIL] null read did not kill the child with SIGSEGV");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] null pointer dereference");
+}
+
+#define ICACHE_FILES 30
+
+static void icache_name(char *buf, int i) {
+    const char *prefix = "icache_";
+    int n = 0;
+    while (prefix[n]) { buf[n] = prefix[n]; n++; }
+    buf[n++] = '0' + i / 10;
+    buf[n++] = '0' + i % 10;
+    buf[n] = 0;
+}
+
+// 父子进程共打开 60 个不同文件, 超过 inode 缓存 (N_INODE = 50) 时 open 应返回 -1 而不是让内核 panic
+void test_inode_cache(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] inode cache exhaustion");
+
+    char name[16];
+    int fds[ICACHE_FILES];
+    for (int i = 0; i < ICACHE_FILES; i++) {
+        icache_name(name, i);
+        fds[i] = syscall(SYS_open, (long)name, O_CREAT | O_RDWR);
+    }
+
+    int status = 0;
+    int pid = syscall(SYS_fork);
+    if (pid == 0) {
+        int failed = 0;
+        int cfds[ICACHE_FILES];
+        for (int i = 0; i < ICACHE_FILES; i++) {
+            icache_name(name, ICACHE_FILES + i);
+            cfds[i] = syscall(SYS_open, (long)name, O_CREAT | O_RDWR);
+            if (cfds[i] < 0) failed++;
+        }
+        for (int i = 0; i < ICACHE_FILES; i++) if (cfds[i] >= 0) syscall(SYS_close, cfds[i]);
+        syscall(SYS_exit, failed > 0 ? 0 : 1);
+    }
+    syscall(SYS_wait, (long)&status);
+
+    for (int i = 0; i < ICACHE_FILES; i++) if (fds[i] >= 0) syscall(SYS_close, fds[i]);
+
+    // 缓存释放后应能再次打开
+    icache_name(name, 2 * ICACHE_FILES - 1);
+    int fd = syscall(SYS_open, (long)name, O_CREAT | O_RDWR);
+    if (fd >= 0) syscall(SYS_close, fd);
+
+    for (int i = 0; i < 2 * ICACHE_FILES; i++) {
+        icache_name(name, i);
+        syscall(SYS_unlink, (long)name);
+    }
+
+    if (!WIFEXITED(status) || WEXITSTATUS(status) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] open past the inode cache limit did not fail cleanly");
+    else if (fd < 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] open failed after inode cache was released");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] inode cache exhaustion");
+}
+void test_dentry_names(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] dentry name validation");
+
+    char name60[61], name61[62];
+    for (int i = 0; i < 61; i++) name61[i] = 'n';
+    name61[61] = 0;
+    for (int i = 0; i < 60; i++) name60[i] = 'n';
+    name60[60] = 0;
+
+    // 斜杠只能通过 dentry_create 直接传入, 路径里的斜杠会被当作分隔符
+    int ok = syscall(SYS_dentry_create, 0, 0, (long)"a/b") < 0;
+    const char *bad[] = {"", ".", "..", name61};
+    for (int i = 0; i < 4; i++) {
+        int fd = syscall(SYS_open, (long)bad[i], O_CREAT | O_RDWR);
+        if (fd >= 0) {
+            ok = 0;
+            syscall(SYS_close, fd);
+        }
+    }
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] invalid name accepted");
+        return;
+    }
+
+    int fd = syscall(SYS_open, (long)name60, O_CREAT | O_RDWR);
+    if (fd < 0) {
+        syscall(SYS_copyinstr, (long)"[FAIL] 60-char name rejected");
+        return;
+    }
+    syscall(SYS_close, fd);
+    syscall(SYS_unlink, (long)name60);
+    syscall(SYS_copyinstr, (long)"[PASS] dentry name validation");
+}
+// 读出文件的前 n 字节与 want 比较, 文件不存在或内容不同返回 0
+static int file_has(const char *path, const char *want, int n) {
+    char buf[16] = {0};
+    int fd = syscall(SYS_open, (long)path, O_RDONLY);
+    if (fd < 0) return 0;
+    int got = syscall(SYS_read, fd, (long)buf, n);
+    syscall(SYS_close, fd);
+    if (got != n) return 0;
+    for (int i = 0; i < n; i++)
+        if (buf[i] != want[i]) return 0;
+    return 1;
+}
+
+void test_rename(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] rename");
+
+    int fd = syscall(SYS_open, (long)"rn_a", O_CREAT | O_RDWR | O_TRUNC);
+    syscall(SYS_write, fd, (long)"abc", 3);
+    syscall(SYS_close, fd);
+    if (syscall(SYS_rename, (long)"rn_a", (long)"rn_b") != 0 || syscall(SYS_open, (long)"rn_a", O_RDONLY) >= 0 ||
+        !file_has("rn_b", "abc", 3)) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rename in one directory");
+        return;
+    }
+    // 同一个目录项: 什么也不做
+    if (syscall(SYS_rename, (long)"rn_b", (long)"rn_b") != 0 || !file_has("rn_b", "abc", 3)) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rename onto itself");
+        return;
+    }
+    if (syscall(SYS_rename, (long)"rn_missing", (long)"rn_x") != -1) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rename of a missing file succeeded");
+        return;
+    }
+
+    // 覆盖已有文件
+    fd = syscall(SYS_open, (long)"rn_c", O_CREAT | O_RDWR | O_TRUNC);
+    syscall(SYS_write, fd, (long)"old", 3);
+    syscall(SYS_close, fd);
+    if (syscall(SYS_rename, (long)"rn_b", (long)"rn_c") != 0 || syscall(SYS_open, (long)"rn_b", O_RDONLY) >= 0 ||
+        !file_has("rn_c", "abc", 3)) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rename over an existing file");
+        return;
+    }
+
+    // 跨目录移动; 目录不能移进自己的子树, 也不能覆盖非空目录或被文件覆盖
+    syscall(SYS_mkdir, (long)"rn_dir");
+    syscall(SYS_mkdir, (long)"rn_dir/sub");
+    syscall(SYS_mkdir, (long)"rn_empty");
+    int ok = syscall(SYS_rename, (long)"rn_c", (long)"rn_dir/moved") == 0 && file_has("rn_dir/moved", "abc", 3);
+    ok = ok && syscall(SYS_rename, (long)"rn_dir", (long)"rn_dir/sub/loop") == -1;
+    ok = ok && syscall(SYS_rename, (long)"rn_empty", (long)"rn_dir") == -1;
+    ok = ok && syscall(SYS_rename, (long)"rn_dir/moved", (long)"rn_empty") == -1;
+    ok = ok && syscall(SYS_rename, (long)"rn_empty", (long)"rn_dir/sub/empty") == 0;
+    ok = ok && syscall(SYS_rename, (long)"rn_dir/moved", (long)"moved_back") == 0 && file_has("moved_back", "abc", 3);
+    syscall(SYS_unlink, (long)"moved_back");
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rename across directories");
+        return;
+    }
+
+    // 深层子树的目录照常可以移动, 但仍不能移进自己最深处的子目录
+    char deep[64] = "rn_deep";
+    int len = 7;
+    syscall(SYS_mkdir, (long)deep);
+    for (int i = 0; i < 20; i++) {
+        deep[len++] = '/';
+        deep[len++] = 'd';
+        deep[len] = 0;
+        syscall(SYS_mkdir, (long)deep);
+    }
+    deep[len++] = '/';
+    deep[len++] = 'x';
+    deep[len] = 0;
+    ok = syscall(SYS_rename, (long)"rn_deep", deep) == -1;
+    ok = ok && syscall(SYS_rename, (long)"rn_deep", (long)"rn_dir/deep") == 0;
+    ok = ok && syscall(SYS_rename, (long)"rn_dir/deep", (long)"rn_deep") == 0;
+    for (len -= 2; len >= 7; len -= 2) {
+        deep[len] = 0;
+        syscall(SYS_rmdir, (long)deep);
+    }
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rename of a deep directory");
+        return;
+    }
+    syscall(SYS_copyinstr, (long)"[PASS] rename");
+}
+
+void test_truncate(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] truncate");
+
+    int fd = syscall(SYS_open, (long)"tr_file", O_CREAT | O_RDWR | O_TRUNC);
+    syscall(SYS_write, fd, (long)"abcdefgh", 8);
+    syscall(SYS_close, fd);
+
+    // 变长: 原内容不变, 后面的空洞读出为零
+    struct stat st;
+    char buf[8];
+    fd = syscall(SYS_open, (long)"tr_file", O_RDONLY);
+    int ok = syscall(SYS_truncate, (long)"tr_file", 9000) == 0 && syscall(SYS_fstat, fd, (long)&st) == 0 &&
+             st.size == 9000 && file_has("tr_file", "abcdefgh", 8);
+    syscall(SYS_lseek, fd, 8990, 0);
+    ok = ok && syscall(SYS_read, fd, (long)buf, 8) == 8;
+    for (int i = 0; ok && i < 8; i++)
+        if (buf[i] != 0) ok = 0;
+    syscall(SYS_close, fd);
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] truncate to a larger size");
+        return;
+    }
+
+    // 变短后再变长, 截掉的部分读出为零
+    fd = syscall(SYS_open, (long)"tr_file", O_RDONLY);
+    ok = syscall(SYS_truncate, (long)"tr_file", 3) == 0 && syscall(SYS_fstat, fd, (long)&st) == 0 && st.size == 3 &&
+         file_has("tr_file", "abc", 3);
+    ok = ok && syscall(SYS_truncate, (long)"tr_file", 8) == 0 && file_has("tr_file", "abc\0\0\0\0\0", 8);
+    syscall(SYS_close, fd);
+    syscall(SYS_unlink, (long)"tr_file");
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] truncate to a smaller size");
+        return;
+    }
+
+    syscall(SYS_mkdir, (long)"tr_dir");
+    ok = syscall(SYS_truncate, (long)"tr_dir", 0) == -1 && syscall(SYS_truncate, (long)"tr_missing", 0) == -1;
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] truncate of a directory or missing file succeeded");
+        return;
+    }
+    syscall(SYS_copyinstr, (long)"[PASS] truncate");
+}
+
+// 目录的链接数: 自身的 . 和父目录中的名字, 再加每个子目录的 ..
+static int dir_nlink(const char *path) {
+    struct stat st;
+    int fd = syscall(SYS_open, (long)path, O_RDONLY);
+    if (fd < 0) return -1;
+    int ok = syscall(SYS_fstat, fd, (long)&st) == 0;
+    syscall(SYS_close, fd);
+    return ok ? st.nlink : -1;
+}
+
+void test_rmdir(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] rmdir");
+
+    syscall(SYS_mkdir, (long)"rd_dir");
+    syscall(SYS_mkdir, (long)"rd_dir/sub");
+    int fd = syscall(SYS_open, (long)"rd_dir/file", O_CREAT | O_RDWR);
+    syscall(SYS_close, fd);
+    int ok = fd >= 0 && dir_nlink("rd_dir") == 3;
+    // 还有文件或子目录时不能删除
+    ok = ok && syscall(SYS_rmdir, (long)"rd_dir") == -1;
+    ok = ok && syscall(SYS_unlink, (long)"rd_dir/file") == 0 && syscall(SYS_rmdir, (long)"rd_dir") == -1;
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rmdir of a non-empty directory");
+        return;
+    }
+
+    ok = syscall(SYS_rmdir, (long)"rd_dir/sub") == 0 && dir_nlink("rd_dir") == 2;
+    ok = ok && syscall(SYS_rmdir, (long)"rd_dir") == 0 && syscall(SYS_open, (long)"rd_dir", O_RDONLY) == -1;
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rmdir of an empty directory");
+        return;
+    }
+
+    fd = syscall(SYS_open, (long)"rd_file", O_CREAT | O_RDWR);
+    syscall(SYS_close, fd);
+    ok = syscall(SYS_rmdir, (long)"rd_file") == -1 && syscall(SYS_rmdir, (long)"rd_missing") == -1;
+    syscall(SYS_unlink, (long)"rd_file");
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] rmdir of a file or missing path succeeded");
+        return;
+    }
+    syscall(SYS_copyinstr, (long)"[PASS] rmdir");
+}
+
+// 路径对应的 inode 号, 不存在时返回 -1
+static int path_inum(const char *path) {
+    struct stat st;
+    int fd = syscall(SYS_open, (long)path, O_RDONLY);
+    if (fd < 0) return -1;
+    int ok = syscall(SYS_fstat, fd, (long)&st) == 0;
+    syscall(SYS_close, fd);
+    return ok ? (int)st.inum : -1;
+}
+
+void test_dot_paths(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] . and .. in paths");
+
+    syscall(SYS_mkdir, (long)"/dp_a");
+    syscall(SYS_mkdir, (long)"/dp_b");
+    // 经由 .. 创建, 经由 . 和多余的 '/' 访问
+    int fd = syscall(SYS_open, (long)"/dp_a/../dp_b/c", O_CREAT | O_RDWR);
+    syscall(SYS_write, fd, (long)"xyz", 3);
+    syscall(SYS_close, fd);
+    int ok = fd >= 0 && file_has("/dp_b/c", "xyz", 3) && file_has("dp_a//..//dp_b/./c", "xyz", 3);
+    ok = ok && path_inum("/..") == path_inum("/") && path_inum("/../../dp_a/") == path_inum("/dp_a");
+    ok = ok && syscall(SYS_chdir, (long)"/dp_b") == 0 && file_has("./c", "xyz", 3) &&
+         path_inum("..") == path_inum("/") && path_inum("../dp_a/..") == path_inum("/");
+    syscall(SYS_chdir, (long)"/");
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] path with . or .. resolved wrongly");
+        return;
+    }
+
+    // 移到另一个目录后 .. 跟着改变, 只剩 . 和 .. 的目录可以删除
+    ok = syscall(SYS_rename, (long)"/dp_b", (long)"/dp_a/b") == 0 && path_inum("/dp_a/b/..") == path_inum("/dp_a");
+    ok = ok && syscall(SYS_unlink, (long)"/dp_a/b/c") == 0 && syscall(SYS_rmdir, (long)"/dp_a/b") == 0 &&
+         syscall(SYS_rmdir, (long)"/dp_a") == 0;
+    if (!ok) {
+        syscall(SYS_copyinstr, (long)"[FAIL] .. after moving a directory");
+        return;
+    }
+    syscall(SYS_copyinstr, (long)"[PASS] . and .. in paths");
+}
+
+// O_DIRECTORY: 普通文件打开失败 (ENOTDIR), 目录照常打开并可以列出目录项
+void test_o_directory(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] O_DIRECTORY");
+
+    syscall(SYS_mkdir, (long)"/od_dir");
+    int fd = syscall(SYS_open, (long)"/od_dir/f", O_CREAT | O_RDWR);
+    if (fd >= 0) syscall(SYS_close, fd);
+
+    struct dirent de[4];
+    int ffd = syscall(SYS_open, (long)"/od_dir/f", O_RDONLY | O_DIRECTORY);
+    int dfd = syscall(SYS_open, (long)"/od_dir", O_RDONLY | O_DIRECTORY);
+    int cfd = syscall(SYS_open, (long)"/od_dir/g", O_CREAT | O_RDWR | O_DIRECTORY);
+    int n = dfd >= 0 ? syscall(SYS_get_dentries, dfd, (long)de, 4) : -1;
+    int found = 0;
+    for (int i = 0; i < n; i++) found |= streq(de[i].name, "f");
+    if (ffd >= 0) syscall(SYS_close, ffd);
+    if (dfd >= 0) syscall(SYS_close, dfd);
+    if (cfd >= 0) syscall(SYS_close, cfd);
+    syscall(SYS_unlink, (long)"/od_dir/f");
+    syscall(SYS_unlink, (long)"/od_dir/g");
+    syscall(SYS_rmdir, (long)"/od_dir");
+
+    if (fd < 0 || ffd >= 0 || cfd >= 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] O_DIRECTORY opened a regular file");
+    else if (dfd < 0 || !found)
+        syscall(SYS_copyinstr, (long)"[FAIL] O_DIRECTORY on a directory");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] O_DIRECTORY");
+}
+
+// 只读数据中占满整页的常量, 中间一页只有 exec 后被读到时才应从文件装入
+static const char lazy_blob[3 * PGSIZE] __attribute__((aligned(PGSIZE))) = {
+    [PGSIZE] = 'L',
+    [2 * PGSIZE - 1] = 'Z',
+};
+
+// 以 "hello lazy" 被 exec 时运行: 返回 0 表示中间页按需装入
+static int lazy_check(void) {
+    const char *mid = lazy_blob + PGSIZE;
+    unsigned char vec[1] = {1};
+    if (syscall(SYS_mincore, (long)mid, PGSIZE, (long)vec) != 0 || vec[0] != 0) return 1;
+    if (*(volatile const char *)mid != 'L' || *(volatile const char *)(mid + PGSIZE - 1) != 'Z') return 2;
+    if (syscall(SYS_mincore, (long)mid, PGSIZE, (long)vec) != 0 || vec[0] != 1) return 3;
+    return 0;
+}
+
+void test_lazy_exec(void) {
+    syscall(SYS_copyinstr, (long)"[TEST] demand-paged exec");
+
+    int status = 0;
+    int pid = syscall(SYS_fork);
+    if (pid == 0) {
+        char *argv[] = {"hello", "lazy", 0};
+        syscall(SYS_exec, (long)"/hello", (long)argv);
+        syscall(SYS_exit, 9);
+    }
+    syscall(SYS_wait, (long)&status);
+
+    if (!WIFEXITED(status))
+        syscall(SYS_copyinstr, (long)"[FAIL] exec'd child crashed");
+    else if (WEXITSTATUS(status) == 9)
+        syscall(SYS_copyinstr, (long)"[FAIL] exec of /hello failed");
+    else if (WEXITSTATUS(status) != 0)
+        syscall(SYS_copyinstr, (long)"[FAIL] segment page loaded before first access");
+    else
+        syscall(SYS_copyinstr, (long)"[PASS] demand-paged exec");
+}
+
+int main(int argc, char **argv)
 {
+  // 启动进程没有参数, a0 是 TrapFrame 地址, 不会等于 2
+  if (argc == 2 && argv[1][0] == 'l' && argv[1][1] == 'a' && argv[1][2] == 'z' &&
+      argv[1][3] == 'y' && argv[1][4] == 0)
+    syscall(SYS_exit, lazy_check());
+  if (argc == 4 && streq(argv[1], "cloexec"))
+    syscall(SYS_exit, cloexec_check(argv));
+
   syscall(SYS_prepare_root);
 
   lab9_test_1();
   lab9_test_2();
   lab9_test_3();
+  test_symlink();
+  test_trapstat();
+  test_vfork();
+  test_access();
+  test_mincore();
+  test_ebreak();
+  test_madvise();
+  test_copyout_cow();
+  test_exit_status();
+  test_waitid();
+  test_inode_cache();
+  test_dentry_names();
+  test_lazy_exec();
+  test_timestamps();
+  test_uptime();
+  test_exec_reserved_va();
+  test_nanosleep();
+  test_syscall_irq();
+  test_pipe();
+  test_pipe_fork();
+  test_cloexec();
+  test_fsync();
+  test_gettid();
+  test_syscall_storm();
+  test_rename();
+  test_truncate();
+  test_rmdir();
+  test_dot_paths();
+  test_o_directory();
+  test_null_deref();
+  test_exec_interp();
   // lab9_test_4(); // Uncomment to test exec (will restart program)
 
   syscall(SYS_copyinstr, (long)"[ALL PASS] LAB-9 tests completed.");
@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
@@ -72,7 +72,64 @@ enum Cmd {
     /// Show section sizes
     Size,
     /// Generate disk.img
-    Mkfs,
+    Mkfs {
+        #[command(flatten)]
+        geometry: Geometry,
+    },
+    /// Remove files generated by xtask
+    Clean {
+        /// Also remove the kernel's cargo target directory
+        #[arg(long, default_value_t = false)]
+        all: bool,
+    },
+}
+
+// disk.img 的几何参数; 超级块不记录块大小, 内核固定按 4096 字节的块读写 (buffer::BLOCK_SIZE)
+const DEFAULT_INODES: usize = 200;
+const DEFAULT_DATA_BLOCKS: usize = 1000;
+const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+#[derive(Args, Debug, Clone, Copy)]
+struct Geometry {
+    /// Number of on-disk inodes
+    #[arg(long = "inodes", default_value_t = DEFAULT_INODES)]
+    inodes: usize,
+
+    /// Number of data blocks
+    #[arg(long = "data-blocks", default_value_t = DEFAULT_DATA_BLOCKS)]
+    data_blocks: usize,
+
+    /// Block size in bytes (the kernel only supports 4096)
+    #[arg(long = "block-size", default_value_t = DEFAULT_BLOCK_SIZE)]
+    block_size: usize,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self { inodes: DEFAULT_INODES, data_blocks: DEFAULT_DATA_BLOCKS, block_size: DEFAULT_BLOCK_SIZE }
+    }
+}
+
+impl Geometry {
+    fn validate(&self) -> anyhow::Result<()> {
+        // 别的块大小生成的镜像内核无法挂载
+        if self.block_size != DEFAULT_BLOCK_SIZE {
+            return Err(anyhow::anyhow!(
+                "[ ERROR ] block size {} is not supported, the kernel uses {}-byte blocks",
+                self.block_size,
+                DEFAULT_BLOCK_SIZE
+            ));
+        }
+        // inode 位图只有一块, 数据位图按需占多块
+        let bits = self.block_size * 8;
+        if self.inodes == 0 || self.inodes > bits {
+            return Err(anyhow::anyhow!("[ ERROR ] inode count must be in 1..={}", bits));
+        }
+        if self.data_blocks == 0 || self.data_blocks > u32::MAX as usize {
+            return Err(anyhow::anyhow!("[ ERROR ] data block count must be in 1..={}", u32::MAX));
+        }
+        Ok(())
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -83,8 +140,8 @@ fn main() -> anyhow::Result<()> {
         Cmd::Build => build(mode, &xtask.features)?,
         Cmd::Run { cpus, mem, display } => {
             build(mode, &xtask.features)?;
-            mkfs()?;
-            qemu_run(mode, cpus, &mem, &display)?;
+            mkfs(Geometry::default())?;
+            qemu_run(mode, cpus, &mem, &display, "")?;
         }
         Cmd::Gdb { cpus, mem, display, test } => {
             let mut feats = xtask.features.clone();
@@ -94,7 +151,7 @@ fn main() -> anyhow::Result<()> {
                 }
             }
             build(mode, &feats)?;
-            mkfs()?;
+            mkfs(Geometry::default())?;
             qemu_gdb(mode, cpus, &mem, &display)?;
         }
         Cmd::Test { cpus, mem, display } => {
@@ -103,64 +160,105 @@ fn main() -> anyhow::Result<()> {
                 feats.push(String::from("tests"));
             }
             build(mode, &feats)?;
-            mkfs()?;
-            qemu_run(mode, cpus, &mem, &display)?;
+            mkfs(Geometry::default())?;
+            // 测试时放大 inode/块缓存, 检查缓存大小跟随启动参数
+            qemu_run(mode, cpus, &mem, &display, TEST_BOOTARGS)?;
         }
         Cmd::Objdump => objdump(mode)?,
         Cmd::Size => size(mode)?,
-        Cmd::Mkfs => mkfs()?,
+        Cmd::Mkfs { geometry } => mkfs(geometry)?,
+        Cmd::Clean { all } => clean(all)?,
+    }
+    Ok(())
+}
+
+fn clean(all: bool) -> anyhow::Result<()> {
+    let service_dir = Path::new("target").join("service").join("hello");
+    let mut paths = vec![
+        PathBuf::from("disk.img"),
+        Path::new("target").join("proc_payload.rs"),
+        service_dir.join("hello.elf"),
+        service_dir.join("hello.bin"),
+        service_dir.join("hello.dump"),
+    ];
+    if all {
+        paths.push(Path::new("target").join("riscv64gc-unknown-none-elf"));
+    }
+    for path in paths {
+        let removed = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match removed {
+            std::result::Result::Ok(()) => println!("[ INFO ] Removed {}", path.display()),
+            std::result::Result::Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            std::result::Result::Err(e) => {
+                return Err(anyhow::anyhow!("[ ERROR ] failed to remove {}: {}", path.display(), e));
+            }
+        }
     }
     Ok(())
 }
 
-fn mkfs() -> anyhow::Result<()> {
+fn mkfs(geometry: Geometry) -> anyhow::Result<()> {
     use std::fs::File;
     use std::io::{Seek, SeekFrom, Write};
 
     // Parameters
-    const BLOCK_SIZE: usize = 4096;
-    const N_INODES: usize = 200;
-    const N_DATA_BLOCKS: usize = 1000;
+    geometry.validate()?;
+    let block_size = geometry.block_size;
+    let n_inodes = geometry.inodes;
+    let n_data_blocks = geometry.data_blocks;
     const MAGIC: u32 = 0x10203040;
+    const FS_VERSION: u32 = 2;
+    const INODE_SIZE: usize = 80; // On-disk inode size (version 1: + mode, version 2: + atime/mtime/ctime)
 
     // Sizes
     let sb_size = 1;
     let inode_bitmap_size = 1;
 
-    // Inode size 64 bytes
-    const IPB: usize = BLOCK_SIZE / 64;
-    let inode_blocks = (N_INODES + IPB - 1) / IPB;
+    let ipb = block_size / INODE_SIZE;
+    let inode_blocks = (n_inodes + ipb - 1) / ipb;
 
-    let data_bitmap_size = 1;
+    // 数据位图按需占多块, 内核按 nblocks 算出同样的块数
+    let data_bitmap_size = n_data_blocks.div_ceil(block_size * 8);
 
-    let total_blocks =
-        sb_size + inode_bitmap_size + inode_blocks + data_bitmap_size + N_DATA_BLOCKS;
+    // 数据区之后是块校验和区, 每块存 block_size / 8 项, 覆盖包括它自己在内的整个磁盘; 全零表示没有记录
+    let fs_blocks = sb_size + inode_bitmap_size + inode_blocks + data_bitmap_size + n_data_blocks;
+    let csum_blocks = fs_blocks.div_ceil(block_size / 8 - 1);
+    let total_blocks = fs_blocks + csum_blocks;
 
     let inode_region_start = sb_size + inode_bitmap_size;
     let data_bitmap_start = inode_region_start + inode_blocks;
+    let data_start = data_bitmap_start + data_bitmap_size; // absolute block of first data block
 
     println!(
         "[ INFO ] Generating disk.img (Size: {} blocks / {} bytes)",
         total_blocks,
-        total_blocks * BLOCK_SIZE
+        total_blocks * block_size
     );
     println!(
-        "[ INFO ] Layout: SB:0, IBMap:1, IRegions:{}-{}, DBMap:{}, Data:{}...",
+        "[ INFO ] Layout: SB:0, IBMap:1, IRegions:{}-{}, DBMap:{}-{}, Data:{}-{}, Csum:{}-{}",
         inode_region_start,
         inode_region_start + inode_blocks - 1,
         data_bitmap_start,
-        data_bitmap_start + 1
+        data_start - 1,
+        data_start,
+        fs_blocks - 1,
+        fs_blocks,
+        total_blocks - 1
     );
 
     let mut file = File::create("disk.img")?;
 
-    file.set_len((total_blocks * BLOCK_SIZE) as u64)?;
+    file.set_len((total_blocks * block_size) as u64)?;
 
-    let mut sb_buf = [0u8; BLOCK_SIZE];
+    let mut sb_buf = vec![0u8; block_size];
     let magic_bytes = MAGIC.to_le_bytes();
     let size_bytes = (total_blocks as u32).to_le_bytes();
-    let nblocks_bytes = (N_DATA_BLOCKS as u32).to_le_bytes();
-    let ninodes_bytes = (N_INODES as u32).to_le_bytes();
+    let nblocks_bytes = (n_data_blocks as u32).to_le_bytes();
+    let ninodes_bytes = (n_inodes as u32).to_le_bytes();
     let inode_start_bytes = (inode_region_start as u32).to_le_bytes();
     let bmap_start_bytes = (data_bitmap_start as u32).to_le_bytes();
 
@@ -170,6 +268,8 @@ fn mkfs() -> anyhow::Result<()> {
     sb_buf[12..16].copy_from_slice(&ninodes_bytes);
     sb_buf[16..20].copy_from_slice(&inode_start_bytes);
     sb_buf[20..24].copy_from_slice(&bmap_start_bytes);
+    sb_buf[24..28].copy_from_slice(&FS_VERSION.to_le_bytes());
+    sb_buf[28..32].copy_from_slice(&(fs_blocks as u32).to_le_bytes()); // csum_start
 
     file.seek(SeekFrom::Start(0))?;
     file.write_all(&sb_buf)?;
@@ -187,22 +287,19 @@ fn mkfs() -> anyhow::Result<()> {
     }
 
     let mut write_block = |file: &mut File, blk: u64, data: &[u8]| -> anyhow::Result<()> {
-        if data.len() != BLOCK_SIZE { return Err(anyhow::anyhow!("block size mismatch")); }
-        file.seek(SeekFrom::Start(blk * BLOCK_SIZE as u64))?;
+        if data.len() != block_size { return Err(anyhow::anyhow!("block size mismatch")); }
+        file.seek(SeekFrom::Start(blk * block_size as u64))?;
         file.write_all(data)?;
         Ok(())
     };
 
-    let zero_block = || -> [u8; BLOCK_SIZE] { [0u8; BLOCK_SIZE] };
+    let zero_block = || -> Vec<u8> { vec![0u8; block_size] };
 
     // Derived constants for FS content
     const ROOT_INODE: u32 = 0;
     const INODE_INDEX_3: usize = 13; // 10 direct + 2 single indirect + 1 double indirect
     const MAXLEN_FILENAME: usize = 60; // Make dentry 64 bytes total
-    const INODE_SIZE: usize = 64; // On-disk inode size
     const DENTRY_SIZE: usize = 64; // On-disk dentry size
-    let _ipb = BLOCK_SIZE / INODE_SIZE; // inodes per block
-    let data_start = data_bitmap_start + 1; // absolute block of first data block
 
     // Inode bitmap: mark 0,1,2,3 as used
     let mut ibmap = zero_block();
@@ -219,15 +316,16 @@ fn mkfs() -> anyhow::Result<()> {
     } else {
         Vec::new()
     };
-    let elf_blocks = (elf_data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    let elf_blocks = (elf_data.len() + block_size - 1) / block_size;
 
     // Data bitmap: allocate blocks (root dir + 2 files + hello.elf + possible indirect)
     let mut dbmap = zero_block();
-    let hello_start_block = data_bitmap_start + 4;
-    let mut total_data_blocks = 4 + elf_blocks;
+    let hello_start_block = data_start + 3;
+    // 位图中只标记实际引用的块, 否则新镜像上 fsck 会报告泄漏
+    let mut total_data_blocks = 3 + elf_blocks;
     let mut hello_indirect_block = 0;
     if elf_blocks > 10 { // NINDIRECT is 1024, but let's stick to 10 direct for simplicity in mkfs
-         hello_indirect_block = data_bitmap_start + total_data_blocks;
+         hello_indirect_block = data_start + total_data_blocks;
          total_data_blocks += 1;
     }
 
@@ -242,7 +340,7 @@ fn mkfs() -> anyhow::Result<()> {
     let mut inode_block0 = zero_block();
     let mut put_inode = |buf: &mut [u8], slot: usize,
                          typ: u16, major: u16, minor: u16, nlink: u16,
-                         size: u32, indices: &[u32]| {
+                         size: u32, indices: &[u32], mode: u16| {
         let base = slot * INODE_SIZE;
         buf[base + 0..base + 2].copy_from_slice(&typ.to_le_bytes());
         buf[base + 2..base + 4].copy_from_slice(&major.to_le_bytes());
@@ -254,15 +352,18 @@ fn mkfs() -> anyhow::Result<()> {
             let val = if i < indices.len() { indices[i] } else { 0 };
             buf[off..off + 4].copy_from_slice(&val.to_le_bytes());
         }
+        let off = base + 12 + INODE_INDEX_3 * 4;
+        buf[off..off + 2].copy_from_slice(&mode.to_le_bytes());
     };
 
     let root_dir_block = (data_start + 0) as u32;
     let upper_block = (data_start + 1) as u32;
     let lower_block = (data_start + 2) as u32;
 
-    put_inode(&mut inode_block0, 0, 1, 0, 0, 1, 5 * DENTRY_SIZE as u32, &[root_dir_block]);
-    put_inode(&mut inode_block0, 1, 2, 0, 0, 1, BLOCK_SIZE as u32, &[upper_block]);
-    put_inode(&mut inode_block0, 2, 2, 0, 0, 1, BLOCK_SIZE as u32, &[lower_block]);
+    // 根目录的 "." 和 ".." 都指向自己, nlink 为 2
+    put_inode(&mut inode_block0, 0, 1, 0, 0, 2, 5 * DENTRY_SIZE as u32, &[root_dir_block], 0o755);
+    put_inode(&mut inode_block0, 1, 2, 0, 0, 1, block_size as u32, &[upper_block], 0o644);
+    put_inode(&mut inode_block0, 2, 2, 0, 0, 1, block_size as u32, &[lower_block], 0o644);
     
     let mut hello_indices = Vec::new();
     for i in 0..std::cmp::min(elf_blocks, 10) {
@@ -271,7 +372,7 @@ fn mkfs() -> anyhow::Result<()> {
     if hello_indirect_block != 0 {
         hello_indices.push(hello_indirect_block as u32);
     }
-    put_inode(&mut inode_block0, 3, 2, 0, 0, 1, elf_data.len() as u32, &hello_indices);
+    put_inode(&mut inode_block0, 3, 2, 0, 0, 1, elf_data.len() as u32, &hello_indices, 0o755);
 
     write_block(&mut file, inode_region_start as u64, &inode_block0)?;
 
@@ -294,7 +395,7 @@ fn mkfs() -> anyhow::Result<()> {
     // File data blocks
     let mut upper = zero_block();
     let mut lower = zero_block();
-    for i in 0..BLOCK_SIZE {
+    for i in 0..block_size {
         upper[i] = b'A' + (i % 26) as u8;
         lower[i] = b'a' + (i % 26) as u8;
     }
@@ -304,8 +405,8 @@ fn mkfs() -> anyhow::Result<()> {
     // Hello ELF data
     for i in 0..elf_blocks {
         let mut b = zero_block();
-        let start = i * BLOCK_SIZE;
-        let end = std::cmp::min(start + BLOCK_SIZE, elf_data.len());
+        let start = i * block_size;
+        let end = std::cmp::min(start + block_size, elf_data.len());
         b[0..end - start].copy_from_slice(&elf_data[start..end]);
         
         if i < 10 {
@@ -313,7 +414,7 @@ fn mkfs() -> anyhow::Result<()> {
         } else {
             // Indirect logic
             let idx_in_indirect = i - 10;
-            let indirect_off = hello_indirect_block as u64 * BLOCK_SIZE as u64 + idx_in_indirect as u64 * 4;
+            let indirect_off = hello_indirect_block as u64 * block_size as u64 + idx_in_indirect as u64 * 4;
             let data_blk = hello_start_block + i;
             write_block(&mut file, data_blk as u64, &b)?;
             file.seek(SeekFrom::Start(indirect_off))?;
@@ -388,7 +489,9 @@ fn qemu_cmd() -> anyhow::Result<String> {
     Ok(qemu.to_string_lossy().into_owned())
 }
 
-fn qemu_run(mode: &str, cpus: u32, mem: &str, display: &str) -> anyhow::Result<()> {
+const TEST_BOOTARGS: &str = "ninode=128 nbuf=64";
+
+fn qemu_run(mode: &str, cpus: u32, mem: &str, display: &str, bootargs: &str) -> anyhow::Result<()> {
     let elf = elf_path(mode);
     if !elf.exists() {
         return Err(anyhow::anyhow!("[ ERROR ] ELF not found: {}", elf.display()));
@@ -414,6 +517,9 @@ fn qemu_run(mode: &str, cpus: u32, mem: &str, display: &str) -> anyhow::Result<(
     cmd.arg("-drive").arg("file=disk.img,if=none,format=raw,id=x0");
     cmd.arg("-device").arg("virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0");
     cmd.arg("-bios").arg("default").arg("-kernel").arg(elf.to_str().unwrap());
+    if !bootargs.is_empty() {
+        cmd.arg("-append").arg(bootargs);
+    }
     run(&mut cmd)
 }
 